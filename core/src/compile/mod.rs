@@ -29,11 +29,14 @@ use errors::ExpectICE;
 use forktable::ForkTable;
 use position::Positional;
 use semantic::SymbolTable;
+use semantic::infer::Typing;
+use semantic::instances::{ self, Specialisation };
 use ast::{ Node
          , Form
          , DefForm
          , Ident
-         , Function };
+         , Function
+         , Module };
 
 use semantic::annotations::{ ScopedState
                            , Scoped
@@ -214,6 +217,34 @@ impl<'a> Drop for LLVMContext<'a> {
     }
 }
 
+/// Compile a type checked module to LLVM IR.
+///
+/// The module's typeclasses are resolved before any IR is generated, so
+/// that every function it compiles is monomorphic: calls to constrained
+/// functions become calls to their specialised copies, and calls to class
+/// functions become calls to the functions of the instances they use.
+/// `requested` are the specialisations of the module's exported functions
+/// that its importers want, as for `instances::resolve_module()`.
+///
+/// # Returns:
+///   - `Ok` containing the LLVM context holding the module's IR, and the
+///     specialisations of imported functions that the module wants.
+///   - An `Err` with a vector of error messages containing any errors
+///     that occured while resolving typeclasses or during compilation.
+pub fn compile_module<'a>( module: &mut Scoped<'a, Module<'a, ScopedState>>
+                         , typing: &Typing
+                         , requested: Vec<Specialisation>)
+                         -> CompileResult<(LLVMContext<'a>, Vec<Specialisation>)> {
+    let wanted = try!(instances::resolve_module(module, typing, requested));
+    let context = LLVMContext::new(&module.node.name.value);
+    let mut errs: Errors = vec![];
+    for expr in &module.node.body {
+        if let Err(mut e) = expr.to_ir(&context) { errs.append(&mut e) }
+    }
+    try_vec!(errs);
+    Ok((context, wanted))
+}
+
 impl<'a> Compile for Scoped<'a, Form<'a, ScopedState>> {
     fn to_ir(&self, context: &LLVMContext) -> IRResult {
         match **self {
@@ -222,10 +253,24 @@ impl<'a> Compile for Scoped<'a, Form<'a, ScopedState>> {
           , Form::If { .. } => unimplemented!()
           , Form::App (ref form) => unimplemented!()
           , Form::Lambda(ref fun) => unimplemented!()
+          , Form::Data(ref data) => unimplemented!()
+          , Form::Class(_) | Form::Instance(_) =>
+                ice!("typeclasses should be removed by resolution")
           , Form::Logical(ref exp) => unimplemented!()
+          , Form::Bool(ref exp) => unimplemented!()
           , Form::Lit(ref c) => unimplemented!()
           , Form::NameRef(ref form) => unimplemented!()
+          , Form::Sigil(ref sigil) => unimplemented!()
+          , Form::Begin(ref body) => unimplemented!()
+          , Form::Set(ref form) => unimplemented!()
+          , Form::Match(ref form) => unimplemented!()
+          , Form::Field(ref access) => unimplemented!()
+          , Form::With(ref update) => unimplemented!()
           , Form::Num(ref n) => unimplemented!()
+          , Form::Quote(ref data) => unimplemented!()
+          , Form::Quasiquote(ref data) => unimplemented!()
+          , Form::Macro(_) =>
+                ice!("macro definitions should be removed by expansion")
         }
     }
 }
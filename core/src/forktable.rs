@@ -378,6 +378,44 @@ where K: Eq + Hash
                   }
     }
 
+    /// Collapses this level of the table and all of its parents into a
+    /// single level, with no parent.
+    ///
+    /// The new table contains every entry that is visible from this
+    /// level, so it may outlive the levels it was collapsed from.
+    ///
+    /// ```
+    /// # use mnemosyne::forktable::ForkTable;
+    /// let mut level_1: ForkTable<isize,&str> = ForkTable::new();
+    /// level_1.insert(1, "One");
+    /// level_1.insert(2, "Two");
+    ///
+    /// let mut level_2: ForkTable<isize,&str> = level_1.fork();
+    /// level_2.insert(1, "Uno");
+    /// level_2.remove(&2);
+    ///
+    /// let collapsed: ForkTable<isize,&str> = level_2.collapse();
+    /// assert_eq!(collapsed.get(&1), Some(&"Uno"));
+    /// assert_eq!(collapsed.get(&2), None);
+    /// ```
+    pub fn collapse<'b>(&self) -> ForkTable<'b, K, V>
+    where K: Clone + 'b
+        , V: Clone + 'b {
+        let mut table = match self.parent {
+            Some(parent) => parent.collapse().table
+          , None => HashMap::new()
+        };
+        for key in &self.whiteouts { table.remove(key); }
+        for (key, value) in &self.table {
+            table.insert(key.clone(), value.clone());
+        }
+        ForkTable { table: table
+                  , whiteouts: HashSet::new()
+                  , parent: None
+                  , level: 0
+                  }
+    }
+
     /// Constructs a new `ForkTable<K,V>`
     pub fn new() -> ForkTable<'a, K,V> {
         ForkTable { table: HashMap::new()
@@ -74,6 +74,7 @@ macro_rules! ice {
 }
 
 pub mod position;
+pub mod source_map;
 pub mod semantic;
 pub mod compile;
 pub mod llvm;
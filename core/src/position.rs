@@ -12,20 +12,24 @@
 use std::ops::{Deref, DerefMut};
 use std::hash;
 use std::fmt;
+use std::cmp;
 use std::convert::From;
 
 use combine::primitives::SourcePosition;
 
-/// Struct representing a position within a source code file.
+/// A line and column within a source code file.
 ///
+/// Both are 1-indexed, and columns count characters rather than bytes.
 /// This represents positions using `i32`s because that's how
 /// positions are represented in `combine` (the parsing library
 /// that we will use for the Mnemosyne parser). I personally would
 /// have used `usize`s...
-#[derive(Copy, Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+///
+/// AST nodes are annotated with `Span`s rather than `Position`s; use a
+/// `LineTable` to find the `Position` of a `Span`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, PartialOrd, Ord, Hash)]
 pub struct Position { pub col: i32
                     , pub row: i32
-                    , pub raw: i32
                     }
 
 impl Position {
@@ -35,12 +39,10 @@ impl Position {
     pub fn new(col: i32, row: i32) -> Self {
         Position { col: col
                  , row: row
-                 , raw: col + row
                  }
     }
 
 }
-
 impl From<SourcePosition> for Position {
     /// Create a new `Position` from a `combine` `SourcePosition`.
     ///
@@ -77,23 +79,188 @@ impl fmt::Display for Position {
     }
 }
 
-/// A pointer to a value with an associated `Position`
+/// Identifies a source code file.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, PartialOrd, Ord, Hash)]
+pub struct FileId(pub usize);
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "file {}", self.0)
+    }
+}
+
+/// A range of source code within a file.
+///
+/// `start` and `end` are byte offsets into the file; `end` is exclusive.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Span { pub file: FileId
+                , pub start: usize
+                , pub end: usize
+                }
+
+impl Span {
+
+    /// Create a new `Span` covering the bytes `start..end` of `file`.
+    #[inline]
+    pub fn new(file: FileId, start: usize, end: usize) -> Self {
+        Span { file: file
+             , start: start
+             , end: end
+             }
+    }
+
+    /// Returns the length of this span, in bytes.
+    #[inline] pub fn len(&self) -> usize { self.end - self.start }
+
+    /// Returns a span covering both this span and `other`, and everything
+    /// between them.
+    pub fn to(&self, other: Span) -> Span {
+        if self.file != other.file {
+            ice!("cannot join spans in different files: {:?} and {:?}"
+                , self, other)
+        }
+        Span::new( self.file
+                 , cmp::min(self.start, other.start)
+                 , cmp::max(self.end, other.end) )
+    }
+}
+
+/// Spans are displayed as byte offsets; use `SourceMap::describe()` or a
+/// `LineTable` to display them as lines and columns.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bytes {}..{} of {}", self.start, self.end, self.file)
+    }
+}
+
+/// Maps byte offsets in a source code file to `Position`s.
 #[derive(Clone, Debug)]
-pub struct Positional<T> { pub pos: Position
+pub struct LineTable { source: String
+                     , /// The byte offset of the start of each line.
+                       line_starts: Vec<usize>
+                     , /// Whether each line contains only ASCII, so that
+                       /// its columns are the same as its byte offsets.
+                       ascii_lines: Vec<bool>
+                     }
+
+impl LineTable {
+
+    pub fn new(source: &str) -> Self {
+        let line_starts = Some(0).into_iter()
+                                 .chain(source.char_indices()
+                                              .filter(|&(_, c)| c == '\n')
+                                              .map(|(i, _)| i + 1))
+                                 .collect();
+        let ascii_lines = source.split('\n')
+                                .map(|line| line.bytes().all(|b| b < 0x80))
+                                .collect();
+        LineTable { source: String::from(source)
+                  , line_starts: line_starts
+                  , ascii_lines: ascii_lines
+                  }
+    }
+
+    /// Returns the source code of the file.
+    #[inline] pub fn source(&self) -> &str { &self.source }
+
+    /// Returns the number of lines in the file.
+    #[inline] pub fn lines(&self) -> usize { self.line_starts.len() }
+
+    /// Returns the index of the line containing the byte `offset`.
+    fn line_index(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line
+          , Err(next) => next - 1
+        }
+    }
+
+    /// Returns the `Position` of the byte `offset`.
+    pub fn position(&self, offset: usize) -> Position {
+        let line = self.line_index(offset);
+        let col = self.source[self.line_starts[line]..offset].chars().count();
+        Position::new(col as i32 + 1, line as i32 + 1)
+    }
+
+    /// Returns the byte offset of a `Position`.
+    ///
+    /// Positions past the end of a line are clamped to the end of that
+    /// line, and positions past the end of the file to the end of the file.
+    pub fn offset(&self, pos: Position) -> usize {
+        let line = self.clamp_row(pos.row);
+        let start = self.line_starts[line];
+        let col = cmp::max(pos.col, 1) as usize - 1;
+        if self.ascii_lines[line] {
+            return cmp::min(start + col, self.line_end(line))
+        }
+        self.source[start..]
+            .char_indices()
+            .map(|(i, _)| start + i)
+            .chain(Some(self.source.len()).into_iter())
+            .take_while(|&i| i == start || self.source.as_bytes()[i - 1] != b'\n')
+            .nth(col)
+            .unwrap_or_else(|| self.line_end(line))
+    }
+
+    /// Returns the index of the 1-indexed line `row`, clamped to the lines
+    /// of the file.
+    fn clamp_row(&self, row: i32) -> usize {
+        cmp::min(cmp::max(row, 1) as usize - 1, self.lines() - 1)
+    }
+
+    /// Returns the byte offset of the end of a line, excluding the newline.
+    fn line_end(&self, line: usize) -> usize {
+        self.line_starts.get(line + 1)
+            .map(|&next| next - 1)
+            .unwrap_or(self.source.len())
+    }
+
+    /// Returns the text of the 1-indexed line `row`, without its newline.
+    ///
+    /// Rows outside of the file are clamped to its first or last line.
+    pub fn line(&self, row: i32) -> &str {
+        let line = self.clamp_row(row);
+        &self.source[self.line_starts[line]..self.line_end(line)]
+    }
+
+    /// Returns the first line of code in `span`, with the span underlined.
+    ///
+    /// Spans covering more than one line are underlined to the end of
+    /// their first line.
+    pub fn underline(&self, span: Span) -> String {
+        let start = self.position(span.start);
+        let end = if span.end > span.start { self.position(span.end) }
+                  else { start };
+        let text = self.line(start.row);
+        let width = if end.row == start.row { end.col - start.col }
+                    else { text.chars().count() as i32 + 1 - start.col };
+        let padding: String = text.chars()
+                                  .take(start.col as usize - 1)
+                                  .map(|c| if c == '\t' { '\t' } else { ' ' })
+                                  .collect();
+        format!( "{}\n{}{}"
+               , text, padding
+               , (0..cmp::max(width, 1)).map(|_| '^').collect::<String>() )
+    }
+}
+
+/// A pointer to a value with an associated `Span`
+#[derive(Clone, Debug)]
+pub struct Positional<T> { pub pos: Span
                          , pub value: T
                          }
 
 impl<A> Positional<A> {
 
-    /// Create a new Positional marker at the given position.
+    /// Create a new Positional marker spanning `start..end` of `file`.
     #[inline]
-    pub fn at(col: i32, row: i32, value: A) -> Positional<A> {
-        Positional { pos: Position::new(col, row)
+    pub fn at(file: FileId, start: usize, end: usize, value: A)
+             -> Positional<A> {
+        Positional { pos: Span::new(file, start, end)
                    , value: value }
     }
 
     #[inline]
-    pub fn from(pos: Position, value: A) -> Positional<A> {
+    pub fn from(pos: Span, value: A) -> Positional<A> {
         Positional { pos: pos, value: value }
     }
 
@@ -107,6 +274,19 @@ impl<A> Positional<A> {
     pub fn value(&self) -> &A { &self.value }
 }
 
+impl<T> Positional<T>
+where T: fmt::Display {
+
+    /// Format this value with the line and column of its span, followed
+    /// by the underlined source code.
+    pub fn describe(&self, lines: &LineTable) -> String {
+        format!( "{} at {}\n{}"
+               , self.value
+               , lines.position(self.pos.start)
+               , lines.underline(self.pos) )
+    }
+}
+
 
 impl<T> fmt::Display for Positional<T>
 where T: fmt::Display {
@@ -175,4 +355,53 @@ mod tests {
         let tuple: (i32,i32) = (1,1);
         assert_eq!(Position::from(tuple), Position::new(1,1));
     }
+
+    #[test]
+    fn test_line_table_position() {
+        let lines = LineTable::new("ab\ncd\n\nλx");
+        assert_eq!(lines.position(0), Position::new(1,1));
+        assert_eq!(lines.position(1), Position::new(2,1));
+        assert_eq!(lines.position(3), Position::new(1,2));
+        assert_eq!(lines.position(6), Position::new(1,3));
+        assert_eq!(lines.position(9), Position::new(2,4));
+        assert_eq!(lines.position(10), Position::new(3,4));
+    }
+
+    #[test]
+    fn test_line_table_offset() {
+        let lines = LineTable::new("ab\ncd\n\nλx");
+        for &offset in &[0, 1, 2, 3, 5, 6, 7, 9, 10] {
+            assert_eq!(lines.offset(lines.position(offset)), offset);
+        }
+        // past the end of a line
+        assert_eq!(lines.offset(Position::new(7,1)), 2);
+    }
+
+    #[test]
+    fn test_underline() {
+        let lines = LineTable::new("(def x\n\t(foo bar))");
+        let span = Span::new(FileId(0), 9, 12);
+        assert_eq!(lines.underline(span), "\t(foo bar))\n\t ^^^");
+    }
+
+    #[test]
+    fn test_line_table_clamps_rows() {
+        let lines = LineTable::new("ab\ncd");
+        assert_eq!(lines.line(0), "ab");
+        assert_eq!(lines.line(3), "cd");
+        assert_eq!(lines.offset(Position::new(1, 0)), 0);
+    }
+
+    #[test]
+    fn test_span_display() {
+        let span = Span::new(FileId(7), 4, 6);
+        assert_eq!(format!("{}", span), "bytes 4..6 of file 7");
+    }
+
+    #[test]
+    fn test_span_to() {
+        let a = Span::new(FileId(0), 4, 7);
+        let b = Span::new(FileId(0), 10, 12);
+        assert_eq!(a.to(b), Span::new(FileId(0), 4, 12));
+    }
 }
@@ -8,17 +8,19 @@
 //  or at https://github.com/hawkw/mnemosyne/.
 //
 use std::borrow::Borrow;
+use std::cell::Cell;
 use std::hash::Hash;
 use std::ops;
 use std::fmt;
 use std::marker::PhantomData;
+use std::rc::Rc;
 
 use ast;
 
 use super::{ SymbolAnnotation
            , SymbolTable
            };
-use position::{ Position
+use position::{ Span
               , Positional
               };
 //==-----------------------------------------------------==
@@ -51,13 +53,37 @@ impl fmt::Display for UnscopedState {
 }
 //==------- exiting typesystem danger zone --------------==
 
-/// An AST node which has been annotated with position &
+/// Identifies a node in the scoped typestate.
+///
+/// A node is given an id of its own when it is annotated with its scope,
+/// and copies of the node keep it. Unlike the node's position, which a
+/// macro may copy into each of its expansions, the id tells the
+/// expansions apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+thread_local! {
+    /// The number of nodes given ids so far.
+    static NODES: Cell<usize> = Cell::new(0)
+}
+
+impl NodeId {
+    fn next() -> Self {
+        NODES.with(|n| {
+            n.set(n.get() + 1);
+            NodeId(n.get())
+        })
+    }
+}
+
+/// An AST node which has been annotated with its span &
 /// (possibly) scope information.
 #[derive(Clone, Debug)]
 pub struct Annotated<'a, T, S>
 where S: ScopednessTypestate { pub node: T
-                             , pub position: Position
-                             , scope: Option<SymbolTable<'a>>
+                             , pub position: Span
+                             , scope: Option<Rc<SymbolTable<'a>>>
+                             , id: Option<NodeId>
                              , my_typestate: PhantomData<S>
                              }
 
@@ -68,17 +94,61 @@ pub type Unscoped<'a, T> = Annotated<'a, T, UnscopedState>;
 /// for annotations which are in the Scoped typestate.
 impl<'a, T> Scoped<'a, T> {
 
+    /// Annotate `node`, found at `position`, with `scope`, the scope it
+    /// is in.
+    ///
+    /// The nodes in the same scope share its symbol table.
+    pub fn in_scope(node: T, position: Span, scope: Rc<SymbolTable<'a>>)
+                   -> Self {
+        Annotated { node: node
+                  , position: position
+                  , scope: Some(scope)
+                  , id: Some(NodeId::next())
+                  , my_typestate: PhantomData
+                  }
+    }
+
+    /// The id of this node.
+    pub fn id(&self) -> NodeId {
+        match self.id { Some(id) => id
+                      , None => scope_typestate_err!("id()")
+                      }
+    }
+
     /// Extract the symbol table from this node's scope annotation.
     ///
     /// This fails with the typestate error if there is no symbol table
     /// for this annotation. Of course, since this node is in the scoped state,
     /// this should only ever fail if things have gone horribly wrong.
     pub fn symbol_table(&'a self) -> &'a SymbolTable<'a> {
-        match self.scope { Some(ref table) => table
+        match self.scope { Some(ref table) => &**table
                          , None => scope_typestate_err!("symbol_table()")
                          }
     }
 
+    /// Mutably borrow the symbol table from this node's scope annotation.
+    ///
+    /// If the table is shared with other nodes, this node is given its own
+    /// copy of it first.
+    pub fn symbol_table_mut(&mut self) -> &mut SymbolTable<'a> {
+        Rc::make_mut(self.scope_mut())
+    }
+
+    /// The shared symbol table of this node's scope.
+    pub fn scope(&self) -> Rc<SymbolTable<'a>> {
+        match self.scope { Some(ref table) => table.clone()
+                         , None => scope_typestate_err!("scope()")
+                         }
+    }
+
+    /// Mutably borrow the shared symbol table of this node's scope, so
+    /// that the node may be given another.
+    pub fn scope_mut(&mut self) -> &mut Rc<SymbolTable<'a>> {
+        match self.scope { Some(ref mut table) => table
+                         , None => scope_typestate_err!("scope_mut()")
+                         }
+    }
+
     /// Get the type signature associated with the given name.
     ///
     /// This returns a borrowed reference to the type signature
@@ -115,18 +185,20 @@ where T: ast::Node {
     /// Consume this unscoped annotation to produce a new
     /// annotation in the scoped typestate with the given
     /// scope.
-    pub fn with_scope(self, scope: SymbolTable<'a>) -> Scoped<'a, T>{
+    pub fn with_scope(self, scope: Rc<SymbolTable<'a>>) -> Scoped<'a, T>{
         Annotated { node: self.node
                   , position: self.position
                   , scope: Some(scope)
+                  , id: Some(NodeId::next())
                   , my_typestate: PhantomData
                   }
     }
 
-    pub fn new(node: T, position: Position) -> Self {
+    pub fn new(node: T, position: Span) -> Self {
         Annotated { node: node
                   , position: position
                   , scope: None
+                  , id: None
                   , my_typestate: PhantomData
                   }
     }
@@ -10,7 +10,6 @@
 //! Mnemosyne abstract syntax tree
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
 use std::hash::Hash;
 use std::{ fmt
          , iter
@@ -29,8 +28,9 @@ use super::annotations::{ Annotated
                         , Scoped
                         };
 use super::types;
-use super::{SymbolTable, SymbolAnnotation};
+use super::{SharedScope, SymbolTable, SymbolAnnotation};
 use ::{CompileResult, Errors};
+use errors::ExpectICE;
 
 pub type Ident = Positional<String>;
 
@@ -87,12 +87,22 @@ impl fmt::Display for Node + Sized {
     }
 }
 
+/// Resolution of the names in an unscoped node.
+///
+/// The scoping rules are described in `semantic::scope`, which implements
+/// this trait for the nodes of the AST.
 pub trait AnnotateTypes<'a>: Sized {
 
-   #[allow(unused_variables)]
-   fn annotate_types(self, scope: SymbolTable) -> Scoped<'a, Self> {
-       unimplemented!() //TODO: implement
-   }
+    /// This node, in the scoped typestate.
+    type Output;
+
+    /// Annotate this node, which is in `scope`, and its children with the
+    /// scopes they are in.
+    ///
+    /// An error is added to `errs` for each name that is not defined;
+    /// the node is annotated as though it were.
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output;
 }
 
 
@@ -101,6 +111,8 @@ pub struct Module<'a, S>
 where S: ScopednessTypestate
     , S: 'a {
     pub name: Ident
+  , /// The names of the modules imported by this module.
+    pub imports: Vec<Ident>
   , pub exporting: Vec<Ident>
   , pub body: Body<'a, S>
 }
@@ -112,6 +124,66 @@ where S: ScopednessTypestate
     /// Returns true if the namespace is exporting any names
     #[inline] pub fn is_lib (&self) -> bool { !self.exporting.is_empty() }
 
+    /// Returns the names defined at the top level of the module.
+    ///
+    /// These are the names of its definitions, data types and their
+    /// variants, typeclasses and their functions, and macros.
+    pub fn definitions(&self) -> Vec<&Ident> {
+        let mut names = vec![];
+        for expr in &self.body {
+            match expr.node {
+                Form::Define(DefForm::TopLevel { ref name, .. }) |
+                Form::Define(DefForm::Function { ref name, .. }) =>
+                    names.push(name)
+              , Form::Data(ref data) => {
+                    names.push(&data.name);
+                    names.extend(data.constructors());
+                }
+              , Form::Class(ref class) => {
+                    names.push(&class.name);
+                    names.extend(class.defs.iter().map(|proto| &proto.name));
+                }
+              , Form::Macro(ref mac) => names.push(&mac.name)
+              , _ => {}
+            }
+        }
+        names
+    }
+
+    /// Check that every name the module exports is defined in it.
+    ///
+    /// # Returns
+    ///   - An error for each exported name that the module does not
+    ///     define, located at the name in the export list.
+    ///   - An error for each exported macro, since macros are expanded
+    ///     within the module that defines them.
+    pub fn check_exports(&self) -> Errors {
+        let defined = self.definitions();
+        let macros = self.macros();
+        self.exporting.iter()
+            .filter_map(|name|
+                if macros.contains(&name) {
+                    Some(name.map(format!(
+                        "[error] module {} exports the macro {}, but macros \
+                         cannot be exported", *self.name, **name)))
+                } else if !defined.contains(&name) {
+                    Some(name.map(format!(
+                        "[error] module {} exports {}, but does not define it"
+                       , *self.name, **name)))
+                } else { None })
+            .collect()
+    }
+
+    /// Returns the names of the module's macros.
+    pub fn macros(&self) -> Vec<&Ident> {
+        self.body.iter()
+            .filter_map(|expr| match expr.node {
+                Form::Macro(ref mac) => Some(&mac.name)
+              , _ => None
+            })
+            .collect()
+    }
+
 }
 
 impl<'a> Scoped<'a, Module<'a, ScopedState>> {
@@ -128,6 +200,67 @@ impl<'a> Scoped<'a, Module<'a, ScopedState>> {
             .is_some()
     }
 
+    /// Returns the symbol table annotations for the values and types the
+    /// module exports.
+    ///
+    /// Exporting a data type exports its constructors, and exporting a
+    /// typeclass exports its functions. Each class is exported with all of
+    /// the instances of it that the module can see, and a class that the
+    /// module declares an instance of is exported with that instance even
+    /// if the class was imported, so that importers see every instance of
+    /// the classes they use. Importers of the module see only these names.
+    pub fn exported_symbols<'b>(&self) -> Vec<(String, SymbolAnnotation<'b>)> {
+        let module = &self.node;
+        let exported = |name: &str| module.exporting.iter().any(|e| e.value == name);
+        let scope = self.scope();
+        // classes are taken from the module's scope, in which they have
+        // the instances that it imported and those that it declares
+        let class = |name: &str| match scope.get(name) {
+            Some(&SymbolAnnotation::Class { ref ty_param, ref methods
+                                          , ref instances }) =>
+                SymbolAnnotation::Class { ty_param: ty_param.clone()
+                                        , methods: methods.clone()
+                                        , instances: instances.clone()
+                                        }
+          , _ => ice!("class {} is not in the scope of its module", name)
+        };
+        let mut symbols = vec![];
+        let mut classes: Vec<&String> = vec![];
+        let mut local: Vec<&String> = vec![];
+        let mut implemented: Vec<&String> = vec![];
+        for expr in &module.body {
+            match expr.node {
+                Form::Define(ref def) if exported(&def.name().value) =>
+                    symbols.push((def.name().value.clone(), def.annotation()))
+              , Form::Data(ref data) => {
+                    let all = exported(&data.name.value);
+                    symbols.extend(data.annotations()
+                                       .into_iter()
+                                       .filter(|&(ref name, _)| all || exported(name)))
+                }
+              , Form::Class(ref class) => {
+                    local.push(&class.name.value);
+                    let all = exported(&class.name.value);
+                    for (name, annotation) in class.annotations() {
+                        if !(all || exported(&name)) { continue }
+                        if annotation.is_class() {
+                            classes.push(&class.name.value)
+                        } else {
+                            symbols.push((name, annotation))
+                        }
+                    }
+                }
+              , Form::Instance(ref inst) => implemented.push(&inst.class.value)
+              , _ => {}
+            }
+        }
+        classes.extend(implemented.into_iter().filter(|name| !local.contains(name)));
+        classes.sort();
+        classes.dedup();
+        symbols.extend(classes.into_iter().map(|name| (name.clone(), class(name))));
+        symbols
+    }
+
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -145,10 +278,37 @@ where S: ScopednessTypestate
   , Let(LetForm<'a, S>)
   , App(AppForm<'a, S>)
   , Lambda(Function<'a, S>)
+  , Data(Data<'a, S>)
+  , Class(Class)
+  , Instance(Instance<'a, S>)
   , Logical(Logical<'a, S>)
+  , /// A comparison, such as `(< a b)`.
+    Bool(BoolBOp<'a, S>)
   , Num(NumExpr<'a, S>)
   , Lit(Literal)
   , NameRef(NameRef)
+  , Sigil(Sigil<'a, S>)
+  , /// A sequence of expressions, evaluated in order, as in
+    /// `(begin a b c)` or `(do a b c)`. Its value is that of the last
+    /// expression.
+    Begin(Body<'a, S>)
+  , /// Assignment to a mutable local, as in `(set! x 1)`.
+    Set(SetForm<'a, S>)
+  , /// A `match` (or `case`) expression.
+    Match(MatchForm<'a, S>)
+  , /// Access to a field of a record, as in `(: day my_date)`.
+    Field(FieldAccess<'a, S>)
+  , /// A copy of a record with some of its fields replaced, as in
+    /// `(with my_date (day Monday))`.
+    With(RecordUpdate<'a, S>)
+  , /// Quoted data, as in `'(a b c)` or `(quote (a b c))`.
+    Quote(Datum<'a, S>)
+  , /// Quasiquoted data, as in `` `(a ,b c) ``. Unlike `Quote`, the data
+    /// may contain unquoted expressions, which are evaluated.
+    Quasiquote(Datum<'a, S>)
+  , /// A macro definition. Macros are expanded by `macros::expand()`,
+    /// which removes their definitions.
+    Macro(Macro<'a, S>)
 }
 
 /// AST node for a function application
@@ -168,6 +328,15 @@ where S: ScopednessTypestate
               pub params: Body<'a, S>
             }
 
+impl<'a, S> Node for AppForm<'a, S>
+where S: ScopednessTypestate {
+    fn to_sexpr(&self, level: usize) -> String {
+        format!( "({} {})"
+               , self.fun.to_sexpr(level)
+               , concat_exprs!(self.params, level) )
+    }
+}
+
 impl<'a> Scoped<'a, AppForm<'a, ScopedState>> {
 
     /// Get the function definition for the function that is being applied
@@ -192,9 +361,86 @@ pub enum NameRef { Owned(Ident)
                  , Unique(Ident)
                  }
 
+/// A sigil operator applied to an arbitrary expression.
+///
+/// Unlike the sigils in a `NameRef`, which may only prefix a name, these
+/// construct a pointer to, or unwrap, the value of any expression.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Sigil<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { /// `(& e)`: a borrowed pointer to the value of `e`.
+              Borrow(Rc<Expr<'a, S>>)
+            , /// `(@ e)`: the value of `e`, boxed on the heap.
+              Unique(Rc<Expr<'a, S>>)
+            , /// `(* e)`: a raw (unsafe) pointer to the value of `e`.
+              Raw(Rc<Expr<'a, S>>)
+            , /// `(? e)` or `?e`: the value in the option `e`, which panics
+              /// if there is none, or `(? e default)`, which evaluates to
+              /// `default` instead.
+              Unwrap { option: Rc<Expr<'a, S>>
+                     , default: Option<Rc<Expr<'a, S>>>
+                     }
+            }
+
+impl<'a, S> Sigil<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    /// Returns the type of this expression, given the type of its operand
+    /// and, for `(? e default)`, the type of its default.
+    ///
+    /// # Returns
+    ///  + `Ok` containing the type of the expression, if the operand types
+    ///    are valid.
+    ///  + `Err` containing a message describing the mismatch otherwise.
+    pub fn ty(&self, operand: types::Type, default: Option<types::Type>)
+             -> Result<types::Type, String> {
+        use super::types::{ Type, Reference };
+        match *self {
+            Sigil::Borrow(_) =>
+                Ok(Type::Ref(Reference::Borrowed(Rc::new(operand))))
+          , Sigil::Unique(_) =>
+                Ok(Type::Ref(Reference::Unique(Rc::new(operand))))
+          , Sigil::Raw(_) => Ok(Type::Ref(Reference::Raw(Rc::new(operand))))
+          , Sigil::Unwrap { .. } => {
+                let inner = match operand {
+                    Type::Option(inner) => (*inner).clone()
+                  , other => return Err(format!( "`?` expects an option, \
+                                                  found `{}`", other))
+                };
+                match default {
+                    Some(ref ty) if *ty != inner =>
+                        Err(format!( "the default of `?` has type `{}`, but \
+                                      the option contains `{}`", ty, inner))
+                  , _ => Ok(inner)
+                }
+            }
+        }
+    }
+}
+
+impl<'a, S> Node for Sigil<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        match *self {
+            Sigil::Borrow(ref e) => format!("(& {})", e.to_sexpr(level))
+          , Sigil::Unique(ref e) => format!("(@ {})", e.to_sexpr(level))
+          , Sigil::Raw(ref e) => format!("(* {})", e.to_sexpr(level))
+          , Sigil::Unwrap { ref option, default: None } =>
+                format!("(? {})", option.to_sexpr(level))
+          , Sigil::Unwrap { ref option, default: Some(ref default) } =>
+                format!( "(? {} {})"
+                       , option.to_sexpr(level)
+                       , default.to_sexpr(level) )
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Formal { pub name: Ident
-                  , pub annot: Ident
+                  , pub annot: types::Type
                   }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -210,22 +456,44 @@ where S: ScopednessTypestate
              }
 }
 
+/// A typeclass declaration.
+///
+/// A typeclass is parameterised over a single type variable, `ty_param`,
+/// and declares the prototypes of the functions that instances of the
+/// class must implement.
 #[derive(PartialEq, Clone, Debug)]
-pub struct Class<'a, S>
-where S: ScopednessTypestate
-    , S: 'a {
-    pub name: Ident
-  , pub ty_param: Ident
-  , pub defs: Vec<Prototype<'a, S>>
-}
+pub struct Class { pub name: Ident
+                 , pub ty_param: Ident
+                 , pub defs: Vec<Prototype>
+                 }
 
+/// An instance of a typeclass for a particular type.
+///
+/// Each of the instance's functions must be a `DefForm::Function`
+/// implementing one of the class's prototypes.
 #[derive(PartialEq, Clone, Debug)]
 pub struct Instance<'a, S>
 where S: ScopednessTypestate
     , S: 'a {
     pub class: Ident
   , pub ty: types::Type
-  , pub functions: Vec<Function<'a, S>>
+  , pub functions: Vec<DefForm<'a, S>>
+}
+
+impl<'a, S> Instance<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    /// Returns the names of the functions this instance defines.
+    pub fn function_names(&self) -> Vec<&Ident> {
+        self.functions
+            .iter()
+            .filter_map(|def| match *def {
+                DefForm::Function { ref name, .. } => Some(name)
+              , _ => None
+            })
+            .collect()
+    }
 }
 
 /// Logical `and` and `or` expressions
@@ -243,21 +511,178 @@ where S: ScopednessTypestate
        }
 }
 
+impl<'a, S> Logical<'a, S>
+where S: ScopednessTypestate {
+
+    /// Returns the type of the expression, which is always `bool`.
+    #[inline] pub fn ty(&self) -> types::Type {
+        types::Type::Prim(types::Primitive::Bool)
+    }
+}
+
+impl<'a> Logical<'a, UnscopedState> {
+
+    /// Returns the conjunction (if `and` is true) or the disjunction of
+    /// `operands`, which nest to the right, so `(and a b c)` is
+    /// `(and a (and b c))`.
+    ///
+    /// # Panics
+    /// With an internal compiler error, if there are fewer than two
+    /// operands.
+    pub fn new(and: bool, mut operands: Vec<Expr<'a, UnscopedState>>)
+               -> Self {
+        let connect = |a, b| if and { Logical::And { a: Rc::new(a)
+                                                   , b: Rc::new(b) } }
+                             else { Logical::Or { a: Rc::new(a)
+                                                , b: Rc::new(b) } };
+        let mut b = operands.pop()
+                            .expect_ice("a logical expression needs operands");
+        while operands.len() > 1 {
+            let a = operands.pop()
+                            .expect_ice("a logical expression lost an operand");
+            let span = a.position.to(b.position);
+            b = Annotated::new(Form::Logical(connect(a, b)), span);
+        }
+        let a = operands.pop()
+                        .expect_ice("a logical expression needs two operands");
+        connect(a, b)
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum Literal { IntConst(i64)
                  , UintConst(u64)
+                 , FloatConst(f64)
                  , StringLit(String)
+                 , CharLit(char)
+                 , BoolLit(bool)
                  }
 
+/// Escape the contents of a string literal so that it can be read back in.
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\")
+          , '\n'  => escaped.push_str("\\n")
+          , '\r'  => escaped.push_str("\\r")
+          , '\t'  => escaped.push_str("\\t")
+          , '"'   => escaped.push_str("\\\"")
+          , c     => escaped.push(c)
+        }
+    }
+    escaped
+}
+
 impl fmt::Display for Literal {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self { Literal::IntConst(ref n)    => write!(f, "{}", n)
-                    , Literal::UintConst(ref n)   => write!(f, "{}", n)
-                    , Literal::StringLit(ref s)   => write!(f, "{}", s)
+                    , Literal::UintConst(ref n)   => write!(f, "{}u", n)
+                      // make sure that whole floats still look like floats
+                    , Literal::FloatConst(ref n) if n.is_finite()
+                                                 && n.fract() == 0.0 =>
+                        write!(f, "{:.1}", n)
+                    , Literal::FloatConst(ref n)  => write!(f, "{}", n)
+                    , Literal::StringLit(ref s)   =>
+                        write!(f, "\"{}\"", escape_string(s))
+                    , Literal::CharLit(' ')       => write!(f, "#\\space")
+                    , Literal::CharLit('\n')      => write!(f, "#\\newline")
+                    , Literal::CharLit('\t')      => write!(f, "#\\tab")
+                    , Literal::CharLit(ref c)     => write!(f, "#\\{}", c)
+                    , Literal::BoolLit(ref b)     => write!(f, "{}", b)
                     }
     }
 }
 
+/// A pattern-based macro, defined with
+/// `(define-syntax name (syntax-rules (literals...) rules...))`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Macro<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { pub name: Ident
+            , /// Names that match only themselves in the rules' patterns,
+              /// rather than being pattern variables.
+              pub literals: Vec<Ident>
+            , pub rules: Vec<Rule<'a, S>>
+            }
+
+/// A macro rule: a call matching `pattern` is replaced with `template`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Rule<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { /// A list whose first element stands for the macro's name.
+              pub pattern: Datum<'a, S>
+            , pub template: Rc<Expr<'a, S>>
+            }
+
+impl<'a, S> Node for Macro<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        let rules = self.rules
+                        .iter()
+                        .map(|rule| format!( "\n{}({} {})"
+                                           , indent!(level + 1)
+                                           , rule.pattern.to_sexpr(level + 1)
+                                           , rule.template.to_sexpr(level + 1)))
+                        .collect::<String>();
+        format!( "(define-syntax {} (syntax-rules ({}){}))"
+               , *self.name
+               , self.literals.iter()
+                     .map(|l| l.value.clone())
+                     .collect::<Vec<String>>()
+                     .join(" ")
+               , rules )
+    }
+}
+
+/// Quoted data: the syntax of an S-expression, as a value.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Datum<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { /// A symbol. The type of a quoted symbol is the unique
+              /// symbol type, `Type::Symbol`.
+              Symbol(Ident)
+            , Lit(Literal)
+            , List(Vec<Datum<'a, S>>)
+            , /// `,e` within a quasiquote: `e` is evaluated and its value
+              /// replaces the unquote.
+              Unquote(Rc<Expr<'a, S>>)
+            , /// `,@e` within a quasiquote: `e` is evaluated to a list, and
+              /// its elements are spliced into the enclosing list.
+              UnquoteSplicing(Rc<Expr<'a, S>>)
+            }
+
+impl<'a, S> Datum<'a, S>
+where S: ScopednessTypestate {
+
+    /// Returns the type of this datum, if it is a quoted symbol.
+    pub fn symbol_type(&self) -> Option<types::Type> {
+        match *self {
+            Datum::Symbol(ref name) => Some(types::Type::Symbol(name.value.clone()))
+          , _ => None
+        }
+    }
+}
+
+impl<'a, S> Node for Datum<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        match *self {
+            Datum::Symbol(ref name) => name.value.clone()
+          , Datum::Lit(ref lit) => format!("{}", lit)
+          , Datum::List(ref data) => format!("({})", concat_exprs!(data, level))
+          , Datum::Unquote(ref expr) =>
+                format!("(unquote {})", expr.to_sexpr(level))
+          , Datum::UnquoteSplicing(ref expr) =>
+                format!("(unquote-splicing {})", expr.to_sexpr(level))
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum LetForm<'a, S>
 where S: ScopednessTypestate
@@ -266,7 +691,10 @@ where S: ScopednessTypestate
     Let { bindings: Bindings<'a, S>
         , body: Body<'a, S>
         }
-  , Invocation { proc_id: Ident
+  , /// A named `let`, which binds `proc_id` to a procedure that may be
+    /// invoked recursively from the body with a new value for `init`.
+    Invocation { proc_id: Ident
+               , ret_ty: types::Type
                , init: Binding<'a, S>
                , body: Body<'a, S>
                }
@@ -284,6 +712,8 @@ where S: ScopednessTypestate
     , S: 'a { pub name: Ident
             , pub typ: types::Type
             , pub value: Rc<Expr<'a, S>>
+            , /// Whether the bound name may be assigned to with `set!`.
+              pub mutable: bool
             }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -323,19 +753,44 @@ impl Node for Pattern {
 /// An element in a pattern-matching expression.
 ///
 /// A pattern element can be a name binding, a typed name binding,
-/// a literal, or the magic underscore (anything). Destructuring bind
-/// will come later, as will equality.
+/// a dereferencing binding, a literal, the magic underscore (anything),
+/// or a destructuring pattern that matches a constructor or a list and
+/// binds its contents using nested patterns.
 #[derive(PartialEq, Clone, Debug)]
 pub enum PatElement { // eventually this could just be implemented using an Expr,
                       // but it's currently this way because the types of patterns
                       // that you can write are currently quite limited
                       Name(Ident)
                     , Typed { name: Ident, ty: types::Type }
+                    , /// Binds the value pointed to by a pointer argument
+                      /// (`$a`).
+                      Deref(Ident)
                     , Lit(Literal)
-                      // TODO: write literal types for other things (i.e. lists)
                     , Anything
+                    , /// Destructures a value built with a data constructor,
+                      /// as in `(Cons x xs)`.
+                      Constructor { name: Ident, fields: Pattern }
+                    , /// Destructures a list into its head and tail, as in
+                      /// `[x:xs]`.
+                      Cons { head: Box<PatElement>, tail: Box<PatElement> }
+                    , /// Matches a list with exactly these elements, as in
+                      /// `[a b c]` or `[]`.
+                      List(Pattern)
                     }
 
+impl PatElement {
+
+    /// Returns true if this pattern matches every value: a name, a typed
+    /// name, a dereferencing binding, or `_`.
+    pub fn is_irrefutable(&self) -> bool {
+        match *self {
+            PatElement::Name(_) | PatElement::Typed { .. }
+          | PatElement::Deref(_) | PatElement::Anything => true
+          , _ => false
+        }
+    }
+}
+
 impl Node for PatElement {
    #[allow(unused_variables)]
    fn to_sexpr(&self, level: usize) -> String {
@@ -345,13 +800,137 @@ impl Node for PatElement {
             format!( "{}: {}"
                    , name.to_sexpr(level)
                    , ty )
+        , PatElement::Deref(ref n) => format!("${}", **n)
         , PatElement::Lit(ref c) => format!("{}", c)
         , PatElement::Anything => String::from("_")
+        , PatElement::Constructor { ref name, ref fields } if fields.is_empty() =>
+            format!("({})", name.to_sexpr(level))
+        , PatElement::Constructor { ref name, ref fields } =>
+            format!( "({} {})"
+                   , name.to_sexpr(level)
+                   , concat_exprs!(fields, level) )
+        , PatElement::Cons { ref head, ref tail } =>
+            format!( "[{}:{}]"
+                   , head.to_sexpr(level)
+                   , tail.to_sexpr(level) )
+        , PatElement::List(ref elems) =>
+            format!("[{}]", concat_exprs!(elems, level))
        }
    }
 
 }
 
+/// AST node for assignment, `(set! name value)`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct SetForm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { pub name: Ident
+            , pub value: Rc<Expr<'a, S>>
+            }
+
+impl<'a, S> SetForm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    /// Check that the name being assigned to may be assigned to in
+    /// `scope`.
+    ///
+    /// Only names bound by a `let` binding marked `mut` may be assigned
+    /// to, and never if they are borrowed references.
+    pub fn check(&self, scope: &SymbolTable) -> CompileResult<()> {
+        let name = &self.name;
+        match scope.get(&name.value) {
+            Some(&SymbolAnnotation::Value {
+                ty: types::Type::Ref(types::Reference::Borrowed(_)), .. }) =>
+                Err(vec![name.map(format!(
+                    "[error] cannot assign to {}, since it is a borrowed \
+                     reference", **name))])
+          , Some(&SymbolAnnotation::Value { mutable: false, .. }) =>
+                Err(vec![name.map(format!(
+                    "[error] cannot assign to immutable binding {}", **name))])
+          , Some(&SymbolAnnotation::Value { .. }) => Ok(())
+          , Some(_) => Err(vec![name.map(format!(
+                "[error] cannot assign to {}, since it is not a value"
+               , **name))])
+          , None => Err(vec![name.map(format!(
+                "[error] cannot assign to undefined name {}", **name))])
+        }
+    }
+}
+
+impl<'a, S> Node for SetForm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        format!("(set! {} {})", *self.name, self.value.to_sexpr(level))
+    }
+}
+
+/// AST node for a `match` expression.
+///
+/// `(match value (pattern body...)...)` evaluates the body of the first
+/// arm whose pattern matches `value`. `case` is a synonym for `match`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct MatchForm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { pub scrutinee: Rc<Expr<'a, S>>
+            , pub arms: Vec<Annotated<'a, Arm<'a, S>, S>>
+            }
+
+impl<'a, S> MatchForm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    /// Returns the first arm whose pattern matches any value, if there is
+    /// one.
+    ///
+    /// A `match` with such an arm is exhaustive, and any arms following it
+    /// are unreachable.
+    pub fn catch_all(&self) -> Option<&Annotated<'a, Arm<'a, S>, S>> {
+        self.arms.iter().find(|arm| arm.pattern.is_irrefutable())
+    }
+
+    /// Returns the arms following the first catch-all arm, which can never
+    /// be reached.
+    pub fn unreachable_arms(&self) -> &[Annotated<'a, Arm<'a, S>, S>] {
+        match self.arms.iter().position(|arm| arm.pattern.is_irrefutable()) {
+            Some(i) => &self.arms[i + 1..]
+          , None => &[]
+        }
+    }
+}
+
+/// An arm of a `match` expression.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Arm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { pub pattern: PatElement
+            , pub body: Body<'a, S>
+            }
+
+impl<'a, S> Node for Arm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        format!( "({} {})"
+               , self.pattern.to_sexpr(level)
+               , concat_exprs!(self.body, level) )
+    }
+}
+
+impl<'a, S> Node for MatchForm<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        format!( "(match {} {})"
+               , self.scrutinee.to_sexpr(level)
+               , concat_exprs!(self.arms, level) )
+    }
+}
+
 /// A function equation definition
 #[derive(PartialEq, Clone, Debug)]
 pub struct Equation<'a, S>
@@ -384,11 +963,14 @@ where S: ScopednessTypestate
     pub fn pattern_length(&self) -> usize { self.pattern.len() }
 }
 
+/// A function prototype in a typeclass declaration.
 #[derive(PartialEq, Clone, Debug)]
-pub struct Prototype<'a, S: ScopednessTypestate> {
-    pub formals: Vec<Annotated<'a, Formal, S>>
-  , pub annot: Ident
-}
+pub struct Prototype { pub name: Ident
+                     , pub sig: types::Signature
+                     }
+
+/// The named variants of a sum type, in the order they are declared.
+pub type Variants<'a, S: ScopednessTypestate> = Vec<(Ident, Variant<'a, S>)>;
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Variant<'a, S>
@@ -402,7 +984,7 @@ where S: ScopednessTypestate
             , /// A variant that is a single value
               Value(types::Type)
             , /// A variant that is itself a sum type
-              Sum(HashMap<Ident, Variant<'a, S>>)
+              Sum(Variants<'a, S>)
             }
 
 
@@ -410,7 +992,7 @@ where S: ScopednessTypestate
 pub struct Data<'a, S>
 where S: ScopednessTypestate
     , S: 'a { pub name: Ident
-            , pub variants: HashMap<Ident, Variant<'a, S>>
+            , pub variants: Variants<'a, S>
             }
 
 // #[derive(PartialEq, Clone, Debug)]
@@ -448,11 +1030,260 @@ where S: ScopednessTypestate
     #[inline]
     pub fn get_struct_fields(&self) -> Option<&Vec<Annotated<'a, Formal, S>>> {
         self.variants
-            .get(&self.name)
-            .and_then(|var| match var { &Variant::Record(ref fs) => Some(fs)
-                                      , _                        => None
-                                      })
+            .iter()
+            .find(|&&(ref name, _)| *name == self.name)
+            .and_then(|&(_, ref var)| match var { &Variant::Record(ref fs) => Some(fs)
+                                                , _                        => None
+                                                })
+
+    }
 
+    /// Returns the names of the type's constructors: its variants, with
+    /// the variants of each nested sum in place of the sum itself.
+    pub fn constructors(&self) -> Vec<&Ident> {
+        fn collect<'v, 'a, S>( variants: &'v Variants<'a, S>
+                             , names: &mut Vec<&'v Ident>)
+        where S: ScopednessTypestate
+            , S: 'a {
+            for &(ref name, ref variant) in variants {
+                match *variant {
+                    Variant::Sum(ref nested) => collect(nested, names)
+                  , _ => names.push(name)
+                }
+            }
+        }
+        let mut names = vec![];
+        collect(&self.variants, &mut names);
+        names
+    }
+
+    /// Check that no two of the type's constructors, and no two fields of
+    /// any of its records, have the same name.
+    ///
+    /// # Returns
+    ///   - An error for each constructor or field whose name the type has
+    ///     already defined, followed by a note at its first definition.
+    pub fn check_duplicates(&self) -> Errors {
+        fn records<'v, 'a, S>( variants: &'v Variants<'a, S>
+                             , errs: &mut Errors)
+        where S: ScopednessTypestate
+            , S: 'a {
+            for &(_, ref variant) in variants {
+                match *variant {
+                    Variant::Record(ref fields) => {
+                        let names: Vec<&Ident> = fields.iter()
+                                                       .map(|f| &f.node.name)
+                                                       .collect();
+                        duplicates("field", &names, errs)
+                    }
+                  , Variant::Sum(ref nested) => records(nested, errs)
+                  , _ => {}
+                }
+            }
+        }
+        let mut errs = vec![];
+        duplicates("variant", &self.constructors(), &mut errs);
+        records(&self.variants, &mut errs);
+        errs
+    }
+
+}
+
+/// A type whose fields may be accessed: either the definition of a data
+/// type, or its annotation in a symbol table.
+pub trait RecordType {
+
+    /// Returns the type of the field `field` of this type.
+    ///
+    /// # Returns
+    ///  + `Ok` containing the field's type, if this is a record type with
+    ///    that field.
+    ///  + `Err` located at `field` otherwise.
+    fn field_type(&self, field: &Ident) -> CompileResult<&types::Type>;
+}
+
+impl<'a, S> RecordType for Data<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn field_type(&self, field: &Ident) -> CompileResult<&types::Type> {
+        let fields = match self.get_struct_fields() {
+            Some(fields) if self.is_struct() => fields
+          , _ => return Err(vec![field.map(format!(
+                "[error] {} is not a record type, so it has no field {}"
+               , *self.name, **field))])
+        };
+        fields.iter()
+              .find(|f| f.node.name == *field)
+              .map(|f| &f.node.annot)
+              .ok_or_else(|| vec![field.map(format!(
+                    "[error] record type {} has no field {}"
+                   , *self.name, **field))])
+    }
+}
+
+/// AST node for access to a field of a record.
+///
+/// Written `(: field record)`, or `record.field`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct FieldAccess<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { pub field: Ident
+            , pub record: Rc<Expr<'a, S>>
+            }
+
+impl<'a, S> FieldAccess<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    /// Check this access against the definition of the record's type.
+    ///
+    /// # Returns
+    ///  + `Ok` containing the type of the field, if `record` has it.
+    ///  + `Err` if `record` is not a record type or has no such field.
+    pub fn check<R>(&self, record: &R) -> CompileResult<types::Type>
+    where R: RecordType {
+        record.field_type(&self.field).map(Clone::clone)
+    }
+}
+
+/// AST node for a functional record update.
+///
+/// `(with record (field value)...)` evaluates to a copy of `record` with
+/// each named field replaced by its new value. `record` itself is not
+/// modified.
+#[derive(PartialEq, Clone, Debug)]
+pub struct RecordUpdate<'a, S>
+where S: ScopednessTypestate
+    , S: 'a { pub record: Rc<Expr<'a, S>>
+            , pub fields: Vec<(Ident, Expr<'a, S>)>
+            }
+
+impl<'a, S> RecordUpdate<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    /// Check this update against the definition of the record's type.
+    ///
+    /// # Returns
+    ///  + `Ok` containing the types of the updated fields, in order, if
+    ///    `record` has all of them and no field is updated twice.
+    ///  + `Err` containing an error for each unknown or repeated field
+    ///    otherwise.
+    pub fn check<R>(&self, record: &R) -> CompileResult<Vec<types::Type>>
+    where R: RecordType {
+        let mut errs = vec![];
+        let mut types = vec![];
+        for (i, &(ref field, _)) in self.fields.iter().enumerate() {
+            if let Some(&(ref prev, _)) = self.fields[..i].iter()
+                                                .find(|&&(ref f, _)| f == field) {
+                errs.push(field.map(format!(
+                    "[error] field {} is updated more than once", **field)));
+                errs.push(prev.map(String::from("[note] first updated here")));
+                continue
+            }
+            match record.field_type(field) {
+                Ok(ty) => types.push(ty.clone())
+              , Err(mut e) => errs.append(&mut e)
+            }
+        }
+        if errs.is_empty() { Ok(types) } else { Err(errs) }
+    }
+}
+
+impl<'a, S> Node for FieldAccess<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        format!("(: {} {})", *self.field, self.record.to_sexpr(level))
+    }
+}
+
+impl<'a, S> Node for RecordUpdate<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        format!( "(with {} {})"
+               , self.record.to_sexpr(level)
+               , self.fields.iter()
+                     .map(|&(ref field, ref value)|
+                          format!("({} {})", **field, value.to_sexpr(level)))
+                     .intersperse(String::from(" "))
+                     .collect::<String>() )
+    }
+}
+
+/// Add an error to `errs` for each of `names` that repeats an earlier
+/// name, where `what` is the kind of thing that they name.
+fn duplicates(what: &str, names: &[&Ident], errs: &mut Errors) {
+    for (i, name) in names.iter().enumerate() {
+        if let Some(first) = names[..i].iter().find(|n| n.value == name.value) {
+            errs.push(name.map(format!( "[error] {} {} is defined more than once"
+                                      , what, ***name)));
+            errs.push(first.map(format!( "[note] {} is first defined here"
+                                       , ***name)));
+        }
+    }
+}
+
+/// Format the variants of a sum type as a `(| ...)` S-expression.
+fn sum_to_sexpr<'a, S>(variants: &Variants<'a, S>, level: usize) -> String
+where S: ScopednessTypestate
+    , S: 'a {
+    format!( "(| {})"
+           , variants.iter()
+                     .map(|&(ref name, ref variant)|
+                          variant.to_sexpr_named(name, level))
+                     .intersperse(String::from(" "))
+                     .collect::<String>() )
+}
+
+/// Format the fields of a record type as a quoted list of pairs.
+fn fields_to_sexpr<'a, S>( fields: &Vec<Annotated<'a, Formal, S>>)
+                         -> String
+where S: ScopednessTypestate
+    , S: 'a {
+    format!( "'({})"
+           , fields.iter()
+                   .map(|f| format!("({} {})", *(f.name), f.annot))
+                   .intersperse(String::from(" "))
+                   .collect::<String>() )
+}
+
+impl<'a, S> Variant<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    /// Pretty-print this variant as an S-expression, given the name
+    /// it is bound to in its enclosing `Data` definition.
+    pub fn to_sexpr_named(&self, name: &Ident, level: usize) -> String {
+        match *self {
+            Variant::Tagword(ref tag) => tag.to_sexpr(level)
+          , Variant::Constant(ref c)  => format!("({} {})", **name, c)
+          , Variant::Record(ref fs)   =>
+                format!("({} {})", **name, fields_to_sexpr(fs))
+          , Variant::Value(ref ty)    => format!("({} {})", **name, ty)
+          , Variant::Sum(ref vs)      =>
+                format!("({} {})", **name, sum_to_sexpr(vs, level))
+        }
+    }
+}
+
+impl<'a, S> Node for Data<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        let body = match self.get_struct_fields() {
+            Some(fields) if !self.is_sum_type() => fields_to_sexpr(fields)
+          , _ => sum_to_sexpr(&self.variants, level)
+        };
+        format!( "{}(define {} data {})"
+               , indent!(level)
+               , self.name.to_sexpr(level)
+               , body )
     }
 }
 
@@ -464,16 +1295,38 @@ where S: ScopednessTypestate
        match *self {
            Form::Define(ref form)  => form.to_sexpr(level)
          , Form::Let(ref form)     => form.to_sexpr(level)
-         , Form::If { .. }         => unimplemented!()
-         , Form::App(ref form)=>
-               format!( "({} {})"
-                      , form.fun.to_sexpr(level)
-                      , concat_exprs!(form.params, level) )
+         , Form::If { ref condition, ref if_clause, else_clause: None } =>
+               format!( "(if {} {})"
+                      , condition.to_sexpr(level)
+                      , if_clause.to_sexpr(level) )
+         , Form::If { ref condition, ref if_clause
+                    , else_clause: Some(ref else_clause) } =>
+               format!( "(if {} {} {})"
+                      , condition.to_sexpr(level)
+                      , if_clause.to_sexpr(level)
+                      , else_clause.to_sexpr(level) )
+         , Form::App(ref form)=> form.to_sexpr(level)
          , Form::Lambda(ref fun)   => fun.to_sexpr(level)
+         , Form::Data(ref data)    => data.to_sexpr(level)
+         , Form::Class(ref class)  => class.to_sexpr(level)
+         , Form::Instance(ref inst) => inst.to_sexpr(level)
          , Form::Logical(ref form) => form.to_sexpr(level)
+         , Form::Bool(ref form)    => form.to_sexpr(level)
          , Form::Lit(ref c)   => format!("{}", c)
          , Form::NameRef(ref n)    => n.to_sexpr(level)
-         , Form::Num(ref n) => unimplemented!()
+         , Form::Sigil(ref sigil)  => sigil.to_sexpr(level)
+         , Form::Begin(ref body)   =>
+               format!("(begin {})", concat_exprs!(body, level))
+         , Form::Set(ref form)     => form.to_sexpr(level)
+         , Form::Match(ref form)   => form.to_sexpr(level)
+         , Form::Field(ref access) => access.to_sexpr(level)
+         , Form::With(ref update)  => update.to_sexpr(level)
+         , Form::Num(ref n)   => n.to_sexpr(level)
+         , Form::Quote(ref data) =>
+               format!("(quote {})", data.to_sexpr(level))
+         , Form::Quasiquote(ref data) =>
+               format!("(quasiquote {})", data.to_sexpr(level))
+         , Form::Macro(ref mac) => mac.to_sexpr(level)
        }
    }
 
@@ -503,6 +1356,42 @@ where S: ScopednessTypestate
 
 }
 
+impl Node for Prototype {
+    fn to_sexpr(&self, level: usize) -> String {
+        format!( "({} {})"
+               , self.name.to_sexpr(level)
+               , self.sig.to_sexpr(0) )
+    }
+}
+
+impl Node for Class {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        format!( "{}(class {} {}\n{}{})"
+               , indent!(level)
+               , self.name.to_sexpr(level)
+               , self.ty_param.to_sexpr(level)
+               , indent!(level + 1)
+               , concat_exprs!( self.defs
+                              , level + 1
+                              , format!("\n{}", indent!(level + 1)) )
+               )
+    }
+}
+
+impl<'a, S> Node for Instance<'a, S>
+where S: ScopednessTypestate
+    , S: 'a {
+
+    fn to_sexpr(&self, level: usize) -> String {
+        format!( "{}(instance {} {}\n{})"
+               , indent!(level)
+               , self.class.to_sexpr(level)
+               , self.ty
+               , concat_exprs!(self.functions, level + 1, "\n") )
+    }
+}
+
 impl<'a, S> Node for LetForm<'a, S>
 where S: ScopednessTypestate
     , S: 'a {
@@ -527,7 +1416,14 @@ where S: ScopednessTypestate
                     , concat_exprs!(bindings, level, "\n")
                     , concat_exprs!(body, level + 1)
                     )
-          , _ => unimplemented!()
+          , LetForm::Invocation { ref proc_id, ref ret_ty, ref init, ref body } =>
+                format!("{}(let {} {} [{}]\n{})"
+                    , indent!(level)
+                    , proc_id.to_sexpr(level)
+                    , ret_ty
+                    , init.to_sexpr(level)
+                    , concat_exprs!(body, level + 1)
+                    )
         }
     }
 
@@ -546,7 +1442,7 @@ where S: ScopednessTypestate
                        , b.to_sexpr(level)
                        )
          ,  Logical::Or { ref a, ref b }  =>
-                format!( "(and {} {})"
+                format!( "(or {} {})"
                        , a.to_sexpr(level)
                        , b.to_sexpr(level)
                        )
@@ -575,20 +1471,34 @@ where S: ScopednessTypestate
 impl<'a, S> Node for Binding<'a, S>
 where S: ScopednessTypestate
     , S: 'a {
-    #[allow(unused_variables)]
     fn to_sexpr(&self, level: usize) -> String {
-        unimplemented!()
+        format!( "({}{} {} {})"
+               , if self.mutable { "mut " } else { "" }
+               , self.name.to_sexpr(level)
+               , self.typ
+               , self.value.to_sexpr(level) )
     }
 }
 
 impl Node for Formal {
     #[allow(unused_variables)]
     fn to_sexpr(&self, level: usize) -> String {
-        format!("{}: {}", *(self.name), *(self.annot))
+        format!("{}: {}", *(self.name), self.annot)
     }
 
 }
 
+impl NameRef {
+    /// Returns the name that is referred to.
+    pub fn name(&self) -> &Ident {
+        match *self { NameRef::Owned(ref name)
+                    | NameRef::Borrowed(ref name)
+                    | NameRef::Deref(ref name)
+                    | NameRef::Unique(ref name) => name
+                    }
+    }
+}
+
 impl Node for NameRef {
     #[allow(unused_variables)]
     fn to_sexpr(&self, level: usize) -> String {
@@ -606,14 +1516,24 @@ impl Node for Ident {
     fn to_sexpr(&self, level: usize) -> String { self.value.clone() }
 
 }
+/// An arithmetic or bitwise expression.
+///
+/// Applications of the built-in operators, such as `(+ a 1)`, are parsed
+/// to `NumExpr`s rather than to function calls. Operators are positioned
+/// at their names, so that errors in constant expressions can be
+/// reported there, and literal and call operands keep the positions of
+/// the expressions they were made from.
 #[derive(PartialEq, Clone, Debug)]
 pub enum NumExpr<'a, S>
 where S: ScopednessTypestate
-    , S: 'a { BOp(NumBOp<'a, S>)
-            , Neg(Box<NumExpr<'a, S>>)
-            , Lit(Literal)
+    , S: 'a { BOp(Positional<NumBOp<'a, S>>)
+            , /// Negation, `(- a)`.
+              Neg(Positional<Box<NumExpr<'a, S>>>)
+            , Lit(Positional<Literal>)
             , Deref(NameRef)
-            , Call(AppForm<'a, S>)
+            , Call(Positional<AppForm<'a, S>>)
+            , /// Any other expression used as an operand, such as an `if`.
+              Other(Rc<Expr<'a, S>>)
             }
 
 
@@ -680,24 +1600,340 @@ where S: ScopednessTypestate {
 }
 
 
+impl<'a, S> NumBOp<'a, S>
+where S: ScopednessTypestate {
+
+    /// Returns the name of the operator.
+    pub fn operator(&self) -> &'static str {
+        match *self { NumBOp::Add(_)    => "+"
+                    , NumBOp::Sub(_)    => "-"
+                    , NumBOp::Mul(_)    => "*"
+                    , NumBOp::Div(_)    => "/"
+                    , NumBOp::BitAnd(_) => "bitwise-and"
+                    , NumBOp::BitOr(_)  => "bitwise-or"
+                    , NumBOp::BitXor(_) => "bitwise-xor"
+                    , NumBOp::ShiftL(_) => "<<"
+                    , NumBOp::ShiftR(_) => ">>"
+                    }
+    }
+
+    pub fn operands(&self) -> &[NumExpr<'a, S>] {
+        match *self { NumBOp::Add(ref operands)
+                    | NumBOp::Sub(ref operands)
+                    | NumBOp::Mul(ref operands)
+                    | NumBOp::Div(ref operands)
+                    | NumBOp::BitAnd(ref operands)
+                    | NumBOp::BitOr(ref operands)
+                    | NumBOp::BitXor(ref operands)
+                    | NumBOp::ShiftL(ref operands)
+                    | NumBOp::ShiftR(ref operands) => operands
+                    }
+    }
+
+    /// Returns the same operator, applied to `operands`.
+    pub fn with_operands<T>(&self, operands: Vec<NumExpr<'a, T>>) -> NumBOp<'a, T>
+    where T: ScopednessTypestate {
+        match *self { NumBOp::Add(_)    => NumBOp::Add(operands)
+                    , NumBOp::Sub(_)    => NumBOp::Sub(operands)
+                    , NumBOp::Mul(_)    => NumBOp::Mul(operands)
+                    , NumBOp::Div(_)    => NumBOp::Div(operands)
+                    , NumBOp::BitAnd(_) => NumBOp::BitAnd(operands)
+                    , NumBOp::BitOr(_)  => NumBOp::BitOr(operands)
+                    , NumBOp::BitXor(_) => NumBOp::BitXor(operands)
+                    , NumBOp::ShiftL(_) => NumBOp::ShiftL(operands)
+                    , NumBOp::ShiftR(_) => NumBOp::ShiftR(operands)
+                    }
+    }
+}
+
 impl<'a> NumExpr<'a, UnscopedState> {
 
-    pub fn new(op: String, exps: Vec<NumExpr<'a, UnscopedState>>) -> Self {
-        match op.as_ref() {
-            "+" if exps.is_const() => unimplemented!()
-            , _ => unimplemented!()
+    /// Apply the operator named `op` to `operands`.
+    ///
+    /// # Returns
+    ///   - `Some` containing the arithmetic expression, if `op` is a
+    ///     built-in operator that can be applied to that many operands.
+    ///     `-` applied to one operand is negation, `<<` and `>>` take two
+    ///     operands, and every other operator takes two or more.
+    ///   - `None` if the application is an ordinary function call.
+    pub fn new(op: &Ident, operands: &[Expr<'a, UnscopedState>])
+               -> Option<Self> {
+        let nums = || operands.iter().map(NumExpr::operand).collect();
+        let bop = match (&op.value[..], operands.len()) {
+            ("-", 1) => return Some(NumExpr::Neg(op.map(Box::new(
+                                        NumExpr::operand(&operands[0])))))
+          , (_, n) if n < 2       => return None
+          , ("+", _)              => NumBOp::Add(nums())
+          , ("-", _)              => NumBOp::Sub(nums())
+          , ("*", _)              => NumBOp::Mul(nums())
+          , ("/", _)              => NumBOp::Div(nums())
+          , ("bitwise-and", _)    => NumBOp::BitAnd(nums())
+          , ("bitwise-or", _)     => NumBOp::BitOr(nums())
+          , ("bitwise-xor", _)    => NumBOp::BitXor(nums())
+          , ("<<", 2)             => NumBOp::ShiftL(nums())
+          , (">>", 2)             => NumBOp::ShiftR(nums())
+          , _                     => return None
+        };
+        Some(NumExpr::BOp(op.map(bop)))
+    }
+
+    /// Returns an expression as an operand of an arithmetic expression.
+    pub fn operand(expr: &Expr<'a, UnscopedState>) -> Self {
+        match expr.node {
+            Form::Num(ref num) => num.clone()
+          , Form::Lit(ref lit @ Literal::IntConst(_))
+          | Form::Lit(ref lit @ Literal::UintConst(_))
+          | Form::Lit(ref lit @ Literal::FloatConst(_)) =>
+                NumExpr::Lit(Positional::from(expr.position, lit.clone()))
+          , Form::NameRef(ref name) => NumExpr::Deref(name.clone())
+          , Form::App(ref app) =>
+                NumExpr::Call(Positional::from(expr.position, app.clone()))
+          , _ => NumExpr::Other(Rc::new(expr.clone()))
+        }
+    }
+}
+
+impl<'a, S> NumExpr<'a, S>
+where S: ScopednessTypestate + Clone {
+
+    /// Fold the constant subexpressions of this expression into literals.
+    ///
+    /// Operations on integer constants are checked: division by zero,
+    /// overflow, and shifts by a negative amount or by 64 or more bits
+    /// are reported as errors at the operator. Operations whose operands
+    /// are literals of different types are left for the type checker.
+    pub fn fold(self) -> CompileResult<Self> {
+        match self {
+            NumExpr::BOp(op) => {
+                let mut operands = vec![];
+                let mut errs = vec![];
+                for operand in op.operands().iter().cloned() {
+                    match operand.fold() {
+                        Ok(operand) => operands.push(operand)
+                      , Err(mut e) => errs.append(&mut e)
+                    }
+                }
+                if !errs.is_empty() { return Err(errs) }
+                let lits = operands.iter()
+                                   .filter_map(|operand| match *operand {
+                                        NumExpr::Lit(ref lit) => Some(&lit.value)
+                                      , _ => None
+                                   })
+                                   .collect::<Vec<_>>();
+                if lits.len() == operands.len() {
+                    if let Some(lit) = try!(fold_literals(&op, &lits)) {
+                        return Ok(NumExpr::Lit(op.map(lit)))
+                    }
+                }
+                Ok(NumExpr::BOp(op.map(op.with_operands(operands))))
+            }
+          , NumExpr::Neg(operand) =>
+                match try!((*operand.value).clone().fold()) {
+                    NumExpr::Lit(Positional { value: Literal::IntConst(n), .. }) =>
+                        n.checked_neg()
+                         .map(|n| NumExpr::Lit(operand.map(Literal::IntConst(n))))
+                         .ok_or_else(|| vec![operand.map(String::from(
+                            "[error] constant expression overflows a \
+                             64-bit integer"))])
+                  , NumExpr::Lit(Positional { value: Literal::FloatConst(n), .. }) =>
+                        Ok(NumExpr::Lit(operand.map(Literal::FloatConst(-n))))
+                  , folded => Ok(NumExpr::Neg(operand.map(Box::new(folded))))
+                }
+          , num => Ok(num)
+        }
+    }
+}
+
+/// Define a function applying a step of a constant integer operation.
+macro_rules! int_step {
+    ($name:ident, $ty:ty) => {
+        fn $name<'a, S>(op: &NumBOp<'a, S>, a: $ty, b: $ty)
+                        -> Result<$ty, String>
+        where S: ScopednessTypestate {
+            let overflow = || String::from(
+                "[error] constant expression overflows a 64-bit integer");
+            match *op {
+                NumBOp::Add(_) => a.checked_add(b).ok_or_else(overflow)
+              , NumBOp::Sub(_) => a.checked_sub(b).ok_or_else(overflow)
+              , NumBOp::Mul(_) => a.checked_mul(b).ok_or_else(overflow)
+              , NumBOp::Div(_) if b == 0 => Err(String::from(
+                    "[error] division by zero in constant expression"))
+              , NumBOp::Div(_) => a.checked_div(b).ok_or_else(overflow)
+              , NumBOp::BitAnd(_) => Ok(a & b)
+              , NumBOp::BitOr(_)  => Ok(a | b)
+              , NumBOp::BitXor(_) => Ok(a ^ b)
+                // negative shifts are also caught, since they're huge
+                // when cast to `u64`
+              , NumBOp::ShiftL(_) | NumBOp::ShiftR(_) if b as u64 >= 64 =>
+                    Err(format!( "[error] constant shift by {} overflows a \
+                                  64-bit integer", b))
+              , NumBOp::ShiftL(_) => Ok(a << b)
+              , NumBOp::ShiftR(_) => Ok(a >> b)
+            }
         }
     }
 }
 
+int_step!(int_step, i64);
+int_step!(uint_step, u64);
+
+/// Apply the operator `op` to constant operands.
+///
+/// # Returns
+///   - `Ok(Some)` containing the result, if the operation was folded.
+///   - `Ok(None)` if the operation can't be folded, because its operands
+///     are of different types, or are not numbers that the operator
+///     applies to.
+///   - `Err` if the operation is an error, positioned at the operator.
+fn fold_literals<'a, S>(op: &Positional<NumBOp<'a, S>>, lits: &[&Literal])
+                        -> CompileResult<Option<Literal>>
+where S: ScopednessTypestate {
+    let fail = |msg| vec![op.map(msg)];
+    let (first, rest) = match lits.split_first() { Some(split) => split
+                                                 , None => return Ok(None) };
+    match **first {
+        Literal::IntConst(first) => {
+            let mut acc = first;
+            for lit in rest {
+                match **lit {
+                    Literal::IntConst(n) =>
+                        acc = try!(int_step(&op.value, acc, n).map_err(&fail))
+                  , _ => return Ok(None)
+                }
+            }
+            Ok(Some(Literal::IntConst(acc)))
+        }
+      , Literal::UintConst(first) => {
+            let mut acc = first;
+            for lit in rest {
+                match **lit {
+                    Literal::UintConst(n) =>
+                        acc = try!(uint_step(&op.value, acc, n).map_err(&fail))
+                  , _ => return Ok(None)
+                }
+            }
+            Ok(Some(Literal::UintConst(acc)))
+        }
+      , Literal::FloatConst(first) => {
+            let mut acc = first;
+            for lit in rest {
+                acc = match (&op.value, *lit) {
+                    (&NumBOp::Add(_), &Literal::FloatConst(n)) => acc + n
+                  , (&NumBOp::Sub(_), &Literal::FloatConst(n)) => acc - n
+                  , (&NumBOp::Mul(_), &Literal::FloatConst(n)) => acc * n
+                  , (&NumBOp::Div(_), &Literal::FloatConst(n)) => acc / n
+                  , _ => return Ok(None)
+                }
+            }
+            Ok(Some(Literal::FloatConst(acc)))
+        }
+      , _ => Ok(None)
+    }
+}
+
+impl<'a, S> Node for NumExpr<'a, S>
+where S: ScopednessTypestate {
+    fn to_sexpr(&self, level: usize) -> String {
+        match *self {
+            NumExpr::BOp(ref op) =>
+                format!( "({} {})", op.operator()
+                       , concat_exprs!(op.operands(), level))
+          , NumExpr::Neg(ref operand) =>
+                format!("(- {})", operand.value.to_sexpr(level))
+          , NumExpr::Lit(ref lit) => format!("{}", lit.value)
+          , NumExpr::Deref(ref name) => name.to_sexpr(level)
+          , NumExpr::Call(ref app) => app.to_sexpr(level)
+          , NumExpr::Other(ref expr) => expr.to_sexpr(level)
+        }
+    }
+}
+
+/// A comparison of two expressions, yielding a `bool`.
+///
+/// The logical connectives `and` and `or` are `Logical`s.
+#[derive(PartialEq, Clone, Debug)]
 pub enum BoolBOp<'a, S>
 where S: ScopednessTypestate
-    , S: 'a { Lt(Expr<'a, S>, Expr<'a, S>)
-            , LtE(Expr<'a, S>, Expr<'a, S>)
-            , Gt(Expr<'a, S>, Expr<'a, S>)
-            , GtE(Expr<'a, S>, Expr<'a, S>)
-            , Equal(Expr<'a, S>, Expr<'a, S>)
-            , NEqual(Expr<'a, S>, Expr<'a, S>)
-            , And(Expr<'a, S>, Expr<'a, S>)
-            , Or(Expr<'a, S>, Expr<'a, S>)
+    , S: 'a { Lt(Rc<Expr<'a, S>>, Rc<Expr<'a, S>>)
+            , LtE(Rc<Expr<'a, S>>, Rc<Expr<'a, S>>)
+            , Gt(Rc<Expr<'a, S>>, Rc<Expr<'a, S>>)
+            , GtE(Rc<Expr<'a, S>>, Rc<Expr<'a, S>>)
+            , Equal(Rc<Expr<'a, S>>, Rc<Expr<'a, S>>)
+            , NEqual(Rc<Expr<'a, S>>, Rc<Expr<'a, S>>)
             }
+
+impl<'a, S> BoolBOp<'a, S>
+where S: ScopednessTypestate {
+
+    /// Returns the name of the operator.
+    pub fn operator(&self) -> &'static str {
+        match *self { BoolBOp::Lt(..)     => "<"
+                    , BoolBOp::LtE(..)    => "<="
+                    , BoolBOp::Gt(..)     => ">"
+                    , BoolBOp::GtE(..)    => ">="
+                    , BoolBOp::Equal(..)  => "=="
+                    , BoolBOp::NEqual(..) => "/="
+                    }
+    }
+
+    pub fn operands(&self) -> (&Rc<Expr<'a, S>>, &Rc<Expr<'a, S>>) {
+        match *self { BoolBOp::Lt(ref a, ref b)
+                    | BoolBOp::LtE(ref a, ref b)
+                    | BoolBOp::Gt(ref a, ref b)
+                    | BoolBOp::GtE(ref a, ref b)
+                    | BoolBOp::Equal(ref a, ref b)
+                    | BoolBOp::NEqual(ref a, ref b) => (a, b)
+                    }
+    }
+
+    /// Returns the same comparison, of `a` and `b`.
+    pub fn with_operands<T>(&self, a: Rc<Expr<'a, T>>, b: Rc<Expr<'a, T>>)
+                            -> BoolBOp<'a, T>
+    where T: ScopednessTypestate {
+        match *self { BoolBOp::Lt(..)     => BoolBOp::Lt(a, b)
+                    , BoolBOp::LtE(..)    => BoolBOp::LtE(a, b)
+                    , BoolBOp::Gt(..)     => BoolBOp::Gt(a, b)
+                    , BoolBOp::GtE(..)    => BoolBOp::GtE(a, b)
+                    , BoolBOp::Equal(..)  => BoolBOp::Equal(a, b)
+                    , BoolBOp::NEqual(..) => BoolBOp::NEqual(a, b)
+                    }
+    }
+
+    /// Returns the type of the comparison, which is always `bool`.
+    #[inline] pub fn ty(&self) -> types::Type {
+        types::Type::Prim(types::Primitive::Bool)
+    }
+}
+
+impl<'a> BoolBOp<'a, UnscopedState> {
+
+    /// Apply the operator named `op` to `operands`.
+    ///
+    /// # Returns
+    ///   - `Some` containing the comparison, if `op` is a comparison
+    ///     operator and there are two operands.
+    ///   - `None` if the application is an ordinary function call.
+    pub fn new(op: &Ident, operands: &[Expr<'a, UnscopedState>])
+               -> Option<Self> {
+        if operands.len() != 2 { return None }
+        let a = Rc::new(operands[0].clone());
+        let b = Rc::new(operands[1].clone());
+        match &op.value[..] { "<"  => Some(BoolBOp::Lt(a, b))
+                            , "<=" => Some(BoolBOp::LtE(a, b))
+                            , ">"  => Some(BoolBOp::Gt(a, b))
+                            , ">=" => Some(BoolBOp::GtE(a, b))
+                            , "==" => Some(BoolBOp::Equal(a, b))
+                            , "/=" => Some(BoolBOp::NEqual(a, b))
+                            , _    => None
+                            }
+    }
+}
+
+impl<'a, S> Node for BoolBOp<'a, S>
+where S: ScopednessTypestate {
+    fn to_sexpr(&self, level: usize) -> String {
+        let (a, b) = self.operands();
+        format!( "({} {} {})", self.operator()
+               , a.to_sexpr(level), b.to_sexpr(level))
+    }
+}
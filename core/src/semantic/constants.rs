@@ -0,0 +1,57 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Constant folding
+//!
+//! After macro expansion, arithmetic and bitwise expressions whose
+//! operands are all constants are folded into literals, so that
+//! `(* 2 (+ 3 4))` becomes `14`. Errors in constant expressions, such as
+//! division by zero, are reported at the operator at fault.
+use ast::*;
+use errors::{ CompileResult, Errors };
+use super::annotations::{ Annotated, UnscopedState };
+use super::rewrite::{ Rewrite, walk_expr };
+
+type U = UnscopedState;
+
+/// Folds constant arithmetic expressions.
+///
+/// Errors are collected in `errs` rather than returned, so that folding
+/// continues past them, and every error in a form is reported. An
+/// expression in error is left unfolded, as are those containing it.
+struct Folder { errs: Errors }
+
+impl<'a> Rewrite<'a> for Folder {
+    fn expr(&mut self, expr: Expr<'a, U>) -> CompileResult<Expr<'a, U>> {
+        let errs = self.errs.len();
+        let expr = try!(walk_expr(self, expr));
+        if self.errs.len() > errs { return Ok(expr) }
+        let node = match expr.node {
+            Form::Num(num) => match num.clone().fold() {
+                Ok(NumExpr::Lit(lit)) => Form::Lit(lit.value)
+              , Ok(num) => Form::Num(num)
+              , Err(mut e) => { self.errs.append(&mut e); Form::Num(num) }
+            }
+          , node => node
+        };
+        Ok(Annotated::new(node, expr.position))
+    }
+}
+
+/// Fold the constant arithmetic expressions in `body`.
+///
+/// # Returns
+///  + `Ok` containing the folded forms, if every constant expression
+///    could be evaluated.
+///  + `Err` containing every error in a constant expression otherwise.
+pub fn fold<'a>(body: Body<'a, U>) -> CompileResult<Body<'a, U>> {
+    let mut folder = Folder { errs: vec![] };
+    let folded = try!(folder.exprs(body));
+    if folder.errs.is_empty() { Ok(folded) } else { Err(folder.errs) }
+}
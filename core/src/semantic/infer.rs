@@ -0,0 +1,1147 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Type inference
+//!
+//! `check_module()` infers the type of every expression in a scoped
+//! module, in the manner of Hindley and Milner, and checks them against
+//! the types the module declares:
+//!
+//!  + Functions are checked against their signatures, and bindings
+//!    against their `Binding.typ`. Within a definition, the type variables
+//!    of its signature stand for *any* type, so they may not be unified
+//!    with any other type. A type variable may only be written within a
+//!    signature that declares it, or within the definition it belongs to;
+//!    any other lowercase type name is reported as unknown.
+//!  + Definitions and `let`-bound names are generalised over the type
+//!    variables in their declared types which are not bound by an
+//!    enclosing signature, and so may be used at many types. Names bound
+//!    by patterns are not.
+//!  + Each use of a name whose type is constrained, as `(=> Eq a)`
+//!    constrains `a`, wants the type it is used at to be an instance of
+//!    the class. Where that type is a type variable of the enclosing
+//!    signature, the signature must constrain it likewise. The predicates
+//!    are returned in the `Typing` of the module, so that those on other
+//!    types can be resolved against the instances of their classes.
+//!  + Integer literals may have any numeric type, and floating-point
+//!    literals any floating-point type. Those whose types are not
+//!    otherwise determined are `int` and `double`, respectively.
+//!
+//! The inferred type of each name bound by a pattern is recorded in the
+//! `SymbolAnnotation::Value` of that name, in every scope that can see it.
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use ast::*;
+use errors::{ CompileResult, Errors, ExpectICE };
+use forktable::ForkTable;
+use position::{ Positional, Span };
+use super::{ SymbolAnnotation, SymbolTable };
+use super::annotations::{ NodeId, Scoped, ScopedState };
+use super::types::{ Constraint, Primitive, Reference, Signature, Type };
+use super::visit::{ self, Tables, VisitMut };
+
+type S = ScopedState;
+
+/// The type schemes of the names visible to an expression.
+type Env<'s> = ForkTable<'s, String, Scheme>;
+
+/// A typeclass predicate: `ty` must be an instance of `class`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Predicate { pub class: Ident
+                     , pub ty: Type
+                     , /// The type variable of the used name's signature
+                       /// that `ty` was substituted for.
+                       pub var: String
+                     , /// The use of a constrained name that wants the
+                       /// predicate to hold.
+                       pub pos: Span
+                     , /// The node in which the name is used.
+                       ///
+                       /// A macro may copy a use into several nodes at the
+                       /// same position, so the node tells the copies
+                       /// apart.
+                       pub node: NodeId
+                     }
+
+/// The result of inferring the types of a module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Typing { /// The predicates wanted by the module.
+                    ///
+                    /// Those on the type variables of a signature are
+                    /// satisfied by its constraints, and come first; those
+                    /// on concrete types follow, in the order in which
+                    /// they were found.
+                    pub predicates: Vec<Predicate>
+                  }
+
+/// Infer the types of `module`, recording the inferred types of names
+/// bound by patterns in its symbol tables.
+///
+/// # Returns
+///  + `Ok` containing the typeclass predicates wanted by the module, if it
+///    is well-typed.
+///  + `Err` containing an error for each type error otherwise. Errors are
+///    located at the expression whose type was unexpected, and followed by
+///    a note at the node that expected another type.
+pub fn check_module<'a>(module: &mut Scoped<'a, Module<'a, S>>)
+                       -> CompileResult<Typing> {
+    let (subst, predicates, errs) = {
+        let mut checker = Checker::new();
+        let mut env = Env::new();
+        {
+            let table = module.symbol_table();
+            for name in table.keys() {
+                match table.get(name) {
+                    Some(&SymbolAnnotation::Class { ref ty_param
+                                                  , ref methods, .. }) => {
+                        checker.classes.insert( name.clone()
+                                              , (ty_param.clone(), methods.clone()));
+                    }
+                  , Some(annotation) => if let Some(ty) = annotation.value_type() {
+                        env.insert(name.clone(), Scheme::generalize(&ty, &[], None));
+                    }
+                  , None => {}
+                }
+            }
+        }
+        // the module's own definitions replace the schemes above with ones
+        // that know where they were declared
+        for expr in &module.node.body { checker.declare(&mut env, expr) }
+        for expr in &module.node.body { checker.infer(&env, expr); }
+        checker.finish()
+    };
+    if !errs.is_empty() { return Err(errs) }
+    visit::walk_module(&mut Record(&subst, Tables::new()), module);
+    Ok(Typing { predicates: predicates })
+}
+
+/// Returns true if the type variable `name` stands for a type that is not
+/// yet known, rather than being written in the source code.
+///
+/// The variables made up by scope resolution and type inference begin
+/// with an underscore, but those in the source code begin with a letter.
+#[inline] pub fn is_inferred(name: &str) -> bool { name.starts_with('_') }
+
+/// Returns true if `ty` is an instance of `class`, where `class` is one of
+/// the numeric classes `Num`, `Integral` and `Fractional`.
+///
+/// The numeric classes are not declared by a `class` form; they are
+/// implemented by the primitive numeric types, by way of the arithmetic
+/// operators.
+pub fn is_numeric_instance(class: &str, ty: &Type) -> bool {
+    let kind = match class { "Num" => Kind::Numeric
+                           , "Integral" => Kind::Integral
+                           , "Fractional" => Kind::Floating
+                           , _ => return false
+                           };
+    match *ty { Type::Prim(ref p) => kind.admits(p)
+              , _ => false
+              }
+}
+
+/// Returns the type bound by a definition.
+fn def_type<'a>(def: &DefForm<'a, S>) -> Type {
+    match *def {
+        DefForm::TopLevel { ref annot, .. } => annot.clone()
+      , DefForm::Function { ref fun, .. } => Type::Function(fun.node.sig.clone())
+    }
+}
+
+/// Returns the type of lists of `elem`.
+///
+/// There is no list type yet, so the lists matched by list patterns are
+/// given the named type `(List a)`.
+fn list_of(elem: Type) -> Type { Type::Named(String::from("List"), vec![elem]) }
+
+/// The type of a name, which is polymorphic in the type variables `vars`,
+/// subject to `constraints`.
+#[derive(Clone, Debug)]
+struct Scheme { vars: Vec<String>
+              , constraints: Vec<Constraint>
+              , ty: Type
+              , /// Where the type was declared, if that is known.
+                pos: Option<Span>
+              }
+
+impl Scheme {
+
+    /// The scheme of a name with the single type `ty`, declared at `pos`.
+    fn mono(ty: Type, pos: Span) -> Self {
+        Scheme { vars: vec![], constraints: vec![], ty: ty, pos: Some(pos) }
+    }
+
+    /// The scheme of a name declared to have the type `ty`, generalised
+    /// over the type variables in it which are not in `bound`.
+    fn generalize(ty: &Type, bound: &[String], pos: Option<Span>) -> Self {
+        let vars = ty.type_vars().into_iter()
+                                .filter(|v| !is_inferred(v) && !bound.contains(v))
+                                .collect();
+        let constraints = match *ty {
+            Type::Function(ref sig) => sig.constraints.clone().unwrap_or(vec![])
+          , _ => vec![]
+        };
+        Scheme { vars: vars, constraints: constraints, ty: ty.clone(), pos: pos }
+    }
+}
+
+/// The kinds of numeric types that a literal or arithmetic may have.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Kind { Numeric, Integral, Floating }
+
+impl Kind {
+
+    fn describe(&self) -> &'static str {
+        match *self { Kind::Numeric => "a numeric type"
+                    , Kind::Integral => "an integer type"
+                    , Kind::Floating => "a floating-point type"
+                    }
+    }
+
+    /// The typeclass that constrains a type variable to this kind.
+    fn class(&self) -> &'static str {
+        match *self { Kind::Numeric => "Num"
+                    , Kind::Integral => "Integral"
+                    , Kind::Floating => "Fractional"
+                    }
+    }
+
+    /// Returns true if a constraint on the class `class` constrains a type
+    /// variable to this kind.
+    fn constrained_by(&self, class: &str) -> bool {
+        match *self {
+            Kind::Numeric =>
+                class == "Num" || class == "Integral" || class == "Fractional"
+          , _ => class == self.class()
+        }
+    }
+
+    fn admits(&self, prim: &Primitive) -> bool {
+        let integral = match *prim {
+            Primitive::Int(_) | Primitive::IntSize | Primitive::Uint(_)
+          | Primitive::UintSize | Primitive::Byte => true
+          , _ => false
+        };
+        let floating = match *prim {
+            Primitive::Float | Primitive::Double => true
+          , _ => false
+        };
+        match *self { Kind::Numeric => integral || floating
+                    , Kind::Integral => integral
+                    , Kind::Floating => floating
+                    }
+    }
+
+    /// Returns the kind of types that are of both this kind and `other`,
+    /// if there are any.
+    fn meet(self, other: Kind) -> Option<Kind> {
+        match (self, other) {
+            (Kind::Numeric, k) | (k, Kind::Numeric) => Some(k)
+          , (a, b) if a == b => Some(a)
+          , _ => None
+        }
+    }
+
+    /// The type of a value of this kind, if nothing else determines it.
+    fn default(&self) -> Type {
+        match *self { Kind::Floating => Type::Prim(Primitive::Double)
+                    , _ => Type::Prim(Primitive::IntSize)
+                    }
+    }
+}
+
+/// The types inferred for type variables.
+#[derive(Clone, Debug, Default)]
+struct Substitution(HashMap<String, Type>);
+
+impl Substitution {
+
+    /// Returns `ty`, with every type variable whose type has been inferred
+    /// replaced by that type.
+    fn resolve(&self, ty: &Type) -> Type {
+        match *ty {
+            Type::Var(ref v) => match self.0.get(v) {
+                Some(inferred) => self.resolve(inferred)
+              , None => ty.clone()
+            }
+          , _ => ty.map_children(|t| self.resolve(t))
+        }
+    }
+}
+
+/// The reason two types could not be unified, if it is more specific than
+/// that they differ.
+type Mismatch = Option<String>;
+
+struct Checker {
+    subst: Substitution
+  , /// The kinds of the numeric type variables.
+    kinds: HashMap<String, Kind>
+  , /// The type variables of the signatures being checked.
+    rigid: Vec<String>
+  , /// The constraints of the signatures being checked.
+    given: Vec<Constraint>
+  , /// The predicates wanted by the expressions checked so far.
+    wanted: Vec<Predicate>
+  , /// The predicates satisfied by the constraints of a signature.
+    satisfied: Vec<Predicate>
+  , /// The type parameter and function signatures of each typeclass.
+    classes: HashMap<String, (String, HashMap<String, Signature>)>
+  , /// The expression being checked.
+    node: Option<NodeId>
+  , next_var: usize
+  , errs: Errors
+}
+
+impl Checker {
+
+    fn new() -> Self {
+        Checker { subst: Substitution::default()
+                , kinds: HashMap::new()
+                , rigid: vec![]
+                , given: vec![]
+                , wanted: vec![]
+                , satisfied: vec![]
+                , classes: HashMap::new()
+                , node: None
+                , next_var: 0
+                , errs: vec![]
+                }
+    }
+
+    /// Returns a new type variable.
+    fn fresh(&mut self) -> Type {
+        self.next_var += 1;
+        Type::Var(format!("_t{}", self.next_var))
+    }
+
+    /// Returns a new type variable of the numeric kind `kind`.
+    fn fresh_of_kind(&mut self, kind: Kind) -> Type {
+        let ty = self.fresh();
+        if let Type::Var(ref v) = ty { self.kinds.insert(v.clone(), kind); }
+        ty
+    }
+
+    /// Make the type variables in `ty` rigid, until `self.rigid` is
+    /// truncated to its current length.
+    fn bind_rigid(&mut self, ty: &Type) {
+        for v in ty.type_vars() {
+            if !is_inferred(&v) && !self.rigid.contains(&v) { self.rigid.push(v) }
+        }
+    }
+
+    /// Report each type variable in `ty`, the type declared at `pos`, that
+    /// is not declared by a signature: either one enclosing `pos`, or one
+    /// written within `ty` itself.
+    ///
+    /// Returns true if every type variable in `ty` was declared.
+    fn check_type_vars(&mut self, ty: &Type, pos: Span) -> bool {
+        fn undeclared(ty: &Type, rigid: &[String], vars: &mut Vec<String>) {
+            match *ty {
+                Type::Var(ref v) if !is_inferred(v) && !rigid.contains(v) =>
+                    if !vars.contains(v) { vars.push(v.clone()) }
+              , Type::Function(_) => {}
+              , _ => for t in ty.children() { undeclared(t, rigid, vars) }
+            }
+        }
+        let mut vars = vec![];
+        undeclared(ty, &self.rigid, &mut vars);
+        for v in &vars {
+            self.errs.push(Positional::from(pos, format!(
+                "[error] unknown type `{}`: type variables may only be used \
+                 within the signature that declares them", v)));
+        }
+        vars.is_empty()
+    }
+
+    fn generalize(&self, ty: &Type, pos: Span) -> Scheme {
+        Scheme::generalize(ty, &self.rigid, Some(pos))
+    }
+
+    fn is_given(&self, class: &str, var: &str) -> bool {
+        self.given.iter().any(|c| c.typeclass.value == class
+                               && c.generics.iter().any(|g| g.value == var))
+    }
+
+    /// Returns the type of a use of `name`, and where that type was
+    /// declared.
+    fn lookup(&mut self, env: &Env, name: &Ident) -> (Type, Span) {
+        match env.get(&name.value) {
+            Some(scheme) => {
+                let mut vars = HashMap::new();
+                for v in &scheme.vars { let t = self.fresh(); vars.insert(v.clone(), t); }
+                for c in &scheme.constraints {
+                    for g in &c.generics {
+                        let ty = vars.get(&g.value)
+                                     .cloned()
+                                     .unwrap_or_else(|| Type::Var(g.value.clone()));
+                        let node = self.node.expect_ice(
+                            "a name was used outside of any expression");
+                        self.wanted.push(Predicate { class: c.typeclass.clone()
+                                                   , ty: ty
+                                                   , var: g.value.clone()
+                                                   , pos: name.pos
+                                                   , node: node });
+                    }
+                }
+                (scheme.ty.substitute(&vars), scheme.pos.unwrap_or(name.pos))
+            }
+            // scope resolution has already reported the undefined name
+          , None => (self.fresh(), name.pos)
+        }
+    }
+
+    /// Unify `found`, the type of the node at `found_pos`, with `expected`,
+    /// the type that the node at `expected_pos` requires it to have.
+    fn unify(&mut self, expected: &Type, expected_pos: Span
+                      , found: &Type, found_pos: Span) {
+        if let Err(mismatch) = self.unify_types(expected, found) {
+            let expected = self.subst.resolve(expected);
+            let found = self.subst.resolve(found);
+            let (msg, note) = match mismatch {
+                Some(msg) => (msg, String::from("required because of this"))
+              , None => ( format!( "type mismatch: expected `{}`, found `{}`"
+                                 , expected, found)
+                        , format!("`{}` is expected because of this", expected) )
+            };
+            self.errs.push(Positional::from(found_pos, format!("[error] {}", msg)));
+            if expected_pos != found_pos {
+                self.errs.push(Positional::from( expected_pos
+                                               , format!("[note] {}", note)));
+            }
+        }
+    }
+
+    fn unify_types(&mut self, a: &Type, b: &Type) -> Result<(), Mismatch> {
+        let (a, b) = (self.subst.resolve(a), self.subst.resolve(b));
+        match (&a, &b) {
+            (&Type::Var(ref v), &Type::Var(ref w)) if v == w => Ok(())
+          , (&Type::Var(ref v), _) if is_inferred(v) => self.bind(v, &b)
+          , (_, &Type::Var(ref v)) if is_inferred(v) => self.bind(v, &a)
+          , (&Type::Prim(ref p), &Type::Prim(ref q)) if p == q => Ok(())
+          , (&Type::Symbol(ref s), &Type::Symbol(ref t)) if s == t => Ok(())
+          , (&Type::Ref(ref r), &Type::Ref(ref s)) => match (r, s) {
+                (&Reference::Borrowed(ref x), &Reference::Borrowed(ref y))
+              | (&Reference::Moved(ref x), &Reference::Moved(ref y))
+              | (&Reference::Unique(ref x), &Reference::Unique(ref y))
+              | (&Reference::Raw(ref x), &Reference::Raw(ref y)) =>
+                    self.unify_types(x, y)
+              , _ => Err(None)
+            }
+          , (&Type::Option(ref x), &Type::Option(ref y)) => self.unify_types(x, y)
+          , (&Type::Function(ref f), &Type::Function(ref g))
+                if f.typechain.len() == g.typechain.len() =>
+                    self.unify_all(&f.typechain, &g.typechain)
+          , (&Type::Named(ref n, ref xs), &Type::Named(ref m, ref ys))
+                if n == m && xs.len() == ys.len() => self.unify_all(xs, ys)
+          , (&Type::Algebraic(ref xs), &Type::Algebraic(ref ys))
+                if xs.len() == ys.len() => self.unify_all(xs, ys)
+          , _ => Err(None)
+        }
+    }
+
+    fn unify_all(&mut self, xs: &[Type], ys: &[Type]) -> Result<(), Mismatch> {
+        for (x, y) in xs.iter().zip(ys) { try!(self.unify_types(x, y)) }
+        Ok(())
+    }
+
+    /// Infer that the type variable `var` is `ty`, which has been resolved.
+    fn bind(&mut self, var: &str, ty: &Type) -> Result<(), Mismatch> {
+        if ty.type_vars().iter().any(|v| v == var) {
+            return Err(Some(format!( "cannot construct the infinite type \
+                                      `{} = {}`", var, ty)))
+        }
+        if let Some(kind) = self.kinds.get(var).cloned() {
+            try!(self.constrain_kind(kind, ty))
+        }
+        self.subst.0.insert(var.to_string(), ty.clone());
+        Ok(())
+    }
+
+    /// Require `ty`, which has been resolved, to be of the numeric kind
+    /// `kind`.
+    fn constrain_kind(&mut self, kind: Kind, ty: &Type) -> Result<(), Mismatch> {
+        match *ty {
+            Type::Var(ref v) if is_inferred(v) => {
+                let meet = match self.kinds.get(v).cloned() {
+                    Some(other) => match kind.meet(other) {
+                        Some(meet) => meet
+                      , None => return Err(Some(format!(
+                            "a type cannot be both {} and {}"
+                           , kind.describe(), other.describe())))
+                    }
+                  , None => kind
+                };
+                self.kinds.insert(v.clone(), meet);
+                Ok(())
+            }
+          , Type::Var(ref v) if self.given.iter().any(|c|
+                kind.constrained_by(&c.typeclass.value)
+                    && c.generics.iter().any(|g| g.value == *v)) => Ok(())
+          , Type::Var(ref v) =>
+                Err(Some(format!( "`{}` is not known to be {}; add the \
+                                   constraint `(=> {} {})` to the signature"
+                                , v, kind.describe(), kind.class(), v)))
+          , Type::Prim(ref p) if kind.admits(p) => Ok(())
+          , _ => Err(Some(format!("`{}` is not {}", ty, kind.describe())))
+        }
+    }
+
+    /// Check the predicates wanted since there were `wanted` of them
+    /// against the constraints of the signature being checked, whose type
+    /// variables are those in `self.rigid` from the index `rigid`.
+    fn discharge(&mut self, wanted: usize, rigid: usize) {
+        for p in self.wanted.split_off(wanted) {
+            let ty = self.subst.resolve(&p.ty);
+            let var = match ty {
+                Type::Var(ref v) if !is_inferred(v) => Some(v.clone())
+              , _ => None
+            };
+            match var {
+                Some(ref v) if self.is_given(&p.class.value, v) =>
+                    self.satisfied.push(Predicate { ty: ty.clone(), ..p })
+              , Some(ref v) if self.rigid[rigid..].contains(v) =>
+                    self.unconstrained(&p, v)
+              , _ => self.wanted.push(Predicate { ty: ty, ..p })
+            }
+        }
+    }
+
+    fn unconstrained(&mut self, p: &Predicate, var: &str) {
+        self.errs.push(Positional::from(p.pos, format!(
+            "[error] {} is not known to be an instance of {}; add the \
+             constraint `(=> {} {})` to the signature"
+           , var, *p.class, *p.class, var)))
+    }
+
+    /// Default the types of literals that are not otherwise determined,
+    /// and check the predicates that remain.
+    fn finish(mut self) -> (Substitution, Vec<Predicate>, Errors) {
+        let kinds: Vec<(String, Kind)> = self.kinds
+                                             .iter()
+                                             .map(|(v, k)| (v.clone(), *k))
+                                             .collect();
+        for (v, kind) in kinds {
+            if let Type::Var(ref w) = self.subst.resolve(&Type::Var(v)) {
+                if !is_inferred(w) { continue }
+                let kind = self.kinds.get(w).cloned().unwrap_or(kind);
+                self.subst.0.insert(w.clone(), kind.default());
+            }
+        }
+        let mut predicates = mem::replace(&mut self.satisfied, vec![]);
+        for p in mem::replace(&mut self.wanted, vec![]) {
+            match self.subst.resolve(&p.ty) {
+                Type::Var(ref v) if !is_inferred(v) => self.unconstrained(&p, v)
+              , ty => predicates.push(Predicate { ty: ty, ..p })
+            }
+        }
+        (self.subst, predicates, self.errs)
+    }
+
+    /// Add the name defined by `expr`, if it is a definition or a data
+    /// type, to `env`.
+    fn declare(&self, env: &mut Env, expr: &Expr<S>) {
+        match expr.node {
+            Form::Define(ref def) => {
+                let name = def.name();
+                env.insert( name.value.clone()
+                          , self.generalize(&def_type(def), name.pos));
+            }
+          , Form::Data(ref data) =>
+                for (name, annotation) in data.annotations() {
+                    if let Some(ty) = annotation.value_type() {
+                        env.insert(name.clone(), self.generalize(&ty, data.name.pos));
+                    }
+                }
+          , _ => {}
+        }
+    }
+
+    /// Bind the name `name` to a value of type `ty` in `env`.
+    ///
+    /// The name's type is unified with the type that scope resolution gave
+    /// it in `table`, the symbol table of its pattern, so that its inferred
+    /// type may be recorded.
+    fn bind_name(&mut self, env: &mut Env, table: &SymbolTable, name: &Ident
+                , ty: &Type) {
+        let var = match table.get(&name.value) {
+            Some(&SymbolAnnotation::Value { ref ty, .. }) => ty.clone()
+          , _ => ice!("{} is not in the symbol table of its pattern", *name)
+        };
+        self.unify(ty, name.pos, &var, name.pos);
+        env.insert(name.value.clone(), Scheme::mono(var, name.pos));
+    }
+
+    /// Bind the names in the pattern `element`, which matches values of
+    /// type `ty` expected by the node at `pos`, in `env`. `table` is the
+    /// symbol table of the pattern.
+    fn bind_pattern(&mut self, env: &mut Env, table: &SymbolTable
+                   , element: &PatElement, ty: &Type, pos: Span) {
+        match *element {
+            PatElement::Name(ref name) => self.bind_name(env, table, name, ty)
+          , PatElement::Typed { ref name, ty: ref declared } => {
+                if self.check_type_vars(declared, name.pos) {
+                    self.unify(ty, pos, declared, name.pos);
+                }
+                self.bind_name(env, table, name, declared);
+            }
+          , PatElement::Deref(ref name) => {
+                let pointee = self.pointee(ty, name.pos);
+                self.bind_name(env, table, name, &pointee);
+            }
+          , PatElement::Lit(ref lit) => {
+                let found = self.literal(lit);
+                self.unify(ty, pos, &found, pos);
+            }
+          , PatElement::Anything => {}
+          , PatElement::Constructor { ref name, ref fields } => {
+                let (constructor, _) = self.lookup(env, name);
+                let (params, value) = match self.subst.resolve(&constructor) {
+                    Type::Function(sig) =>
+                        (sig.param_types().to_vec(), sig.return_type().clone())
+                  , value => (vec![], value)
+                };
+                if params.len() != fields.len() {
+                    self.errs.push(name.map(format!(
+                        "[error] constructor {} has {} fields, but the \
+                         pattern has {}", **name, params.len(), fields.len())));
+                }
+                self.unify(ty, pos, &value, name.pos);
+                for (field, param) in fields.iter().zip(&params) {
+                    self.bind_pattern(env, table, field, param, name.pos)
+                }
+            }
+          , PatElement::Cons { ref head, ref tail } => {
+                let elem = self.fresh();
+                let list = list_of(elem.clone());
+                self.unify(ty, pos, &list, pos);
+                self.bind_pattern(env, table, head, &elem, pos);
+                self.bind_pattern(env, table, tail, &list, pos);
+            }
+          , PatElement::List(ref elements) => {
+                let elem = self.fresh();
+                self.unify(ty, pos, &list_of(elem.clone()), pos);
+                for element in elements {
+                    self.bind_pattern(env, table, element, &elem, pos)
+                }
+            }
+        }
+    }
+
+    /// Returns the type pointed to by a reference of type `ty`,
+    /// dereferenced at `pos`.
+    fn pointee(&mut self, ty: &Type, pos: Span) -> Type {
+        match self.subst.resolve(ty) {
+            Type::Ref(Reference::Borrowed(t)) | Type::Ref(Reference::Moved(t))
+          | Type::Ref(Reference::Unique(t)) | Type::Ref(Reference::Raw(t)) =>
+                (*t).clone()
+          , Type::Var(ref v) if is_inferred(v) => {
+                let pointee = self.fresh();
+                let reference = Type::Ref(Reference::Borrowed(Rc::new(pointee.clone())));
+                self.unify(&reference, pos, ty, pos);
+                pointee
+            }
+          , other => {
+                self.errs.push(Positional::from(pos, format!(
+                    "[error] cannot dereference a value of type `{}`, which \
+                     is not a reference", other)));
+                self.fresh()
+            }
+        }
+    }
+
+    fn literal(&mut self, lit: &Literal) -> Type {
+        match *lit {
+            Literal::IntConst(_) => self.fresh_of_kind(Kind::Numeric)
+          , Literal::UintConst(_) => Type::Prim(Primitive::UintSize)
+          , Literal::FloatConst(_) => self.fresh_of_kind(Kind::Floating)
+          , Literal::StringLit(_) => Type::Prim(Primitive::Str)
+          , Literal::CharLit(_) => Type::Prim(Primitive::Char)
+          , Literal::BoolLit(_) => Type::Prim(Primitive::Bool)
+        }
+    }
+
+    /// Check a function against its signature. `pos` is the position of
+    /// the function's name, or of the lambda.
+    fn check_fn<'s>(&mut self, env: &'s Env<'s>, fun: &Function<S>, pos: Span) {
+        let sig = &fun.sig;
+        let (rigid, given, wanted) =
+            (self.rigid.len(), self.given.len(), self.wanted.len());
+        self.bind_rigid(&Type::Function(sig.clone()));
+        if let Some(ref constraints) = sig.constraints {
+            self.given.extend(constraints.iter().cloned())
+        }
+        for eq in &fun.equations {
+            if eq.node.pattern.len() != sig.arity() {
+                self.errs.push(Positional::from(eq.position, format!(
+                    "[error] this equation has {} patterns, but the function \
+                     has {} parameters", eq.node.pattern.len(), sig.arity())));
+                continue
+            }
+            let mut inner = env.fork();
+            let table = eq.symbol_table();
+            for (element, ty) in eq.node.pattern.iter().zip(sig.param_types()) {
+                self.bind_pattern(&mut inner, table, element, ty, eq.position)
+            }
+            let (ty, found) = self.infer_body(&inner, &eq.node.body, eq.position);
+            self.unify(sig.return_type(), pos, &ty, found);
+        }
+        self.discharge(wanted, rigid);
+        self.rigid.truncate(rigid);
+        self.given.truncate(given);
+    }
+
+    /// Check a definition, returning the type it binds.
+    fn check_def<'s>(&mut self, env: &'s Env<'s>, def: &DefForm<S>) -> Type {
+        match *def {
+            DefForm::TopLevel { ref name, ref annot, ref value } => {
+                let rigid = self.rigid.len();
+                let declared = self.check_type_vars(annot, name.pos);
+                self.bind_rigid(annot);
+                let ty = self.infer(env, value);
+                if declared { self.unify(annot, name.pos, &ty, value.position) }
+                self.rigid.truncate(rigid);
+            }
+          , DefForm::Function { ref name, ref fun } =>
+                self.check_fn(env, &fun.node, name.pos)
+        }
+        def_type(def)
+    }
+
+    fn check_binding<'s>(&mut self, env: &'s Env<'s>, binding: &Binding<S>) {
+        let rigid = self.rigid.len();
+        let declared = self.check_type_vars(&binding.typ, binding.name.pos);
+        self.bind_rigid(&binding.typ);
+        let ty = self.infer(env, &binding.value);
+        if declared {
+            self.unify(&binding.typ, binding.name.pos, &ty, binding.value.position)
+        }
+        self.rigid.truncate(rigid);
+    }
+
+    /// Check the functions of an instance against the signatures declared
+    /// by its class, with the instance's type for the class's parameter.
+    fn check_instance<'s>(&mut self, env: &'s Env<'s>, inst: &Instance<S>) {
+        for def in &inst.functions { self.check_def(env, def); }
+        let (param, methods) = match self.classes.get(&inst.class.value) {
+            Some(class) => class.clone()
+            // scope resolution has already reported the undefined class
+          , None => return
+        };
+        let mut vars = HashMap::new();
+        vars.insert(param, inst.ty.clone());
+        for def in &inst.functions {
+            if let DefForm::Function { ref name, ref fun } = *def {
+                let method = match methods.get(&name.value) {
+                    Some(method) => method
+                  , None => continue
+                };
+                let expected = Signature {
+                    constraints: None
+                  , typechain: method.typechain
+                                     .iter()
+                                     .map(|t| t.substitute(&vars))
+                                     .collect()
+                };
+                if expected.typechain != fun.node.sig.typechain {
+                    self.errs.push(name.map(format!(
+                        "[error] {} has type `{}` in instance {} {}, but \
+                         typeclass {} declares it as `{}`"
+                       , **name, fun.node.sig, *inst.class, inst.ty
+                       , *inst.class, expected)));
+                }
+            }
+        }
+    }
+
+    /// Infer the type of a body, in which each definition is visible
+    /// throughout, returning the type and position of its last expression.
+    fn infer_body<'s>(&mut self, env: &'s Env<'s>, body: &Body<S>, pos: Span)
+                     -> (Type, Span) {
+        let mut inner = env.fork();
+        for expr in body { self.declare(&mut inner, expr) }
+        let mut last = None;
+        for expr in body {
+            let ty = self.infer(&inner, expr);
+            last = Some((ty, expr.position));
+        }
+        match last { Some(last) => last
+                   , None => (self.fresh(), pos)
+                   }
+    }
+
+    fn infer_let<'s>(&mut self, env: &'s Env<'s>, form: &LetForm<S>, pos: Span)
+                    -> Type {
+        let mut scope = env.fork();
+        let body = match *form {
+            LetForm::Let { ref bindings, ref body } => {
+                for binding in bindings { self.check_binding(env, binding) }
+                for binding in bindings {
+                    let scheme = self.generalize(&binding.typ, binding.name.pos);
+                    scope.insert(binding.name.value.clone(), scheme);
+                }
+                body
+            }
+          , LetForm::LetSplat { ref bindings, ref body } => {
+                for binding in bindings {
+                    self.check_binding(&scope, binding);
+                    let scheme = self.generalize(&binding.typ, binding.name.pos);
+                    scope.insert(binding.name.value.clone(), scheme);
+                }
+                body
+            }
+          , LetForm::LetRec { ref bindings, ref body } => {
+                for binding in bindings {
+                    let scheme = self.generalize(&binding.typ, binding.name.pos);
+                    scope.insert(binding.name.value.clone(), scheme);
+                }
+                for binding in bindings { self.check_binding(&scope, binding) }
+                body
+            }
+          , LetForm::Invocation { ref proc_id, ref ret_ty, ref init, ref body } => {
+                self.check_binding(env, init);
+                let proc_ty = Signature { constraints: None
+                                        , typechain: vec![ init.typ.clone()
+                                                         , ret_ty.clone() ]
+                                        };
+                scope.insert( init.name.value.clone()
+                            , Scheme::mono(init.typ.clone(), init.name.pos));
+                scope.insert( proc_id.value.clone()
+                            , Scheme::mono(Type::Function(proc_ty), proc_id.pos));
+                body
+            }
+        };
+        let (ty, found) = self.infer_body(&scope, body, pos);
+        if let LetForm::Invocation { ref proc_id, ref ret_ty, .. } = *form {
+            self.unify(ret_ty, proc_id.pos, &ty, found);
+        }
+        ty
+    }
+
+    /// Infer the type of a function application at `pos`.
+    fn infer_app<'s>(&mut self, env: &'s Env<'s>, app: &AppForm<S>, pos: Span)
+                    -> Type {
+        let (fun, declared) = self.lookup(env, &app.fun);
+        let args: Vec<(Type, Span)> = app.params
+                                         .iter()
+                                         .map(|p| (self.infer(env, p), p.position))
+                                         .collect();
+        match self.subst.resolve(&fun) {
+            Type::Function(sig) => {
+                if sig.arity() != args.len() {
+                    self.errs.push(Positional::from(pos, format!(
+                        "[error] {} takes {} arguments, but {} were given"
+                       , *app.fun, sig.arity(), args.len())));
+                }
+                for (param, &(ref arg, arg_pos)) in sig.param_types().iter().zip(&args) {
+                    self.unify(param, declared, arg, arg_pos)
+                }
+                sig.return_type().clone()
+            }
+          , Type::Var(ref v) if is_inferred(v) => {
+                let ret = self.fresh();
+                let mut typechain: Vec<Type> = args.iter()
+                                                   .map(|&(ref t, _)| t.clone())
+                                                   .collect();
+                typechain.push(ret.clone());
+                let sig = Signature { constraints: None, typechain: typechain };
+                self.unify(&fun, declared, &Type::Function(sig), pos);
+                ret
+            }
+          , other => {
+                self.errs.push(app.fun.map(format!(
+                    "[error] {} is not a function, but has type `{}`"
+                   , *app.fun, other)));
+                self.fresh()
+            }
+        }
+    }
+
+    fn infer_name_ref(&mut self, env: &Env, name: &NameRef) -> Type {
+        match *name {
+            NameRef::Owned(ref n) => self.lookup(env, n).0
+          , NameRef::Borrowed(ref n) =>
+                Type::Ref(Reference::Borrowed(Rc::new(self.lookup(env, n).0)))
+          , NameRef::Unique(ref n) =>
+                Type::Ref(Reference::Unique(Rc::new(self.lookup(env, n).0)))
+          , NameRef::Deref(ref n) => {
+                let ty = self.lookup(env, n).0;
+                self.pointee(&ty, n.pos)
+            }
+        }
+    }
+
+    /// Infer the type of an arithmetic expression.
+    fn infer_num<'s>(&mut self, env: &'s Env<'s>, num: &NumExpr<S>) -> Type {
+        match *num {
+            NumExpr::BOp(ref op) => {
+                let kind = match op.value {
+                    NumBOp::Add(_) | NumBOp::Sub(_)
+                  | NumBOp::Mul(_) | NumBOp::Div(_) => Kind::Numeric
+                  , _ => Kind::Integral
+                };
+                let ty = self.fresh_of_kind(kind);
+                for operand in op.value.operands() {
+                    let found = self.infer_num(env, operand);
+                    let found_pos = num_position(operand);
+                    self.unify(&ty, op.pos, &found, found_pos);
+                }
+                ty
+            }
+          , NumExpr::Neg(ref operand) => {
+                let ty = self.fresh_of_kind(Kind::Numeric);
+                let found = self.infer_num(env, &operand.value);
+                let found_pos = num_position(&operand.value);
+                self.unify(&ty, operand.pos, &found, found_pos);
+                ty
+            }
+          , NumExpr::Lit(ref lit) => self.literal(lit)
+          , NumExpr::Deref(ref name) => self.infer_name_ref(env, name)
+          , NumExpr::Call(ref app) => self.infer_app(env, app, app.pos)
+          , NumExpr::Other(ref expr) => self.infer(env, expr)
+        }
+    }
+
+    /// Returns the definition of the type `ty` of a record found at `pos`,
+    /// as it is known to `table`, the symbol table of the record's node.
+    fn record_type<'t>( &mut self, table: &'t SymbolTable<'t>, ty: &Type
+                      , pos: Span) -> Option<&'t SymbolAnnotation<'t>> {
+        match self.subst.resolve(ty) {
+            Type::Named(ref name, _) => {
+                let found = table.get(name);
+                match found {
+                    Some(&SymbolAnnotation::Record { .. })
+                  | Some(&SymbolAnnotation::TypeDef(_)) => found
+                  , _ => {
+                        self.errs.push(Positional::from(pos, format!(
+                            "[error] the record type `{}` is not known here"
+                           , name)));
+                        None
+                    }
+                }
+            }
+          , Type::Var(ref v) if is_inferred(v) => {
+                self.errs.push(Positional::from(pos, String::from(
+                    "[error] the type of this record must be known here, but \
+                     could not be inferred")));
+                None
+            }
+          , other => {
+                self.errs.push(Positional::from(pos, format!(
+                    "[error] expected a record, found `{}`", other)));
+                None
+            }
+        }
+    }
+
+    /// Infer the type of quoted data.
+    ///
+    /// Quoted lists are not yet typed, except for the expressions unquoted
+    /// within them.
+    fn infer_datum<'s>(&mut self, env: &'s Env<'s>, datum: &Datum<S>) -> Type {
+        match *datum {
+            Datum::Symbol(ref name) => Type::Symbol(name.value.clone())
+          , Datum::Lit(ref lit) => self.literal(lit)
+          , Datum::List(ref data) => {
+                for d in data { self.infer_datum(env, d); }
+                self.fresh()
+            }
+          , Datum::Unquote(ref expr) => self.infer(env, expr)
+          , Datum::UnquoteSplicing(ref expr) => {
+                self.infer(env, expr);
+                self.fresh()
+            }
+        }
+    }
+
+    /// Infer the type of `expr`.
+    fn infer<'s>(&mut self, env: &'s Env<'s>, expr: &Expr<S>) -> Type {
+        let outer = mem::replace(&mut self.node, Some(expr.id()));
+        let ty = self.infer_node(env, expr);
+        self.node = outer;
+        ty
+    }
+
+    /// Infer the type of `expr`, which is the expression being checked.
+    fn infer_node<'s>(&mut self, env: &'s Env<'s>, expr: &Expr<S>) -> Type {
+        let pos = expr.position;
+        let bool_ty = Type::Prim(Primitive::Bool);
+        match expr.node {
+            Form::Define(ref def) => self.check_def(env, def)
+          , Form::If { ref condition, ref if_clause, ref else_clause } => {
+                let cond = self.infer(env, condition);
+                self.unify(&bool_ty, pos, &cond, condition.position);
+                let ty = self.infer(env, if_clause);
+                if let Some(ref else_clause) = *else_clause {
+                    let found = self.infer(env, else_clause);
+                    self.unify(&ty, if_clause.position, &found, else_clause.position);
+                }
+                ty
+            }
+          , Form::Let(ref form) => self.infer_let(env, form, pos)
+          , Form::App(ref app) => self.infer_app(env, app, pos)
+          , Form::Lambda(ref fun) => {
+                self.check_fn(env, fun, pos);
+                Type::Function(fun.sig.clone())
+            }
+          , Form::Data(ref data) => Type::Named(data.name.value.clone(), vec![])
+          , Form::Class(_) => self.fresh()
+          , Form::Instance(ref inst) => {
+                self.check_instance(env, inst);
+                self.fresh()
+            }
+          , Form::Logical(ref logical) => {
+                match *logical {
+                    Logical::And { ref a, ref b } | Logical::Or { ref a, ref b } =>
+                        for operand in &[a, b] {
+                            let found = self.infer(env, operand);
+                            self.unify(&bool_ty, pos, &found, operand.position);
+                        }
+                }
+                logical.ty()
+            }
+          , Form::Bool(ref cmp) => {
+                let (a, b) = cmp.operands();
+                let (ty_a, ty_b) = (self.infer(env, a), self.infer(env, b));
+                self.unify(&ty_a, a.position, &ty_b, b.position);
+                cmp.ty()
+            }
+          , Form::Num(ref num) => self.infer_num(env, num)
+          , Form::Lit(ref lit) => self.literal(lit)
+          , Form::NameRef(ref name) => self.infer_name_ref(env, name)
+          , Form::Sigil(Sigil::Unwrap { ref option, ref default }) => {
+                let found = self.infer(env, option);
+                let inner = self.fresh();
+                self.unify( &Type::Option(Rc::new(inner.clone())), pos
+                          , &found, option.position);
+                if let Some(ref default) = *default {
+                    let found = self.infer(env, default);
+                    self.unify(&inner, option.position, &found, default.position);
+                }
+                inner
+            }
+          , Form::Sigil(ref sigil) => {
+                let operand = match *sigil {
+                    Sigil::Borrow(ref e) | Sigil::Unique(ref e) | Sigil::Raw(ref e) =>
+                        self.infer(env, e)
+                  , Sigil::Unwrap { .. } => unreachable!()
+                };
+                match sigil.ty(operand, None) {
+                    Ok(ty) => ty
+                  , Err(msg) => {
+                        self.errs.push(Positional::from(pos, format!("[error] {}", msg)));
+                        self.fresh()
+                    }
+                }
+            }
+          , Form::Begin(ref body) => self.infer_body(env, body, pos).0
+          , Form::Set(ref form) => {
+                let (expected, declared) = self.lookup(env, &form.name);
+                let found = self.infer(env, &form.value);
+                self.unify(&expected, declared, &found, form.value.position);
+                found
+            }
+          , Form::Match(ref form) => {
+                let scrutinee = self.infer(env, &form.scrutinee);
+                let mut result: Option<(Type, Span)> = None;
+                for arm in &form.arms {
+                    let mut inner = env.fork();
+                    self.bind_pattern( &mut inner, arm.symbol_table()
+                                     , &arm.node.pattern, &scrutinee
+                                     , form.scrutinee.position);
+                    let (ty, found) = self.infer_body(&inner, &arm.node.body, arm.position);
+                    match result {
+                        Some((ref expected, expected_pos)) =>
+                            self.unify(expected, expected_pos, &ty, found)
+                      , None => {}
+                    }
+                    if result.is_none() { result = Some((ty, found)) }
+                }
+                if let Some(catch_all) = form.catch_all() {
+                    for arm in form.unreachable_arms() {
+                        self.errs.push(Positional::from(arm.position, String::from(
+                            "[error] this arm is unreachable")));
+                        self.errs.push(Positional::from(catch_all.position, String::from(
+                            "[note] every value is matched by this earlier arm")));
+                    }
+                }
+                match result { Some((ty, _)) => ty
+                             , None => self.fresh()
+                             }
+            }
+          , Form::Field(ref access) => {
+                let record = self.infer(env, &access.record);
+                let table = access.record.symbol_table();
+                match self.record_type(table, &record, access.record.position) {
+                    Some(def) => match access.check(def) {
+                        Ok(ty) => ty
+                      , Err(e) => { self.errs.extend(e); self.fresh() }
+                    }
+                  , None => self.fresh()
+                }
+            }
+          , Form::With(ref update) => {
+                let record = self.infer(env, &update.record);
+                let values: Vec<(Type, Span)> =
+                    update.fields
+                          .iter()
+                          .map(|&(_, ref value)| (self.infer(env, value), value.position))
+                          .collect();
+                let table = update.record.symbol_table();
+                if let Some(def) = self.record_type(table, &record, update.record.position) {
+                    match update.check(def) {
+                        Ok(types) =>
+                            for (ty, (&(ref field, _), &(ref found, found_pos))) in
+                                types.iter().zip(update.fields.iter().zip(&values)) {
+                                self.unify(ty, field.pos, found, found_pos)
+                            }
+                      , Err(e) => self.errs.extend(e)
+                    }
+                }
+                record
+            }
+          , Form::Quote(ref datum) | Form::Quasiquote(ref datum) =>
+                self.infer_datum(env, datum)
+          , Form::Macro(ref mac) =>
+                ice!( "the definition of macro {} should have been removed \
+                       by macro expansion", *mac.name)
+        }
+    }
+}
+
+/// Returns the position of an operand of an arithmetic operator.
+fn num_position<'a>(num: &NumExpr<'a, S>) -> Span {
+    match *num {
+        NumExpr::BOp(ref op) => op.pos
+      , NumExpr::Neg(ref operand) => operand.pos
+      , NumExpr::Lit(ref lit) => lit.pos
+      , NumExpr::Deref(ref name) => name.name().pos
+      , NumExpr::Call(ref app) => app.pos
+      , NumExpr::Other(ref expr) => expr.position
+    }
+}
+
+/// Records the inferred types of the values in the symbol tables of a
+/// module.
+struct Record<'s, 'a>(&'s Substitution, Tables<'a>);
+
+impl<'s, 'a> VisitMut<'a> for Record<'s, 'a> {
+    fn tables(&mut self) -> Option<&mut Tables<'a>> { Some(&mut self.1) }
+
+    fn table(&mut self, table: &mut SymbolTable<'a>) {
+        let names: Vec<String> = table.keys().cloned().collect();
+        for name in names {
+            if let Some(&mut SymbolAnnotation::Value { ref mut ty, .. }) =
+                table.get_mut(&name) {
+                *ty = self.0.resolve(ty);
+            }
+        }
+    }
+}
@@ -0,0 +1,578 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Typeclass resolution
+//!
+//! `resolve_module()` lowers the typeclasses of a type-checked module to
+//! plain functions, by specialisation, so that it can be compiled:
+//!
+//!  + The functions of each instance are hoisted to the top level of the
+//!    module, named for the class, the instance type and the function, as
+//!    in `Eq<int>.eq`. Each use of a class function is resolved to the
+//!    function of the instance for the type it is used at.
+//!  + Each top-level function whose signature is constrained is replaced
+//!    by a copy for each combination of types at which its constrained
+//!    type variables are used, named as in `same<int>`. The uses of class
+//!    functions within a copy are resolved at its types, and a function
+//!    that is never used at any is not compiled at all.
+//!  + A constrained function imported from another module is specialised
+//!    by that module, so the specialisations an importer wants are
+//!    returned, to be passed to the module that defines them. Importers
+//!    are resolved before the modules they import.
+//!  + The numeric classes `Num`, `Integral` and `Fractional` have no
+//!    functions, so a predicate on one of them is only checked.
+//!
+//! The instances of a class must be coherent: no type may be an instance
+//! of a class by two instance declarations, or the function used would
+//! depend on which was found first.
+//!
+//! Class and instance declarations are removed from the resolved module,
+//! so that `compile` never sees them.
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use ast::*;
+use errors::{ CompileResult, Errors, ExpectICE };
+use position::{ Positional, Span };
+use super::{ infer, SymbolAnnotation, SymbolTable };
+use super::annotations::{ NodeId, Scoped, ScopedState };
+use super::infer::{ Predicate, Typing };
+use super::types::{ Signature, Type };
+use super::visit::{ self, Tables, VisitMut };
+
+type S = ScopedState;
+
+/// Resolve the typeclass functions and constrained functions used in
+/// `module`, whose predicates are given by `typing`, where `requested` are
+/// the specialisations of the module's constrained functions wanted by
+/// its importers.
+///
+/// # Returns
+///  + `Ok` if every predicate is satisfied by exactly one instance,
+///    containing the specialisations wanted of imported functions. The
+///    module's body is replaced by its resolved definitions, and the names
+///    of the functions hoisted from instances and specialised are added
+///    to every symbol table in the module.
+///  + `Err` containing an error for each overlapping instance, and for
+///    each predicate that no instance satisfies, otherwise.
+pub fn resolve_module<'a>( module: &mut Scoped<'a, Module<'a, S>>
+                         , typing: &Typing
+                         , requested: Vec<Specialisation>)
+                         -> CompileResult<Vec<Specialisation>> {
+    let globals = module.scope();
+    let mut methods = HashMap::new();
+    let mut instances = HashMap::new();
+    for name in globals.keys() {
+        if let Some(&SymbolAnnotation::Class { methods: ref ms
+                                             , instances: ref tys, .. }) =
+            globals.get(name) {
+            for method in ms.keys() { methods.insert(method.clone(), name.clone()); }
+            instances.insert(name.clone(), tys.clone());
+        }
+    }
+    let errs = check_coherence(&module.node.body, &instances);
+    if !errs.is_empty() { return Err(errs) }
+
+    // the constrained functions of the module's scope are those it defines
+    // at the top level and those it imports
+    let mut generic = HashMap::new();
+    for name in globals.keys() {
+        if let Some(&SymbolAnnotation::Value { ty: Type::Function(ref sig), .. }) =
+            globals.get(name) {
+            let vars = constrained_vars(sig);
+            if !vars.is_empty() && !methods.contains_key(name) {
+                generic.insert(name.clone(), vars);
+            }
+        }
+    }
+    let mut uses = HashMap::new();
+    for p in &typing.predicates {
+        uses.entry((p.node, p.pos)).or_insert(vec![]).push(p);
+    }
+
+    let mut resolver = Resolver { methods: methods
+                                , instances: instances
+                                , generic: generic
+                                , uses: uses
+                                , node: None
+                                , globals: globals
+                                , vars: HashMap::new()
+                                , tables: Tables::new()
+                                , wanted: vec![]
+                                , defined: vec![]
+                                , errs: vec![]
+                                };
+    for spec in requested { resolver.want(spec) }
+
+    // resolve the module's own expressions, and the functions of its
+    // instances, setting aside its constrained functions to be specialised
+    let mut items = vec![];
+    let mut originals = HashMap::new();
+    for mut expr in mem::replace(&mut module.node.body, vec![]) {
+        let table = expr.scope();
+        let position = expr.position;
+        let is_generic = match expr.node {
+            Form::Define(ref def) =>
+                resolver.generic.contains_key(&def.name().value)
+          , _ => false
+        };
+        if is_generic {
+            if let Form::Define(def) = expr.node {
+                let name = def.name().value.clone();
+                items.push(Item::Generic(name.clone()));
+                originals.insert(name, (def, position, table));
+            }
+            continue
+        }
+        match expr.node {
+            Form::Class(_) => {}
+          , Form::Instance(inst) =>
+                for def in inst.functions {
+                    let name = mangle_method( &inst.class.value, &inst.ty
+                                            , &def.name().value);
+                    let def = rename(def, name);
+                    resolver.define(&def);
+                    let mut hoisted = Scoped::in_scope( Form::Define(def)
+                                                      , position
+                                                      , table.clone());
+                    resolver.expr(&mut hoisted);
+                    items.push(Item::Resolved(hoisted));
+                }
+          , node => {
+                let mut expr = Scoped::in_scope(node, position, table);
+                resolver.expr(&mut expr);
+                items.push(Item::Resolved(expr));
+            }
+        }
+    }
+
+    // specialise the constrained functions at the types they are used at,
+    // which may want further specialisations in turn
+    let mut copies: HashMap<String, Vec<Expr<'a, S>>> = HashMap::new();
+    let mut imported = vec![];
+    let mut next = 0;
+    while next < resolver.wanted.len() {
+        let spec = resolver.wanted[next].clone();
+        next += 1;
+        let vars: HashMap<String, Type> = resolver.generic[&spec.name]
+                                                  .iter()
+                                                  .cloned()
+                                                  .zip(spec.types.iter().cloned())
+                                                  .collect();
+        let (def, position, table) = match originals.get(&spec.name) {
+            Some(&(ref def, position, ref table)) =>
+                (def.clone(), position, table.clone())
+          , None => {
+                // the module that defines an imported function compiles
+                // its copy, which is only declared here
+                let ty = resolver.globals.get(&spec.name)
+                                         .and_then(|a| a.value_type());
+                if let Some(Type::Function(sig)) = ty {
+                    let ty = Type::Function(specialise_signature(&sig, &vars));
+                    let annotation = SymbolAnnotation::Value { ty: ty
+                                                             , proven_value: None
+                                                             , mutable: false };
+                    resolver.defined.push((spec.mangled.clone(), annotation));
+                }
+                imported.push(spec);
+                continue
+            }
+        };
+        let def = specialise(def, &vars, spec.mangled);
+        resolver.define(&def);
+        let mut copy = Scoped::in_scope(Form::Define(def), position, table);
+        // the tables are substituted anew for each specialisation
+        resolver.vars = vars;
+        resolver.tables = Tables::new();
+        resolver.expr(&mut copy);
+        resolver.vars = HashMap::new();
+        copies.entry(spec.name).or_insert(vec![]).push(copy);
+    }
+
+    if !resolver.errs.is_empty() { return Err(resolver.errs) }
+
+    for item in items {
+        match item {
+            Item::Resolved(expr) => module.node.body.push(expr)
+          , Item::Generic(name) =>
+                if let Some(specialised) = copies.remove(&name) {
+                    module.node.body.extend(specialised)
+                }
+        }
+    }
+    visit::walk_module(&mut Declare(resolver.defined, Tables::new()), module);
+    Ok(imported)
+}
+
+/// An expression of a resolved module, or the place of a constrained
+/// function's specialised copies.
+enum Item<'a> { Resolved(Expr<'a, S>)
+              , Generic(String)
+              }
+
+/// A copy of the constrained function `name`, wanted at `types`.
+#[derive(Clone, Debug)]
+pub struct Specialisation { pub name: String
+                          , /// The types of the function's constrained type
+                            /// variables, in the order of `constrained_vars()`.
+                            pub types: Vec<Type>
+                          , /// The name of the copy.
+                            pub mangled: String
+                          }
+
+/// How a predicate is satisfied.
+enum Satisfied { /// By the primitive numeric types.
+                 Builtin
+               , /// By the instance declared for this type.
+                 Instance(Type)
+               }
+
+/// Resolves the uses of constrained names in the expressions it visits.
+struct Resolver<'r, 'a> {
+    /// The class that declares each class function.
+    methods: HashMap<String, String>
+  , /// The instance types of each class.
+    instances: HashMap<String, Vec<Type>>
+  , /// The constrained type variables of each constrained function defined
+    /// at the top level of the module or imported by it.
+    generic: HashMap<String, Vec<String>>
+  , /// The predicates wanted by the use of a name at each position of
+    /// each node.
+    uses: HashMap<(NodeId, Span), Vec<&'r Predicate>>
+  , /// The expression being visited.
+    node: Option<NodeId>
+  , /// The module's symbol table, to tell the names of the module from
+    /// those bound within a function.
+    globals: Rc<SymbolTable<'a>>
+  , /// The types at which the function being visited is specialised.
+    vars: HashMap<String, Type>
+  , /// The tables substituted so far in the function being specialised.
+    tables: Tables<'a>
+  , /// The specialisations wanted so far, in the order they were wanted.
+    wanted: Vec<Specialisation>
+  , /// The names of the functions hoisted from instances and specialised.
+    defined: Vec<(String, SymbolAnnotation<'a>)>
+  , errs: Errors
+}
+
+impl<'r, 'a> Resolver<'r, 'a> {
+
+    fn define(&mut self, def: &DefForm<'a, S>) {
+        self.defined.push((def.name().value.clone(), def.annotation()))
+    }
+
+    /// Wants `spec`, unless it is already wanted.
+    fn want(&mut self, spec: Specialisation) {
+        if !self.wanted.iter().any(|s| s.mangled == spec.mangled) {
+            self.wanted.push(spec)
+        }
+    }
+
+    /// Returns how `p`, whose type is known, is satisfied, or reports that
+    /// it is not.
+    fn satisfy(&mut self, p: &Predicate) -> Option<Satisfied> {
+        if p.ty.type_vars().iter().any(|v| infer::is_inferred(v)) {
+            self.errs.push(Positional::from(p.pos, format!(
+                "[error] cannot tell which instance of {} is wanted here, \
+                 since the type `{}` is not known", *p.class, p.ty)));
+            return None
+        }
+        if infer::is_numeric_instance(&p.class.value, &p.ty) {
+            return Some(Satisfied::Builtin)
+        }
+        let found = self.instances
+                        .get(&p.class.value)
+                        .and_then(|tys| tys.iter()
+                                           .find(|t| matches(t, &p.ty, &mut HashMap::new()))
+                                           .cloned());
+        match found {
+            Some(ty) => Some(Satisfied::Instance(ty))
+          , None => {
+                self.errs.push(Positional::from(p.pos, format!(
+                    "[error] no instance of {} for `{}`", *p.class, p.ty)));
+                None
+            }
+        }
+    }
+}
+
+impl<'r, 'a> VisitMut<'a> for Resolver<'r, 'a> {
+
+    fn expr(&mut self, expr: &mut Expr<'a, S>) {
+        let unspecialisable = match expr.node {
+            Form::Define(DefForm::Function { ref name, ref fun })
+                if !constrained_vars(&fun.node.sig).is_empty() =>
+                    Some(name.map(format!(
+                        "[error] {} cannot be specialised, since it is not \
+                         defined at the top level of the module", **name)))
+          , Form::Lambda(ref fun) if !constrained_vars(&fun.sig).is_empty() =>
+                Some(Positional::from(expr.position, String::from(
+                    "[error] a lambda cannot be specialised, so its \
+                     signature may not be constrained")))
+          , _ => None
+        };
+        match unspecialisable {
+            Some(err) => self.errs.push(err)
+          , None => {
+                let outer = mem::replace(&mut self.node, Some(expr.id()));
+                visit::walk_expr(self, expr);
+                self.node = outer;
+            }
+        }
+    }
+
+    /// The tables of a function are only updated while it is specialised.
+    fn tables(&mut self) -> Option<&mut Tables<'a>> {
+        if self.vars.is_empty() { None } else { Some(&mut self.tables) }
+    }
+
+    /// Substitute the types of a specialisation in the types of the names
+    /// bound within the function.
+    fn table(&mut self, table: &mut SymbolTable<'a>) {
+        let names: Vec<String> = table.keys().cloned().collect();
+        for name in names {
+            if let Some(&mut SymbolAnnotation::Value { ref mut ty, .. }) =
+                table.get_mut(&name) {
+                let global = match self.globals.get(&name) {
+                    Some(&SymbolAnnotation::Value { ty: ref g, .. }) => *g == *ty
+                  , _ => false
+                };
+                if !global { *ty = ty.substitute(&self.vars) }
+            }
+        }
+    }
+
+    fn reference(&mut self, name: &mut Ident) {
+        let node = self.node.expect_ice("a name was used outside of any expression");
+        let predicates: Vec<Predicate> = match self.uses.get(&(node, name.pos)) {
+            Some(ps) => ps.iter()
+                          .map(|p| Predicate { ty: p.ty.substitute(&self.vars)
+                                             , ..(*p).clone() })
+                          .collect()
+          , None => return
+        };
+        let mut satisfied = vec![];
+        for p in &predicates { satisfied.push(self.satisfy(p)) }
+        if satisfied.iter().any(|s| s.is_none()) { return }
+
+        let resolved = if let Some(class) = self.methods.get(&name.value).cloned() {
+            // the class's own constraint is the first in the signature of
+            // each of its functions
+            match predicates.iter()
+                            .zip(&satisfied)
+                            .find(|&(p, _)| p.class.value == class) {
+                Some((_, &Some(Satisfied::Instance(ref ty)))) =>
+                    mangle_method(&class, ty, &name.value)
+              , _ => return
+            }
+        } else if let Some(vars) = self.generic.get(&name.value).cloned() {
+            let types: Vec<Type> =
+                vars.iter()
+                    .map(|v| predicates.iter()
+                                       .find(|p| p.var == *v)
+                                       .map(|p| p.ty.clone())
+                                       .unwrap_or_else(|| Type::Var(v.clone())))
+                    .collect();
+            let mangled = mangle_specialisation(&name.value, &types);
+            self.want(Specialisation { name: name.value.clone()
+                                     , types: types
+                                     , mangled: mangled.clone()
+                                     });
+            mangled
+        } else {
+            self.errs.push(name.map(format!(
+                "[error] cannot resolve the typeclass constraints of {}, \
+                 since it is not defined at the top level of the module"
+               , **name)));
+            return
+        };
+        name.value = resolved;
+    }
+}
+
+/// Declares the functions hoisted from instances and specialised in every
+/// scope of a module.
+struct Declare<'a>(Vec<(String, SymbolAnnotation<'a>)>, Tables<'a>);
+
+impl<'a> VisitMut<'a> for Declare<'a> {
+    fn tables(&mut self) -> Option<&mut Tables<'a>> { Some(&mut self.1) }
+
+    fn table(&mut self, table: &mut SymbolTable<'a>) {
+        for &(ref name, ref annotation) in &self.0 {
+            table.insert(name.clone(), annotation.clone());
+        }
+    }
+}
+
+/// Check that no two instances of the same class overlap, where
+/// `instances` are the instance types of each class visible to `body`.
+fn check_coherence<'a>( body: &Body<'a, S>
+                      , instances: &HashMap<String, Vec<Type>>)
+                      -> Errors {
+    let local: Vec<&Instance<'a, S>> =
+        body.iter()
+            .filter_map(|expr| match expr.node {
+                Form::Instance(ref inst) => Some(inst)
+              , _ => None
+            })
+            .collect();
+    // the instances visible to the module, less its own, were imported
+    let mut imported = instances.clone();
+    for inst in &local {
+        if let Some(tys) = imported.get_mut(&inst.class.value) {
+            if let Some(i) = tys.iter().position(|t| *t == inst.ty) {
+                tys.remove(i);
+            }
+        }
+    }
+
+    let mut errs = vec![];
+    for (i, inst) in local.iter().enumerate() {
+        let class = &inst.class;
+        let other = imported.get(&class.value)
+                            .and_then(|tys| tys.iter().find(|t| overlap(&inst.ty, t)));
+        if let Some(other) = other {
+            errs.push(class.map(format!(
+                "[error] instance {} {} overlaps the imported instance {} {}"
+               , **class, inst.ty, **class, other)));
+        } else if let Some(prior) = local[..i].iter().find(|p|
+            p.class.value == class.value && overlap(&inst.ty, &p.ty)) {
+            errs.push(class.map(format!(
+                "[error] instance {} {} overlaps instance {} {}"
+               , **class, inst.ty, **class, prior.ty)));
+            errs.push(prior.class.map(String::from(
+                "[note] the overlapping instance is declared here")));
+        }
+    }
+    errs
+}
+
+/// Returns the type variables constrained by `sig`, in the order in which
+/// they are first constrained.
+fn constrained_vars(sig: &Signature) -> Vec<String> {
+    let mut vars = vec![];
+    for c in sig.constraints.iter().flat_map(|cs| cs.iter()) {
+        for g in &c.generics {
+            if !vars.contains(&g.value) { vars.push(g.value.clone()) }
+        }
+    }
+    vars
+}
+
+/// Returns the name of the function `method` of the instance of `class`
+/// for `ty`, as in `Eq<int>.eq`.
+fn mangle_method(class: &str, ty: &Type, method: &str) -> String {
+    format!("{}<{}>.{}", class, ty, method)
+}
+
+/// Returns the name of the copy of the function `name` specialised at
+/// `types`, as in `same<int>`.
+fn mangle_specialisation(name: &str, types: &[Type]) -> String {
+    let types: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+    format!("{}<{}>", name, types.join(" "))
+}
+
+/// Returns `def`, defining `name` in place of the name it defined.
+fn rename<'a>(def: DefForm<'a, S>, name: String) -> DefForm<'a, S> {
+    match def {
+        DefForm::TopLevel { name: old, annot, value } =>
+            DefForm::TopLevel { name: old.map(name), annot: annot, value: value }
+      , DefForm::Function { name: old, fun } =>
+            DefForm::Function { name: old.map(name), fun: fun }
+    }
+}
+
+/// Returns the constrained function `def` specialised at `vars`, as the
+/// function `name`.
+///
+/// Only its signature is specialised here; the types of the names bound
+/// within it are specialised by the `Resolver` that visits it.
+fn specialise<'a>(def: DefForm<'a, S>, vars: &HashMap<String, Type>, name: String)
+                  -> DefForm<'a, S> {
+    match rename(def, name) {
+        DefForm::Function { name, mut fun } => {
+            fun.node.sig = specialise_signature(&fun.node.sig, vars);
+            DefForm::Function { name: name, fun: fun }
+        }
+      , def => def
+    }
+}
+
+/// Returns the signature `sig` specialised at `vars`, which satisfy its
+/// constraints.
+fn specialise_signature(sig: &Signature, vars: &HashMap<String, Type>) -> Signature {
+    let typechain = sig.typechain.iter().map(|t| t.substitute(vars)).collect();
+    Signature { constraints: None, typechain: typechain }
+}
+
+/// Returns true if `a` and `b` are made in the same way, out of the same
+/// number of types.
+fn same_shape(a: &Type, b: &Type) -> bool {
+    let unit = |_: &Type| Type::Algebraic(vec![]);
+    a.children().len() == b.children().len()
+        && a.map_children(&unit) == b.map_children(&unit)
+}
+
+/// Returns true if `ty` is an instance of the type `pattern`, binding the
+/// type variables of `pattern` in `vars`.
+fn matches(pattern: &Type, ty: &Type, vars: &mut HashMap<String, Type>) -> bool {
+    match *pattern {
+        Type::Var(ref v) => match vars.get(v).cloned() {
+            Some(bound) => bound == *ty
+          , None => { vars.insert(v.clone(), ty.clone()); true }
+        }
+      , _ => same_shape(pattern, ty)
+          && pattern.children()
+                    .into_iter()
+                    .zip(ty.children())
+                    .all(|(p, t)| matches(p, t, vars))
+    }
+}
+
+/// Returns true if some type is an instance of both `a` and `b`.
+fn overlap(a: &Type, b: &Type) -> bool {
+    // the type variables of different instances are unrelated
+    let apart: HashMap<String, Type> =
+        b.type_vars()
+         .into_iter()
+         .map(|v| { let renamed = Type::Var(format!("{}'", v)); (v, renamed) })
+         .collect();
+    unify(a, &b.substitute(&apart), &mut HashMap::new())
+}
+
+fn unify(a: &Type, b: &Type, subst: &mut HashMap<String, Type>) -> bool {
+    let (a, b) = (resolve(a, subst), resolve(b, subst));
+    match (&a, &b) {
+        (&Type::Var(ref v), &Type::Var(ref w)) if v == w => true
+      , (&Type::Var(ref v), _) => bind(v, &b, subst)
+      , (_, &Type::Var(ref v)) => bind(v, &a, subst)
+      , _ => same_shape(&a, &b)
+          && a.children()
+              .into_iter()
+              .zip(b.children())
+              .all(|(x, y)| unify(x, y, subst))
+    }
+}
+
+fn bind(var: &str, ty: &Type, subst: &mut HashMap<String, Type>) -> bool {
+    if ty.type_vars().iter().any(|v| v == var) { return false }
+    subst.insert(var.to_string(), ty.clone());
+    true
+}
+
+fn resolve(ty: &Type, subst: &HashMap<String, Type>) -> Type {
+    match *ty {
+        Type::Var(ref v) => match subst.get(v) {
+            Some(t) => resolve(t, subst)
+          , None => ty.clone()
+        }
+      , _ => ty.map_children(|t| resolve(t, subst))
+    }
+}
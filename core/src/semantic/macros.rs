@@ -0,0 +1,640 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Pattern-based macros
+//!
+//! Macros are defined with `define-syntax` and `syntax-rules`, as in
+//! Scheme:
+//!
+//! ```text
+//! (define-syntax swap!
+//!   (syntax-rules ()
+//!     ((_ a b) (let ((tmp int a)) (set! a b) (set! b tmp)))))
+//! ```
+//!
+//! Each rule's pattern is matched against the arguments of a call to the
+//! macro, and the first rule that matches is replaced by its template,
+//! with the pattern variables substituted. A pattern followed by `...`
+//! matches any number of arguments, and a template followed by `...` is
+//! repeated once for each of them.
+//!
+//! Macros are expanded after parsing and before `AnnotateTypes`. Since
+//! they are expanded on the AST, the arguments to a macro call must be
+//! expressions.
+//!
+//! Expansion is hygienic: a name bound by a template (in a `let`, or in
+//! the equations of a function) is renamed on each expansion, so it can't
+//! capture a name in the code passed to the macro. Since macros are
+//! defined at the top level, a name that a template uses without binding
+//! it refers to the module's definition of that name, even where a macro
+//! is called within a local binding of the same name.
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ast::*;
+use errors::{ Errors, CompileResult };
+use super::annotations::{ Annotated, UnscopedState };
+use super::rewrite::{ NameUse, Rewrite, walk_expr, walk_def, walk_rc
+                     , walk_bindings, walk_pattern, walk_pat_element };
+
+type U = UnscopedState;
+
+/// The maximum depth of nested macro expansions, after which a macro is
+/// assumed to expand infinitely.
+const MAX_DEPTH: usize = 64;
+
+/// Collects the names used in an expression.
+struct Names { names: Vec<(String, NameUse)> }
+
+impl<'a> Rewrite<'a> for Names {
+    fn name(&mut self, name: Ident, used: NameUse) -> CompileResult<Ident> {
+        self.names.push((name.value.clone(), used));
+        Ok(name)
+    }
+}
+
+/// Returns the names used in `expr`, and how they are used.
+fn names_in<'a>(expr: &Expr<'a, U>) -> Vec<(String, NameUse)> {
+    let mut names = Names { names: vec![] };
+    let _ = names.expr(expr.clone());
+    names.names
+}
+
+/// The syntax matched by a pattern variable.
+#[derive(Clone, Debug)]
+enum Bound<'a> { One(Expr<'a, U>)
+               , /// The syntax matched by each repetition of a pattern
+                 /// followed by `...`.
+                 Many(Vec<Bound<'a>>)
+               }
+
+type Matches<'a> = HashMap<String, Bound<'a>>;
+
+#[inline]
+fn is_ellipsis(datum: &Datum<U>) -> bool {
+    match *datum { Datum::Symbol(ref s) => s.value == "..."
+                 , _ => false }
+}
+
+/// Returns the pattern variables in `pattern`.
+fn pattern_vars<'a>(pattern: &Datum<'a, U>, literals: &[Ident]) -> Vec<String> {
+    match *pattern {
+        Datum::Symbol(ref s) if s.value != "_" && s.value != "..."
+                             && !literals.contains(s) => vec![s.value.clone()]
+      , Datum::List(ref data) =>
+            data.iter().flat_map(|d| pattern_vars(d, literals)).collect()
+      , _ => vec![]
+    }
+}
+
+/// Match an expression against a pattern, binding pattern variables in
+/// `matches`.
+fn match_pattern<'a>( pattern: &Datum<'a, U>, expr: &Expr<'a, U>
+                    , literals: &[Ident], matches: &mut Matches<'a>)
+                    -> bool {
+    match (pattern, &expr.node) {
+        (&Datum::Symbol(ref s), _) if s.value == "_" => true
+      , (&Datum::Symbol(ref s), &Form::NameRef(NameRef::Owned(ref name)))
+            if literals.contains(s) => name.value == s.value
+      , (&Datum::Symbol(ref s), _) if literals.contains(s) => false
+      , (&Datum::Symbol(ref s), _) => {
+            matches.insert(s.value.clone(), Bound::One(expr.clone()));
+            true
+        }
+      , (&Datum::Lit(ref lit), &Form::Lit(ref other)) => lit == other
+      , (&Datum::List(ref pats), &Form::App(ref app)) if !pats.is_empty() => {
+            let fun = Annotated::new( Form::NameRef(NameRef::Owned(app.fun.clone()))
+                                    , app.fun.pos );
+            match_pattern(&pats[0], &fun, literals, matches) &&
+            match_list(&pats[1..], &app.params, literals, matches)
+        }
+      , _ => false
+    }
+}
+
+/// Match a list of expressions against a list of patterns, one of which
+/// may be followed by `...`.
+fn match_list<'a>( pats: &[Datum<'a, U>], exprs: &[Expr<'a, U>]
+                 , literals: &[Ident], matches: &mut Matches<'a>)
+                 -> bool {
+    match pats.iter().position(is_ellipsis) {
+        None => pats.len() == exprs.len() &&
+                pats.iter().zip(exprs.iter())
+                    .all(|(p, e)| match_pattern(p, e, literals, matches))
+      , Some(0) => false
+      , Some(i) => {
+            let repeated = &pats[i - 1];
+            let (before, after) = (&pats[..i - 1], &pats[i + 1..]);
+            if exprs.len() < before.len() + after.len() { return false }
+            let split = exprs.len() - after.len();
+            if !match_list(before, &exprs[..before.len()], literals, matches) ||
+               !match_list(after, &exprs[split..], literals, matches) {
+                return false
+            }
+            let mut reps = vec![];
+            for expr in &exprs[before.len()..split] {
+                let mut rep = HashMap::new();
+                if !match_pattern(repeated, expr, literals, &mut rep) {
+                    return false
+                }
+                reps.push(rep)
+            }
+            for var in pattern_vars(repeated, literals) {
+                let bound = reps.iter()
+                                .map(|rep| rep[&var].clone())
+                                .collect();
+                matches.insert(var, Bound::Many(bound));
+            }
+            true
+        }
+    }
+}
+
+/// Instantiates a macro template.
+struct Instantiate<'a, 'm> { matches: Matches<'a>
+                           , /// Hygienic names for the template's binders.
+                             renames: &'m HashMap<String, String>
+                           , macro_name: &'m str
+                           }
+
+impl<'a, 'm> Instantiate<'a, 'm> {
+
+    /// Instantiate `template` once for each repetition of the pattern
+    /// variables it contains that were matched by a pattern with `...`.
+    fn repeat(&mut self, template: Expr<'a, U>) -> CompileResult<Body<'a, U>> {
+        let mut repeated = vec![];
+        for (name, _) in names_in(&template) {
+            if let Some(&Bound::Many(ref reps)) = self.matches.get(&name) {
+                if !repeated.iter().any(|&(ref n, _)| *n == name) {
+                    repeated.push((name.clone(), reps.clone()))
+                }
+            }
+        }
+        let count = match repeated.first() {
+            Some(&(_, ref reps)) => reps.len()
+          , None => return Err(vec![template.map_pos(format!(
+                "[error] `...` in macro `{}` follows a template with no \
+                 repeated pattern variables", self.macro_name))])
+        };
+        if let Some(&(ref name, _)) = repeated.iter()
+                                              .find(|&&(_, ref r)| r.len() != count) {
+            return Err(vec![template.map_pos(format!(
+                "[error] pattern variable `{}` repeats a different number of \
+                 times than `{}` in macro `{}`"
+               , name, repeated[0].0, self.macro_name))])
+        }
+        let mut instances = vec![];
+        for i in 0..count {
+            let mut matches = self.matches.clone();
+            for &(ref name, ref reps) in &repeated {
+                matches.insert(name.clone(), reps[i].clone());
+            }
+            let mut inst = Instantiate { matches: matches
+                                       , renames: self.renames
+                                       , macro_name: self.macro_name
+                                       };
+            instances.push(try!(inst.expr(template.clone())))
+        }
+        Ok(instances)
+    }
+
+    /// Returns the name matched by the pattern variable `var`, if any.
+    fn matched_name(&self, var: &Ident) -> Option<CompileResult<Ident>> {
+        self.matches.get(&var.value).map(|bound| match *bound {
+            Bound::One(Annotated {
+                node: Form::NameRef(NameRef::Owned(ref name)), .. }) =>
+                Ok(name.clone())
+          , Bound::One(ref other) => Err(vec![other.map_pos(format!(
+                "[error] macro `{}` uses `{}` as a name, but it is `{}`"
+               , self.macro_name, **var, other.to_sexpr(0)))])
+          , Bound::Many(_) => Err(vec![var.map(format!(
+                "[error] pattern variable `{}` must be followed by `...` \
+                 in macro `{}`", **var, self.macro_name))])
+        })
+    }
+}
+
+impl<'a, 'm> Rewrite<'a> for Instantiate<'a, 'm> {
+
+    fn expr(&mut self, expr: Expr<'a, U>) -> CompileResult<Expr<'a, U>> {
+        let var = match expr.node {
+            Form::NameRef(NameRef::Owned(ref name)) => name.clone()
+          , _ => return walk_expr(self, expr)
+        };
+        match self.matches.get(&var.value) {
+            Some(&Bound::One(ref e)) => return Ok(e.clone())
+          , Some(&Bound::Many(_)) => return Err(vec![var.map(format!(
+                "[error] pattern variable `{}` must be followed by `...` \
+                 in macro `{}`", *var, self.macro_name))])
+          , None => {}
+        }
+        walk_expr(self, expr)
+    }
+
+    fn exprs(&mut self, exprs: Body<'a, U>) -> CompileResult<Body<'a, U>> {
+        let mut result = vec![];
+        let mut exprs = exprs.into_iter().peekable();
+        while let Some(expr) = exprs.next() {
+            let repeated = match exprs.peek() {
+                Some(&Annotated { node: Form::NameRef(NameRef::Owned(ref n))
+                                , .. }) => n.value == "..."
+              , _ => false
+            };
+            if repeated {
+                exprs.next();
+                result.extend(try!(self.repeat(expr)))
+            } else {
+                result.push(try!(self.expr(expr)))
+            }
+        }
+        Ok(result)
+    }
+
+    fn name(&mut self, name: Ident, used: NameUse) -> CompileResult<Ident> {
+        if let Some(matched) = self.matched_name(&name) { return matched }
+        match self.renames.get(&name.value) {
+            Some(renamed) if used == NameUse::Binder
+                          || used == NameUse::Reference =>
+                Ok(name.map(renamed.clone()))
+          , _ => Ok(name)
+        }
+    }
+}
+
+/// Returns the names that the templates of `mac` use without binding
+/// them, other than its pattern variables.
+fn free_names<'a>(mac: &Macro<'a, U>) -> Vec<String> {
+    let mut free = vec![];
+    for rule in &mac.rules {
+        let vars = pattern_vars(&rule.pattern, &mac.literals);
+        let names = names_in(&rule.template);
+        for &(ref name, used) in &names {
+            if used == NameUse::Reference && name != "..." && !vars.contains(name)
+                && !names.iter().any(|&(ref n, u)| n == name && u != NameUse::Reference)
+                && !free.contains(name) {
+                free.push(name.clone())
+            }
+        }
+    }
+    free
+}
+
+/// Renames the local bindings of the names that macro templates use
+/// freely, and the names that refer to them, so that the expansion of a
+/// macro called within such a binding can't be captured by it.
+struct Shadows<'f> { /// The names that templates use freely.
+                     free: &'f [String]
+                   , /// The renamed bindings in scope, innermost last.
+                     renamed: Vec<(String, String)>
+                   , /// Counts renamed bindings, to generate fresh names.
+                     count: usize
+                   }
+
+impl<'f> Shadows<'f> {
+
+    /// Bring a local binding of `name` into scope.
+    fn bind(&mut self, name: &str) {
+        if self.free.iter().any(|f| f == name) {
+            self.count += 1;
+            let renamed = format!("{}%{}", name, self.count);
+            self.renamed.push((String::from(name), renamed))
+        }
+    }
+
+    /// Bring the names bound by the pattern `element` into scope.
+    fn bind_pattern(&mut self, element: &PatElement) {
+        match *element {
+            PatElement::Name(ref name) | PatElement::Deref(ref name)
+          | PatElement::Typed { ref name, .. } => self.bind(&name.value)
+          , PatElement::Constructor { ref fields, .. } | PatElement::List(ref fields) =>
+                for field in fields { self.bind_pattern(field) }
+          , PatElement::Cons { ref head, ref tail } => {
+                self.bind_pattern(head);
+                self.bind_pattern(tail);
+            }
+          , PatElement::Lit(_) | PatElement::Anything => {}
+        }
+    }
+
+    /// Rename the bindings of a function, whose equations' patterns bind
+    /// names within their bodies.
+    fn function<'a>(&mut self, fun: Function<'a, U>)
+                   -> CompileResult<Function<'a, U>> {
+        let mut equations = vec![];
+        for eq in fun.equations {
+            let mark = self.renamed.len();
+            let position = eq.position;
+            let Equation { pattern, body } = eq.node;
+            for element in &pattern { self.bind_pattern(element) }
+            let eq = Equation { pattern: try!(walk_pattern(self, pattern))
+                              , body: try!(self.exprs(body))
+                              };
+            self.renamed.truncate(mark);
+            equations.push(Annotated::new(eq, position))
+        }
+        Ok(Function { sig: fun.sig, equations: equations })
+    }
+
+    fn def<'a>(&mut self, def: DefForm<'a, U>) -> CompileResult<DefForm<'a, U>> {
+        match def {
+            DefForm::Function { name, fun } => {
+                let position = fun.position;
+                Ok(DefForm::Function {
+                    name: try!(self.name(name, NameUse::Definition))
+                  , fun: Annotated::new(try!(self.function(fun.node)), position)
+                })
+            }
+          , def => walk_def(self, def)
+        }
+    }
+
+    /// Rename the bindings of a `let` form, following the scoping rules
+    /// of `LetForm::scopes()`.
+    fn let_form<'a>(&mut self, form: LetForm<'a, U>)
+                   -> CompileResult<LetForm<'a, U>> {
+        Ok(match form {
+            LetForm::Let { bindings, body } => {
+                let mut values = vec![];
+                for binding in &bindings {
+                    values.push(try!(walk_rc(self, binding.value.clone())))
+                }
+                for binding in &bindings { self.bind(&binding.name.value) }
+                let mut renamed = vec![];
+                for (binding, value) in bindings.into_iter().zip(values) {
+                    let position = binding.position;
+                    let binding = Binding { value: value, ..binding.node };
+                    let name = try!(self.name(binding.name.clone(), NameUse::Binder));
+                    renamed.push(Annotated::new( Binding { name: name, ..binding }
+                                               , position))
+                }
+                LetForm::Let { bindings: renamed, body: try!(self.exprs(body)) }
+            }
+          , LetForm::LetSplat { bindings, body } => {
+                let mut renamed = vec![];
+                for binding in bindings {
+                    let position = binding.position;
+                    let value = try!(walk_rc(self, binding.node.value.clone()));
+                    self.bind(&binding.node.name.value);
+                    let name = try!(self.name( binding.node.name.clone()
+                                             , NameUse::Binder));
+                    renamed.push(Annotated::new( Binding { name: name
+                                                         , value: value
+                                                         , ..binding.node }
+                                               , position))
+                }
+                LetForm::LetSplat { bindings: renamed, body: try!(self.exprs(body)) }
+            }
+          , LetForm::LetRec { bindings, body } => {
+                for binding in &bindings { self.bind(&binding.name.value) }
+                LetForm::LetRec { bindings: try!(walk_bindings(self, bindings))
+                                , body: try!(self.exprs(body)) }
+            }
+          , LetForm::Invocation { proc_id, ret_ty, init, body } => {
+                let value = try!(walk_rc(self, init.value.clone()));
+                self.bind(&init.name.value);
+                self.bind(&proc_id.value);
+                let name = try!(self.name(init.name.clone(), NameUse::Binder));
+                LetForm::Invocation {
+                    proc_id: try!(self.name(proc_id, NameUse::Binder))
+                  , ret_ty: ret_ty
+                  , init: Binding { name: name, value: value, ..init }
+                  , body: try!(self.exprs(body))
+                }
+            }
+        })
+    }
+}
+
+impl<'a, 'f> Rewrite<'a> for Shadows<'f> {
+
+    fn expr(&mut self, expr: Expr<'a, U>) -> CompileResult<Expr<'a, U>> {
+        let position = expr.position;
+        let mark = self.renamed.len();
+        let node = match expr.node {
+            Form::Define(def) => Form::Define(try!(self.def(def)))
+          , Form::Lambda(fun) => Form::Lambda(try!(self.function(fun)))
+          , Form::Instance(inst) => {
+                let mut functions = vec![];
+                for def in inst.functions { functions.push(try!(self.def(def))) }
+                Form::Instance(Instance { functions: functions, ..inst })
+            }
+          , Form::Let(form) => Form::Let(try!(self.let_form(form)))
+          , Form::Match(form) => {
+                let scrutinee = try!(walk_rc(self, form.scrutinee));
+                let mut arms = vec![];
+                for arm in form.arms {
+                    let position = arm.position;
+                    let Arm { pattern, body } = arm.node;
+                    self.bind_pattern(&pattern);
+                    let arm = Arm { pattern: try!(walk_pat_element(self, pattern))
+                                  , body: try!(self.exprs(body))
+                                  };
+                    self.renamed.truncate(mark);
+                    arms.push(Annotated::new(arm, position))
+                }
+                Form::Match(MatchForm { scrutinee: scrutinee, arms: arms })
+            }
+          , node => return walk_expr(self, Annotated::new(node, position))
+        };
+        self.renamed.truncate(mark);
+        Ok(Annotated::new(node, position))
+    }
+
+    /// A body's definitions are visible throughout it.
+    fn exprs(&mut self, exprs: Body<'a, U>) -> CompileResult<Body<'a, U>> {
+        let mark = self.renamed.len();
+        for expr in &exprs {
+            match expr.node {
+                Form::Define(DefForm::TopLevel { ref name, .. })
+              | Form::Define(DefForm::Function { ref name, .. }) =>
+                    self.bind(&name.value)
+              , _ => {}
+            }
+        }
+        let mut rewritten = vec![];
+        for expr in exprs { rewritten.push(try!(self.expr(expr))) }
+        self.renamed.truncate(mark);
+        Ok(rewritten)
+    }
+
+    fn name(&mut self, name: Ident, used: NameUse) -> CompileResult<Ident> {
+        if used == NameUse::Field { return Ok(name) }
+        let renamed = self.renamed.iter()
+                                  .rev()
+                                  .find(|&&(ref n, _)| *n == name.value)
+                                  .map(|&(_, ref renamed)| renamed.clone());
+        Ok(match renamed { Some(renamed) => name.map(renamed)
+                         , None => name })
+    }
+}
+
+/// Expands macro calls.
+struct Expander<'a> { macros: HashMap<String, Macro<'a, U>>
+                    , /// The depth of nested expansions.
+                      depth: usize
+                    , /// Counts expansions, to generate hygienic names.
+                      expansions: usize
+                    }
+
+impl<'a> Expander<'a> {
+
+    /// Expand a call to the macro `mac`.
+    fn expand_call(&mut self, mac: &Macro<'a, U>, call: &Expr<'a, U>)
+                  -> CompileResult<Expr<'a, U>> {
+        let note = mac.name.map(format!( "[note] macro `{}` is defined here"
+                                       , *mac.name));
+        let args = match call.node {
+            Form::App(ref app) => &app.params
+          , _ => ice!("macro call was not an application: {:?}", call)
+        };
+        for rule in &mac.rules {
+            let mut matches = HashMap::new();
+            let matched = match rule.pattern {
+                Datum::List(ref pats) =>
+                    match_list(&pats[1..], args, &mac.literals, &mut matches)
+              , _ => false
+            };
+            if !matched { continue }
+
+            // hygiene: rename the names that the template binds, unless
+            // they come from the macro call
+            self.expansions += 1;
+            let renames = names_in(&rule.template)
+                .into_iter()
+                .filter(|&(ref name, used)| used == NameUse::Binder
+                                         && !matches.contains_key(name))
+                .map(|(name, _)| {
+                    let renamed = format!("{}%{}", name, self.expansions);
+                    (name, renamed)
+                })
+                .collect();
+            let mut inst = Instantiate { matches: matches
+                                       , renames: &renames
+                                       , macro_name: &mac.name
+                                       };
+            let expansion = inst.expr((*rule.template).clone());
+            // expansions take the position of the call
+            return expansion.map(|e| Annotated::new(e.node, call.position))
+                            .map_err(|mut errs| {
+                                errs.insert(0, call.map_pos(format!(
+                                    "[error] in expansion of macro `{}`"
+                                   , *mac.name)));
+                                errs.push(note);
+                                errs
+                            })
+        }
+        Err(vec![ call.map_pos(format!( "[error] no rule of macro `{}` \
+                                         matches `{}`"
+                                      , *mac.name, call.to_sexpr(0)))
+                , note ])
+    }
+}
+
+impl<'a> Rewrite<'a> for Expander<'a> {
+
+    fn expr(&mut self, expr: Expr<'a, U>) -> CompileResult<Expr<'a, U>> {
+        let mac = match expr.node {
+            Form::App(ref app) => self.macros.get(&app.fun.value).cloned()
+          , _ => None
+        };
+        let mac = match mac { Some(mac) => mac
+                            , None => return walk_expr(self, expr) };
+        if self.depth >= MAX_DEPTH {
+            return Err(vec![
+                expr.map_pos(format!( "[error] expansion of macro `{}` \
+                                       exceeded the maximum depth of {}; \
+                                       does it expand to itself?"
+                                    , *mac.name, MAX_DEPTH))
+              , mac.name.map(format!( "[note] macro `{}` is defined here"
+                                    , *mac.name)) ])
+        }
+        let expansion = try!(self.expand_call(&mac, &expr));
+        self.depth += 1;
+        let result = self.expr(expansion);
+        self.depth -= 1;
+        result
+    }
+}
+
+/// Check that a macro definition is well-formed.
+fn check_macro<'a>(mac: &Macro<'a, U>) -> Errors {
+    let mut errs = vec![];
+    for rule in &mac.rules {
+        match rule.pattern {
+            Datum::List(ref pats) if !pats.is_empty() => {
+                if pats.iter().filter(|p| is_ellipsis(p)).count() > 1 {
+                    errs.push(mac.name.map(format!(
+                        "[error] a pattern of macro `{}` has more than one \
+                         `...`", *mac.name)))
+                }
+            }
+          , _ => errs.push(mac.name.map(format!(
+                "[error] the patterns of macro `{}` must be lists, such as \
+                 `(_ a b)`", *mac.name)))
+        }
+    }
+    errs
+}
+
+/// Expand the macros defined in `body`.
+///
+/// Macro definitions may appear anywhere at the top level of `body`, and
+/// are removed from the result.
+///
+/// # Returns
+///  + `Ok` containing the expanded forms, if every macro expanded
+///    successfully.
+///  + `Err` containing every expansion error otherwise. Each error
+///    at a macro call is followed by a note at the macro's definition.
+pub fn expand<'a>(body: Body<'a, U>) -> CompileResult<Body<'a, U>> {
+    let mut errs = vec![];
+    let mut macros: HashMap<String, Macro<'a, U>> = HashMap::new();
+    let mut forms = vec![];
+
+    for expr in body {
+        match expr.node {
+            Form::Macro(mac) => {
+                errs.extend(check_macro(&mac));
+                if let Some(prev) = macros.get(&mac.name.value) {
+                    errs.push(mac.name.map(format!(
+                        "[error] macro `{}` is defined more than once"
+                       , *mac.name)));
+                    errs.push(prev.name.map(String::from(
+                        "[note] previously defined here")));
+                }
+                macros.insert(mac.name.value.clone(), mac);
+            }
+          , node => forms.push(Annotated::new(node, expr.position))
+        }
+    }
+
+    // local bindings are renamed before expansion, so that the names they
+    // capture are only those written within them
+    let free: Vec<String> = macros.values().flat_map(free_names).collect();
+    let mut shadows = Shadows { free: &free, renamed: vec![], count: 0 };
+    let mut unshadowed = vec![];
+    for form in forms {
+        match shadows.expr(form) {
+            Ok(e) => unshadowed.push(e)
+          , Err(mut e) => errs.append(&mut e)
+        }
+    }
+
+    let mut expander = Expander { macros: macros
+                                , depth: 0
+                                , expansions: shadows.count };
+    let mut expanded = vec![];
+    for form in unshadowed {
+        match expander.expr(form) {
+            Ok(e) => expanded.push(e)
+          , Err(mut e) => errs.append(&mut e)
+        }
+    }
+    if errs.is_empty() { Ok(expanded) } else { Err(errs) }
+}
@@ -9,12 +9,16 @@
 //
 //! Mnemosyne semantic analysis
 use std::rc::Rc;
+use std::cell::{ Cell, RefCell };
+use std::collections::HashMap;
+use std::ops;
 
 use ::forktable::ForkTable;
+use errors::CompileResult;
 
 use ast::*;
 use self::annotations::*;
-use self::types::Type;
+use self::types::{ Type, Signature };
 
 /// A symbol table is a `ForkTable` mapping `String`s to `Type` annotations.
 ///
@@ -25,6 +29,71 @@ use self::types::Type;
 /// This table should be forked upon entering a new scope.
 pub type SymbolTable<'a> = ForkTable<'a, String, SymbolAnnotation<'a>>;
 
+/// A level of a symbol table during scope resolution.
+///
+/// Unlike a `SymbolTable`, the levels of a `Scope` may be shorter-lived
+/// than the symbols in them, so that a level may be forked on the stack
+/// upon entering each scope. Nodes are annotated with `collapse()`d
+/// copies of their scopes, which are shared by way of a `SharedScope`.
+pub type Scope<'s, 'a> = ForkTable<'s, String, SymbolAnnotation<'a>>;
+
+/// A `Scope` whose nodes share one `collapse()`d copy of it.
+///
+/// The copy is made when the first node in the scope is annotated, so a
+/// scope must not be changed once it is shared.
+#[derive(Debug)]
+pub struct SharedScope<'s, 'a: 's> { scope: Scope<'s, 'a>
+                                   , table: RefCell<Option<Rc<SymbolTable<'a>>>>
+                                   , /// The number of type variables made up
+                                     /// by `unknown()` in the module so far.
+                                     unknowns: Rc<Cell<usize>>
+                                   }
+
+impl<'s, 'a> SharedScope<'s, 'a> {
+
+    /// Share the root scope of a module.
+    pub fn new(scope: Scope<'s, 'a>) -> Self {
+        SharedScope { scope: scope
+                    , table: RefCell::new(None)
+                    , unknowns: Rc::new(Cell::new(0))
+                    }
+    }
+
+    /// Share `scope`, which is nested within this scope.
+    pub fn nested<'t>(&self, scope: Scope<'t, 'a>) -> SharedScope<'t, 'a> {
+        SharedScope { scope: scope
+                    , table: RefCell::new(None)
+                    , unknowns: self.unknowns.clone()
+                    }
+    }
+
+    /// Returns a new type variable standing for the type of a name bound
+    /// by a pattern, which is not known until types are inferred.
+    ///
+    /// Each binding has a variable of its own, even if a macro expanded to
+    /// several copies of it. The variables are named `_u` and a number, so
+    /// they cannot clash with the type variables in the source code, which
+    /// begin with a letter, nor with those made up by inference.
+    pub fn unknown(&self) -> Type {
+        self.unknowns.set(self.unknowns.get() + 1);
+        Type::Var(format!("_u{}", self.unknowns.get()))
+    }
+
+    /// The symbol table shared by the nodes in this scope.
+    pub fn table(&self) -> Rc<SymbolTable<'a>> {
+        let mut table = self.table.borrow_mut();
+        if table.is_none() { *table = Some(Rc::new(self.scope.collapse())) }
+        match *table { Some(ref table) => table.clone()
+                     , None => ice!("a shared scope was not collapsed")
+                     }
+    }
+}
+
+impl<'s, 'a> ops::Deref for SharedScope<'s, 'a> {
+    type Target = Scope<'s, 'a>;
+    fn deref(&self) -> &Scope<'s, 'a> { &self.scope }
+}
+
 #[macro_use]
 macro_rules! indent {
     ($to:expr) => ( iter::repeat('\t')
@@ -35,25 +104,198 @@ macro_rules! indent {
 pub mod ast;
 pub mod types;
 pub mod annotations;
+pub mod typeclass;
+pub mod macros;
+pub mod constants;
+pub mod scope;
+pub mod infer;
+pub mod instances;
+pub mod moves;
+mod rewrite;
+mod visit;
+
+/// The scopes in which the parts of a `let` form are evaluated.
+#[derive(Clone, Debug)]
+pub struct LetScopes<'s, 'a> { /// The scope for each binding's value, in order.
+                               pub bindings: Vec<Scope<'s, 'a>>
+                             , /// The scope for the body of the `let` form.
+                               pub body: Scope<'s, 'a>
+                             }
 
-impl<'a> AnnotateTypes<'a> for Unscoped<'a, Form<'a, UnscopedState>> {
-    #[allow(unused_variables)]
-    fn annotate_types(self, scope: SymbolTable) -> Scoped<'a, Self> {
-        unimplemented!() //TODO: implement
+impl<'a, S> LetForm<'a, S>
+where S: ScopednessTypestate {
+
+    /// Compute the scopes for this `let` form's bindings and body, forking
+    /// them from the enclosing scope `parent`.
+    ///
+    ///  + In a `let`, every value is evaluated in the enclosing scope, and
+    ///    only the body can see the bound names.
+    ///  + In a `let*`, each value can see the names bound before it.
+    ///  + In a `letrec`, every value can see every bound name, so that
+    ///    bindings may be recursive or mutually recursive.
+    ///  + In a named `let`, the initial value is evaluated in the enclosing
+    ///    scope, and the body can see both the bound name and the name of
+    ///    the procedure, so that it may invoke itself.
+    pub fn scopes<'s, 'b>(&self, parent: &'s Scope<'s, 'b>) -> LetScopes<'s, 'b> {
+        match *self {
+            LetForm::Let { ref bindings, .. } => {
+                let mut body = parent.fork();
+                for binding in bindings {
+                    body.insert(binding.name.value.clone(), binding.annotation());
+                }
+                LetScopes { bindings: bindings.iter()
+                                              .map(|_| parent.fork())
+                                              .collect()
+                          , body: body }
+            }
+          , LetForm::LetSplat { ref bindings, .. } => {
+                let mut scope = parent.fork();
+                let mut scopes = Vec::with_capacity(bindings.len());
+                for binding in bindings {
+                    scopes.push(scope.clone());
+                    scope.insert(binding.name.value.clone(), binding.annotation());
+                }
+                LetScopes { bindings: scopes, body: scope }
+            }
+          , LetForm::LetRec { ref bindings, .. } => {
+                let mut scope = parent.fork();
+                for binding in bindings {
+                    scope.insert(binding.name.value.clone(), binding.annotation());
+                }
+                LetScopes { bindings: bindings.iter()
+                                              .map(|_| scope.clone())
+                                              .collect()
+                          , body: scope }
+            }
+          , LetForm::Invocation { ref proc_id, ref ret_ty, ref init, .. } => {
+                let proc_ty = Signature { constraints: None
+                                        , typechain: vec![ init.typ.clone()
+                                                         , ret_ty.clone() ]
+                                        };
+                let mut body = parent.fork();
+                body.insert(init.name.value.clone(), init.annotation());
+                body.insert( proc_id.value.clone()
+                           , SymbolAnnotation::Value {
+                                ty: Type::Function(proc_ty)
+                              , proven_value: None
+                              , mutable: false
+                              });
+                LetScopes { bindings: vec![parent.fork()], body: body }
+            }
+        }
     }
 }
 
-impl<'a> AnnotateTypes<'a> for Unscoped<'a, DefForm<'a, UnscopedState>> {
-    #[allow(unused_variables)]
-    fn annotate_types(self, scope: SymbolTable) -> Scoped<'a, Self>{
-        unimplemented!() //TODO: implement
+impl<'a, S> Binding<'a, S>
+where S: ScopednessTypestate {
+
+    /// The symbol table annotation for the name bound by this binding.
+    pub fn annotation<'b>(&self) -> SymbolAnnotation<'b> {
+        SymbolAnnotation::Value { ty: self.typ.clone()
+                                , proven_value: None
+                                , mutable: self.mutable }
     }
 }
 
+impl<'a, S> DefForm<'a, S>
+where S: ScopednessTypestate {
+
+    /// The name bound by this definition.
+    pub fn name(&self) -> &Ident {
+        match *self { DefForm::TopLevel { ref name, .. }
+                    | DefForm::Function { ref name, .. } => name
+                    }
+    }
+
+    /// The symbol table annotation for the name bound by this definition.
+    pub fn annotation<'b>(&self) -> SymbolAnnotation<'b> {
+        let ty = match *self {
+            DefForm::TopLevel { ref annot, .. } => annot.clone()
+          , DefForm::Function { ref fun, .. } =>
+                Type::Function(fun.node.sig.clone())
+        };
+        SymbolAnnotation::Value { ty: ty, proven_value: None, mutable: false }
+    }
+}
+
+impl<'a, S> Data<'a, S>
+where S: ScopednessTypestate {
+
+    /// The symbol table annotations for this data type and its
+    /// constructors.
+    ///
+    /// The name of the type is bound to its definition. A constructor
+    /// with no fields is a value of the type, and any other constructor
+    /// is a function from its fields to the type.
+    ///
+    /// The constructor of a record type shares the type's name, so the
+    /// name is bound to a `Record`, which stands for both.
+    pub fn annotations<'b>(&self) -> Vec<(String, SymbolAnnotation<'b>)> {
+        let ty = Type::Named(self.name.value.clone(), vec![]);
+        match self.get_struct_fields() {
+            Some(fields) if self.is_struct() =>
+                vec![( self.name.value.clone()
+                     , SymbolAnnotation::Record {
+                            ty: ty
+                          , fields: fields.iter().map(|f| f.node.clone()).collect()
+                       } )]
+          , _ => {
+                let mut annotations = vec![( self.name.value.clone()
+                                           , SymbolAnnotation::TypeDef(ty.clone()) )];
+                constructors(&self.variants, &ty, &mut annotations);
+                annotations
+            }
+        }
+    }
+}
+
+/// The type of a constructor of `ty` whose fields have the types `params`.
+///
+/// A constructor with no fields is a value of the type, and any other
+/// constructor is a function from its fields to the type.
+fn constructor_type(params: Vec<Type>, ty: &Type) -> Type {
+    if params.is_empty() { return ty.clone() }
+    let mut typechain = params;
+    typechain.push(ty.clone());
+    Type::Function(Signature { constraints: None, typechain: typechain })
+}
+
+/// Add the annotations for the constructors of the sum type `variants`,
+/// whose values have type `ty`, to `annotations`.
+fn constructors<'a, 'b, S>( variants: &Variants<'a, S>
+                          , ty: &Type
+                          , annotations: &mut Vec<(String, SymbolAnnotation<'b>)>)
+where S: ScopednessTypestate {
+    let constructor = |params: Vec<Type>|
+        SymbolAnnotation::Value { ty: constructor_type(params, ty)
+                                , proven_value: None
+                                , mutable: false };
+    for &(ref name, ref variant) in variants {
+        match *variant {
+            Variant::Tagword(_) | Variant::Constant(_) =>
+                annotations.push((name.value.clone(), constructor(vec![])))
+          , Variant::Record(ref fields) =>
+                annotations.push(( name.value.clone()
+                                 , constructor(fields.iter()
+                                                     .map(|f| f.node.annot.clone())
+                                                     .collect()) ))
+          , Variant::Value(ref field) =>
+                annotations.push(( name.value.clone()
+                                 , constructor(vec![field.clone()]) ))
+          , Variant::Sum(ref variants) =>
+                constructors(variants, ty, annotations)
+        }
+    }
+}
 
 #[derive(Clone,Debug,PartialEq)]
 pub enum SymbolAnnotation<'a> {
     TypeDef(Type)
+  , /// A record type, and its constructor, which shares its name.
+    Record { ty: Type
+           , /// The fields of the type, in the order they are declared.
+             fields: Vec<Formal>
+           }
   , Value { /// The type of the symbol
             ty: Type,
             /// An optional proven value for the symbol.
@@ -62,6 +304,16 @@ pub enum SymbolAnnotation<'a> {
             /// or a constant expression, or if we were able to prove that the value
             /// remains constant within the current scope.
             proven_value: Option<Rc<Expr<'a, ScopedState>>>
+          , /// Whether the symbol may be assigned to with `set!`.
+            mutable: bool
+          }
+  , /// A typeclass declaration.
+    Class { /// The name of the type variable the class is parameterised over
+            ty_param: String
+          , /// The signatures of the class's functions
+            methods: HashMap<String, Signature>
+          , /// The types which have been declared instances of the class
+            instances: Vec<Type>
           }
 }
 
@@ -77,9 +329,30 @@ impl<'a> SymbolAnnotation<'a> {
                 match *ty { Type::Function(_) => true
                           , _                => false
                           }
+          , SymbolAnnotation::Record { ref fields, .. } => !fields.is_empty()
+          , SymbolAnnotation::Class { .. } => false
         }
     }
 
+    /// The type of the value this symbol names, if it names one: either
+    /// a value, or the constructor of a record type.
+    pub fn value_type(&self) -> Option<Type> {
+        match *self {
+            SymbolAnnotation::Value { ref ty, .. } => Some(ty.clone())
+          , SymbolAnnotation::Record { ref ty, ref fields } =>
+                Some(constructor_type( fields.iter().map(|f| f.annot.clone()).collect()
+                                     , ty))
+          , _ => None
+        }
+    }
+
+    /// Returns true if this symbol is a typeclass.
+    pub fn is_class(&self) -> bool {
+        match *self { SymbolAnnotation::Class { .. } => true
+                    , _                              => false
+                    }
+    }
+
     pub fn is_prim_type(&self) -> bool {
         match *self {
             SymbolAnnotation::TypeDef(ref ty) =>
@@ -90,3 +363,24 @@ impl<'a> SymbolAnnotation<'a> {
         }
     }
 }
+
+/// Only a record type has fields, so any other type has none.
+impl<'a> RecordType for SymbolAnnotation<'a> {
+
+    fn field_type(&self, field: &Ident) -> CompileResult<&Type> {
+        match *self {
+            SymbolAnnotation::Record { ref ty, ref fields } =>
+                fields.iter()
+                      .find(|f| f.name == *field)
+                      .map(|f| &f.annot)
+                      .ok_or_else(|| vec![field.map(format!(
+                            "[error] record type {} has no field {}"
+                           , ty, **field))])
+          , SymbolAnnotation::TypeDef(ref ty) =>
+                Err(vec![field.map(format!(
+                    "[error] {} is not a record type, so it has no field {}"
+                   , ty, **field))])
+          , _ => ice!("field {} of {:?}, which is not a type", **field, self)
+        }
+    }
+}
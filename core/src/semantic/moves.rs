@@ -0,0 +1,458 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Move checking
+//!
+//! `check_module()` follows the flow of values through the functions of a
+//! type-checked module, and reports each use of a name whose value has
+//! already been moved out of it:
+//!
+//!  + Using a name as an owned value (`NameRef::Owned`) moves its value,
+//!    unless it is of a type that is copied instead: a primitive other
+//!    than `string`, a symbol, a function, a borrowed or raw pointer, or
+//!    an option of one of those. Borrowing (`&x`), dereferencing (`$x`)
+//!    and uniquely borrowing (`@x`) a name do not move it, but are uses of
+//!    it all the same.
+//!  + A name that is matched on, compared, borrowed with a sigil, or whose
+//!    field is accessed, is used in place, and not moved, unless part of
+//!    its value is moved out of it: an owned name is moved by a `match`
+//!    whose patterns bind a value that is not copied, and by reading a
+//!    field whose value is not copied.
+//!  + Only the names bound within a function are tracked; a binding that
+//!    shadows a moved name, or `set!`ting a moved name, makes it usable
+//!    again.
+//!  + Of the branches of an `if`, the arms of a `match`, and the operands
+//!    evaluated only sometimes (the second operand of `and` and `or`, and
+//!    the default of an unwrapped option), only one is taken, so the
+//!    names moved by one branch may still be used by another. After the
+//!    branches, a name moved by any of them may have been moved.
+//!  + Each equation of a function begins with all the names visible to
+//!    the function unmoved. A function or lambda that moves a name bound
+//!    outside it captures the name's value, and so moves it where it is
+//!    defined.
+//!
+//! Each error is located at the use of the moved name, and followed by a
+//! note at the place where it was moved.
+use std::collections::HashMap;
+use std::mem;
+
+use ast::*;
+use errors::{ CompileResult, Errors };
+use position::{ Positional, Span };
+use super::{ SymbolAnnotation, SymbolTable };
+use super::annotations::{ Scoped, ScopedState };
+use super::types::{ Primitive, Reference, Type };
+
+type S = ScopedState;
+
+/// Check that no name in `module` is used after its value was moved.
+///
+/// # Returns
+///  + `Ok` if no moved name is used.
+///  + `Err` containing an error at each use of a moved name, followed by
+///    a note at the place where it was moved, otherwise.
+pub fn check_module<'a>(module: &Scoped<'a, Module<'a, S>>) -> CompileResult<()> {
+    let mut checker = Checker { moved: HashMap::new()
+                              , locals: vec![]
+                              , shadowed: vec![]
+                              , errs: vec![]
+                              };
+    for expr in &module.node.body { checker.top_level(expr) }
+    if checker.errs.is_empty() { Ok(()) } else { Err(checker.errs) }
+}
+
+/// Returns true if values of type `ty` are copied, rather than moved,
+/// when they are used.
+fn is_copy(ty: &Type) -> bool {
+    match *ty {
+        Type::Prim(Primitive::Str) => false
+      , Type::Prim(_) | Type::Symbol(_) | Type::Function(_)
+      | Type::Ref(Reference::Borrowed(_)) | Type::Ref(Reference::Raw(_)) => true
+      , Type::Option(ref t) => is_copy(t)
+      , Type::Ref(Reference::Moved(_)) | Type::Ref(Reference::Unique(_))
+      | Type::Algebraic(_) | Type::Named(..) | Type::Var(_) => false
+    }
+}
+
+/// Returns true if the value of `name` in `table` is copied, rather than
+/// moved, when it is used.
+fn is_copied(table: &SymbolTable, name: &str) -> bool {
+    match table.get(name) {
+        Some(&SymbolAnnotation::Value { ref ty, .. }) => is_copy(ty)
+      , _ => true
+    }
+}
+
+/// Returns the type of the field read by `access`, if it is known.
+fn field_type(access: &FieldAccess<S>) -> Option<Type> {
+    let table = access.record.symbol_table();
+    let record = match access.record.node {
+        Form::NameRef(ref name) => match table.get(&name.name().value) {
+            Some(&SymbolAnnotation::Value { ref ty, .. }) => ty.clone()
+          , _ => return None
+        }
+      , Form::Field(ref inner) => match field_type(inner) {
+            Some(ty) => ty
+          , None => return None
+        }
+      , _ => return None
+    };
+    match record {
+        Type::Named(ref name, _) => table.get(name)
+                                         .and_then(|def| access.check(def).ok())
+      , _ => None
+    }
+}
+
+/// Add the names bound by a pattern to `names`.
+fn pattern_names<'p>(element: &'p PatElement, names: &mut Vec<&'p Ident>) {
+    match *element {
+        PatElement::Name(ref name) | PatElement::Typed { ref name, .. }
+      | PatElement::Deref(ref name) => names.push(name)
+      , PatElement::Constructor { ref fields, .. } | PatElement::List(ref fields) =>
+            for field in fields { pattern_names(field, names) }
+      , PatElement::Cons { ref head, ref tail } => {
+            pattern_names(head, names);
+            pattern_names(tail, names);
+        }
+      , PatElement::Lit(_) | PatElement::Anything => {}
+    }
+}
+
+/// Where a name's value was moved.
+#[derive(Copy, Clone, Debug)]
+struct Move { pos: Span
+            , /// Whether the value was moved on only some of the paths
+              /// through the function to here.
+              conditional: bool
+            }
+
+type Moves = HashMap<String, Move>;
+
+/// Returns the moves made by either of two branches.
+fn join(a: Moves, mut b: Moves) -> Moves {
+    let mut joined = HashMap::new();
+    for (name, m) in a {
+        let conditional = match b.remove(&name) {
+            Some(other) => m.conditional || other.conditional
+          , None => true
+        };
+        joined.insert(name, Move { conditional: conditional, ..m });
+    }
+    for (name, m) in b {
+        joined.insert(name, Move { conditional: true, ..m });
+    }
+    joined
+}
+
+struct Checker {
+    /// The names whose values have been moved, on some path to the
+    /// expression being checked.
+    moved: Moves
+  , /// The names bound within the function being checked, innermost last.
+    locals: Vec<String>
+  , /// The moves of the names shadowed by the bindings in scope, to be
+    /// restored when their scopes end.
+    shadowed: Vec<(String, Option<Move>)>
+  , errs: Errors
+}
+
+impl Checker {
+
+    /// Check an expression at the top level of the module, whose names
+    /// are not tracked, since any function may use them.
+    fn top_level(&mut self, expr: &Expr<S>) {
+        self.moved.clear();
+        match expr.node {
+            Form::Define(ref def) => self.top_level_def(def)
+          , Form::Instance(ref inst) =>
+                for def in &inst.functions { self.top_level_def(def) }
+          , _ => self.expr(expr)
+        }
+    }
+
+    fn top_level_def(&mut self, def: &DefForm<S>) {
+        match *def {
+            DefForm::TopLevel { ref value, .. } => self.expr(value)
+          , DefForm::Function { ref fun, .. } => self.function(&fun.node)
+        }
+    }
+
+    /// Check a use of `name`, which moves its value if `moving` is true,
+    /// where `table` is the scope of the use.
+    fn use_name(&mut self, table: &SymbolTable, name: &Ident, moving: bool) {
+        if !self.locals.contains(&name.value) { return }
+        if let Some(m) = self.moved.get(&name.value).cloned() {
+            let msg = if moving {
+                format!( "[error] cannot move {}, since it has already \
+                          been moved", **name)
+            } else {
+                format!("[error] cannot use {}, since it has been moved", **name)
+            };
+            let note = if m.conditional {
+                format!("[note] {} may have been moved here, in one branch", **name)
+            } else {
+                format!("[note] {} was moved here", **name)
+            };
+            self.errs.push(name.map(msg));
+            self.errs.push(Positional::from(m.pos, note));
+            return
+        }
+        if moving && !is_copied(table, &name.value) {
+            self.moved.insert( name.value.clone()
+                             , Move { pos: name.pos, conditional: false });
+        }
+    }
+
+    fn name_ref(&mut self, table: &SymbolTable, name: &NameRef) {
+        match *name {
+            NameRef::Owned(ref n) => self.use_name(table, n, true)
+          , NameRef::Borrowed(ref n) | NameRef::Deref(ref n)
+          | NameRef::Unique(ref n) => self.use_name(table, n, false)
+        }
+    }
+
+    /// Bind `name` in the current scope, so that it is unmoved until the
+    /// scope ends.
+    fn bind(&mut self, name: &Ident) {
+        let previous = self.moved.remove(&name.value);
+        self.shadowed.push((name.value.clone(), previous));
+        self.locals.push(name.value.clone());
+    }
+
+    /// Check `f` in a new scope, in which the names bound by `bind()` go
+    /// out of scope when `f` returns.
+    fn scope<F>(&mut self, f: F)
+    where F: FnOnce(&mut Self) {
+        let (locals, shadowed) = (self.locals.len(), self.shadowed.len());
+        f(self);
+        self.locals.truncate(locals);
+        for (name, previous) in self.shadowed.split_off(shadowed).into_iter().rev() {
+            match previous {
+                Some(m) => { self.moved.insert(name, m); }
+              , None => { self.moved.remove(&name); }
+            }
+        }
+    }
+
+    /// Check an expression that is evaluated on only some of the paths
+    /// through the function, such as the second operand of `and`.
+    fn sometimes(&mut self, expr: &Expr<S>) {
+        let skipped = self.moved.clone();
+        self.expr(expr);
+        let taken = mem::replace(&mut self.moved, HashMap::new());
+        self.moved = join(taken, skipped);
+    }
+
+    /// Check a function, each of whose equations begins with the names
+    /// moved where the function is defined, and no others.
+    fn function(&mut self, fun: &Function<S>) {
+        let before = self.moved.clone();
+        let mut captured = HashMap::new();
+        for eq in &fun.equations {
+            self.moved = before.clone();
+            self.scope(|c| {
+                let mut names = vec![];
+                for element in &eq.node.pattern { pattern_names(element, &mut names) }
+                for name in names { c.bind(name) }
+                for expr in &eq.node.body { c.expr(expr) }
+            });
+            // the names bound outside the function, which it moves
+            for (name, m) in mem::replace(&mut self.moved, HashMap::new()) {
+                if !before.contains_key(&name) && self.locals.contains(&name) {
+                    captured.entry(name).or_insert(m);
+                }
+            }
+        }
+        self.moved = before;
+        self.moved.extend(captured);
+    }
+
+    fn def(&mut self, def: &DefForm<S>) {
+        match *def {
+            DefForm::TopLevel { ref name, ref value, .. } => {
+                self.expr(value);
+                self.bind(name);
+            }
+          , DefForm::Function { ref name, ref fun } => {
+                self.bind(name);
+                self.function(&fun.node);
+            }
+        }
+    }
+
+    fn bindings(&mut self, bindings: &Bindings<S>, recursive: bool) {
+        if recursive {
+            for binding in bindings { self.bind(&binding.node.name) }
+        }
+        for binding in bindings {
+            self.expr(&binding.node.value);
+            if !recursive { self.bind(&binding.node.name) }
+        }
+    }
+
+    fn body(&mut self, body: &Body<S>) {
+        for expr in body { self.expr(expr) }
+    }
+
+    fn app(&mut self, table: &SymbolTable, app: &AppForm<S>) {
+        self.use_name(table, &app.fun, false);
+        self.body(&app.params);
+    }
+
+    /// Check an expression that is used in place, rather than moved.
+    fn place(&mut self, expr: &Expr<S>) {
+        match expr.node {
+            Form::NameRef(ref name) =>
+                self.use_name(expr.symbol_table(), name.name(), false)
+          , _ => self.expr(expr)
+        }
+    }
+
+    /// Check the record of a field access, which is moved out of an owned
+    /// name if `moving` is true, and used in place otherwise.
+    fn record(&mut self, record: &Expr<S>, moving: bool) {
+        match record.node {
+            Form::NameRef(NameRef::Owned(ref name)) =>
+                self.use_name(record.symbol_table(), name, moving)
+          , Form::Field(ref access) => self.record(&access.record, moving)
+          , _ => self.place(record)
+        }
+    }
+
+    fn num(&mut self, table: &SymbolTable, num: &NumExpr<S>) {
+        match *num {
+            NumExpr::BOp(ref op) => match op.value {
+                NumBOp::Add(ref operands) | NumBOp::Sub(ref operands)
+              | NumBOp::Mul(ref operands) | NumBOp::Div(ref operands)
+              | NumBOp::BitAnd(ref operands) | NumBOp::BitOr(ref operands)
+              | NumBOp::BitXor(ref operands) | NumBOp::ShiftL(ref operands)
+              | NumBOp::ShiftR(ref operands) =>
+                    for operand in operands { self.num(table, operand) }
+            }
+          , NumExpr::Neg(ref operand) => self.num(table, &operand.value)
+          , NumExpr::Deref(ref name) => self.name_ref(table, name)
+          , NumExpr::Call(ref app) => self.app(table, &app.value)
+          , NumExpr::Other(ref expr) => self.expr(expr)
+          , NumExpr::Lit(_) => {}
+        }
+    }
+
+    fn datum(&mut self, datum: &Datum<S>) {
+        match *datum {
+            Datum::List(ref data) => for d in data { self.datum(d) }
+          , Datum::Unquote(ref expr) | Datum::UnquoteSplicing(ref expr) =>
+                self.expr(expr)
+          , Datum::Symbol(_) | Datum::Lit(_) => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr<S>) {
+        let table = expr.symbol_table();
+        match expr.node {
+            Form::Define(ref def) => self.def(def)
+          , Form::If { ref condition, ref if_clause, ref else_clause } => {
+                self.expr(condition);
+                let before = self.moved.clone();
+                self.expr(if_clause);
+                let then = mem::replace(&mut self.moved, before);
+                if let Some(ref e) = *else_clause { self.expr(e) }
+                let otherwise = mem::replace(&mut self.moved, HashMap::new());
+                self.moved = join(then, otherwise);
+            }
+          , Form::Let(LetForm::Let { ref bindings, ref body })
+          | Form::Let(LetForm::LetSplat { ref bindings, ref body }) =>
+                self.scope(|c| { c.bindings(bindings, false); c.body(body) })
+          , Form::Let(LetForm::LetRec { ref bindings, ref body }) =>
+                self.scope(|c| { c.bindings(bindings, true); c.body(body) })
+          , Form::Let(LetForm::Invocation { ref proc_id, ref init, ref body, .. }) =>
+                self.scope(|c| {
+                    c.expr(&init.value);
+                    c.bind(proc_id);
+                    c.bind(&init.name);
+                    c.body(body)
+                })
+          , Form::App(ref app) => self.app(table, app)
+          , Form::Lambda(ref fun) => self.function(fun)
+          , Form::Instance(ref inst) =>
+                for def in &inst.functions { self.top_level_def(def) }
+          , Form::Logical(Logical::And { ref a, ref b })
+          | Form::Logical(Logical::Or { ref a, ref b }) => {
+                self.expr(a);
+                self.sometimes(b);
+            }
+          , Form::Bool(BoolBOp::Lt(ref a, ref b))
+          | Form::Bool(BoolBOp::LtE(ref a, ref b))
+          | Form::Bool(BoolBOp::Gt(ref a, ref b))
+          | Form::Bool(BoolBOp::GtE(ref a, ref b))
+          | Form::Bool(BoolBOp::Equal(ref a, ref b))
+          | Form::Bool(BoolBOp::NEqual(ref a, ref b)) => {
+                self.place(a);
+                self.place(b);
+            }
+          , Form::Num(ref num) => self.num(table, num)
+          , Form::NameRef(ref name) => self.name_ref(table, name)
+          , Form::Sigil(Sigil::Borrow(ref e)) | Form::Sigil(Sigil::Raw(ref e)) =>
+                self.place(e)
+          , Form::Sigil(Sigil::Unique(ref e)) => self.expr(e)
+          , Form::Sigil(Sigil::Unwrap { ref option, ref default }) => {
+                self.expr(option);
+                if let Some(ref e) = *default { self.sometimes(e) }
+            }
+          , Form::Begin(ref body) => self.body(body)
+          , Form::Set(ref form) => {
+                self.expr(&form.value);
+                self.moved.remove(&form.name.value);
+            }
+          , Form::Match(ref form) => {
+                // the value of an owned name is moved into the bindings
+                // of the arms, unless they are all copied
+                let moving = form.arms.iter().any(|arm| {
+                    let (table, mut names) = (arm.symbol_table(), vec![]);
+                    pattern_names(&arm.node.pattern, &mut names);
+                    let copied = names.iter().all(|n| is_copied(table, &n.value));
+                    !copied
+                });
+                match form.scrutinee.node {
+                    Form::NameRef(NameRef::Owned(ref name)) =>
+                        self.use_name(form.scrutinee.symbol_table(), name, moving)
+                  , _ => self.place(&form.scrutinee)
+                }
+                let before = self.moved.clone();
+                let mut joined: Option<Moves> = None;
+                for arm in &form.arms {
+                    self.moved = before.clone();
+                    self.scope(|c| {
+                        let mut names = vec![];
+                        pattern_names(&arm.node.pattern, &mut names);
+                        for name in names { c.bind(name) }
+                        c.body(&arm.node.body)
+                    });
+                    let moved = mem::replace(&mut self.moved, HashMap::new());
+                    joined = Some(match joined {
+                        Some(moves) => join(moves, moved)
+                      , None => moved
+                    });
+                }
+                self.moved = joined.unwrap_or(before);
+            }
+          , Form::Field(ref access) => {
+                let moving = field_type(access).map_or(false, |ty| !is_copy(&ty));
+                self.record(&access.record, moving)
+            }
+          , Form::With(ref update) => {
+                self.expr(&update.record);
+                for &(_, ref value) in &update.fields { self.expr(value) }
+            }
+          , Form::Quote(ref datum) | Form::Quasiquote(ref datum) => self.datum(datum)
+            // these contain no expressions
+          , Form::Data(_) | Form::Class(_) | Form::Lit(_) | Form::Macro(_) => {}
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Generic rewriting of unscoped ASTs
+//!
+//! Passes that transform the AST between parsing and `AnnotateTypes`,
+//! such as macro expansion and constant folding, implement `Rewrite` and
+//! override only the nodes they change.
+use std::rc::Rc;
+
+use ast::*;
+use errors::CompileResult;
+use super::annotations::{ Annotated, UnscopedState };
+
+type U = UnscopedState;
+
+/// How a name is used, for `Rewrite::name()`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NameUse { /// A local binding, in a `let` or a function's equation.
+                   Binder
+                 , Reference
+                 , /// The name of a definition.
+                   Definition
+                 , /// The name of a record field.
+                   Field
+                 }
+
+/// A rewrite of an unscoped AST.
+///
+/// The default methods rebuild the AST unchanged; implementors override
+/// the methods for the nodes they rewrite, and call `walk_expr()` to
+/// rewrite the children of an expression.
+pub trait Rewrite<'a>: Sized {
+
+    fn expr(&mut self, expr: Expr<'a, U>) -> CompileResult<Expr<'a, U>> {
+        walk_expr(self, expr)
+    }
+
+    /// Rewrite each of `exprs`, returning the errors in all of them if
+    /// any fails.
+    fn exprs(&mut self, exprs: Body<'a, U>) -> CompileResult<Body<'a, U>> {
+        let mut rewritten = vec![];
+        let mut errs = vec![];
+        for expr in exprs {
+            match self.expr(expr) {
+                Ok(expr) => rewritten.push(expr)
+              , Err(mut e) => errs.append(&mut e)
+            }
+        }
+        if errs.is_empty() { Ok(rewritten) } else { Err(errs) }
+    }
+
+    #[allow(unused_variables)]
+    fn name(&mut self, name: Ident, used: NameUse) -> CompileResult<Ident> {
+        Ok(name)
+    }
+}
+
+pub fn walk_expr<'a, R>(r: &mut R, expr: Expr<'a, U>)
+                        -> CompileResult<Expr<'a, U>>
+where R: Rewrite<'a> {
+    let position = expr.position;
+    let form = try!(walk_form(r, expr.node));
+    Ok(Annotated::new(form, position))
+}
+
+pub fn walk_rc<'a, R>(r: &mut R, expr: Rc<Expr<'a, U>>)
+                     -> CompileResult<Rc<Expr<'a, U>>>
+where R: Rewrite<'a> {
+    r.expr((*expr).clone()).map(Rc::new)
+}
+
+pub fn walk_form<'a, R>(r: &mut R, form: Form<'a, U>)
+                        -> CompileResult<Form<'a, U>>
+where R: Rewrite<'a> {
+    Ok(match form {
+        Form::Define(def) => Form::Define(try!(walk_def(r, def)))
+      , Form::If { condition, if_clause, else_clause } =>
+            Form::If { condition: try!(walk_rc(r, condition))
+                     , if_clause: try!(walk_rc(r, if_clause))
+                     , else_clause: match else_clause {
+                            Some(e) => Some(try!(walk_rc(r, e)))
+                          , None => None
+                       }
+                     }
+      , Form::Let(form) => Form::Let(try!(walk_let(r, form)))
+      , Form::App(app) => Form::App(try!(walk_app(r, app)))
+      , Form::Lambda(fun) => Form::Lambda(try!(walk_fn(r, fun)))
+      , Form::Instance(inst) => {
+            let mut functions = vec![];
+            for def in inst.functions { functions.push(try!(walk_def(r, def))) }
+            Form::Instance(Instance { class: inst.class
+                                    , ty: inst.ty
+                                    , functions: functions
+                                    })
+        }
+      , Form::Logical(Logical::And { a, b }) =>
+            Form::Logical(Logical::And { a: try!(walk_rc(r, a))
+                                       , b: try!(walk_rc(r, b)) })
+      , Form::Logical(Logical::Or { a, b }) =>
+            Form::Logical(Logical::Or { a: try!(walk_rc(r, a))
+                                      , b: try!(walk_rc(r, b)) })
+      , Form::Bool(cmp) => {
+            let (a, b) = { let (a, b) = cmp.operands(); (a.clone(), b.clone()) };
+            Form::Bool(cmp.with_operands( try!(walk_rc(r, a))
+                                        , try!(walk_rc(r, b))))
+        }
+      , Form::Num(num) => Form::Num(try!(walk_num(r, num)))
+      , Form::NameRef(name) => Form::NameRef(try!(walk_name_ref(r, name)))
+      , Form::Sigil(sigil) => Form::Sigil(match sigil {
+            Sigil::Borrow(e) => Sigil::Borrow(try!(walk_rc(r, e)))
+          , Sigil::Unique(e) => Sigil::Unique(try!(walk_rc(r, e)))
+          , Sigil::Raw(e) => Sigil::Raw(try!(walk_rc(r, e)))
+          , Sigil::Unwrap { option, default } =>
+                Sigil::Unwrap { option: try!(walk_rc(r, option))
+                              , default: match default {
+                                    Some(e) => Some(try!(walk_rc(r, e)))
+                                  , None => None
+                                }
+                              }
+        })
+      , Form::Begin(body) => Form::Begin(try!(r.exprs(body)))
+      , Form::Set(form) =>
+            Form::Set(SetForm { name: try!(r.name(form.name, NameUse::Reference))
+                              , value: try!(walk_rc(r, form.value))
+                              })
+      , Form::Match(form) => {
+            let mut arms = vec![];
+            for arm in form.arms {
+                let position = arm.position;
+                let Arm { pattern, body } = arm.node;
+                let arm = Arm { pattern: try!(walk_pat_element(r, pattern))
+                              , body: try!(r.exprs(body))
+                              };
+                arms.push(Annotated::new(arm, position))
+            }
+            Form::Match(MatchForm { scrutinee: try!(walk_rc(r, form.scrutinee))
+                                  , arms: arms
+                                  })
+        }
+      , Form::Field(access) =>
+            Form::Field(FieldAccess {
+                field: try!(r.name(access.field, NameUse::Field))
+              , record: try!(walk_rc(r, access.record))
+            })
+      , Form::With(update) => {
+            let mut fields = vec![];
+            for (field, value) in update.fields {
+                fields.push(( try!(r.name(field, NameUse::Field))
+                            , try!(r.expr(value)) ))
+            }
+            Form::With(RecordUpdate { record: try!(walk_rc(r, update.record))
+                                    , fields: fields
+                                    })
+        }
+      , Form::Quasiquote(datum) => Form::Quasiquote(try!(walk_datum(r, datum)))
+        // these contain no expressions
+      , form @ Form::Data(_) | form @ Form::Class(_) | form @ Form::Lit(_)
+      | form @ Form::Quote(_) | form @ Form::Macro(_) => form
+    })
+}
+
+pub fn walk_def<'a, R>(r: &mut R, def: DefForm<'a, U>)
+                      -> CompileResult<DefForm<'a, U>>
+where R: Rewrite<'a> {
+    Ok(match def {
+        DefForm::TopLevel { name, annot, value } =>
+            DefForm::TopLevel { name: try!(r.name(name, NameUse::Definition))
+                              , annot: annot
+                              , value: try!(walk_rc(r, value))
+                              }
+      , DefForm::Function { name, fun } => {
+            let position = fun.position;
+            DefForm::Function {
+                name: try!(r.name(name, NameUse::Definition))
+              , fun: Annotated::new(try!(walk_fn(r, fun.node)), position)
+            }
+        }
+    })
+}
+
+pub fn walk_fn<'a, R>(r: &mut R, fun: Function<'a, U>)
+                     -> CompileResult<Function<'a, U>>
+where R: Rewrite<'a> {
+    let mut equations = vec![];
+    for eq in fun.equations {
+        let position = eq.position;
+        let Equation { pattern, body } = eq.node;
+        let eq = Equation { pattern: try!(walk_pattern(r, pattern))
+                          , body: try!(r.exprs(body))
+                          };
+        equations.push(Annotated::new(eq, position))
+    }
+    Ok(Function { sig: fun.sig, equations: equations })
+}
+
+pub fn walk_pattern<'a, R>(r: &mut R, pattern: Pattern)
+                           -> CompileResult<Pattern>
+where R: Rewrite<'a> {
+    pattern.into_iter().map(|e| walk_pat_element(r, e)).collect()
+}
+
+pub fn walk_pat_element<'a, R>(r: &mut R, element: PatElement)
+                              -> CompileResult<PatElement>
+where R: Rewrite<'a> {
+    Ok(match element {
+        PatElement::Name(name) =>
+            PatElement::Name(try!(r.name(name, NameUse::Binder)))
+      , PatElement::Typed { name, ty } =>
+            PatElement::Typed { name: try!(r.name(name, NameUse::Binder))
+                              , ty: ty }
+      , PatElement::Deref(name) =>
+            PatElement::Deref(try!(r.name(name, NameUse::Binder)))
+      , PatElement::Constructor { name, fields } =>
+            PatElement::Constructor {
+                name: try!(r.name(name, NameUse::Reference))
+              , fields: try!(walk_pattern(r, fields))
+            }
+      , PatElement::Cons { head, tail } =>
+            PatElement::Cons { head: Box::new(try!(walk_pat_element(r, *head)))
+                             , tail: Box::new(try!(walk_pat_element(r, *tail)))
+                             }
+      , PatElement::List(elements) =>
+            PatElement::List(try!(walk_pattern(r, elements)))
+      , element @ PatElement::Lit(_) | element @ PatElement::Anything => element
+    })
+}
+
+pub fn walk_binding<'a, R>(r: &mut R, binding: Binding<'a, U>)
+                          -> CompileResult<Binding<'a, U>>
+where R: Rewrite<'a> {
+    Ok(Binding { name: try!(r.name(binding.name, NameUse::Binder))
+               , typ: binding.typ
+               , value: try!(walk_rc(r, binding.value))
+               , mutable: binding.mutable
+               })
+}
+
+pub fn walk_bindings<'a, R>(r: &mut R, bindings: Bindings<'a, U>)
+                           -> CompileResult<Bindings<'a, U>>
+where R: Rewrite<'a> {
+    let mut walked = vec![];
+    for binding in bindings {
+        let position = binding.position;
+        walked.push(Annotated::new( try!(walk_binding(r, binding.node))
+                                  , position ))
+    }
+    Ok(walked)
+}
+
+pub fn walk_let<'a, R>(r: &mut R, form: LetForm<'a, U>)
+                      -> CompileResult<LetForm<'a, U>>
+where R: Rewrite<'a> {
+    Ok(match form {
+        LetForm::Let { bindings, body } =>
+            LetForm::Let { bindings: try!(walk_bindings(r, bindings))
+                         , body: try!(r.exprs(body)) }
+      , LetForm::LetRec { bindings, body } =>
+            LetForm::LetRec { bindings: try!(walk_bindings(r, bindings))
+                            , body: try!(r.exprs(body)) }
+      , LetForm::LetSplat { bindings, body } =>
+            LetForm::LetSplat { bindings: try!(walk_bindings(r, bindings))
+                              , body: try!(r.exprs(body)) }
+      , LetForm::Invocation { proc_id, ret_ty, init, body } =>
+            LetForm::Invocation {
+                proc_id: try!(r.name(proc_id, NameUse::Binder))
+              , ret_ty: ret_ty
+              , init: try!(walk_binding(r, init))
+              , body: try!(r.exprs(body))
+            }
+    })
+}
+
+pub fn walk_app<'a, R>(r: &mut R, app: AppForm<'a, U>)
+                      -> CompileResult<AppForm<'a, U>>
+where R: Rewrite<'a> {
+    Ok(AppForm { fun: try!(r.name(app.fun, NameUse::Reference))
+               , params: try!(r.exprs(app.params))
+               })
+}
+
+pub fn walk_name_ref<'a, R>(r: &mut R, name: NameRef) -> CompileResult<NameRef>
+where R: Rewrite<'a> {
+    Ok(match name {
+        NameRef::Owned(n) => NameRef::Owned(try!(r.name(n, NameUse::Reference)))
+      , NameRef::Borrowed(n) =>
+            NameRef::Borrowed(try!(r.name(n, NameUse::Reference)))
+      , NameRef::Deref(n) => NameRef::Deref(try!(r.name(n, NameUse::Reference)))
+      , NameRef::Unique(n) =>
+            NameRef::Unique(try!(r.name(n, NameUse::Reference)))
+    })
+}
+
+/// Returns an operand of an arithmetic expression as an expression, so
+/// that rewrites may treat it like any other expression.
+fn operand_expr<'a>(num: NumExpr<'a, U>) -> Expr<'a, U> {
+    match num {
+        NumExpr::BOp(op) => {
+            let pos = op.pos;
+            Annotated::new(Form::Num(NumExpr::BOp(op)), pos)
+        }
+      , NumExpr::Neg(operand) => {
+            let pos = operand.pos;
+            Annotated::new(Form::Num(NumExpr::Neg(operand)), pos)
+        }
+      , NumExpr::Lit(lit) => Annotated::new(Form::Lit(lit.value), lit.pos)
+      , NumExpr::Deref(name) => {
+            let pos = name.name().pos;
+            Annotated::new(Form::NameRef(name), pos)
+        }
+      , NumExpr::Call(app) => Annotated::new(Form::App(app.value), app.pos)
+      , NumExpr::Other(expr) => (*expr).clone()
+    }
+}
+
+/// Rewrite the operands of an arithmetic expression.
+///
+/// The operands are rewritten as expressions, so that they may be
+/// replaced by any expression, such as the arguments to a macro, and so
+/// that a list of operands may be rewritten like any other list.
+pub fn walk_num<'a, R>(r: &mut R, num: NumExpr<'a, U>)
+                      -> CompileResult<NumExpr<'a, U>>
+where R: Rewrite<'a> {
+    Ok(match num {
+        NumExpr::BOp(op) => {
+            let operands = op.operands()
+                             .iter()
+                             .cloned()
+                             .map(operand_expr)
+                             .collect();
+            let operands = try!(r.exprs(operands));
+            NumExpr::BOp(op.map(op.with_operands(
+                operands.iter().map(NumExpr::operand).collect())))
+        }
+      , NumExpr::Neg(operand) => {
+            let expr = operand_expr((*operand.value).clone());
+            let rewritten = try!(r.expr(expr));
+            NumExpr::Neg(operand.map(Box::new(NumExpr::operand(&rewritten))))
+        }
+      , num => num
+    })
+}
+
+pub fn walk_datum<'a, R>(r: &mut R, datum: Datum<'a, U>)
+                        -> CompileResult<Datum<'a, U>>
+where R: Rewrite<'a> {
+    Ok(match datum {
+        Datum::List(data) => {
+            let mut walked = vec![];
+            for d in data { walked.push(try!(walk_datum(r, d))) }
+            Datum::List(walked)
+        }
+      , Datum::Unquote(e) => Datum::Unquote(try!(walk_rc(r, e)))
+      , Datum::UnquoteSplicing(e) => Datum::UnquoteSplicing(try!(walk_rc(r, e)))
+      , datum => datum
+    })
+}
@@ -0,0 +1,589 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Scope resolution
+//!
+//! `annotate_module()` takes an unscoped module to the scoped typestate,
+//! annotating every node with the names that are visible to it and
+//! reporting every name that is used but not defined.
+//!
+//!  + The top level of a module can see all of the module's definitions,
+//!    data types and their constructors, and typeclasses and their
+//!    functions, wherever they are defined, as well as the names
+//!    exported by the modules it imports.
+//!  + Similarly, a body (of a function equation, `let`, `begin` or
+//!    `match` arm) can see all of the definitions in it.
+//!  + Each equation of a function can see the names bound by its
+//!    pattern, and each `match` arm those bound by its pattern.
+//!  + The scopes of `let` forms are described by `LetForm::scopes()`.
+//!  + A comparison whose operator the module defines or imports, as a
+//!    typeclass may declare `==`, is a call to that definition rather
+//!    than a built-in comparison.
+//!
+//! Macros must be expanded with `macros::expand()` before scopes are
+//! resolved.
+use std::rc::Rc;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use ast::*;
+use errors::{ CompileResult, Errors };
+use position::Positional;
+use super::{ typeclass, Scope, SharedScope, SymbolAnnotation, SymbolTable
+           , LetScopes };
+use super::rewrite::{ Rewrite, walk_expr };
+use super::annotations::{ Annotated, Scoped, ScopedState, UnscopedState };
+use super::types::{ Type, Reference };
+
+type U = UnscopedState;
+type S = ScopedState;
+
+/// Resolve the scopes of `module`, which imports the symbols `imported`.
+///
+/// Definitions in the module shadow any imported symbols of the same
+/// name, and a class imported from several modules has all of the
+/// instances that they export.
+///
+/// # Returns
+///  + `Ok` containing the module in the scoped typestate, annotated with
+///    the scope of its top level, if every name it uses is defined.
+///  + `Err` containing an error for each undefined name otherwise, and
+///    for each name defined more than once.
+pub fn annotate_module<'a>( module: Module<'a, U>
+                          , imported: Vec<(String, SymbolAnnotation<'a>)>)
+                          -> CompileResult<Scoped<'a, Module<'a, S>>> {
+    let mut errs = vec![];
+    let mut root = SymbolTable::new();
+    for expr in &module.body {
+        match expr.node {
+            Form::Class(ref class) =>
+                if let Err(e) = typeclass::register_class(&mut root, class) {
+                    errs.extend(e)
+                }
+          , _ => declare(&mut root, expr, &mut errs)
+        }
+    }
+    // a class may be imported from several modules, each of which exports
+    // it with the instances of it that that module sees
+    let mut imports = HashMap::new();
+    for (name, annotation) in imported {
+        match imports.entry(name) {
+            Entry::Vacant(entry) => { entry.insert(annotation); }
+          , Entry::Occupied(mut entry) =>
+                if let SymbolAnnotation::Class { instances: tys, .. } = annotation {
+                    if let SymbolAnnotation::Class { ref mut instances, .. } =
+                        *entry.get_mut() {
+                        for ty in tys {
+                            if !instances.contains(&ty) { instances.push(ty) }
+                        }
+                    }
+                }
+        }
+    }
+    for (name, annotation) in imports {
+        if !root.contains_key(&name) { root.insert(name, annotation); }
+    }
+    // instances are registered once all of the classes have been, including
+    // those that were imported
+    for expr in &module.body {
+        if let Form::Instance(ref inst) = expr.node {
+            if let Err(e) = typeclass::register_instance(&mut root, inst) {
+                errs.extend(e)
+            }
+        }
+    }
+
+    let Module { name, imports, exporting, body } = module;
+    let defined = COMPARISONS.iter()
+                             .cloned()
+                             .filter(|op| root.contains_key(*op))
+                             .collect();
+    let body = try!(Overloads { defined: defined }.exprs(body));
+    let root = SharedScope::new(root);
+    let body = body.into_iter()
+                   .map(|expr| expr.annotate_types(&root, &mut errs))
+                   .collect();
+    if !errs.is_empty() { return Err(errs) }
+    let position = name.pos;
+    Ok(Annotated::in_scope( Module { name: name
+                                   , imports: imports
+                                   , exporting: exporting
+                                   , body: body
+                                   }
+                          , position
+                          , root.table() ))
+}
+
+/// The operators of the built-in comparisons.
+const COMPARISONS: [&'static str; 6] = ["<", "<=", ">", ">=", "==", "/="];
+
+/// Rewrites the comparisons whose operators are in `defined` as calls to
+/// the functions of those names.
+struct Overloads { defined: Vec<&'static str> }
+
+impl<'a> Rewrite<'a> for Overloads {
+    fn expr(&mut self, expr: Expr<'a, U>) -> CompileResult<Expr<'a, U>> {
+        let expr = try!(walk_expr(self, expr));
+        let node = match expr.node {
+            Form::Bool(ref cmp) if self.defined.contains(&cmp.operator()) => {
+                let (a, b) = cmp.operands();
+                let fun = Positional::from( expr.position
+                                          , String::from(cmp.operator()));
+                Form::App(AppForm { fun: fun
+                                  , params: vec![(**a).clone(), (**b).clone()]
+                                  })
+            }
+          , node => node
+        };
+        Ok(Annotated::new(node, expr.position))
+    }
+}
+
+/// Declare the name defined by `expr`, if it is a definition or a data
+/// type, in `scope`.
+///
+/// A data type also declares its constructors, which must have distinct
+/// names, as must the fields of each of its records.
+fn declare<'s, 'a>( scope: &mut Scope<'s, 'a>, expr: &Expr<'a, U>
+                  , errs: &mut Errors) {
+    let name = match expr.node {
+        Form::Define(ref def) => def.name()
+      , Form::Data(ref data) => {
+            errs.extend(data.check_duplicates());
+            &data.name
+        }
+      , _ => return
+    };
+    if scope.contains_key(&name.value) {
+        return errs.push(name.map(format!(
+            "[error] {} is already defined in this scope", **name)))
+    }
+    match expr.node {
+        Form::Define(ref def) => {
+            scope.insert(name.value.clone(), def.annotation());
+        }
+      , Form::Data(ref data) =>
+            for (name, annotation) in data.annotations() {
+                scope.insert(name, annotation);
+            }
+      , _ => {}
+    }
+}
+
+/// Add an error to `errs` if `name` is not defined in `scope`.
+fn resolve<'s, 'a>(name: &Ident, scope: &Scope<'s, 'a>, errs: &mut Errors) {
+    if scope.get(&name.value).is_none() {
+        errs.push(name.map(format!("[error] undefined name {}", **name)))
+    }
+}
+
+/// Bind the names in the pattern `element` in `scope`, which is nested
+/// within `outer`.
+///
+/// `ty` is the type of the values the element matches, if it is known.
+fn bind_pattern<'s, 'o, 'a>( element: &PatElement, ty: Option<&Type>
+                           , scope: &mut Scope<'s, 'a>, outer: &SharedScope<'o, 'a>
+                           , errs: &mut Errors) {
+    let value = |ty| SymbolAnnotation::Value { ty: ty
+                                             , proven_value: None
+                                             , mutable: false };
+    match *element {
+        PatElement::Name(ref name) => {
+            let ty = ty.cloned().unwrap_or_else(|| outer.unknown());
+            scope.insert(name.value.clone(), value(ty));
+        }
+      , PatElement::Typed { ref name, ref ty } => {
+            scope.insert(name.value.clone(), value(ty.clone()));
+        }
+      , PatElement::Deref(ref name) => {
+            let ty = match ty {
+                Some(&Type::Ref(Reference::Borrowed(ref pointee)))
+              | Some(&Type::Ref(Reference::Moved(ref pointee)))
+              | Some(&Type::Ref(Reference::Unique(ref pointee)))
+              | Some(&Type::Ref(Reference::Raw(ref pointee))) =>
+                    (**pointee).clone()
+              , _ => outer.unknown()
+            };
+            scope.insert(name.value.clone(), value(ty));
+        }
+      , PatElement::Constructor { ref name, ref fields } => {
+            resolve(name, scope, errs);
+            for field in fields { bind_pattern(field, None, scope, outer, errs) }
+        }
+      , PatElement::Cons { ref head, ref tail } => {
+            bind_pattern(head, None, scope, outer, errs);
+            bind_pattern(tail, ty, scope, outer, errs);
+        }
+      , PatElement::List(ref elements) =>
+            for element in elements {
+                bind_pattern(element, None, scope, outer, errs)
+            }
+      , PatElement::Lit(_) | PatElement::Anything => {}
+    }
+}
+
+/// Annotate a body, in which each definition is visible throughout.
+fn annotate_body<'s, 'a>( body: Body<'a, U>, scope: &'s SharedScope<'s, 'a>
+                        , errs: &mut Errors) -> Body<'a, S> {
+    let mut inner = scope.fork();
+    for expr in &body { declare(&mut inner, expr, errs) }
+    let inner = scope.nested(inner);
+    body.into_iter()
+        .map(|expr| expr.annotate_types(&inner, errs))
+        .collect()
+}
+
+/// Annotate a list of bindings, each of which is in the scope at the
+/// same index of `scopes`.
+fn annotate_bindings<'s, 'a>( bindings: Bindings<'a, U>
+                            , scopes: &'s [SharedScope<'s, 'a>]
+                            , errs: &mut Errors) -> Bindings<'a, S> {
+    bindings.into_iter()
+            .zip(scopes)
+            .map(|(binding, scope)| binding.annotate_types(scope, errs))
+            .collect()
+}
+
+impl<'a, T> AnnotateTypes<'a> for Annotated<'a, T, U>
+where T: AnnotateTypes<'a> {
+    type Output = Scoped<'a, T::Output>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        let position = self.position;
+        Annotated::in_scope( self.node.annotate_types(scope, errs)
+                           , position
+                           , scope.table() )
+    }
+}
+
+impl<'a, T> AnnotateTypes<'a> for Rc<T>
+where T: AnnotateTypes<'a> + Clone {
+    type Output = Rc<T::Output>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        let node = Rc::try_unwrap(self).unwrap_or_else(|rc| (*rc).clone());
+        Rc::new(node.annotate_types(scope, errs))
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for Form<'a, U> {
+    type Output = Form<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        match self {
+            Form::Define(def) => Form::Define(def.annotate_types(scope, errs))
+          , Form::If { condition, if_clause, else_clause } =>
+                Form::If { condition: condition.annotate_types(scope, errs)
+                         , if_clause: if_clause.annotate_types(scope, errs)
+                         , else_clause: else_clause.map(|e|
+                                e.annotate_types(scope, errs))
+                         }
+          , Form::Let(form) => Form::Let(form.annotate_types(scope, errs))
+          , Form::App(app) => Form::App(app.annotate_types(scope, errs))
+          , Form::Lambda(fun) => Form::Lambda(fun.annotate_types(scope, errs))
+          , Form::Data(data) => Form::Data(data.annotate_types(scope, errs))
+          , Form::Class(class) => Form::Class(class)
+          , Form::Instance(inst) =>
+                Form::Instance(Instance {
+                    class: inst.class
+                  , ty: inst.ty
+                  , functions: inst.functions
+                                   .into_iter()
+                                   .map(|def| def.annotate_types(scope, errs))
+                                   .collect()
+                })
+          , Form::Logical(Logical::And { a, b }) =>
+                Form::Logical(Logical::And { a: a.annotate_types(scope, errs)
+                                           , b: b.annotate_types(scope, errs) })
+          , Form::Logical(Logical::Or { a, b }) =>
+                Form::Logical(Logical::Or { a: a.annotate_types(scope, errs)
+                                          , b: b.annotate_types(scope, errs) })
+          , Form::Bool(cmp) => {
+                let (a, b) = { let (a, b) = cmp.operands(); (a.clone(), b.clone()) };
+                Form::Bool(cmp.with_operands( a.annotate_types(scope, errs)
+                                            , b.annotate_types(scope, errs)))
+            }
+          , Form::Num(num) => Form::Num(num.annotate_types(scope, errs))
+          , Form::Lit(lit) => Form::Lit(lit)
+          , Form::NameRef(name) => {
+                resolve(name.name(), scope, errs);
+                Form::NameRef(name)
+            }
+          , Form::Sigil(sigil) => Form::Sigil(match sigil {
+                Sigil::Borrow(e) => Sigil::Borrow(e.annotate_types(scope, errs))
+              , Sigil::Unique(e) => Sigil::Unique(e.annotate_types(scope, errs))
+              , Sigil::Raw(e) => Sigil::Raw(e.annotate_types(scope, errs))
+              , Sigil::Unwrap { option, default } =>
+                    Sigil::Unwrap { option: option.annotate_types(scope, errs)
+                                  , default: default.map(|e|
+                                        e.annotate_types(scope, errs))
+                                  }
+            })
+          , Form::Begin(body) => Form::Begin(annotate_body(body, scope, errs))
+          , Form::Set(form) => {
+                if let Err(e) = form.check(scope) { errs.extend(e) }
+                Form::Set(SetForm { name: form.name
+                                  , value: form.value.annotate_types(scope, errs)
+                                  })
+            }
+          , Form::Match(form) =>
+                Form::Match(MatchForm {
+                    scrutinee: form.scrutinee.annotate_types(scope, errs)
+                  , arms: form.arms
+                              .into_iter()
+                              .map(|arm| annotate_arm(arm, scope, errs))
+                              .collect()
+                })
+          , Form::Field(access) =>
+                Form::Field(FieldAccess {
+                    field: access.field
+                  , record: access.record.annotate_types(scope, errs)
+                })
+          , Form::With(update) =>
+                Form::With(RecordUpdate {
+                    record: update.record.annotate_types(scope, errs)
+                  , fields: update.fields
+                                  .into_iter()
+                                  .map(|(field, value)|
+                                        (field, value.annotate_types(scope, errs)))
+                                  .collect()
+                })
+          , Form::Quote(datum) => Form::Quote(datum.annotate_types(scope, errs))
+          , Form::Quasiquote(datum) =>
+                Form::Quasiquote(datum.annotate_types(scope, errs))
+          , Form::Macro(ref mac) =>
+                ice!( "the definition of macro {} should have been removed \
+                       by macro expansion", *mac.name)
+        }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for DefForm<'a, U> {
+    type Output = DefForm<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        match self {
+            DefForm::TopLevel { name, annot, value } =>
+                DefForm::TopLevel { name: name
+                                  , annot: annot
+                                  , value: value.annotate_types(scope, errs)
+                                  }
+          , DefForm::Function { name, fun } =>
+                DefForm::Function { name: name
+                                  , fun: fun.annotate_types(scope, errs)
+                                  }
+        }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for Function<'a, U> {
+    type Output = Function<'a, S>;
+
+    /// Annotate each equation with a scope containing the names bound by
+    /// its pattern, whose types are given by the function's signature.
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        let Function { sig, equations } = self;
+        let equations = equations.into_iter().map(|eq| {
+            let position = eq.position;
+            let Equation { pattern, body } = eq.node;
+            let mut inner = scope.fork();
+            for (i, element) in pattern.iter().enumerate() {
+                bind_pattern( element, sig.param_types().get(i)
+                            , &mut inner, scope, errs)
+            }
+            let inner = scope.nested(inner);
+            let body = annotate_body(body, &inner, errs);
+            Annotated::in_scope( Equation { pattern: pattern, body: body }
+                               , position
+                               , inner.table() )
+        }).collect();
+        Function { sig: sig, equations: equations }
+    }
+}
+
+/// Annotate a `match` arm with a scope containing the names bound by its
+/// pattern, like the equations of a function.
+fn annotate_arm<'s, 'a>( arm: Annotated<'a, Arm<'a, U>, U>
+                       , scope: &'s SharedScope<'s, 'a>
+                       , errs: &mut Errors) -> Annotated<'a, Arm<'a, S>, S> {
+    let position = arm.position;
+    let Arm { pattern, body } = arm.node;
+    let mut inner = scope.fork();
+    bind_pattern(&pattern, None, &mut inner, scope, errs);
+    let inner = scope.nested(inner);
+    let body = annotate_body(body, &inner, errs);
+    Annotated::in_scope(Arm { pattern: pattern, body: body }, position, inner.table())
+}
+
+impl<'a> AnnotateTypes<'a> for LetForm<'a, U> {
+    type Output = LetForm<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        let LetScopes { bindings: scopes, body: body_scope } = self.scopes(scope);
+        let scopes: Vec<SharedScope> = scopes.into_iter()
+                                             .map(|s| scope.nested(s))
+                                             .collect();
+        let body_scope = scope.nested(body_scope);
+        match self {
+            LetForm::Let { bindings, body } =>
+                LetForm::Let { bindings: annotate_bindings(bindings, &scopes, errs)
+                             , body: annotate_body(body, &body_scope, errs) }
+          , LetForm::LetRec { bindings, body } =>
+                LetForm::LetRec {
+                    bindings: annotate_bindings(bindings, &scopes, errs)
+                  , body: annotate_body(body, &body_scope, errs) }
+          , LetForm::LetSplat { bindings, body } =>
+                LetForm::LetSplat {
+                    bindings: annotate_bindings(bindings, &scopes, errs)
+                  , body: annotate_body(body, &body_scope, errs) }
+          , LetForm::Invocation { proc_id, ret_ty, init, body } =>
+                LetForm::Invocation {
+                    proc_id: proc_id
+                  , ret_ty: ret_ty
+                  , init: init.annotate_types(&scopes[0], errs)
+                  , body: annotate_body(body, &body_scope, errs)
+                }
+        }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for Binding<'a, U> {
+    type Output = Binding<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        Binding { name: self.name
+                , typ: self.typ
+                , value: self.value.annotate_types(scope, errs)
+                , mutable: self.mutable
+                }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for AppForm<'a, U> {
+    type Output = AppForm<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        if scope.get(&self.fun.value).is_none() {
+            errs.push(self.fun.map(format!(
+                "[error] undefined function {}", *self.fun)))
+        }
+        AppForm { fun: self.fun
+                , params: self.params
+                              .into_iter()
+                              .map(|e| e.annotate_types(scope, errs))
+                              .collect()
+                }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for NumExpr<'a, U> {
+    type Output = NumExpr<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        match self {
+            NumExpr::BOp(op) => {
+                let operands: Vec<NumExpr<'a, S>> = op.operands()
+                                 .iter()
+                                 .cloned()
+                                 .map(|n| n.annotate_types(scope, errs))
+                                 .collect();
+                NumExpr::BOp(op.map(op.with_operands(operands)))
+            }
+          , NumExpr::Neg(operand) => {
+                let negated = (*operand.value).clone()
+                                              .annotate_types(scope, errs);
+                NumExpr::Neg(operand.map(Box::new(negated)))
+            }
+          , NumExpr::Lit(lit) => NumExpr::Lit(lit)
+          , NumExpr::Deref(name) => {
+                resolve(name.name(), scope, errs);
+                NumExpr::Deref(name)
+            }
+          , NumExpr::Call(app) =>
+                NumExpr::Call(Positional::from( app.pos
+                                              , app.value.annotate_types(scope, errs)))
+          , NumExpr::Other(expr) =>
+                NumExpr::Other(expr.annotate_types(scope, errs))
+        }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for Datum<'a, U> {
+    type Output = Datum<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        match self {
+            Datum::Symbol(name) => Datum::Symbol(name)
+          , Datum::Lit(lit) => Datum::Lit(lit)
+          , Datum::List(data) =>
+                Datum::List(data.into_iter()
+                                .map(|d| d.annotate_types(scope, errs))
+                                .collect())
+          , Datum::Unquote(e) => Datum::Unquote(e.annotate_types(scope, errs))
+          , Datum::UnquoteSplicing(e) =>
+                Datum::UnquoteSplicing(e.annotate_types(scope, errs))
+        }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for Data<'a, U> {
+    type Output = Data<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        Data { name: self.name
+             , variants: self.variants
+                             .into_iter()
+                             .map(|(name, v)| (name, v.annotate_types(scope, errs)))
+                             .collect()
+             }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for Variant<'a, U> {
+    type Output = Variant<'a, S>;
+
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        match self {
+            Variant::Tagword(name) => Variant::Tagword(name)
+          , Variant::Constant(lit) => Variant::Constant(lit)
+          , Variant::Record(fields) =>
+                Variant::Record(fields.into_iter()
+                                      .map(|f| f.annotate_types(scope, errs))
+                                      .collect())
+          , Variant::Value(ty) => Variant::Value(ty)
+          , Variant::Sum(variants) =>
+                Variant::Sum(variants.into_iter()
+                                     .map(|(name, v)|
+                                          (name, v.annotate_types(scope, errs)))
+                                     .collect())
+        }
+    }
+}
+
+impl<'a> AnnotateTypes<'a> for Formal {
+    type Output = Formal;
+
+    #[allow(unused_variables)]
+    fn annotate_types<'s>( self, scope: &'s SharedScope<'s, 'a>
+                         , errs: &mut Errors) -> Self::Output{
+        self
+    }
+}
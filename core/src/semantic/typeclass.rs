@@ -0,0 +1,195 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Typeclass declarations and instances
+//!
+//! This module registers `class` and `instance` declarations in the
+//! symbol table, and checks that the typeclass constraints in function
+//! signatures refer to classes that have actually been declared.
+
+use ast::{ Class, Instance };
+use ::{ CompileResult, Errors };
+use super::{ SymbolTable, SymbolAnnotation };
+use super::annotations::ScopednessTypestate;
+use super::types::{ Type, Signature, Constraint };
+
+/// Returns `sig`, with an additional constraint that its type parameter
+/// is an instance of `class`.
+fn constrain(sig: &Signature, class: &Class) -> Signature {
+    let constraint = Constraint { typeclass: class.name.clone()
+                                , generics: vec![class.ty_param.clone()]
+                                };
+    let mut constraints = sig.constraints.clone().unwrap_or(vec![]);
+    constraints.insert(0, constraint);
+    Signature { constraints: Some(constraints)
+              , typechain: sig.typechain.clone()
+              }
+}
+
+impl Class {
+
+    /// The symbol table annotations for this class and its functions.
+    ///
+    /// Each of the class's prototypes is bound to a function value whose
+    /// signature is constrained by the class, and the name of the class is
+    /// bound to a `SymbolAnnotation::Class` with no instances.
+    pub fn annotations<'b>(&self) -> Vec<(String, SymbolAnnotation<'b>)> {
+        let mut annotations: Vec<(String, SymbolAnnotation<'b>)> =
+            self.defs.iter()
+                .map(|proto| ( proto.name.value.clone()
+                             , SymbolAnnotation::Value {
+                                 ty: Type::Function(constrain(&proto.sig, self))
+                               , proven_value: None
+                               , mutable: false
+                               } ))
+                .collect();
+        let methods = self.defs.iter()
+                          .map(|proto| (proto.name.value.clone(), proto.sig.clone()))
+                          .collect();
+        annotations.push(( self.name.value.clone()
+                         , SymbolAnnotation::Class { ty_param: self.ty_param
+                                                                   .value
+                                                                   .clone()
+                                                   , methods: methods
+                                                   , instances: vec![]
+                                                   } ));
+        annotations
+    }
+}
+
+/// Register a typeclass declaration in the symbol table.
+///
+/// The name of the class is bound to a `SymbolAnnotation::Class`, and
+/// each of its prototypes is bound to a function value whose signature is
+/// constrained by the class, so that calls to class functions can be
+/// resolved like calls to any other function.
+///
+/// # Returns
+///   - `Ok` if the class was registered.
+///   - An `Err` containing any errors in the declaration, such as
+///     duplicate prototypes or a redefinition of the class.
+pub fn register_class<'a>(scope: &mut SymbolTable<'a>, class: &Class)
+                         -> CompileResult<()> {
+    let mut errs: Errors = vec![];
+
+    if scope.contains_key(&class.name.value) {
+        errs.push(class.name.map(format!(
+            "[error] typeclass {} is already defined in this scope"
+           , *class.name)));
+    }
+
+    let mut methods = vec![];
+    for proto in &class.defs {
+        if methods.contains(&&proto.name.value) {
+            errs.push(proto.name.map(format!(
+                "[error] duplicate prototype {} in typeclass {}"
+               , *proto.name, *class.name)));
+        } else {
+            methods.push(&proto.name.value);
+        }
+    }
+
+    for proto in &class.defs {
+        errs.extend(check_constraints(scope, &proto.sig));
+    }
+
+    if !errs.is_empty() { return Err(errs) }
+
+    for (name, annotation) in class.annotations() {
+        scope.insert(name, annotation);
+    }
+    Ok(())
+}
+
+/// Register an instance of a typeclass in the symbol table.
+///
+/// The instance's type is added to the list of instances recorded in the
+/// class's `SymbolAnnotation`. If the class was declared in an enclosing
+/// scope, the updated annotation shadows it in the current scope.
+///
+/// # Returns
+///   - `Ok` if the instance was registered.
+///   - An `Err` if the class is not defined, if the instance defines
+///     functions that are not members of the class, or if it does not
+///     implement all of the class's prototypes.
+pub fn register_instance<'a, 'b, S>( scope: &mut SymbolTable<'b>
+                                   , instance: &Instance<'a, S>)
+                                   -> CompileResult<()>
+where S: ScopednessTypestate {
+    let class_name = &instance.class;
+    let (ty_param, methods, mut instances) =
+        match scope.get(&class_name.value) {
+            Some(&SymbolAnnotation::Class { ref ty_param
+                                          , ref methods
+                                          , ref instances }) =>
+                (ty_param.clone(), methods.clone(), instances.clone())
+          , Some(_) => return Err(vec![class_name.map(format!(
+                "[error] {} is not a typeclass", **class_name))])
+          , None => return Err(vec![class_name.map(format!(
+                "[error] undefined typeclass {}", **class_name))])
+        };
+
+    let mut errs: Errors = vec![];
+    let mut defined: Vec<&String> = vec![];
+    for name in instance.function_names() {
+        if !methods.contains_key(&name.value) {
+            errs.push(name.map(format!(
+                "[error] {} is not a member of typeclass {}"
+               , **name, **class_name)));
+        } else if defined.contains(&&name.value) {
+            errs.push(name.map(format!(
+                "[error] duplicate definition of {} in instance {} {}"
+               , **name, **class_name, instance.ty)));
+        } else {
+            defined.push(&name.value);
+        }
+    }
+    let mut missing = methods.keys()
+                             .filter(|m| !defined.contains(m))
+                             .collect::<Vec<&String>>();
+    missing.sort();
+    for name in missing {
+        errs.push(class_name.map(format!(
+            "[error] instance {} {} is missing an implementation of {}"
+           , **class_name, instance.ty, name)));
+    }
+
+    if !errs.is_empty() { return Err(errs) }
+
+    instances.push(instance.ty.clone());
+    scope.insert( class_name.value.clone()
+                , SymbolAnnotation::Class { ty_param: ty_param
+                                          , methods: methods
+                                          , instances: instances
+                                          });
+    Ok(())
+}
+
+/// Check that every typeclass named in a signature's constraints has
+/// been declared.
+///
+/// # Returns
+///   - A vector containing an error for each constraint on an undefined
+///     typeclass, or on a name that is not a typeclass. If every
+///     constraint is valid, the vector is empty.
+pub fn check_constraints<'a>(scope: &SymbolTable<'a>, sig: &Signature)
+                            -> Errors {
+    sig.constraints
+       .iter()
+       .flat_map(|cs| cs.iter())
+       .filter_map(|c| match scope.get(&c.typeclass.value) {
+            Some(&SymbolAnnotation::Class { .. }) => None
+          , Some(_) => Some(c.typeclass.map(format!(
+                "[error] {} is not a typeclass", *c.typeclass)))
+          , None => Some(c.typeclass.map(format!(
+                "[error] constraint on undefined typeclass {}"
+               , *c.typeclass)))
+        })
+       .collect()
+}
@@ -7,6 +7,7 @@
 //  the LICENSE file at the top-level directory of this distribution
 //  or at https://github.com/hawkw/mnemosyne/.
 //
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::fmt;
 use std::fmt::Write;
@@ -31,7 +32,19 @@ pub enum Type {
     /// A function.
     Function(Signature),
     /// A unique symbol type (`'symbol` syntax)
-    Symbol(String)
+    Symbol(String),
+    /// A type variable, as in a polymorphic signature or a typeclass
+    /// declaration.
+    ///
+    /// Type variables begin with a lower-case letter.
+    Var(String),
+    /// An optional value (`?i64` syntax).
+    Option(Rc<Type>),
+    /// A named type, such as a `data` type, applied to its type
+    /// parameters, if it has any (as in `Weekday` or `(List a)`).
+    ///
+    /// Named types begin with an upper-case letter.
+    Named(String, Vec<Type>)
 }
 
 
@@ -43,10 +56,83 @@ impl fmt::Display for Type {
                         unimplemented!()
                    , &Type::Function(ref fun) => write!(f, "{}", fun)
                    , &Type::Symbol(ref s) => write!(f, "{}", s)
+                   , &Type::Var(ref v) => write!(f, "{}", v)
+                   , &Type::Option(ref t) => write!(f, "?{}", t)
+                   , &Type::Named(ref name, ref params) if params.is_empty() =>
+                        write!(f, "{}", name)
+                   , &Type::Named(ref name, ref params) =>
+                        write!(f, "({} {})", name, concat_all(params.iter()))
                    }
     }
 }
 
+impl Type {
+
+    /// Returns the types of which this type is made.
+    pub fn children(&self) -> Vec<&Type> {
+        match *self {
+            Type::Ref(Reference::Borrowed(ref t))
+          | Type::Ref(Reference::Moved(ref t))
+          | Type::Ref(Reference::Unique(ref t))
+          | Type::Ref(Reference::Raw(ref t))
+          | Type::Option(ref t) => vec![&**t]
+          , Type::Algebraic(ref ts) | Type::Named(_, ref ts) => ts.iter().collect()
+          , Type::Function(ref sig) => sig.typechain.iter().collect()
+          , Type::Prim(_) | Type::Symbol(_) | Type::Var(_) => vec![]
+        }
+    }
+
+    /// Returns this type, with each of the types of which it is made
+    /// replaced by `f` applied to that type.
+    pub fn map_children<F>(&self, f: F) -> Type
+    where F: Fn(&Type) -> Type {
+        match *self {
+            Type::Ref(ref r) => Type::Ref(match *r {
+                Reference::Borrowed(ref t) => Reference::Borrowed(Rc::new(f(t)))
+              , Reference::Moved(ref t) => Reference::Moved(Rc::new(f(t)))
+              , Reference::Unique(ref t) => Reference::Unique(Rc::new(f(t)))
+              , Reference::Raw(ref t) => Reference::Raw(Rc::new(f(t)))
+            })
+          , Type::Option(ref t) => Type::Option(Rc::new(f(t)))
+          , Type::Algebraic(ref ts) =>
+                Type::Algebraic(ts.iter().map(|t| f(t)).collect())
+          , Type::Named(ref name, ref ts) =>
+                Type::Named(name.clone(), ts.iter().map(|t| f(t)).collect())
+          , Type::Function(ref sig) =>
+                Type::Function(Signature { constraints: sig.constraints.clone()
+                                         , typechain: sig.typechain
+                                                         .iter()
+                                                         .map(|t| f(t))
+                                                         .collect()
+                                         })
+          , Type::Prim(_) | Type::Symbol(_) | Type::Var(_) => self.clone()
+        }
+    }
+
+    /// Returns the names of the type variables in this type, in the order
+    /// in which they first occur.
+    pub fn type_vars(&self) -> Vec<String> {
+        fn collect(ty: &Type, vars: &mut Vec<String>) {
+            match *ty {
+                Type::Var(ref v) => if !vars.contains(v) { vars.push(v.clone()) }
+              , _ => for t in ty.children() { collect(t, vars) }
+            }
+        }
+        let mut vars = vec![];
+        collect(self, &mut vars);
+        vars
+    }
+
+    /// Returns this type, with each of the type variables in `vars`
+    /// replaced by the type it maps to.
+    pub fn substitute(&self, vars: &HashMap<String, Type>) -> Type {
+        match *self {
+            Type::Var(ref v) => vars.get(v).cloned().unwrap_or_else(|| self.clone())
+          , _ => self.map_children(|t| t.substitute(vars))
+        }
+    }
+}
+
 /// A function signature
 ///
 /// This implements both `Type` and `Node`, since it can be used as
@@ -63,7 +149,7 @@ impl Signature {
     ///
     /// This just returns the last element in the type glob
     pub fn return_type(&self) -> &Type {
-        &self.typechain[self.typechain.len()]
+        &self.typechain[self.typechain.len() - 1]
     }
 
     /// Returns the arity of the function
@@ -77,7 +163,7 @@ impl Signature {
 
  impl fmt::Display for Signature {
      fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-         unimplemented!()
+         write!(f, "{}", ast::Node::to_sexpr(self, 0))
      }
  }
 
@@ -118,7 +204,9 @@ pub struct Constraint { pub typeclass: ast::Ident
 
 impl fmt::Display for Constraint {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unimplemented!()
+        write!( f, "{} {}"
+              , *self.typeclass
+              , concat_all(self.generics.iter().map(|g| &g.value)) )
     }
 }
 
@@ -192,7 +280,9 @@ impl fmt::Display for Primitive {
                    , Primitive::Double     => write!(f, "double")
                    , Primitive::Float      => write!(f, "float")
                    , Primitive::Bool       => write!(f, "bool")
-                   , _ => unimplemented!()
+                   , Primitive::Byte       => write!(f, "byte")
+                   , Primitive::Char       => write!(f, "char")
+                   , Primitive::Str        => write!(f, "string")
                    }
    }
 }
@@ -0,0 +1,232 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Generic traversal of scoped ASTs
+//!
+//! Passes that update a scoped AST in place, such as recording inferred
+//! types, implement `VisitMut` and override only the nodes they update.
+//! Unlike `Rewrite`, a visitor may not change the shape of the tree.
+//!
+//! The nodes in a scope share its symbol table, so a visitor that updates
+//! symbol tables visits each shared table once, and gives every node that
+//! shared it the updated copy.
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ast::*;
+use super::SymbolTable;
+use super::annotations::{ Scoped, ScopedState };
+
+type S = ScopedState;
+
+/// A traversal of a scoped AST, which may update it in place.
+///
+/// The default methods visit every node and leave it unchanged;
+/// implementors override the methods for the nodes they update, and call
+/// `walk_expr()` to visit the children of an expression.
+pub trait VisitMut<'a>: Sized {
+
+    fn expr(&mut self, expr: &mut Expr<'a, S>) { walk_expr(self, expr) }
+
+    /// Visit the symbol table of a node that has one.
+    ///
+    /// This is only called for visitors whose `tables()` are `Some`.
+    #[allow(unused_variables)]
+    fn table(&mut self, table: &mut SymbolTable<'a>) {}
+
+    /// The symbol tables this visitor has visited so far, or `None` if it
+    /// leaves symbol tables unchanged.
+    fn tables(&mut self) -> Option<&mut Tables<'a>> { None }
+
+    /// Visit a name that refers to a value, either as an expression or as
+    /// the function of an application.
+    #[allow(unused_variables)]
+    fn reference(&mut self, name: &mut Ident) {}
+}
+
+/// The updated copies of the shared symbol tables a visitor has visited.
+pub struct Tables<'a> { /// Each table visited, keyed by its address, and
+                        /// its updated copy. The table itself is kept so
+                        /// that its address is not reused.
+                        visited: HashMap< usize
+                                        , (Rc<SymbolTable<'a>>, Rc<SymbolTable<'a>>)>
+                      }
+
+impl<'a> Tables<'a> {
+    pub fn new() -> Self { Tables { visited: HashMap::new() } }
+}
+
+/// Visit the shared symbol table `table`, unless it has been visited
+/// already, and replace it with its updated copy.
+pub fn walk_table<'a, V>(v: &mut V, table: &mut Rc<SymbolTable<'a>>)
+where V: VisitMut<'a> {
+    let key = &**table as *const SymbolTable<'a> as usize;
+    match v.tables() {
+        None => return
+      , Some(tables) =>
+            if let Some(&(_, ref visited)) = tables.visited.get(&key) {
+                *table = visited.clone();
+                return
+            }
+    }
+    let mut visited = (**table).clone();
+    v.table(&mut visited);
+    let visited = Rc::new(visited);
+    if let Some(tables) = v.tables() {
+        tables.visited.insert(key, (table.clone(), visited.clone()));
+    }
+    *table = visited;
+}
+
+/// Visit the symbol table and the body of a module.
+pub fn walk_module<'a, V>(v: &mut V, module: &mut Scoped<'a, Module<'a, S>>)
+where V: VisitMut<'a> {
+    walk_table(v, module.scope_mut());
+    walk_body(v, &mut module.node.body)
+}
+
+pub fn walk_expr<'a, V>(v: &mut V, expr: &mut Expr<'a, S>)
+where V: VisitMut<'a> {
+    walk_table(v, expr.scope_mut());
+    match expr.node {
+        Form::Define(ref mut def) => walk_def(v, def)
+      , Form::If { ref mut condition, ref mut if_clause, ref mut else_clause } => {
+            walk_rc(v, condition);
+            walk_rc(v, if_clause);
+            if let Some(ref mut e) = *else_clause { walk_rc(v, e) }
+        }
+      , Form::Let(LetForm::Let { ref mut bindings, ref mut body })
+      | Form::Let(LetForm::LetSplat { ref mut bindings, ref mut body })
+      | Form::Let(LetForm::LetRec { ref mut bindings, ref mut body }) => {
+            for binding in bindings {
+                walk_table(v, binding.scope_mut());
+                walk_rc(v, &mut binding.node.value);
+            }
+            walk_body(v, body);
+        }
+      , Form::Let(LetForm::Invocation { ref mut init, ref mut body, .. }) => {
+            walk_rc(v, &mut init.value);
+            walk_body(v, body);
+        }
+      , Form::App(ref mut app) => walk_app(v, app)
+      , Form::Lambda(ref mut fun) => walk_fn(v, fun)
+      , Form::Instance(ref mut inst) =>
+            for def in &mut inst.functions { walk_def(v, def) }
+      , Form::Logical(Logical::And { ref mut a, ref mut b })
+      | Form::Logical(Logical::Or { ref mut a, ref mut b })
+      | Form::Bool(BoolBOp::Lt(ref mut a, ref mut b))
+      | Form::Bool(BoolBOp::LtE(ref mut a, ref mut b))
+      | Form::Bool(BoolBOp::Gt(ref mut a, ref mut b))
+      | Form::Bool(BoolBOp::GtE(ref mut a, ref mut b))
+      | Form::Bool(BoolBOp::Equal(ref mut a, ref mut b))
+      | Form::Bool(BoolBOp::NEqual(ref mut a, ref mut b)) => {
+            walk_rc(v, a);
+            walk_rc(v, b);
+        }
+      , Form::Num(ref mut num) => walk_num(v, num)
+      , Form::NameRef(ref mut name) => walk_name_ref(v, name)
+      , Form::Sigil(Sigil::Borrow(ref mut e))
+      | Form::Sigil(Sigil::Unique(ref mut e))
+      | Form::Sigil(Sigil::Raw(ref mut e)) => walk_rc(v, e)
+      , Form::Sigil(Sigil::Unwrap { ref mut option, ref mut default }) => {
+            walk_rc(v, option);
+            if let Some(ref mut e) = *default { walk_rc(v, e) }
+        }
+      , Form::Begin(ref mut body) => walk_body(v, body)
+      , Form::Set(ref mut form) => walk_rc(v, &mut form.value)
+      , Form::Match(ref mut form) => {
+            walk_rc(v, &mut form.scrutinee);
+            for arm in &mut form.arms {
+                walk_table(v, arm.scope_mut());
+                walk_body(v, &mut arm.node.body);
+            }
+        }
+      , Form::Field(ref mut access) => walk_rc(v, &mut access.record)
+      , Form::With(ref mut update) => {
+            walk_rc(v, &mut update.record);
+            for field in &mut update.fields { v.expr(&mut field.1) }
+        }
+      , Form::Quote(ref mut datum) | Form::Quasiquote(ref mut datum) =>
+            walk_datum(v, datum)
+        // these contain no expressions
+      , Form::Data(_) | Form::Class(_) | Form::Lit(_) | Form::Macro(_) => {}
+    }
+}
+
+pub fn walk_rc<'a, V>(v: &mut V, expr: &mut Rc<Expr<'a, S>>)
+where V: VisitMut<'a> {
+    v.expr(Rc::make_mut(expr))
+}
+
+pub fn walk_body<'a, V>(v: &mut V, body: &mut Body<'a, S>)
+where V: VisitMut<'a> {
+    for expr in body { v.expr(expr) }
+}
+
+pub fn walk_def<'a, V>(v: &mut V, def: &mut DefForm<'a, S>)
+where V: VisitMut<'a> {
+    match *def {
+        DefForm::TopLevel { ref mut value, .. } => walk_rc(v, value)
+      , DefForm::Function { ref mut fun, .. } => {
+            walk_table(v, fun.scope_mut());
+            walk_fn(v, &mut fun.node);
+        }
+    }
+}
+
+pub fn walk_fn<'a, V>(v: &mut V, fun: &mut Function<'a, S>)
+where V: VisitMut<'a> {
+    for eq in &mut fun.equations {
+        walk_table(v, eq.scope_mut());
+        walk_body(v, &mut eq.node.body);
+    }
+}
+
+pub fn walk_app<'a, V>(v: &mut V, app: &mut AppForm<'a, S>)
+where V: VisitMut<'a> {
+    v.reference(&mut app.fun);
+    walk_body(v, &mut app.params)
+}
+
+pub fn walk_name_ref<'a, V>(v: &mut V, name: &mut NameRef)
+where V: VisitMut<'a> {
+    match *name {
+        NameRef::Owned(ref mut n) | NameRef::Borrowed(ref mut n)
+      | NameRef::Deref(ref mut n) | NameRef::Unique(ref mut n) => v.reference(n)
+    }
+}
+
+pub fn walk_num<'a, V>(v: &mut V, num: &mut NumExpr<'a, S>)
+where V: VisitMut<'a> {
+    match *num {
+        NumExpr::BOp(ref mut op) => match op.value {
+            NumBOp::Add(ref mut operands) | NumBOp::Sub(ref mut operands)
+          | NumBOp::Mul(ref mut operands) | NumBOp::Div(ref mut operands)
+          | NumBOp::BitAnd(ref mut operands) | NumBOp::BitOr(ref mut operands)
+          | NumBOp::BitXor(ref mut operands) | NumBOp::ShiftL(ref mut operands)
+          | NumBOp::ShiftR(ref mut operands) =>
+                for operand in operands { walk_num(v, operand) }
+        }
+      , NumExpr::Neg(ref mut operand) => walk_num(v, &mut operand.value)
+      , NumExpr::Deref(ref mut name) => walk_name_ref(v, name)
+      , NumExpr::Call(ref mut app) => walk_app(v, &mut app.value)
+      , NumExpr::Other(ref mut expr) => walk_rc(v, expr)
+      , NumExpr::Lit(_) => {}
+    }
+}
+
+pub fn walk_datum<'a, V>(v: &mut V, datum: &mut Datum<'a, S>)
+where V: VisitMut<'a> {
+    match *datum {
+        Datum::List(ref mut data) => for d in data { walk_datum(v, d) }
+      , Datum::Unquote(ref mut expr) | Datum::UnquoteSplicing(ref mut expr) =>
+            walk_rc(v, expr)
+      , Datum::Symbol(_) | Datum::Lit(_) => {}
+    }
+}
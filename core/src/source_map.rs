@@ -0,0 +1,121 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Source code file management
+//!
+//! A `SourceMap` owns the source code of every file in a program, and maps
+//! the `FileId`s in `Span`s back to the files they refer to.
+
+use std::fmt;
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+
+use position::{ FileId, LineTable, Position, Positional, Span };
+
+/// A source code file.
+#[derive(Clone, Debug)]
+pub struct SourceFile { /// The name of the module defined by this file.
+                        pub name: String
+                      , pub path: PathBuf
+                      , pub lines: Rc<LineTable>
+                      }
+
+impl SourceFile {
+    /// Returns the source code of this file.
+    #[inline] pub fn source(&self) -> &str { self.lines.source() }
+}
+
+/// Owns the source code of every file in a program.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap { files: Vec<SourceFile> }
+
+impl SourceMap {
+
+    pub fn new() -> Self { SourceMap { files: vec![] } }
+
+    /// Add a file to the source map, returning its `FileId`.
+    pub fn add<P>(&mut self, name: String, path: P, source: &str) -> FileId
+    where P: AsRef<Path> {
+        let file = FileId(self.files.len());
+        let lines = Rc::new(LineTable::new(source));
+        self.files.push(SourceFile { name: name
+                                   , path: path.as_ref().to_path_buf()
+                                   , lines: lines
+                                   });
+        file
+    }
+
+    /// Returns the file with the given `FileId`.
+    ///
+    /// # Panics
+    /// If the `FileId` was not issued by this source map.
+    #[inline]
+    pub fn get(&self, file: FileId) -> &SourceFile { &self.files[file.0] }
+
+    /// Returns the `FileId` of the file at `path`, if it has been added.
+    pub fn find<P>(&self, path: P) -> Option<FileId>
+    where P: AsRef<Path> {
+        self.files.iter()
+            .position(|f| f.path == path.as_ref())
+            .map(FileId)
+    }
+
+    /// Returns the number of files in the source map.
+    #[inline] pub fn len(&self) -> usize { self.files.len() }
+
+    #[inline] pub fn is_empty(&self) -> bool { self.files.is_empty() }
+
+    /// Returns the `FileId`s of every file in the source map.
+    pub fn ids(&self) -> Vec<FileId> {
+        (0..self.files.len()).map(FileId).collect()
+    }
+
+    /// Returns the `Position` of the start of a `Span`.
+    pub fn position(&self, span: Span) -> Position {
+        self.get(span.file).lines.position(span.start)
+    }
+
+    /// Format a positional value with the path, line, and column of its
+    /// span, followed by the underlined source code.
+    pub fn describe<T>(&self, value: &Positional<T>) -> String
+    where T: fmt::Display {
+        format!( "{}: {}"
+               , self.get(value.pos.file).path.display()
+               , value.describe(&self.get(value.pos.file).lines) )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use position::{ FileId, Position, Positional, Span };
+
+    #[test]
+    fn test_add_and_find() {
+        let mut map = SourceMap::new();
+        let a = map.add(String::from("a"), "src/a.mn", "(a)");
+        let b = map.add(String::from("b"), "src/b.mn", "(b)\n(c)");
+        assert_eq!(a, FileId(0));
+        assert_eq!(b, FileId(1));
+        assert_eq!(map.find("src/b.mn"), Some(b));
+        assert_eq!(map.find("src/c.mn"), None);
+        assert_eq!(map.get(b).source(), "(b)\n(c)");
+        assert_eq!( map.position(Span::new(b, 4, 7)), Position::new(1, 2));
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut map = SourceMap::new();
+        map.add(String::from("a"), "a.mn", "(a)");
+        let b = map.add(String::from("b"), "b.mn", "(b)\n(c)");
+        let err = Positional::at(b, 5, 6, "oh no");
+        assert_eq!( map.describe(&err)
+                  , "b.mn: oh no at line 2, column 2\n(c)\n ^");
+    }
+}
@@ -0,0 +1,856 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Lossless concrete syntax tree
+//!
+//! The concrete syntax tree (CST) records every character of the source
+//! code: each token keeps its exact text, along with the whitespace and
+//! comments ("trivia") that precede it. Printing a `SourceFile` with
+//! `Display` reproduces the original source exactly, so tools such as
+//! formatters can rewrite a module without losing its comments.
+//!
+//! The CST only knows about delimiters and tokens, not about the forms of
+//! the language; `SourceFile::lower()` lowers each top-level form to the
+//! abstract syntax tree using the Mnemosyne parser.
+use std::{ cmp, fmt };
+use std::rc::Rc;
+
+use combine::primitives::{ State, SourcePosition };
+use combine::Parser;
+
+use core::errors::{ Errors, CompileResult };
+use core::position::{ FileId, LineTable, Position, Positional, Span };
+use core::semantic::annotations::UnscopedState;
+use core::semantic::ast::{ Expr, Ident, Module };
+
+use super::{ MnEnv, mn_env, positional_error };
+
+/// Whitespace or a comment.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trivia<'a> { Whitespace(&'a str)
+                    , /// A `#` comment, not including the newline that
+                      /// ends it.
+                      LineComment(&'a str)
+                    , /// A `#| ... |#` comment.
+                      BlockComment(&'a str)
+                    }
+
+impl<'a> Trivia<'a> {
+    pub fn text(&self) -> &'a str {
+        match *self { Trivia::Whitespace(s)
+                    | Trivia::LineComment(s)
+                    | Trivia::BlockComment(s) => s
+                    }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TokenKind { /// An opening `(`, `[` or `{`.
+                     Open(char)
+                   , /// A closing `)`, `]` or `}`.
+                     Close(char)
+                   , /// A reader prefix: `'`, `` ` ``, `,` or `,@`.
+                     Prefix
+                   , /// A string literal.
+                     Str
+                   , /// A character literal.
+                     Char
+                   , /// Any other token: a name, number, or operator.
+                     Atom
+                   , /// Source text that could not be tokenized, such as an
+                     /// unterminated string or comment.
+                     Error
+                   }
+
+/// A token, with the trivia preceding it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token<'a> { pub kind: TokenKind
+                     , /// The exact source text of the token.
+                       pub text: &'a str
+                     , /// The whitespace and comments before the token.
+                       pub leading: Vec<Trivia<'a>>
+                     , /// The byte offset of the token's text.
+                       pub offset: usize
+                     , /// The position of the token's text.
+                       pub pos: Position
+                     }
+
+impl<'a> Token<'a> {
+    /// The byte offset just past the end of the token's text.
+    #[inline] pub fn end(&self) -> usize { self.offset + self.text.len() }
+
+    /// Returns the span of the token's text in `file`.
+    #[inline] pub fn span(&self, file: FileId) -> Span {
+        Span::new(file, self.offset, self.end())
+    }
+}
+
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trivia in &self.leading { try!(write!(f, "{}", trivia.text())) }
+        write!(f, "{}", self.text)
+    }
+}
+
+/// A node in the concrete syntax tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cst<'a> { /// A single token.
+                   Atom(Token<'a>)
+                 , /// A delimited list. `close` is `None` if the list was
+                   /// never closed.
+                   List { open: Token<'a>
+                        , children: Vec<Cst<'a>>
+                        , close: Option<Token<'a>>
+                        }
+                 , /// A reader prefix applied to the following node.
+                   Prefixed { prefix: Token<'a>
+                            , node: Box<Cst<'a>>
+                            }
+                 , /// A closing delimiter with no matching opener.
+                   Unmatched(Token<'a>)
+                 }
+
+impl<'a> Cst<'a> {
+
+    /// Returns the first token in this node.
+    pub fn first_token(&self) -> &Token<'a> {
+        match *self { Cst::Atom(ref t)
+                    | Cst::Unmatched(ref t)        => t
+                    , Cst::List { ref open, .. }   => open
+                    , Cst::Prefixed { ref prefix, .. } => prefix
+                    }
+    }
+
+    /// Returns the byte offset just past the end of this node's text.
+    pub fn end(&self) -> usize {
+        match *self {
+            Cst::Atom(ref t) | Cst::Unmatched(ref t) => t.end()
+          , Cst::Prefixed { ref node, .. } => node.end()
+          , Cst::List { ref open, ref children, ref close } =>
+                match *close {
+                    Some(ref c) => c.end()
+                  , None => children.last()
+                                    .map(|c| c.end())
+                                    .unwrap_or(open.end())
+                }
+        }
+    }
+
+    /// Push the byte offsets just past the end of each token in this
+    /// node to `ends`, in source order.
+    fn token_ends(&self, ends: &mut Vec<usize>) {
+        match *self {
+            Cst::Atom(ref t) | Cst::Unmatched(ref t) => ends.push(t.end())
+          , Cst::Prefixed { ref prefix, ref node } => {
+                ends.push(prefix.end());
+                node.token_ends(ends);
+            }
+          , Cst::List { ref open, ref children, ref close } => {
+                ends.push(open.end());
+                for child in children { child.token_ends(ends) }
+                if let Some(ref c) = *close { ends.push(c.end()) }
+            }
+        }
+    }
+
+    /// Returns the span of this node's text in `file`, excluding its
+    /// leading trivia.
+    pub fn span(&self, file: FileId) -> Span {
+        Span::new(file, self.first_token().offset, self.end())
+    }
+
+    /// If this node is an `import` or `use` form, returns its keyword.
+    fn import_keyword(&self) -> Option<&Token<'a>> {
+        match *self {
+            Cst::List { ref children, .. } => match children.first() {
+                Some(&Cst::Atom(ref t)) if t.text == "import"
+                                        || t.text == "use" => Some(t)
+              , _ => None
+            }
+          , _ => None
+        }
+    }
+
+    /// If this node is an `(import a b ...)` or `(use a b ...)` form,
+    /// returns the names of the modules it imports.
+    pub fn imports(&self, file: FileId) -> Option<CompileResult<Vec<Ident>>> {
+        let keyword = match self.import_keyword() { Some(k) => k
+                                                  , None => return None };
+        if self.is_malformed() {
+            let mut errs = vec![];
+            self.errors(file, &mut errs);
+            return Some(Err(errs))
+        }
+        let names = match *self {
+            Cst::List { ref children, .. } => &children[1..]
+          , _ => unreachable!()
+        };
+        if names.is_empty() {
+            return Some(Err(vec![Positional::from( self.span(file)
+                                                 , format!(
+                "[error] `{}` must name at least one module"
+               , keyword.text))]))
+        }
+        Some(names_in(names, file, "a module", keyword.text))
+    }
+
+    /// If this node is a `(module ...)` form, returns its children
+    /// following the `module` keyword.
+    fn module_children(&self) -> Option<&[Cst<'a>]> {
+        match *self {
+            Cst::List { ref children, .. } => match children.first() {
+                Some(&Cst::Atom(ref t)) if t.text == "module" =>
+                    Some(&children[1..])
+              , _ => None
+            }
+          , _ => None
+        }
+    }
+
+    /// If this node is a `(module name (export a b ...) forms...)` form,
+    /// returns the module's header and the forms in its body.
+    ///
+    /// The `export` list is optional; a module without one exports no
+    /// names.
+    pub fn module(&self, file: FileId)
+                 -> Option<CompileResult<(ModuleHeader, &[Cst<'a>])>> {
+        let children = match self.module_children() { Some(c) => c
+                                                    , None => return None };
+        if self.is_malformed() {
+            let mut errs = vec![];
+            self.errors(file, &mut errs);
+            return Some(Err(errs))
+        }
+        let name = match children.first() {
+            Some(&Cst::Atom(ref t)) if t.kind == TokenKind::Atom =>
+                Positional::from(t.span(file), String::from(t.text))
+          , _ => return Some(Err(vec![Positional::from( self.span(file)
+                                                      , String::from(
+                "[error] expected a module name after `module`"))]))
+        };
+        let (exporting, body) = match children.get(1) {
+            Some(&Cst::List { children: ref export, .. })
+                if export.first().map_or(false, |c| c.is_atom("export")) =>
+                match names_in(&export[1..], file, "an exported", "export") {
+                    Ok(names) => (names, &children[2..])
+                  , Err(errs) => return Some(Err(errs))
+                }
+          , _ => (vec![], &children[1..])
+        };
+        Some(Ok((ModuleHeader { name: name, exporting: exporting }, body)))
+    }
+
+    /// Returns true if this node is the atom `text`.
+    fn is_atom(&self, text: &str) -> bool {
+        match *self { Cst::Atom(ref t) => t.text == text
+                    , _ => false
+                    }
+    }
+
+    /// Returns true if this node, or any node within it, is malformed.
+    pub fn is_malformed(&self) -> bool {
+        match *self {
+            Cst::Atom(ref t) => t.kind == TokenKind::Error
+          , Cst::Unmatched(_) => true
+          , Cst::Prefixed { ref node, .. } => node.is_malformed()
+          , Cst::List { ref open, ref children, ref close } =>
+                match *close {
+                    Some(ref c) if matching(open_char(open)) == close_char(c) =>
+                        children.iter().any(|c| c.is_malformed())
+                  , _ => true
+                }
+        }
+    }
+
+    /// Collect the structural errors in this node, such as unclosed or
+    /// mismatched delimiters.
+    fn errors(&self, file: FileId, errs: &mut Errors) {
+        match *self {
+            Cst::Atom(ref t) if t.kind == TokenKind::Error =>
+                errs.push(Positional::from(t.span(file), format!(
+                    "[error] syntax error: unterminated {}"
+                   , if t.text.starts_with("\"") { "string literal" }
+                     else { "block comment" })))
+          , Cst::Atom(_) => {}
+          , Cst::Unmatched(ref t) =>
+                errs.push(Positional::from(t.span(file), format!(
+                    "[error] syntax error: unmatched `{}`", t.text)))
+          , Cst::Prefixed { ref node, .. } => node.errors(file, errs)
+          , Cst::List { ref open, ref children, ref close } => {
+                for child in children { child.errors(file, errs) }
+                let expected = matching(open_char(open));
+                match *close {
+                    None => errs.push(Positional::from(open.span(file), format!(
+                        "[error] syntax error: unclosed `{}`", open.text)))
+                  , Some(ref c) if close_char(c) != expected =>
+                        errs.push(Positional::from(c.span(file), format!(
+                            "[error] syntax error: expected `{}` to close \
+                             `{}` at {}, found `{}`"
+                           , expected, open.text, open.pos, c.text)))
+                  , _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl<'a> fmt::Display for Cst<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cst::Atom(ref t) | Cst::Unmatched(ref t) => write!(f, "{}", t)
+          , Cst::Prefixed { ref prefix, ref node } =>
+                write!(f, "{}{}", prefix, node)
+          , Cst::List { ref open, ref children, ref close } => {
+                try!(write!(f, "{}", open));
+                for child in children { try!(write!(f, "{}", child)) }
+                match *close { Some(ref c) => write!(f, "{}", c)
+                             , None        => Ok(())
+                             }
+            }
+        }
+    }
+}
+
+fn open_char(t: &Token) -> char {
+    match t.kind { TokenKind::Open(c) => c
+                 , _ => ice!("list opened with a non-delimiter token {:?}", t)
+                 }
+}
+
+fn close_char(t: &Token) -> char {
+    match t.kind { TokenKind::Close(c) => c
+                 , _ => ice!("list closed with a non-delimiter token {:?}", t)
+                 }
+}
+
+/// Returns the closing delimiter matching an opening delimiter.
+fn matching(open: char) -> char {
+    match open { '(' => ')'
+               , '[' => ']'
+               , '{' => '}'
+               , c   => ice!("{:?} is not an opening delimiter", c)
+               }
+}
+
+/// Collect the names in the body of a `keyword` form, such as
+/// `(import a b)` or `(export a b)`.
+fn names_in<'a>( names: &[Cst<'a>], file: FileId
+               , what: &str, keyword: &str) -> CompileResult<Vec<Ident>> {
+    let mut idents = vec![];
+    let mut errs = vec![];
+    for name in names {
+        match *name {
+            Cst::Atom(ref t) if t.kind == TokenKind::Atom =>
+                idents.push(Positional::from( t.span(file)
+                                            , String::from(t.text)))
+          , ref other => errs.push(Positional::from( other.span(file)
+                                                   , format!(
+                "[error] expected {} name in `{}`, found `{}`"
+               , what, keyword, other.to_string().trim())))
+        }
+    }
+    if errs.is_empty() { Ok(idents) } else { Err(errs) }
+}
+
+/// The header of a module: its name, and the names it exports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleHeader { pub name: Ident
+                        , pub exporting: Vec<Ident>
+                        }
+
+/// Maps byte offsets in source code produced by a reader, such as the
+/// sweet-expression reader, back to the file that it was read from.
+#[derive(Clone, Debug)]
+pub struct Origins { /// The offset in the original file of each byte
+                     /// boundary in the produced source code.
+                     offsets: Vec<usize>
+                   , /// The line table of the original file.
+                     lines: LineTable
+                   }
+
+impl Origins {
+
+    /// Create a new `Origins` for source code read from `source`.
+    ///
+    /// `offsets` must contain an offset in `source` for each byte of the
+    /// produced source code, and one more for its end.
+    pub fn new(source: &str, offsets: Vec<usize>) -> Self {
+        Origins { offsets: offsets, lines: LineTable::new(source) }
+    }
+
+    /// Returns the offset in the original file of `offset`.
+    pub fn offset(&self, offset: usize) -> usize {
+        self.offsets[cmp::min(offset, self.offsets.len() - 1)]
+    }
+
+    /// Returns the span in the original file of `span`.
+    pub fn span(&self, span: Span) -> Span {
+        Span::new(span.file, self.offset(span.start), self.offset(span.end))
+    }
+
+    /// Returns the line table of the original file.
+    #[inline] pub fn lines(&self) -> &LineTable { &self.lines }
+}
+
+impl PartialEq for Origins {
+    fn eq(&self, other: &Self) -> bool {
+        self.offsets == other.offsets
+            && self.lines.source() == other.lines.source()
+    }
+}
+
+/// A concrete syntax tree for an entire source file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceFile<'a> { pub file: FileId
+                          , pub source: &'a str
+                          , /// The top-level forms in the file.
+                            pub forms: Vec<Cst<'a>>
+                          , /// Any trivia after the last form.
+                            pub trailing: Vec<Trivia<'a>>
+                          , /// If `source` was produced by a reader other
+                            /// than the S-expression reader, maps its
+                            /// offsets back to the file that was read.
+                            pub origins: Option<Rc<Origins>>
+                          }
+
+impl<'a> fmt::Display for SourceFile<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for form in &self.forms { try!(write!(f, "{}", form)) }
+        for trivia in &self.trailing { try!(write!(f, "{}", trivia.text())) }
+        Ok(())
+    }
+}
+
+impl<'a> SourceFile<'a> {
+
+    /// Lower the concrete syntax tree to the abstract syntax tree.
+    ///
+    /// Each well-formed top-level form is parsed to an `Expr`. Malformed
+    /// forms are skipped, so that every syntax error in the file is
+    /// reported without errors cascading from one form into the next.
+    /// `import` forms are only meaningful in a module, and are reported
+    /// as errors; use `lower_module()` to resolve them.
+    ///
+    /// # Returns
+    ///  + A vector of every top-level form that was lowered successfully,
+    ///    in source order.
+    ///  + The `Errors` encountered. If this is empty, the whole file was
+    ///    lowered successfully.
+    pub fn lower(&self) -> (Vec<Expr<'a, UnscopedState>>, Errors) {
+        let lines = Rc::new(LineTable::new(self.source));
+        let env = mn_env( self.file, lines.clone(), self.token_ends()
+                        , self.origins.clone());
+        let mut exprs = vec![];
+        let mut errs = vec![];
+
+        for form in &self.forms {
+            if form.import_keyword().is_some() {
+                errs.push(Positional::from( form.span(self.file)
+                                          , String::from(
+                    "[error] `import` is only allowed in a module file")))
+            } else if form.module_children().is_some() {
+                errs.push(Positional::from( form.span(self.file)
+                                          , String::from(
+                    "[error] `module` is only allowed in a module file")))
+            } else if let Some(expr) = self.lower_form(&env, &lines, form
+                                                      , &mut errs) {
+                exprs.push(expr)
+            }
+        }
+        (exprs, self.restore(errs))
+    }
+
+    /// Returns the span in the file that was read of a span in `source`.
+    fn original(&self, span: Span) -> Span {
+        match self.origins { Some(ref origins) => origins.span(span)
+                           , None => span
+                           }
+    }
+
+    /// Move a name lexed from `source` to the file that was read.
+    fn original_name(&self, name: Ident) -> Ident {
+        Positional::from(self.original(name.pos), name.value)
+    }
+
+    /// Move `errors` from `source` to the file that was read.
+    fn restore(&self, mut errors: Errors) -> Errors {
+        for err in &mut errors { err.pos = self.original(err.pos) }
+        errors
+    }
+
+    /// Returns the forms in the body of the file's module.
+    ///
+    /// If the file consists of a `(module ...)` form, these are the forms
+    /// within it; otherwise, they are the file's top-level forms.
+    pub fn body_forms(&self) -> &[Cst<'a>] {
+        match self.forms.first().and_then(|form| form.module(self.file)) {
+            Some(Ok((_, body))) => body
+          , _ => &self.forms
+        }
+    }
+
+    /// Returns the names of the modules imported by the well-formed
+    /// `import` forms in the body of the file's module.
+    pub fn imports(&self) -> Vec<Ident> {
+        self.body_forms()
+            .iter()
+            .filter_map(|form| form.imports(self.file))
+            // malformed imports are reported by `lower_module()`
+            .filter_map(Result::ok)
+            .flat_map(|names| names.into_iter())
+            .map(|name| self.original_name(name))
+            .collect()
+    }
+
+    /// Lower the concrete syntax tree to a module named `name`.
+    ///
+    /// This is like `lower()`, but the names in the file's top-level
+    /// `import` forms are collected in the module's `imports`. If the
+    /// file consists of a `(module name (export a b ...) forms...)` form,
+    /// the module takes its name and exports from the header, and every
+    /// exported name must be defined in the module. A file without a
+    /// module header exports every name that it defines, other than its
+    /// macros.
+    pub fn lower_module(&self, name: Ident)
+                        -> (Module<'a, UnscopedState>, Errors) {
+        let lines = Rc::new(LineTable::new(self.source));
+        let env = mn_env( self.file, lines.clone(), self.token_ends()
+                        , self.origins.clone());
+        let mut module = Module { name: name
+                                , imports: vec![]
+                                , exporting: vec![]
+                                , body: vec![]
+                                };
+        let mut errs = vec![];
+
+        let mut forms = &self.forms[..];
+        let header = self.forms.first().and_then(|form| form.module(self.file));
+        let has_header = header.is_some();
+        if let Some(header) = header {
+            match header {
+                Ok((header, body)) => {
+                    module.name = self.original_name(header.name);
+                    module.exporting = header.exporting
+                                             .into_iter()
+                                             .map(|n| self.original_name(n))
+                                             .collect();
+                    forms = body;
+                }
+              , Err(mut e) => { errs.append(&mut e); forms = &[] }
+            }
+            for form in &self.forms[1..] {
+                errs.push(Positional::from( form.span(self.file)
+                                          , String::from(
+                    "[error] a module file may not have forms outside of \
+                     its `module` form")))
+            }
+        }
+
+        for form in forms {
+            if form.module_children().is_some() {
+                errs.push(Positional::from( form.span(self.file)
+                                          , String::from(
+                    "[error] a `module` form must be the only form in its \
+                     file")));
+                continue
+            }
+            match form.imports(self.file) {
+                Some(Ok(names)) =>
+                    module.imports.extend(names.into_iter()
+                                               .map(|n| self.original_name(n)))
+              , Some(Err(mut e)) => errs.append(&mut e)
+              , None => if let Some(expr) = self.lower_form( &env, &lines, form
+                                                           , &mut errs) {
+                    module.body.push(expr)
+                }
+            }
+        }
+        if !has_header {
+            let exporting: Vec<Ident> = {
+                let macros = module.macros();
+                module.definitions()
+                      .into_iter()
+                      .filter(|name| !macros.contains(name))
+                      .cloned()
+                      .collect()
+            };
+            module.exporting = exporting;
+        }
+        let mut errs = self.restore(errs);
+        errs.extend(module.check_exports());
+        (module, errs)
+    }
+
+    /// Lower a single top-level form, recording any errors in `errs`.
+    fn lower_form( &self, env: &MnEnv<'a, &'a str>, lines: &LineTable
+                 , form: &Cst<'a>, errs: &mut Errors)
+                 -> Option<Expr<'a, UnscopedState>> {
+        if form.is_malformed() {
+            form.errors(self.file, errs);
+            return None
+        }
+        let first = form.first_token();
+        let text = &self.source[first.offset..form.end()];
+        let state = State { position: SourcePosition { column: first.pos.col
+                                                     , line: first.pos.row
+                                                     }
+                          , input: text
+                          };
+        match env.top_level().parse_state(state) {
+            Ok((expr, rest)) => {
+                let rest = rest.into_inner();
+                if rest.input.is_empty() {
+                    return Some(expr)
+                }
+                let offset = lines.offset(Position::from(rest.position));
+                errs.push(Positional::at(
+                    self.file, offset, form.end()
+                  , format!( "[error] syntax error: unexpected `{}`"
+                           , rest.input)))
+            }
+          , Err(err) => errs.push(positional_error( err.into_inner()
+                                               , self.file, lines))
+        }
+        None
+    }
+
+    /// Returns the byte offsets just past the end of each token in the
+    /// file, in ascending order.
+    fn token_ends(&self) -> Vec<usize> {
+        let mut ends = vec![];
+        for form in &self.forms { form.token_ends(&mut ends) }
+        ends
+    }
+}
+
+/// A hand-written lexer that tracks positions and trivia.
+struct Lexer<'a> { source: &'a str
+                 , offset: usize
+                 , pos: Position
+                 }
+
+impl<'a> Lexer<'a> {
+
+    #[inline] fn rest(&self) -> &'a str { &self.source[self.offset..] }
+
+    #[inline] fn peek(&self) -> Option<char> { self.rest().chars().next() }
+
+    /// Advance past the next character.
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.offset += c.len_utf8();
+            if c == '\n' { self.pos = Position::new(1, self.pos.row + 1) }
+            else { self.pos = Position::new(self.pos.col + 1, self.pos.row) }
+        }
+    }
+
+    /// Advance while `pred` holds for the next character.
+    fn bump_while<F>(&mut self, pred: F) where F: Fn(char) -> bool {
+        while self.peek().map_or(false, |c| pred(c)) { self.bump() }
+    }
+
+    /// Advance past `n` characters.
+    fn bump_n(&mut self, n: usize) { for _ in 0..n { self.bump() } }
+
+    fn trivia(&mut self) -> Vec<Trivia<'a>> {
+        let mut trivia = vec![];
+        loop {
+            let start = self.offset;
+            let start_pos = self.pos;
+            let rest = self.rest();
+            if rest.starts_with("#|") {
+                self.bump_n(2);
+                while !self.rest().is_empty() && !self.rest().starts_with("|#") {
+                    self.bump()
+                }
+                if self.rest().is_empty() {
+                    // unterminated block comments are lexed as error tokens
+                    self.offset = start;
+                    self.pos = start_pos;
+                    return trivia
+                }
+                self.bump_n(2);
+                trivia.push(Trivia::BlockComment(&self.source[start..self.offset]));
+            } else if rest.starts_with("#") && !rest.starts_with("#\\") {
+                self.bump_while(|c| c != '\n');
+                trivia.push(Trivia::LineComment(&self.source[start..self.offset]));
+            } else if self.peek().map_or(false, |c| c.is_whitespace()) {
+                self.bump_while(|c| c.is_whitespace());
+                trivia.push(Trivia::Whitespace(&self.source[start..self.offset]));
+            } else {
+                return trivia
+            }
+        }
+    }
+
+    /// Lex the next token.
+    ///
+    /// At the end of the input, any trailing trivia is returned as `Err`.
+    fn next_token(&mut self) -> Result<Token<'a>, Vec<Trivia<'a>>> {
+        let leading = self.trivia();
+        let start = self.offset;
+        let pos = self.pos;
+        let rest = self.rest();
+        let kind = match self.peek() {
+            None => return Err(leading)
+          , Some(c) if c == '(' || c == '[' || c == '{' => {
+                self.bump(); TokenKind::Open(c)
+            }
+          , Some(c) if c == ')' || c == ']' || c == '}' => {
+                self.bump(); TokenKind::Close(c)
+            }
+          , Some('"') => {
+                self.bump();
+                let mut kind = TokenKind::Error;
+                while let Some(c) = self.peek() {
+                    if c == '\\' { self.bump_n(2) }
+                    else if c == '"' { self.bump()
+                                     ; kind = TokenKind::Str
+                                     ; break
+                                     }
+                    else { self.bump() }
+                }
+                kind
+            }
+          , Some('#') if rest.starts_with("#|") => {
+                // an unterminated block comment
+                self.bump_n(rest.chars().count());
+                TokenKind::Error
+            }
+          , Some('#') => {
+                // `#\c`, `#\space`, and friends
+                self.bump_n(3);
+                self.bump_while(|c| c.is_alphanumeric());
+                TokenKind::Char
+            }
+          , Some('\'') if is_quoted_char(rest) => {
+                self.bump();
+                if self.peek() == Some('\\') { self.bump() }
+                self.bump_n(2);
+                TokenKind::Char
+            }
+          , Some(',') if rest.starts_with(",@") => {
+                self.bump_n(2); TokenKind::Prefix
+            }
+          , Some('\'') | Some('`') | Some(',') => {
+                self.bump(); TokenKind::Prefix
+            }
+          , Some(_) => {
+                self.bump_while(|c| !is_delimiter(c));
+                TokenKind::Atom
+            }
+        };
+        Ok(Token { kind: kind
+                 , text: &self.source[start..self.offset]
+                 , leading: leading
+                 , offset: start
+                 , pos: pos
+                 })
+    }
+}
+
+/// Returns true if `c` ends an atom.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()[]{}\"#,`".contains(c)
+}
+
+/// Returns true if `rest` begins with a single-quoted character literal,
+/// such as `'a'` or `'\n'`, rather than a quote prefix.
+fn is_quoted_char(rest: &str) -> bool {
+    let mut chars = rest.chars().skip(1);
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('\\'), Some(_), Some('\'')) => true
+      , (Some(c), Some('\''), _) => c != '\''
+      , _ => false
+    }
+}
+
+/// Builds the tree from a stream of tokens.
+struct TreeBuilder<'a> { lexer: Lexer<'a>
+                       , /// A token that has been lexed but not yet consumed.
+                         peeked: Option<Result<Token<'a>, Vec<Trivia<'a>>>>
+                       }
+
+impl<'a> TreeBuilder<'a> {
+
+    fn peek(&mut self) -> &Result<Token<'a>, Vec<Trivia<'a>>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lexer.next_token());
+        }
+        self.peeked.as_ref().unwrap()
+    }
+
+    fn next(&mut self) -> Result<Token<'a>, Vec<Trivia<'a>>> {
+        self.peek();
+        self.peeked.take().unwrap()
+    }
+
+    /// Build the node beginning with `token`.
+    fn node(&mut self, token: Token<'a>) -> Cst<'a> {
+        match token.kind {
+            TokenKind::Open(_) => {
+                let mut children = vec![];
+                loop {
+                    let is_close = match *self.peek() {
+                        Ok(ref t) => match t.kind { TokenKind::Close(_) => true
+                                                  , _ => false }
+                      , Err(_) => break
+                    };
+                    let next = self.next().ok().unwrap();
+                    if is_close {
+                        return Cst::List { open: token
+                                         , children: children
+                                         , close: Some(next) }
+                    }
+                    children.push(self.node(next));
+                }
+                Cst::List { open: token, children: children, close: None }
+            }
+          , TokenKind::Prefix => {
+                let is_node = match *self.peek() {
+                    Ok(ref t) => match t.kind { TokenKind::Close(_) => false
+                                              , _ => true }
+                  , Err(_) => false
+                };
+                if is_node {
+                    let next = self.next().ok().unwrap();
+                    Cst::Prefixed { prefix: token, node: Box::new(self.node(next)) }
+                } else {
+                    // a prefix with nothing to apply it to
+                    Cst::Unmatched(token)
+                }
+            }
+          , TokenKind::Close(_) => Cst::Unmatched(token)
+          , _ => Cst::Atom(token)
+        }
+    }
+}
+
+/// Parse the source code of `file` into a lossless concrete syntax tree.
+///
+/// This never fails: malformed source, such as unbalanced delimiters,
+/// is represented in the tree and reported by `SourceFile::lower()`.
+pub fn parse<'a>(file: FileId, source: &'a str) -> SourceFile<'a> {
+    let mut builder = TreeBuilder {
+        lexer: Lexer { source: source, offset: 0, pos: Position::new(1, 1) }
+      , peeked: None
+    };
+    let mut forms = vec![];
+    loop {
+        match builder.next() {
+            Ok(token) => forms.push(builder.node(token))
+          , Err(trailing) => return SourceFile { file: file
+                                               , source: source
+                                               , forms: forms
+                                               , trailing: trailing
+                                               , origins: None
+                                               }
+        }
+    }
+}
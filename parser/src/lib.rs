@@ -11,7 +11,8 @@
 
 extern crate combine;
 extern crate combine_language;
-extern crate mnemosyne as core;
+extern crate typed_arena;
+#[macro_use] extern crate mnemosyne as core;
 
 use combine::*;
 use combine_language::{ LanguageEnv
@@ -21,6 +22,9 @@ use combine_language::{ LanguageEnv
 use combine::primitives::{ Stream
                          , Positioner
                          , SourcePosition
+                         , Consumed
+                         , Error
+                         , Info
                          };
 use core::chars;
 use core::semantic::*;
@@ -31,15 +35,21 @@ use core::semantic::annotations::{ Annotated
 use core::semantic::types::*;
 use core::semantic::ast::*;
 use core::position::*;
+use core::errors::{ Errors
+                  , CompileResult
+                  };
 
+use std::cmp;
 use std::rc::Rc;
-use std::collections::HashMap;
 use std::hash::Hash;
 
 type ParseFn<'a, I, T> = fn (&MnEnv<'a, I>, State<I>) -> ParseResult<T, I>;
 
 type U = UnscopedState;
 
+pub mod cst;
+pub mod loader;
+pub mod sweet;
 mod tests;
 
 /// Wraps a parsing function with a language definition environment.
@@ -89,6 +99,17 @@ where I: Stream<Item = char>
     , I::Item: Positioner<Position = SourcePosition>
     , I: 'a {
     env: LanguageEnv<'a, I>
+  , /// The file being parsed.
+    file: FileId
+  , /// The line table of the file being parsed, used to find the byte
+    /// offsets of positions.
+    lines: Rc<LineTable>
+  , /// The byte offsets just past the end of each token in the file, in
+    /// ascending order.
+    token_ends: Vec<usize>
+  , /// If the source code being parsed was produced by another reader,
+    /// maps its offsets back to the file that was read.
+    origins: Option<Rc<cst::Origins>>
 }
 
 impl <'a, I> std::ops::Deref for MnEnv<'a, I>
@@ -109,14 +130,63 @@ where I: Stream<Item=char>
         MnParser { env: self, parser: parser }
     }
 
+    /// Returns the span of the source code between two positions.
+    ///
+    /// Lexeme parsers consume the whitespace and comments following a
+    /// token, so the span ends at the end of the last token before `end`.
+    fn span(&self, start: SourcePosition, end: SourcePosition) -> Span {
+        let start = self.lines.offset(Position::from(start));
+        let end = self.lines.offset(Position::from(end));
+        let end = match self.token_ends.binary_search(&end) {
+            Ok(i) => self.token_ends[i]
+          , Err(0) => start
+          , Err(i) => self.token_ends[i - 1]
+        };
+        let span = Span::new(self.file, start, cmp::max(start, end));
+        match self.origins { Some(ref origins) => origins.span(span)
+                           , None => span
+                           }
+    }
+
+    /// Returns the `Position` of the byte `offset` of a span.
+    fn position(&self, offset: usize) -> Position {
+        match self.origins { Some(ref origins) =>
+                                origins.lines().position(offset)
+                           , None => self.lines.position(offset)
+                           }
+    }
+
+    /// Run `parser` on `input`, and pair its output with the span of
+    /// source code that it consumed.
+    fn parse_spanned<P>(&self, mut parser: P, input: State<I>)
+                        -> ParseResult<(P::Output, Span), I>
+    where P: Parser<Input = I> {
+        let start = input.position.clone();
+        parser.parse_state(input)
+              .map(|(output, rest)| {
+                  let span = match rest {
+                      Consumed::Consumed(ref state)
+                    | Consumed::Empty(ref state) =>
+                          self.span(start, state.position.clone())
+                  };
+                  ((output, span), rest)
+              })
+    }
+
+    /// Run `parser` on `input`, and annotate its output with the span of
+    /// source code that it consumed.
+    fn parse_annotated<P>(&self, parser: P, input: State<I>)
+                          -> ParseResult<Unscoped<'a, P::Output>, I>
+    where P: Parser<Input = I>
+        , P::Output: Node {
+        self.parse_spanned(parser, input)
+            .map(|((node, span), rest)| (Annotated::new(node, span), rest))
+    }
+
     #[allow(dead_code)]
     fn parse_def(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
         let function_form
-            = self.name()
-                  .and(self.annotated_fn())
-                  .map(|(name, fun)| DefForm::Function { name: name
-                                                       , fun: fun
-                                                       });
+            = self.parser(MnEnv::parse_fn_def_body);
 
         let top_level
             = self.name()
@@ -128,40 +198,285 @@ where I: Stream<Item=char>
                                       , value: Rc::new(body) });
 
         self.reserved("def").or(self.reserved("define"))
-            .with(function_form.or(top_level))
-            .map(Form::Define)
+            .with(try(self.data().map(Form::Data))
+                    .or(try(function_form).or(top_level)
+                                          .map(Form::Define)))
+            .parse_state(input)
+    }
+
+    /// Parses the name and function of a function definition.
+    fn parse_fn_def_body(&self, input: State<I>)
+                        -> ParseResult<DefForm<'a, U>, I> {
+        self.name()
+            .and(self.annotated_fn())
+            .map(|(name, fun)| DefForm::Function { name: name
+                                                 , fun: fun
+                                                 })
+            .parse_state(input)
+    }
+
+    /// Parses a function definition, such as those in an `instance`.
+    fn parse_fn_def(&self, input: State<I>)
+                   -> ParseResult<DefForm<'a, U>, I> {
+        self.parens(self.reserved("def")
+                        .or(self.reserved("define"))
+                        .with(self.parser(MnEnv::parse_fn_def_body)))
+            .parse_state(input)
+    }
+
+    pub fn fn_def(&'b self) -> MnParser<'a, 'b, I, DefForm<'a, U>> {
+        self.parser(MnEnv::parse_fn_def)
+    }
+
+    /// Parses a typeclass declaration.
+    ///
+    /// `(class Eq a (== (-> a a bool)) (/= {a -> a -> bool}))`
+    fn parse_class(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        self.reserved("class")
+            .or(self.reserved("typeclass"))
+            .with(self.name())
+            .and(self.name())
+            .and(many(self.prototype()))
+            .map(|((name, ty_param), defs)|
+                Form::Class(Class { name: name
+                                  , ty_param: ty_param
+                                  , defs: defs
+                                  }))
+            .parse_state(input)
+    }
+
+    pub fn class(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_class)
+    }
+
+    fn parse_prototype(&self, input: State<I>) -> ParseResult<Prototype, I> {
+        self.parens(self.name()
+                        .and(self.signature()))
+            .map(|(name, sig)| Prototype { name: name, sig: sig })
             .parse_state(input)
     }
 
+    pub fn prototype(&'b self) -> MnParser<'a, 'b, I, Prototype> {
+        self.parser(MnEnv::parse_prototype)
+    }
+
+    /// Parses an instance of a typeclass.
+    ///
+    /// `(instance Eq int (def == (fn (-> int int bool) ((a b) (prim_eq a b)))))`
+    fn parse_instance(&self, input: State<I>)
+                     -> ParseResult<Form<'a, U>, I> {
+        self.reserved("instance")
+            .or(self.reserved("impl"))
+            .with(self.name())
+            .and(self.type_name())
+            .and(many(self.fn_def()))
+            .map(|((class, ty), functions)|
+                Form::Instance(Instance { class: class
+                                        , ty: ty
+                                        , functions: functions
+                                        }))
+            .parse_state(input)
+    }
+
+    pub fn instance(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_instance)
+    }
+
     pub fn data(&'b self) -> MnParser<'a, 'b, I, Data<'a, U>> {
         self.parser(MnEnv::parse_data)
     }
 
-    #[allow(dead_code)]
+    /// Parses the remainder of a `data` definition.
+    ///
+    /// This expects the name of the type being defined, followed by the
+    /// `data` keyword and either a sum type or a record body. Records are
+    /// stored as a single `Variant::Record` under the type's own name, so
+    /// that `Data::get_struct_fields()` can find them.
     fn parse_data(&self, input: State<I>) -> ParseResult<Data<'a, U>, I> {
         let sum = self.parser(MnEnv::parse_sum);
         let record = self.parser(MnEnv::parse_record);
 
-        self.reserved("data")
-            .with(sum.or(record))
-            .parse_state(input);
+        self.name()
+            .skip(self.reserved("data"))
+            .and(sum.map(Variant::Sum)
+                    .or(record))
+            .map(|(name, body)| {
+                let variants = match body {
+                    Variant::Sum(variants) => variants
+                  , record => vec![(name.clone(), record)]
+                };
+                Data { name: name
+                     , variants: variants }
+            })
+            .parse_state(input)
+    }
 
-        unimplemented!()
+    /// Parses a sum type body.
+    ///
+    /// Both the prefix form `(| A B C)` and the infix form `{ A | B | C }`
+    /// are accepted.
+    fn parse_sum(&self, input: State<I>) -> ParseResult<Variants<'a, U>, I>
+    {
+        let prefix
+            = self.parens(self.reserved_op("|")
+                              .with(many1::<Vec<_>, _>(self.variant())));
+        let infix
+            = self.braces(sep_by1::<Vec<_>, _, _>( self.variant()
+                                                 , self.reserved_op("|")));
+
+        prefix.or(infix)
+              .parse_state(input)
     }
 
-    fn parse_sum(&self, input: State<I>)
-                -> ParseResult<HashMap<Ident, Variant<'a, U>>, I>
+    /// Parses a single variant of a sum type.
+    ///
+    /// A variant may be a bare tagword (`Monday`), or a constructor applied
+    /// to a nested sum, a record, a constant, or a single type.
+    fn parse_variant(&self, input: State<I>)
+                    -> ParseResult<(Ident, Variant<'a, U>), I>
     {
-        self.reserved_op("|")
-            .parse_state(input);
+        let body = try(self.parser(MnEnv::parse_sum).map(Variant::Sum))
+            .or(try(self.parser(MnEnv::parse_record)))
+            .or(try(self.literal().map(Variant::Constant)))
+            .or(self.type_name().map(Variant::Value));
 
-        unimplemented!()
+        self.parens(self.name().and(body))
+            .or(self.name().map(|tag| (tag.clone(), Variant::Tagword(tag))))
+            .parse_state(input)
     }
 
+    pub fn variant(&'b self) -> MnParser<'a, 'b, I, (Ident, Variant<'a, U>)> {
+        self.parser(MnEnv::parse_variant)
+    }
+
+    /// Parses a record body: a quoted list of `(name type)` pairs.
+    ///
+    /// Each pair may optionally be introduced with the `:` operator, as in
+    /// `'((: day Weekday) (: month Month))`.
     fn parse_record(&self, input: State<I>)
-                   -> ParseResult<HashMap<Ident, Variant<'a, U>>, I>
+                   -> ParseResult<Variant<'a, U>, I>
     {
-        unimplemented!()
+        self.lex(char('\''))
+            .with(self.parens(many(self.field())))
+            .map(Variant::Record)
+            .parse_state(input)
+    }
+
+    fn parse_field(&self, input: State<I>)
+                  -> ParseResult<Unscoped<'a, Formal>, I> {
+        let field = self.parens(optional(try(self.symbol(":")))
+                                    .with(self.name())
+                                    .and(self.type_name()))
+                        .map(|(name, ty)| Formal { name: name
+                                                 , annot: ty });
+        self.parse_annotated(field, input)
+    }
+
+    pub fn field(&'b self) -> MnParser<'a, 'b, I, Unscoped<'a, Formal>> {
+        self.parser(MnEnv::parse_field)
+    }
+
+    /// Parses a sequence of expressions, `(begin a b c)` or `(do a b c)`.
+    fn parse_begin(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        self.reserved("begin")
+            .or(self.reserved("do"))
+            .with(many1(self.expr()))
+            .map(Form::Begin)
+            .parse_state(input)
+    }
+
+    pub fn begin(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_begin)
+    }
+
+    /// Parses an assignment, `(set! name value)`.
+    fn parse_set(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        self.reserved("set!")
+            .with(self.name())
+            .and(self.expr())
+            .map(|(name, value)| Form::Set(SetForm { name: name
+                                                   , value: Rc::new(value)
+                                                   }))
+            .parse_state(input)
+    }
+
+    pub fn set(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_set)
+    }
+
+    /// Parses a `match` expression, `(match value (pattern body...)...)`.
+    ///
+    /// `case` is a synonym for `match`.
+    fn parse_match(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        self.reserved("match")
+            .or(self.reserved("case"))
+            .with(self.expr())
+            .and(many1(self.arm()))
+            .map(|(scrutinee, arms)|
+                Form::Match(MatchForm { scrutinee: Rc::new(scrutinee)
+                                      , arms: arms
+                                      }))
+            .parse_state(input)
+    }
+
+    pub fn cond(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_cond)
+    }
+
+    pub fn match_form(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_match)
+    }
+
+    /// Parses an arm of a `match` expression: a pattern element followed
+    /// by a body.
+    fn parse_arm(&self, input: State<I>)
+                -> ParseResult<Unscoped<'a, Arm<'a, U>>, I> {
+        let arm = self.parens(self.pat_element()
+                                  .and(many1(self.expr())))
+                      .map(|(pattern, body)| Arm { pattern: pattern
+                                                 , body: body });
+        self.parse_annotated(arm, input)
+    }
+
+    pub fn arm(&'b self) -> MnParser<'a, 'b, I, Unscoped<'a, Arm<'a, U>>> {
+        self.parser(MnEnv::parse_arm)
+    }
+
+    /// Parses a `cond` expression, `(cond (test value)... (else value))`.
+    ///
+    /// `cond` is sugar for nested `if`s, so
+    /// `(cond (a x) (b y) (else z))` is `(if a x (if b y z))`. Each nested
+    /// `if` is positioned at its clause.
+    fn parse_cond(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        let clause = self.parser(MnEnv::parse_cond_clause);
+        let otherwise = self.parens(self.reserved("else").with(self.expr()));
+        self.reserved("cond")
+            .with(many1::<Vec<_>, _>(try(clause)))
+            .and(optional(otherwise))
+            .map(|(mut clauses, otherwise)| {
+                let rest = clauses.split_off(1);
+                let else_clause = rest.into_iter()
+                    .rev()
+                    .fold(otherwise.map(Rc::new), |else_clause, ((test, value), span)| {
+                        let form = Form::If { condition: Rc::new(test)
+                                            , if_clause: Rc::new(value)
+                                            , else_clause: else_clause
+                                            };
+                        Some(Rc::new(Annotated::new(form, span)))
+                    });
+                let ((test, value), _) = clauses.remove(0);
+                Form::If { condition: Rc::new(test)
+                         , if_clause: Rc::new(value)
+                         , else_clause: else_clause
+                         }
+            })
+            .parse_state(input)
+    }
+
+    /// Parses a clause of a `cond` expression, `(test value)`.
+    fn parse_cond_clause(&self, input: State<I>)
+                         -> ParseResult<((Expr<'a, U>, Expr<'a, U>), Span), I> {
+        self.parse_spanned(self.parens(self.expr().and(self.expr())), input)
     }
 
     #[allow(dead_code)]
@@ -178,12 +493,16 @@ where I: Stream<Item=char>
             .parse_state(input)
     }
 
-    #[allow(dead_code)]
+    /// Parses a lambda, `(lambda sig equation...)`, whose parentheses are
+    /// parsed by `parse_expr`.
     fn parse_lambda(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
         self.reserved("lambda")
             .or(self.reserved(chars::LAMBDA))
-            .with(self.function())
-            .map(Form::Lambda)
+            .with(self.signature())
+            .and(many1(self.equation()))
+            .map(|(sig, eqs)| Form::Lambda(Function { sig: sig
+                                                    , equations: eqs
+                                                    }))
             .parse_state(input)
     }
 
@@ -210,10 +529,7 @@ where I: Stream<Item=char>
     /// Needed for def functions but not lambdas
     fn parse_fn_pos(&self, input: State<I>)
                     -> ParseResult<Annotated<'a, Function<'a, U>, U>, I> {
-        let pos = input.position.clone();
-        self.function()
-            .map(|fun| Annotated::new(fun, Position::from(pos)))
-            .parse_state(input)
+        self.parse_annotated(self.function(), input)
     }
 
     fn annotated_fn(&'b self) -> MnParser<'a, 'b, I
@@ -227,13 +543,17 @@ where I: Stream<Item=char>
         choice([ self.reserved("int")
                      .with(value(Primitive::IntSize))
                , self.reserved("uint")
-                     .with(value(Primitive::IntSize))
+                     .with(value(Primitive::UintSize))
                , self.reserved("float")
                      .with(value(Primitive::Float))
                , self.reserved("double")
                      .with(value(Primitive::Double))
                , self.reserved("bool")
                      .with(value(Primitive::Bool))
+               , self.reserved("char")
+                     .with(value(Primitive::Char))
+               , self.reserved("string")
+                     .with(value(Primitive::Str))
                , self.reserved("i8")
                      .with(value(Primitive::Int(Int::Int8)))
                , self.reserved("i16")
@@ -272,12 +592,66 @@ where I: Stream<Item=char>
                  .map(|t| Type::Ref(Reference::Borrowed(Rc::new(t))))
                  .parse_state(input)
     }
+    pub fn option_ty(&self, input: State<I>) -> ParseResult<Type, I> {
+        char('?').with(self.type_name())
+                 .map(|t| Type::Option(Rc::new(t)))
+                 .parse_state(input)
+    }
+
+    /// Parses a type name.
+    ///
+    /// Names beginning with an upper-case letter are named types, such as
+    /// `Weekday`, and names beginning with a lower-case letter are type
+    /// variables. Type inference reports those that no signature declares.
+    fn parse_named_ty(&self, input: State<I>) -> ParseResult<Type, I> {
+        self.name()
+            .and_then(|name| {
+                match name.value.chars().next() {
+                    Some(c) if c.is_uppercase() =>
+                        Ok(Type::Named(name.value, vec![]))
+                  , Some(c) if c.is_lowercase() => Ok(Type::Var(name.value))
+                  , _ => Err(Error::Message(Info::Owned(format!(
+                        "expected a type, found {}", *name))))
+                }
+            })
+            .parse_state(input)
+    }
+
+    /// Parses a named type applied to type parameters, as in `(List a)`.
+    ///
+    /// `(Option a)` is the same type as `?a`.
+    fn parse_applied_ty(&self, input: State<I>) -> ParseResult<Type, I> {
+        self.parens(self.name()
+                        .and(many1::<Vec<_>, _>(self.type_name())))
+            .and_then(|(name, mut params)| {
+                if !name.value.chars().next().map_or(false, |c| c.is_uppercase()) {
+                    Err(Error::Message(Info::Owned(format!(
+                        "expected a named type, found {}", *name))))
+                } else if *name == "Option" && params.len() == 1 {
+                    Ok(Type::Option(Rc::new(params.remove(0))))
+                } else {
+                    Ok(Type::Named(name.value, params))
+                }
+            })
+            .parse_state(input)
+    }
+
+    /// Parses a type.
+    ///
+    /// A type is a primitive, a pointer (`*i64`, `@i64` or `&i64`), an
+    /// option (`?i64`), a named type or type variable, a named type applied
+    /// to type parameters (`(List a)`), or a function type, written like a
+    /// function's signature (`(-> int int)` or `{int -> int}`).
     fn parse_type(&self, input: State<I>) -> ParseResult<Type, I> {
         choice([ self.parser(MnEnv::parse_primitive_ty)
                , self.parser(MnEnv::raw_ptr_ty)
                , self.parser(MnEnv::unique_ptr_ty)
                , self.parser(MnEnv::borrow_ptr_ty)
+               , self.parser(MnEnv::option_ty)
+               , self.parser(MnEnv::parse_named_ty)
                ])
+            .or(try(self.signature().map(Type::Function)))
+            .or(self.parser(MnEnv::parse_applied_ty))
             .parse_state(input)
     }
 
@@ -305,6 +679,118 @@ where I: Stream<Item=char>
             .parse_state(input)
     }
 
+    /// Parses a sigil operator applied to an expression: `(& e)`,
+    /// `(@ e)`, `(* e)`, `(? e)` or `(? e default)`.
+    ///
+    /// `(* e)` takes exactly one operand, so that `(* a b)` is still a
+    /// multiplication.
+    fn parse_sigil(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        let borrow = self.lex(char('&'))
+                         .with(self.expr())
+                         .map(|e| Sigil::Borrow(Rc::new(e)));
+        let unique = self.lex(char('@'))
+                         .with(self.expr())
+                         .map(|e| Sigil::Unique(Rc::new(e)));
+        let raw = self.reserved("*")
+                      .with(self.expr())
+                      .skip(not_followed_by(satisfy(|c| c != ')')))
+                      .map(|e| Sigil::Raw(Rc::new(e)));
+        let unwrap = self.lex(char('?'))
+                         .with(self.expr())
+                         .and(optional(self.expr()))
+                         .map(|(option, default)|
+                            Sigil::Unwrap { option: Rc::new(option)
+                                          , default: default.map(Rc::new)
+                                          });
+        borrow.or(unique)
+              .or(try(raw))
+              .or(unwrap)
+              .map(Form::Sigil)
+              .parse_state(input)
+    }
+
+    pub fn sigil(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_sigil)
+    }
+
+    /// Parses `?name`, which is sugar for `(? name)`.
+    fn parse_unwrap_sugar(&self, input: State<I>)
+                          -> ParseResult<Form<'a, U>, I> {
+        char('?').with(self.parser(MnEnv::parse_name_expr))
+                 .map(|name| Form::Sigil(Sigil::Unwrap { option: Rc::new(name)
+                                                       , default: None
+                                                       }))
+                 .parse_state(input)
+    }
+
+    pub fn unwrap_sugar(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_unwrap_sugar)
+    }
+
+    /// Parses a name as an expression.
+    fn parse_name_expr(&self, input: State<I>) -> ParseResult<Expr<'a, U>, I> {
+        let name = self.parser(MnEnv::parse_owned_name).map(Form::NameRef);
+        self.parse_annotated(name, input)
+    }
+
+    /// Parses a field access, `(: field record)`.
+    fn parse_field_access(&self, input: State<I>)
+                          -> ParseResult<Form<'a, U>, I> {
+        self.symbol(":")
+            .with(self.name())
+            .and(self.expr())
+            .map(|(field, record)|
+                Form::Field(FieldAccess { field: field
+                                        , record: Rc::new(record)
+                                        }))
+            .parse_state(input)
+    }
+
+    pub fn field_access(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_field_access)
+    }
+
+    /// Parses `record.field`, which is sugar for `(: field record)`.
+    ///
+    /// Accesses may be chained, so `a.b.c` is `(: c (: b a))`.
+    fn parse_field_sugar(&self, input: State<I>)
+                         -> ParseResult<Form<'a, U>, I> {
+        self.parser(MnEnv::parse_name_expr)
+            .and(many1::<Vec<_>, _>(try(char('.').with(self.name()))))
+            .map(|(record, fields)| {
+                fields.into_iter()
+                      .fold(record, |record, field| {
+                          let span = record.position.to(field.pos);
+                          let access = FieldAccess { field: field
+                                                   , record: Rc::new(record)
+                                                   };
+                          Annotated::new(Form::Field(access), span)
+                      })
+                      .node
+            })
+            .parse_state(input)
+    }
+
+    pub fn field_sugar(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_field_sugar)
+    }
+
+    /// Parses a functional record update, `(with record (field value)...)`.
+    fn parse_with(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        self.reserved("with")
+            .with(self.expr())
+            .and(many1(self.parens(self.name().and(self.expr()))))
+            .map(|(record, fields)|
+                Form::With(RecordUpdate { record: Rc::new(record)
+                                        , fields: fields
+                                        }))
+            .parse_state(input)
+    }
+
+    pub fn with_form(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_with)
+    }
+
     fn parse_name_ref(&self, input: State<I>)
                      -> ParseResult<Form<'a, U>, I> {
         choice([ self.parser(MnEnv::parse_name_deref)
@@ -364,7 +850,7 @@ where I: Stream<Item=char>
     fn parse_prefix_sig(&self, input: State<I>) -> ParseResult<Signature, I> {
         self.parens(self.reserved_op("->")
                         .or(self.reserved_op(chars::ARROW))
-                        .with(optional(many1(self.constraint())))
+                        .with(optional(many1(try(self.constraint()))))
                         .and(many1(self.type_name())) )
             .map(|(cs, glob)| Signature { constraints: cs
                                         , typechain: glob })
@@ -373,7 +859,7 @@ where I: Stream<Item=char>
 
     fn parse_infix_sig(&self, input: State<I>) -> ParseResult<Signature, I> {
         self.braces(optional(
-            many1(self.constraint()))
+            many1(try(self.constraint())))
                 .and(sep_by1::< Vec<Type>, _, _>(
                     self.lex(self.type_name())
                   , self.reserved_op("->")
@@ -415,105 +901,521 @@ where I: Stream<Item=char>
 
     fn parse_binding(&self, input: State<I>)
                     -> ParseResult<Unscoped<'a, Binding<'a, U>>, I> {
-        let pos = input.position.clone();
-        self.parser(MnEnv::parse_name)
-            .and(self.type_name())
-            .and(self.expr())
-            .map(|((name, typ), value)|
-                Annotated::new( Binding { name: name
+        let binding = optional(self.reserved("mut"))
+                          .and(self.parser(MnEnv::parse_name))
+                          .and(self.type_name())
+                          .and(self.expr())
+                          .map(|(((mutable, name), typ), value)|
+                                Binding { name: name
                                         , typ: typ
                                         , value: Rc::new(value)
-                                        }
-                               , Position::from(pos)
-                           ))
+                                        , mutable: mutable.is_some()
+                                        });
+        self.parse_annotated(binding, input)
+    }
+
+    /// Parses a logical connective, `and` or `or`, as a name.
+    fn parse_connective(&self, input: State<I>) -> ParseResult<Ident, I> {
+        let connective = self.reserved("and").or(self.reserved("or"));
+        self.parse_spanned(connective, input)
+            .map(|((name, span), rest)|
+                (Positional::from(span, String::from(name)), rest))
+    }
+
+    pub fn connective(&'b self) -> MnParser<'a, 'b, I, Ident> {
+        self.parser(MnEnv::parse_connective)
+    }
+
+    /// Parses a logical expression, such as `and a b`.
+    ///
+    /// More than two operands nest to the right, so `and a b c` is
+    /// `and a (and b c)`.
+    fn parse_logical(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        self.connective()
+            .and(self.expr())
+            .and(many1::<Vec<_>, _>(self.expr()))
+            .map(|((op, first), mut rest)| {
+                rest.insert(0, first);
+                Form::Logical(Logical::new(op.value == "and", rest))
+            })
             .parse_state(input)
     }
 
-    #[allow(dead_code)]
-    fn parse_logical(&self, input: State<I>)
-                    -> ParseResult<Logical<'a, U>, I> {
-        let and = self.reserved("and")
-                      .with(self.expr())
-                      .and(self.expr())
-                      .map(|(a, b)| Logical::And { a: Rc::new(a)
-                                                 , b: Rc::new(b)
-                                                 });
+    pub fn logical(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_logical)
+    }
 
-         let or = self.reserved("or")
-                      .with(self.expr())
-                      .and(self.expr())
-                      .map(|(a, b)| Logical::And { a: Rc::new(a)
-                                                 , b: Rc::new(b)
-                                                 });
+    pub fn literal(&'b self) -> MnParser<'a, 'b, I, Literal> {
+        self.parser(MnEnv::parse_literal)
+    }
+
+    fn parse_literal(&self, input: State<I>) -> ParseResult<Literal, I> {
+        let boolean = self.reserved("true").with(value(true))
+                          .or(self.reserved("false").with(value(false)))
+                          .map(Literal::BoolLit);
+
+        try(self.number())
+            .or(self.string_literal().map(Literal::StringLit))
+            .or(try(self.character()))
+            .or(boolean)
+            .parse_state(input)
+    }
+
+    pub fn number(&'b self) -> MnParser<'a, 'b, I, Literal> {
+        self.parser(MnEnv::parse_number)
+    }
 
-        and.or(or)
-           .parse_state(input)
+    /// Parses a numeric literal.
+    ///
+    /// Integers are a sequence of decimal digits, optionally followed by
+    /// a `u` suffix to make them unsigned (`42u`). Floating-point numbers
+    /// must contain a decimal point, an exponent, or both (`1.5`, `2e10`,
+    /// `6.02e-23`). A signed number may be negative, with a `-` directly
+    /// before its digits (`-1`); `- 1` is the subtraction operator.
+    fn parse_number(&self, input: State<I>) -> ParseResult<Literal, I> {
+        let fraction = char('.').with(many1::<String, _>(digit()));
+        let exponent = satisfy(|c| c == 'e' || c == 'E')
+                           .with(optional(char('-').or(char('+'))))
+                           .and(many1::<String, _>(digit()));
+
+        self.lex(optional(char('-'))
+                     .and(many1::<String, _>(digit()))
+                     .and(optional(fraction))
+                     .and(optional(exponent))
+                     .and(optional(char('u'))))
+            .and_then(|((((minus, int), frac), exp), unsigned)| {
+                let is_float = frac.is_some() || exp.is_some();
+                let mut text = String::new();
+                if let Some(minus) = minus { text.push(minus) }
+                text.push_str(&int);
+                if let Some(frac) = frac {
+                    text.push('.');
+                    text.push_str(&frac);
+                }
+                if let Some((sign, exp)) = exp {
+                    text.push('e');
+                    if let Some(sign) = sign { text.push(sign) }
+                    text.push_str(&exp);
+                }
+                let result = match (is_float, unsigned.is_some()) {
+                    (true, true) => Err(format!(
+                        "unsigned suffix on floating-point literal {}", text))
+                  , (true, false) => text.parse::<f64>()
+                                         .map(Literal::FloatConst)
+                                         .map_err(|_| format!(
+                                            "invalid floating-point literal {}"
+                                           , text))
+                  , (false, true) => text.parse::<u64>()
+                                         .map(Literal::UintConst)
+                                         .map_err(|_| format!(
+                                            "unsigned literal {}u is too large"
+                                           , text))
+                  , (false, false) => text.parse::<i64>()
+                                          .map(Literal::IntConst)
+                                          .map_err(|_| format!(
+                                            "integer literal {} is too large"
+                                           , text))
+                };
+                result.map_err(|msg| Error::Message(Info::Owned(msg)))
+            })
+            .parse_state(input)
     }
 
-    pub fn int_const(&'b self) -> MnParser<'a, 'b, I, Literal> {
-        self.parser(MnEnv::parse_int_const)
+    pub fn character(&'b self) -> MnParser<'a, 'b, I, Literal> {
+        self.parser(MnEnv::parse_character)
     }
 
-    #[allow(dead_code)]
-    fn parse_int_const(&self, input: State<I>) -> ParseResult<Literal, I> {
-        self.integer()
-            .map(Literal::IntConst)
+    /// Parses a character literal.
+    ///
+    /// Characters may be written Scheme-style, as in `#\a`, `#\space`,
+    /// `#\newline` and `#\tab`, or between single quotes, as in `'a'`.
+    fn parse_character(&self, input: State<I>) -> ParseResult<Literal, I> {
+        let named = try(string("space")).with(value(' '))
+                        .or(try(string("newline")).with(value('\n')))
+                        .or(try(string("tab")).with(value('\t')));
+        let scheme = self.lex(string("#\\").with(named.or(any())));
+
+        scheme.or(self.char_literal())
+              .map(Literal::CharLit)
+              .parse_state(input)
+    }
+
+    /// Parses a quoted or quasiquoted expression.
+    ///
+    /// `'d` is sugar for `(quote d)`, and `` `d `` is sugar for
+    /// `(quasiquote d)`.
+    fn parse_quote(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        self.lex(char('\''))
+            .with(self.datum())
+            .map(Form::Quote)
+            .or(self.lex(char('`'))
+                    .with(self.quasi_datum())
+                    .map(Form::Quasiquote))
+            .parse_state(input)
+    }
+
+    pub fn quote(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_quote)
+    }
+
+    /// Parses the body of a `(quote d)` or `(quasiquote d)` form.
+    fn parse_quote_form(&self, input: State<I>)
+                        -> ParseResult<Form<'a, U>, I> {
+        self.reserved("quote")
+            .with(self.datum())
+            .map(Form::Quote)
+            .or(self.reserved("quasiquote")
+                    .with(self.quasi_datum())
+                    .map(Form::Quasiquote))
+            .parse_state(input)
+    }
+
+    pub fn quote_form(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_quote_form)
+    }
+
+    /// Parses a macro definition, as in
+    /// `define-syntax name (syntax-rules (literals...) (pattern template)...)`.
+    ///
+    /// Each rule's pattern is a list of quoted data, whose first element
+    /// stands for the macro's name; its template is an expression.
+    fn parse_define_syntax(&self, input: State<I>)
+                           -> ParseResult<Form<'a, U>, I> {
+        let rule = self.parens(self.datum()
+                                   .and(self.expr()))
+                       .map(|(pattern, template)|
+                            Rule { pattern: pattern
+                                 , template: Rc::new(template)
+                                 });
+        let rules = self.parens(self.reserved("syntax-rules")
+                                    .with(self.parens(many(self.name())))
+                                    .and(many(rule)));
+        self.reserved("define-syntax")
+            .with(self.name())
+            .and(rules)
+            .map(|(name, (literals, rules))|
+                Form::Macro(Macro { name: name
+                                  , literals: literals
+                                  , rules: rules
+                                  }))
+            .parse_state(input)
+    }
+
+    pub fn define_syntax(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_define_syntax)
+    }
+
+    /// Reports an unquote outside of a quasiquote.
+    fn parse_stray_unquote(&self, input: State<I>)
+                           -> ParseResult<Form<'a, U>, I> {
+        self.lex(char(','))
+            .and_then(|_| Err::<Form<'a, U>, _>(Error::Message(Info::Owned(
+                String::from("unquote (`,`) may only appear within a \
+                              quasiquote")))))
+            .parse_state(input)
+    }
+
+    /// Parses a symbol within quoted data.
+    ///
+    /// Reserved words and operators may be quoted, so a symbol is any run
+    /// of characters that cannot end an atom.
+    fn parse_symbol(&self, input: State<I>) -> ParseResult<Ident, I> {
+        let symbol = satisfy(|c| is_symbol_char(c) && c != '\'')
+                         .and(many::<String, _>(satisfy(is_symbol_char)))
+                         .map(|(first, rest)| format!("{}{}", first, rest));
+        self.parse_spanned(self.lex(symbol), input)
+            .map(|((name, span), rest)| (Positional::from(span, name), rest))
+    }
+
+    pub fn symbol_datum(&'b self) -> MnParser<'a, 'b, I, Ident> {
+        self.parser(MnEnv::parse_symbol)
+    }
+
+    /// Parses a reader prefix within quoted data, returning the name of
+    /// the form it abbreviates.
+    fn parse_datum_prefix(&self, input: State<I>) -> ParseResult<Ident, I> {
+        let prefix = choice([ try(self.lex(string(",@")))
+                            , try(self.lex(string(",")))
+                            , try(self.lex(string("'")))
+                            , try(self.lex(string("`")))
+                            ]);
+        self.parse_spanned(prefix, input)
+            .map(|((prefix, span), rest)| {
+                let name = match prefix { ",@" => "unquote-splicing"
+                                        , ","  => "unquote"
+                                        , "'"  => "quote"
+                                        , _    => "quasiquote"
+                                        };
+                (Positional::from(span, String::from(name)), rest)
+            })
+    }
+
+    /// Parses quoted data.
+    ///
+    /// Reader prefixes within quoted data are read as the lists they
+    /// abbreviate, so `'(a ,b)` is the list `(a (unquote b))`.
+    fn parse_datum(&self, input: State<I>) -> ParseResult<Datum<'a, U>, I> {
+        let prefixed = self.parser(MnEnv::parse_datum_prefix)
+                           .and(self.datum())
+                           .map(|(name, datum)|
+                                Datum::List(vec![Datum::Symbol(name), datum]));
+        try(self.literal().map(Datum::Lit))
+            .or(try(self.symbol_datum().map(Datum::Symbol)))
+            .or(prefixed)
+            .or(self.parens(many(self.datum())).map(Datum::List))
+            .or(self.brackets(many(self.datum())).map(Datum::List))
             .parse_state(input)
     }
 
+    pub fn datum(&'b self) -> MnParser<'a, 'b, I, Datum<'a, U>> {
+        self.parser(MnEnv::parse_datum)
+    }
+
+    /// Parses quasiquoted data.
+    ///
+    /// This is like `parse_datum()`, except that `,e` and `(unquote e)`
+    /// unquote the expression `e`, and `,@e` and `(unquote-splicing e)`
+    /// splice it. A quasiquote nested within quasiquoted data is read as
+    /// plain data, so unquotes within it are not evaluated.
+    fn parse_quasi_datum(&self, input: State<I>)
+                         -> ParseResult<Datum<'a, U>, I> {
+        let splice = try(self.lex(string(",@")))
+                         .with(self.expr())
+                         .or(try(self.parens(self.reserved("unquote-splicing")
+                                                 .with(self.expr()))))
+                         .map(|e| Datum::UnquoteSplicing(Rc::new(e)));
+        let unquote = self.lex(char(','))
+                          .with(self.expr())
+                          .or(try(self.parens(self.reserved("unquote")
+                                                  .with(self.expr()))))
+                          .map(|e| Datum::Unquote(Rc::new(e)));
+        let quoted = self.parser(MnEnv::parse_datum_prefix)
+                         .and_then(|name|
+                             if *name == "quote" { Ok(name) }
+                             else { Err(Error::Message(Info::Owned(
+                                 String::from("expected a quoted datum")))) })
+                         .and(self.quasi_datum())
+                         .map(|(name, datum)|
+                              Datum::List(vec![Datum::Symbol(name), datum]));
+        // by now, the only prefix left is a nested quasiquote
+        let nested = self.parser(MnEnv::parse_datum_prefix)
+                         .and(self.datum())
+                         .map(|(name, datum)|
+                              Datum::List(vec![Datum::Symbol(name), datum]));
+        splice.or(unquote)
+              .or(try(quoted))
+              .or(nested)
+              .or(try(self.literal().map(Datum::Lit)))
+              .or(try(self.symbol_datum().map(Datum::Symbol)))
+              .or(self.parens(many(self.quasi_datum())).map(Datum::List))
+              .or(self.brackets(many(self.quasi_datum())).map(Datum::List))
+              .parse_state(input)
+    }
+
+    pub fn quasi_datum(&'b self) -> MnParser<'a, 'b, I, Datum<'a, U>> {
+        self.parser(MnEnv::parse_quasi_datum)
+    }
+
+    /// Parses a list of `let` bindings.
+    ///
+    /// The list may be delimited by either parentheses or brackets.
+    fn parse_bindings(&self, input: State<I>)
+                     -> ParseResult<Bindings<'a, U>, I> {
+        self.parens(many(self.parens(self.binding())))
+            .or(self.brackets(many(self.parens(self.binding()))))
+            .parse_state(input)
+    }
+
+    pub fn bindings(&'b self) -> MnParser<'a, 'b, I, Bindings<'a, U>> {
+        self.parser(MnEnv::parse_bindings)
+    }
+
     fn parse_let(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
 
         let binding_form =
             self.reserved("let")
-                .with(self.parens(many(self.parens(self.binding()))))
+                .with(self.bindings())
                 .and(many(self.expr()))
                 .map(|(bindings, body)| LetForm::Let { bindings: bindings
                                                      , body: body });
 
-        choice([ binding_form ])
+        let splat_form =
+            self.reserved("let*")
+                .with(self.bindings())
+                .and(many(self.expr()))
+                .map(|(bindings, body)| LetForm::LetSplat { bindings: bindings
+                                                          , body: body });
+
+        let rec_form =
+            self.reserved("letrec")
+                .with(self.bindings())
+                .and(many(self.expr()))
+                .map(|(bindings, body)| LetForm::LetRec { bindings: bindings
+                                                        , body: body });
+
+        // named let: `(let loop int ((i int 0)) body)`
+        let named_form =
+            self.reserved("let")
+                .with(self.name())
+                .and(self.type_name())
+                .and(self.parens(self.parens(self.binding()))
+                         .or(self.brackets(self.parens(self.binding()))))
+                .and(many(self.expr()))
+                .map(|(((proc_id, ret_ty), init), body)|
+                    LetForm::Invocation { proc_id: proc_id
+                                        , ret_ty: ret_ty
+                                        , init: init.node
+                                        , body: body });
+
+        try(splat_form)
+            .or(try(rec_form))
+            .or(try(named_form))
+            .or(binding_form)
             .map(Form::Let)
             .parse_state(input)
     }
 
+    /// Parses a name.
+    ///
+    /// An ellipsis, `...`, is also a name, so that it may follow a
+    /// repeated template in a macro definition.
     fn parse_name (&self, input: State<I>) -> ParseResult<Ident, I> {
-        let position = input.position.clone();
-        self.env.identifier::<'b>()
-            .map(|name| Positional { pos: Position::from(position)
-                                   , value: name })
-            .parse_state(input)
+        let ellipsis = self.lex(string("...")).map(String::from);
+        self.parse_spanned(self.env.identifier::<'b>().or(try(ellipsis)), input)
+            .map(|((name, span), rest)| (Positional::from(span, name), rest))
     }
 
     fn parse_call(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
         self.name()
             .and(many(self.expr()))
-            .map(|(name, args)| Form::App(AppForm{ fun: name, params: args }))
+            .map(|(name, args)| application(name, args))
             .parse_state(input)
     }
 
     fn parse_expr(&self, input: State<I>) -> ParseResult<Expr<'a, U>, I> {
-        let pos = Position::from(input.position.clone());
-        self.env.parens(choice([ try(self.call())
-                               , try(self.def())
-                               , try(self.if_form())
-                               , try(self.lambda())
-                               , try(self.let_form())
-                               ]))
-            .or(try(self.int_const()
-                        .map(Form::Lit)))
-            .or(try(self.name_ref()))
-            .map(|f| Annotated::new(f, pos) )
+        let form = self.env.parens(choice([ try(self.sigil())
+                                          , try(self.field_access())
+                                          , try(self.with_form())
+                                          , try(self.match_form())
+                                          , try(self.cond())
+                                          , try(self.begin())
+                                          , try(self.set())
+                                          , try(self.logical())
+                                          , try(self.call())
+                                          , try(self.def())
+                                          , try(self.if_form())
+                                          , try(self.lambda())
+                                          , try(self.let_form())
+                                          , try(self.quote_form())
+                                          ]))
+                       .or(try(self.infix()))
+                       .or(try(self.literal()
+                                   .map(Form::Lit)))
+                       .or(try(self.quote()))
+                       .or(try(self.unwrap_sugar()))
+                       .or(try(self.field_sugar()))
+                       .or(try(self.name_ref()))
+                       .or(self.parser(MnEnv::parse_stray_unquote));
+        self.parse_annotated(form, input)
+    }
+
+    /// Parses a curly-brace infix expression.
+    ///
+    /// `{a + b}` is sugar for `(+ a b)`. A chain of the same operator, such
+    /// as `{a + b + c}`, desugars to a single application, `(+ a b c)`.
+    /// The logical connectives may also be written infix, as `{a and b}`.
+    /// Mixing operators within one pair of braces is ambiguous and is
+    /// rejected; nest the braces to disambiguate (`{a + {b * c}}`).
+    fn parse_infix(&self, input: State<I>) -> ParseResult<Form<'a, U>, I> {
+        let op = try(self.name()).or(self.connective());
+        self.braces(self.expr()
+                        .and(many1::<Vec<_>, _>(op.and(self.expr()))))
+            .and_then(|(first, rest)| {
+                let op = rest[0].0.clone();
+                let mut params = vec![first];
+                for (other_op, operand) in rest {
+                    if other_op != op {
+                        return Err(Error::Message(Info::Owned(format!(
+                            "mixed infix operators `{}` and `{}` at {}; \
+                             use nested braces to group them"
+                           , *op, *other_op
+                           , self.position(other_op.pos.start)))))
+                    }
+                    params.push(operand);
+                }
+                Ok(application(op, params))
+            })
             .parse_state(input)
     }
 
+    pub fn infix(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
+        self.parser(MnEnv::parse_infix)
+    }
+
     fn parse_pattern(&self, input: State<I>) -> ParseResult<Pattern, I> {
-        let pat_elem =
-            self.name().map(PatElement::Name)
-                .or(self.int_const().map(PatElement::Lit));
+        self.parens(many(self.pat_element()))
+            .parse_state(input)
+    }
+
+    /// Parses a single element of a pattern.
+    ///
+    /// A typed name binding, `name: type`, may only be an element of a
+    /// parenthesized pattern, since `[x:xs]` is the cons pattern.
+    fn parse_pat_element(&self, input: State<I>)
+                        -> ParseResult<PatElement, I> {
+        let typed = self.name()
+                        .skip(self.lex(char(':')))
+                        .and(self.type_name())
+                        .map(|(name, ty)| PatElement::Typed { name: name
+                                                            , ty: ty });
+        try(typed)
+            .or(self.parser(MnEnv::parse_untyped_pat_element))
+            .parse_state(input)
+    }
 
-        self.parens(many(pat_elem))
+    /// Parses an element of a pattern other than a typed name binding.
+    fn parse_untyped_pat_element(&self, input: State<I>)
+                                -> ParseResult<PatElement, I> {
+        let element = || self.parser(MnEnv::parse_untyped_pat_element);
+
+        let wildcard = self.symbol("_")
+                           .map(|_| PatElement::Anything);
+
+        let deref = char('$').with(self.name())
+                             .map(PatElement::Deref);
+
+        let constructor
+            = self.parens(self.name()
+                              .and(many(self.pat_element())))
+                  .map(|(name, fields)|
+                        PatElement::Constructor { name: name
+                                                , fields: fields });
+
+        let cons
+            = self.brackets(element()
+                                .skip(self.lex(char(':')))
+                                .and(element()))
+                  .map(|(head, tail)|
+                        PatElement::Cons { head: Box::new(head)
+                                         , tail: Box::new(tail) });
+
+        let list = self.brackets(many(element()))
+                       .map(PatElement::List);
+
+        try(wildcard)
+            .or(try(deref))
+            .or(try(constructor))
+            .or(try(cons))
+            .or(try(list))
+            .or(try(self.literal().map(PatElement::Lit)))
+            .or(self.name().map(PatElement::Name))
             .parse_state(input)
     }
 
+    pub fn pat_element(&'b self) -> MnParser<'a, 'b, I, PatElement> {
+        self.parser(MnEnv::parse_pat_element)
+    }
+
     pub fn pattern(&'b self) -> MnParser<'a, 'b, I, Pattern> {
         self.parser(MnEnv::parse_pattern)
     }
@@ -523,13 +1425,11 @@ where I: Stream<Item=char>
                                               , Equation< 'a, U>
                                               , U>
                                     , I> {
-        let pos = Position::from(input.position.clone());
-        self.parens(self.pattern()
-                        .and(many(self.expr())))
-            .map(|(pat, body)| Annotated::new( Equation { pattern: pat
-                                                        , body: body }
-                                             , pos ))
-            .parse_state(input)
+        let equation = self.parens(self.pattern()
+                                       .and(many(self.expr())))
+                           .map(|(pat, body)| Equation { pattern: pat
+                                                       , body: body });
+        self.parse_annotated(equation, input)
     }
 
     pub fn equation(&'b self) -> MnParser< 'a, 'b, I
@@ -544,6 +1444,33 @@ where I: Stream<Item=char>
         self.parser(MnEnv::parse_expr)
     }
 
+    /// Parses a definition that may only appear at the top level of a
+    /// module, such as a class or instance declaration.
+    ///
+    /// Macros are expanded before scopes are resolved, so a macro
+    /// definition must be at the top level, where every form that may
+    /// call it can see it.
+    fn parse_top_level_def(&self, input: State<I>)
+                           -> ParseResult<Expr<'a, U>, I> {
+        let form = self.env.parens(choice([ try(self.class())
+                                          , try(self.instance())
+                                          , try(self.define_syntax())
+                                          ]));
+        self.parse_annotated(form, input)
+    }
+
+    /// Parses a top-level form: either a top-level definition, or any
+    /// other expression.
+    fn parse_top_level(&self, input: State<I>) -> ParseResult<Expr<'a, U>, I> {
+        try(self.parser(MnEnv::parse_top_level_def))
+            .or(self.expr())
+            .parse_state(input)
+    }
+
+    pub fn top_level(&'b self) -> MnParser<'a, 'b, I, Expr<'a, U>> {
+        self.parser(MnEnv::parse_top_level)
+    }
+
     pub fn def(&'b self) -> MnParser<'a, 'b, I, Form<'a, U>> {
         self.parser(MnEnv::parse_def)
     }
@@ -587,10 +1514,36 @@ where I: Stream<Item=char>
     }
 
 }
-pub fn parse_module<'a>(code: &'a str)
-                        -> Result< Vec<Expr<'a, UnscopedState>>
-                                 , ParseError<&'a str>>
- {
+/// Returns true if `c` may appear in a symbol within quoted data.
+fn is_symbol_char(c: char) -> bool {
+    !c.is_whitespace() && !"()[]{}\",`#".contains(c)
+}
+
+/// Returns the application of `fun` to `params`.
+///
+/// This is a logical expression if `fun` is a connective, an arithmetic
+/// expression if it is a built-in arithmetic or bitwise operator, a
+/// comparison if it is a comparison operator, and a function call otherwise.
+fn application<'a>(fun: Ident, params: Vec<Expr<'a, U>>) -> Form<'a, U> {
+    if (fun.value == "and" || fun.value == "or") && params.len() >= 2 {
+        return Form::Logical(Logical::new(fun.value == "and", params))
+    }
+    if let Some(num) = NumExpr::new(&fun, &params) {
+        return Form::Num(num)
+    }
+    match BoolBOp::new(&fun, &params) {
+        Some(cmp) => Form::Bool(cmp)
+      , None => Form::App(AppForm { fun: fun, params: params })
+    }
+}
+
+/// Construct the Mnemosyne language definition environment for parsing
+/// the source code of `file`, whose line table is `lines`.
+///
+/// If the source code was produced by a reader other than the
+/// S-expression reader, `origins` maps it back to the file that was read.
+fn mn_env<'a>( file: FileId, lines: Rc<LineTable>, token_ends: Vec<usize>
+             , origins: Option<Rc<cst::Origins>>) -> MnEnv<'a, &'a str> {
     let env = LanguageEnv::new(LanguageDef {
         ident: Identifier {
             start: letter().or(satisfy(move |c| chars::ALPHA_EXT.contains(c)))
@@ -608,15 +1561,21 @@ pub fn parse_module<'a>(code: &'a str)
                       , "quasiquote"        , "quote"       , "unquote"
                       , "set!"              , "unquote-splicing"
                       , "struct"            , "union"
+                      , "true"              , "false"
                       , "i8"                , "u8"
                       , "i16"               , "u16"
                       , "i32"               , "u32"         , "f32"
                       , "i64"               , "u64"         , "f64"
                       , "int"               , "uint"        , "float"
                       , "bool"              , "string"      , "double"
+                      , "char"
                       , "ref"               , "move"        , "borrow"
                       , "trait"             , "typeclass"
                       , "instance"          , "impl"
+                      , "import"            , "use"         , "match"
+                      , "mut"
+                      , "define-syntax"     , "syntax-rules"
+                      , "with"
                       ].iter().map(|x| (*x).into())
                        .collect()
         }
@@ -628,14 +1587,76 @@ pub fn parse_module<'a>(code: &'a str)
                       ].iter().map(|x| (*x).into())
                        .collect()
         }
-      , comment_line: string("#").map(|_| ())
+        // `#|` begins a block comment and `#\` begins a character literal,
+        // so neither of those should be consumed as a line comment.
+      , comment_line: try(char('#').skip(not_followed_by(
+                        satisfy(|c| c == '|' || c == '\\'))))
+                          .map(|_| ())
       , comment_start: string("#|").map(|_| ())
       , comment_end: string("|#").map(|_| ())
     });
-    let env = MnEnv { env: env };
+    MnEnv { env: env
+          , file: file
+          , lines: lines
+          , token_ends: token_ends
+          , origins: origins
+          }
+}
+
+/// Format a `combine` `ParseError` in `file` as a positional error message.
+fn positional_error<'a>(err: ParseError<&'a str>, file: FileId, lines: &LineTable)
+                        -> Positional<String> {
+    let msg = err.errors
+                 .iter()
+                 .map(|e| format!("{}", e))
+                 .collect::<Vec<String>>()
+                 .join(", ");
+    let offset = lines.offset(Position::from(err.position));
+    Positional::from( Span::new(file, offset, offset)
+                    , format!("[error] syntax error: {}", msg) )
+}
 
-    env.white_space()
-       .with(many1::<Vec<Expr<'a, U>>, _>(env.expr()))
-       .parse(code)
-       .map(|(e, _)| e)
+/// Parse the source code of `file`, recovering from syntax errors.
+///
+/// The module is first read into a concrete syntax tree (see `cst`), and
+/// each top-level form is then lowered separately. When a form fails to
+/// parse, the error is recorded and lowering continues with the next
+/// form. This means that a single run reports every syntax error in the
+/// module, rather than just the first one.
+///
+/// # Returns
+///  + A vector containing every top-level form that parsed successfully,
+///    in source order.
+///  + The `Errors` encountered while parsing. If this is empty, the
+///    module parsed successfully.
+pub fn parse_module_recovering<'a>(file: FileId, code: &'a str)
+                                   -> (Vec<Expr<'a, UnscopedState>>, Errors)
+{
+    cst::parse(file, code).lower()
+}
+
+/// Parse the source code of `file`.
+///
+/// # Returns
+///  + `Ok` containing the module's top-level forms, if the module
+///    parsed without errors.
+///  + `Err` containing every syntax error in the module, otherwise. Use
+///    `parse_module_recovering()` if the partial AST is also needed.
+pub fn parse_module<'a>(file: FileId, code: &'a str)
+                        -> CompileResult<Vec<Expr<'a, UnscopedState>>> {
+    let (forms, errors) = parse_module_recovering(file, code);
+    if errors.is_empty() { Ok(forms) } else { Err(errors) }
+}
+
+/// Parse the source code of `file` as the module `name`, recovering from
+/// syntax errors.
+///
+/// # Returns
+///  + The module, containing every top-level form that parsed
+///    successfully, and the names of the modules it imports.
+///  + The `Errors` encountered while parsing. If this is empty, the
+///    module parsed successfully.
+pub fn parse_file<'a>(file: FileId, name: Ident, code: &'a str)
+                      -> (Module<'a, UnscopedState>, Errors) {
+    cst::parse(file, code).lower_module(name)
 }
@@ -0,0 +1,253 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Module loading
+//!
+//! A program is made up of one or more root files, and every module that
+//! they import, directly or indirectly. The `Loader` resolves each
+//! `(import name)` form to a file named `name.mn`, looking first in the
+//! directory of the importing file and then in each directory on the
+//! search path. A module name may contain `/`s to name a file in a
+//! subdirectory, so `(import std/list)` loads `std/list.mn`. Modules
+//! written with sweet-expressions (see `sweet`) may be named `name.smn`
+//! instead, so both syntaxes can be used in one program.
+//!
+//! Each file is parsed to a concrete syntax tree once, when it is loaded,
+//! to find its imports; the trees borrow the source code from `Buffers`
+//! so that they can be lowered without parsing the file again.
+use std::fs::{ self, File };
+use std::io::{ self, Read };
+use std::path::{ Path, PathBuf };
+
+use typed_arena::Arena;
+
+use core::errors::{ Errors, ExpectICE };
+use core::position::{ FileId, Positional };
+use core::semantic::annotations::UnscopedState;
+use core::semantic::ast::{ Ident, Module };
+use core::source_map::SourceMap;
+
+use super::{ cst, sweet };
+
+/// The file extension of Mnemosyne source code files.
+pub const EXTENSION: &'static str = "mn";
+
+/// Owns the source code read by a `Loader`, which the concrete syntax
+/// trees of the loaded files borrow.
+pub struct Buffers { sources: Arena<String>
+                   , translations: Arena<sweet::Translation>
+                   }
+
+impl Buffers {
+    pub fn new() -> Self {
+        Buffers { sources: Arena::new()
+                , translations: Arena::new()
+                }
+    }
+}
+
+/// Loads the files making up a program into a `SourceMap`.
+pub struct Loader<'s> { search_path: Vec<PathBuf>
+                      , sources: SourceMap
+                      , buffers: &'s Buffers
+                      , /// The concrete syntax tree of each file, indexed by
+                        /// `FileId`.
+                        trees: Vec<cst::SourceFile<'s>>
+                      , /// The modules imported by each file, indexed by
+                        /// `FileId`.
+                        imports: Vec<Vec<(Ident, FileId)>>
+                      , /// The sweet-expression translation of each file
+                        /// that is written with sweet-expressions, indexed
+                        /// by `FileId`.
+                        translations: Vec<Option<&'s sweet::Translation>>
+                      , /// Errors encountered while resolving imports.
+                        errors: Errors
+                      }
+
+impl<'s> Loader<'s> {
+
+    /// Create a new `Loader` that resolves imports in the directories on
+    /// `search_path`, keeping the source code it reads in `buffers`.
+    pub fn new(buffers: &'s Buffers, search_path: Vec<PathBuf>) -> Self {
+        Loader { search_path: search_path
+               , sources: SourceMap::new()
+               , buffers: buffers
+               , trees: vec![]
+               , imports: vec![]
+               , translations: vec![]
+               , errors: vec![]
+               }
+    }
+
+    /// Returns the source map containing every file loaded so far.
+    #[inline] pub fn sources(&self) -> &SourceMap { &self.sources }
+
+    /// Load a root file of the program, and every module that it imports.
+    ///
+    /// # Returns
+    ///  + `Ok` containing the root file's `FileId`. Errors resolving the
+    ///    root's imports are reported by `modules()`.
+    ///  + `Err` if the root file could not be read.
+    pub fn load_root<P>(&mut self, path: P) -> io::Result<FileId>
+    where P: AsRef<Path> {
+        let path = path.as_ref();
+        let name = path.file_stem()
+                       .map(|stem| stem.to_string_lossy().into_owned())
+                       .unwrap_or_else(|| path.display().to_string());
+        self.load(path, name)
+    }
+
+    /// Load the file at `path` as the module `name`, unless it has been
+    /// loaded already, and then load its imports.
+    fn load(&mut self, path: &Path, name: String) -> io::Result<FileId> {
+        let path = try!(fs::canonicalize(path));
+        if let Some(file) = self.sources.find(&path) { return Ok(file) }
+
+        let mut source = String::new();
+        try!(File::open(&path)
+                 .and_then(|mut f| f.read_to_string(&mut source)));
+        let buffers = self.buffers;
+        let source: &'s str = buffers.sources.alloc(source);
+        let file = self.sources.add(name, &path, source);
+        self.imports.push(vec![]);
+
+        let tree = if sweet::is_sweet(&path, source) {
+            let translation: &'s sweet::Translation =
+                buffers.translations.alloc(sweet::read(file, source));
+            self.translations.push(Some(translation));
+            translation.parse()
+        } else {
+            self.translations.push(None);
+            cst::parse(file, source)
+        };
+        let names = tree.imports();
+        self.trees.push(tree);
+
+        let dir = path.parent().map(Path::to_path_buf);
+        for name in names {
+            let dep_path = match self.resolve(dir.as_ref(), &name) {
+                Some(dep_path) => dep_path
+              , None => {
+                    let msg = format!( "[error] could not find module `{}`"
+                                     , *name);
+                    self.errors.push(name.map(msg));
+                    continue
+                }
+            };
+            match self.load(&dep_path, name.value.clone()) {
+                Ok(dep) => self.imports[file.0].push((name, dep))
+              , Err(err) => {
+                    let msg = format!( "[error] could not read module `{}` \
+                                        from {}: {}"
+                                     , *name, dep_path.display(), err);
+                    self.errors.push(name.map(msg))
+                }
+            }
+        }
+        Ok(file)
+    }
+
+    /// Find the file defining the module `name`.
+    ///
+    /// The directory of the importing file, `dir`, is searched first,
+    /// followed by each directory on the search path, in order. Within
+    /// each directory, a file written with S-expressions is preferred to
+    /// one written with sweet-expressions.
+    fn resolve(&self, dir: Option<&PathBuf>, name: &Ident) -> Option<PathBuf> {
+        let file_names = [ format!("{}.{}", **name, EXTENSION)
+                         , format!("{}.{}", **name, sweet::EXTENSION) ];
+        dir.into_iter()
+           .chain(self.search_path.iter())
+           .flat_map(|d| file_names.iter().map(move |f| d.join(f)))
+           .find(|path| path.is_file())
+    }
+
+    /// Returns the files that `file` imports.
+    pub fn dependencies(&self, file: FileId) -> Vec<FileId> {
+        self.imports[file.0].iter().map(|&(_, dep)| dep).collect()
+    }
+
+    /// Sort the loaded files so that each file comes after every file it
+    /// imports.
+    ///
+    /// # Returns
+    ///  + The `FileId`s of every loaded file, in dependency order.
+    ///  + An error for each import cycle, located at the import that
+    ///    closes the cycle.
+    pub fn order(&self) -> (Vec<FileId>, Errors) {
+        let mut order = vec![];
+        let mut errs = vec![];
+        let mut visited = vec![Visit::Unvisited; self.sources.len()];
+        let mut path = vec![];
+        for file in self.sources.ids() {
+            self.visit(file, &mut visited, &mut path, &mut order, &mut errs);
+        }
+        (order, errs)
+    }
+
+    /// Depth-first traversal of the import graph, for `order()`.
+    fn visit( &self, file: FileId, visited: &mut Vec<Visit>
+            , path: &mut Vec<FileId>, order: &mut Vec<FileId>
+            , errs: &mut Errors) {
+        if visited[file.0] != Visit::Unvisited { return }
+        visited[file.0] = Visit::InProgress;
+        path.push(file);
+        for &(ref name, dep) in &self.imports[file.0] {
+            match visited[dep.0] {
+                Visit::Unvisited =>
+                    self.visit(dep, visited, path, order, errs)
+              , Visit::InProgress => {
+                    let start = path.iter()
+                                    .position(|&f| f == dep)
+                                    .expect_ice("file in progress was not \
+                                                 on the import path");
+                    let cycle = path[start..].iter()
+                                             .chain(Some(&dep).into_iter())
+                                             .map(|&f| self.sources.get(f)
+                                                                   .name
+                                                                   .clone())
+                                             .collect::<Vec<String>>()
+                                             .join(" -> ");
+                    errs.push(name.map(format!( "[error] import cycle: {}"
+                                              , cycle)))
+                }
+              , Visit::Done => {}
+            }
+        }
+        path.pop();
+        visited[file.0] = Visit::Done;
+        order.push(file);
+    }
+
+    /// Parse every loaded file into a `Module`.
+    ///
+    /// # Returns
+    ///  + The modules, in dependency order.
+    ///  + Every error encountered while loading or parsing the program.
+    ///    If this is empty, the program loaded successfully.
+    pub fn modules(&self) -> (Vec<Module<'s, UnscopedState>>, Errors) {
+        let (order, mut errs) = self.order();
+        errs.extend(self.errors.iter().cloned());
+        let modules = order.into_iter().map(|file| {
+            let name = Positional::at( file, 0, 0
+                                     , self.sources.get(file).name.clone());
+            if let Some(translation) = self.translations[file.0] {
+                errs.extend(translation.errors().iter().cloned())
+            }
+            let (module, mut parse_errs) =
+                self.trees[file.0].lower_module(name);
+            errs.append(&mut parse_errs);
+            module
+        }).collect();
+        (modules, errs)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Visit { Unvisited, InProgress, Done }
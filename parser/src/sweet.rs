@@ -0,0 +1,259 @@
+//
+//  0 1 0  Mnemosyne: a functional systems programming language.
+//  0 0 1  (c) 2015 Hawk Weisman
+//  1 1 1  hi@hawkweisman.me
+//
+//  Mnemosyne is released under the MIT License. Please refer to
+//  the LICENSE file at the top-level directory of this distribution
+//  or at https://github.com/hawkw/mnemosyne/.
+//
+//! Sweet-expression reader
+//!
+//! Sweet-expressions ([SRFI-110]) let indentation stand in for the
+//! parentheses around a form:
+//!
+//!  + A line containing more than one datum, or with lines indented
+//!    beneath it, is a list of its data followed by the lines beneath it.
+//!  + A line containing a single datum, with nothing beneath it, is just
+//!    that datum.
+//!  + Indentation is not significant within parentheses, brackets, or
+//!    braces, so S-expressions and infix sugar can be used as usual.
+//!
+//! So
+//!
+//! ```text
+//! def fac
+//!     fn {int -> int}
+//!         (0) 1
+//!         (n) {n * (fac {n - 1})}
+//! ```
+//!
+//! reads the same as `(def fac (fn {int -> int} ((0) 1) ((n) ...)))`.
+//!
+//! The reader translates sweet-expressions into S-expression source code,
+//! which is parsed by the usual reader. Every span in the resulting AST,
+//! and every error, refers to the sweet-expression source that was read.
+//!
+//! A file is read as sweet-expressions if its extension is `smn`, or if
+//! its first line is the pragma `#!sweet`.
+//!
+//! [SRFI-110]: http://srfi.schemers.org/srfi-110/srfi-110.html
+use std::path::Path;
+use std::rc::Rc;
+
+use core::errors::Errors;
+use core::position::{ FileId, Positional };
+use core::semantic::annotations::UnscopedState;
+use core::semantic::ast::{ Expr, Ident, Module };
+
+use super::cst::{ self, Cst, Origins, SourceFile };
+
+/// The file extension of Mnemosyne source code files written with
+/// sweet-expressions.
+pub const EXTENSION: &'static str = "smn";
+
+/// A first line marking a file as written with sweet-expressions.
+///
+/// Since `#` begins a comment, the S-expression reader ignores it.
+pub const PRAGMA: &'static str = "#!sweet";
+
+/// Returns true if the file at `path`, containing `source`, is written
+/// with sweet-expressions.
+pub fn is_sweet<P>(path: P, source: &str) -> bool
+where P: AsRef<Path> {
+    path.as_ref().extension().and_then(|ext| ext.to_str()) == Some(EXTENSION)
+        || source.lines()
+                 .next()
+                 .map_or(false, |line| line.trim_right() == PRAGMA)
+}
+
+/// Sweet-expression source code, translated to S-expressions.
+#[derive(Clone, Debug)]
+pub struct Translation { file: FileId
+                       , /// The translated S-expression source code.
+                         text: String
+                       , origins: Rc<Origins>
+                       , /// Errors in the indentation of the source code.
+                         errors: Errors
+                       }
+
+impl Translation {
+
+    /// Returns the translated S-expression source code.
+    #[inline] pub fn text(&self) -> &str { &self.text }
+
+    /// Returns the errors in the indentation of the source code.
+    #[inline] pub fn errors(&self) -> &Errors { &self.errors }
+
+    /// Parse the translated source code into a concrete syntax tree.
+    ///
+    /// Spans found by lowering the tree refer to the original
+    /// sweet-expression source code.
+    pub fn parse<'a>(&'a self) -> SourceFile<'a> {
+        SourceFile { origins: Some(self.origins.clone())
+                   , ..cst::parse(self.file, &self.text)
+                   }
+    }
+
+    /// Lower the source code to the abstract syntax tree, as
+    /// `SourceFile::lower()`.
+    pub fn lower<'a>(&'a self) -> (Vec<Expr<'a, UnscopedState>>, Errors) {
+        let (exprs, errs) = self.parse().lower();
+        (exprs, self.errors.iter().cloned().chain(errs).collect())
+    }
+
+    /// Lower the source code to a module named `name`, as
+    /// `SourceFile::lower_module()`.
+    pub fn lower_module<'a>(&'a self, name: Ident)
+                            -> (Module<'a, UnscopedState>, Errors) {
+        let (module, errs) = self.parse().lower_module(name);
+        (module, self.errors.iter().cloned().chain(errs).collect())
+    }
+}
+
+/// A line of sweet-expression source code, and the lines indented
+/// beneath it.
+struct Line<'s, 'a: 's> { data: &'s [Cst<'a>]
+                        , children: Vec<Line<'s, 'a>>
+                        }
+
+/// Groups lines into a tree by their indentation.
+struct Reader<'s, 'a: 's> { file: FileId
+                          , /// The indentation and data of each line.
+                            lines: Vec<(usize, &'s [Cst<'a>])>
+                          , /// The index of the next line to read.
+                            next: usize
+                          , errors: Errors
+                          }
+
+impl<'s, 'a: 's> Reader<'s, 'a> {
+
+    /// Read a block of lines indented further than `parent`, or the
+    /// top-level lines of the file if `parent` is `None`.
+    fn block(&mut self, parent: Option<usize>) -> Vec<Line<'s, 'a>> {
+        let indent = match self.lines.get(self.next) {
+            Some(&(indent, _)) if parent.map_or(true, |p| indent > p) =>
+                indent
+          , _ => return vec![]
+        };
+        let mut block = vec![];
+        while let Some(&(line_indent, data)) = self.lines.get(self.next) {
+            if parent.map_or(false, |p| line_indent <= p) { break }
+            if line_indent != indent {
+                // deeper lines have been read as children of the previous
+                // line, so this line is dedented past this block, but
+                // not as far as the enclosing one
+                self.errors.push(Positional::from(
+                    data[0].span(self.file)
+                  , String::from("[error] syntax error: dedent does not \
+                                  match any enclosing indentation level")))
+            }
+            self.next += 1;
+            let children = self.block(Some(line_indent));
+            block.push(Line { data: data, children: children });
+        }
+        block
+    }
+}
+
+/// Writes the S-expression translation of sweet-expression source code.
+struct Writer<'s> { source: &'s str
+                  , text: String
+                  , /// The offset in `source` of each byte of `text`.
+                    offsets: Vec<usize>
+                  , /// The end of the last node copied from `source`.
+                    end: usize
+                  }
+
+impl<'s> Writer<'s> {
+
+    /// Write `s`, which does not appear in the source code, as though it
+    /// were at `origin`.
+    fn synthetic(&mut self, s: &str, origin: usize) {
+        self.text.push_str(s);
+        self.offsets.extend(s.bytes().map(|_| origin));
+    }
+
+    /// Copy the source code of a node.
+    fn copy(&mut self, node: &Cst) {
+        let start = node.first_token().offset;
+        let end = node.end();
+        self.text.push_str(&self.source[start..end]);
+        self.offsets.extend(start..end);
+        self.end = end;
+    }
+
+    /// Write a line and the lines beneath it.
+    fn line(&mut self, line: &Line) {
+        if line.data.len() == 1 && line.children.is_empty() {
+            return self.copy(&line.data[0])
+        }
+        self.synthetic("(", line.data[0].first_token().offset);
+        for (i, datum) in line.data.iter().enumerate() {
+            if i > 0 { let end = self.end; self.synthetic(" ", end) }
+            self.copy(datum)
+        }
+        for child in &line.children {
+            let end = self.end;
+            self.synthetic(" ", end);
+            self.line(child)
+        }
+        let end = self.end;
+        self.synthetic(")", end);
+    }
+}
+
+/// Returns true if `node` begins a new line of source code.
+fn begins_line(node: &Cst) -> bool {
+    node.first_token().leading.iter().any(|t| t.text().contains('\n'))
+}
+
+/// Read the sweet-expression source code of `file`, translating it to
+/// S-expressions.
+///
+/// This never fails: errors in the source code's indentation are
+/// reported by `Translation::errors()`, and syntax errors within its
+/// lines when the translation is lowered.
+pub fn read(file: FileId, source: &str) -> Translation {
+    let tree = cst::parse(file, source);
+
+    // split the top-level data of the file into lines
+    let mut lines = vec![];
+    let mut start = 0;
+    for i in 1..tree.forms.len() + 1 {
+        if i == tree.forms.len() || begins_line(&tree.forms[i]) {
+            let first = tree.forms[start].first_token();
+            lines.push((first.pos.col as usize - 1, &tree.forms[start..i]));
+            start = i;
+        }
+    }
+
+    let mut reader = Reader { file: file, lines: lines, next: 0, errors: vec![] };
+    if let Some(&(indent, data)) = reader.lines.first() {
+        if indent > 0 {
+            reader.errors.push(Positional::from(
+                data[0].span(file)
+              , String::from("[error] syntax error: the first line of a \
+                              file may not be indented")))
+        }
+    }
+    let top_level = reader.block(None);
+
+    let mut writer = Writer { source: source
+                            , text: String::with_capacity(source.len())
+                            , offsets: Vec::with_capacity(source.len())
+                            , end: 0
+                            };
+    for (i, line) in top_level.iter().enumerate() {
+        if i > 0 { let end = writer.end; writer.synthetic("\n", end) }
+        writer.line(line)
+    }
+    let end = writer.end;
+    writer.offsets.push(end);
+
+    Translation { file: file
+                , text: writer.text
+                , origins: Rc::new(Origins::new(source, writer.offsets))
+                , errors: reader.errors
+                }
+}
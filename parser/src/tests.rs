@@ -1,12 +1,24 @@
-use super::parse_module;
+use super::{ cst, parse_file, parse_module, parse_module_recovering, sweet };
+use super::loader::{ Buffers, Loader };
 
-use core::semantic::ast::Node;
+use std::{ env, fs };
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use core::errors::{ CompileResult, Errors };
+use core::position::{ FileId, LineTable, Positional };
+use core::semantic::{ infer, instances, moves, scope, typeclass, LetScopes
+                     , SymbolAnnotation, SymbolTable };
+use core::semantic::annotations::{ Scoped, ScopedState, UnscopedState };
+use core::semantic::ast::{ Node, Form, DefForm, LetForm, Logical, Module, NumExpr };
+use core::semantic::types::{ Type, Primitive, Int, Reference, Signature };
 
 macro_rules! expr_test {
     ($name:ident, $code:expr) => {
         #[test]
         fn $name() {
-            assert_eq!( parse_module($code)
+            assert_eq!( parse_module(FileId(0), $code)
                             .unwrap()[0]
                             .to_sexpr(0)
                       , $code)
@@ -49,9 +61,1816 @@ fn test_defsyntax_sugar() {
 r#"(def fac (fn {int -> int}
     ((0) 1)
     ((n) (fac (- n 1)))))"#;
-    assert_eq!( parse_module(string).unwrap()[0]
+    assert_eq!( parse_module(FileId(0), string).unwrap()[0]
                                    .to_sexpr(0)
               , "(define fac (\u{3bb} (\u{2192} int int)
 \t((0) 1)
 \t((n) (fac (- n 1))))\n)" )
 }
+
+expr_test!(test_data_sum_prefix
+  , "(define Color data (| Blue Green Red))");
+expr_test!(test_data_sum_nested
+  , "(define Shape data (| (Circle i64) (Point (| X Y))))");
+expr_test!(test_data_record
+  , "(define Date data '((day i64) (month i64) (year i64)))");
+
+#[test]
+fn test_data_sum_infix() {
+    let string =
+r#"(def Weekday data { Monday    | Tuesday
+                  | Wednesday | Thursday
+                  | Friday    | Saturday
+                  | Sunday
+                  })"#;
+    match *parse_module(FileId(0), string).unwrap()[0] {
+        Form::Data(ref data) => {
+            assert!(data.is_sum_type());
+            assert!(!data.is_struct());
+            assert_eq!(data.variants.len(), 7);
+        }
+      , ref other => panic!("expected a data form, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_data_record_colon() {
+    let string =
+r#"(def Date data
+    '((: day u8)
+      (: month u8)
+      (: year i64)))"#;
+    match *parse_module(FileId(0), string).unwrap()[0] {
+        Form::Data(ref data) => {
+            assert!(data.is_struct());
+            assert_eq!(data.get_struct_fields().unwrap().len(), 3);
+        }
+      , ref other => panic!("expected a data form, got {:?}", other)
+    }
+}
+
+/// Returns the errors in the definitions of the module `code`, which must
+/// parse.
+fn definition_errors(code: &str) -> Errors {
+    let (module, errors) = module(code);
+    assert!(errors.is_empty(), "{:?}", errors);
+    scope::annotate_module(module, vec![]).unwrap_err()
+}
+
+#[test]
+fn test_data_duplicate_variant() {
+    let string = "(def Color data (| Red Green Red))";
+    let errors = definition_errors(string);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].value, "[error] variant Red is defined more than once");
+    assert_eq!(errors[0].pos.start, 29);
+    assert!(errors[1].value.starts_with("[note]"), "{}", errors[1]);
+    assert_eq!(errors[1].pos.start, 19);
+}
+
+#[test]
+fn test_data_duplicate_variant_in_nested_sum() {
+    let string = "(def Shape data (| (Point (| X Y)) X))";
+    let errors = definition_errors(string);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].value, "[error] variant X is defined more than once");
+    assert_eq!(errors[0].pos.start, string.rfind('X').unwrap());
+    assert_eq!(errors[1].pos.start, string.find('X').unwrap());
+}
+
+#[test]
+fn test_data_duplicate_field() {
+    let string = "(def Date data '((day int) (month int) (day int)))";
+    let errors = definition_errors(string);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].value, "[error] field day is defined more than once");
+    assert_eq!(errors[0].pos.start, string.rfind("day").unwrap());
+    assert_eq!(errors[1].pos.start, string.find("day").unwrap());
+
+    let string = "(def Shape data (| (Circle '((r int) (r int))) Square))";
+    let errors = definition_errors(string);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].value, "[error] field r is defined more than once");
+}
+
+#[test]
+fn test_recover_reports_every_error() {
+    let string = "(my_fn 1 2)\n(if)\n(my_fn 3 4)\n(let)\n(my_fn 5 6)";
+    let (forms, errors) = parse_module_recovering(FileId(0), string);
+    assert_eq!(forms.len(), 3);
+    assert_eq!(errors.len(), 2);
+    let lines = LineTable::new(string);
+    assert_eq!(lines.position(errors[0].pos.start).row, 2);
+    assert_eq!(lines.position(errors[1].pos.start).row, 4);
+    assert_eq!(forms[2].to_sexpr(0), "(my_fn 5 6)");
+}
+
+#[test]
+fn test_recover_skips_nested_and_quoted_delimiters() {
+    let string = "(1 (a \")\" #| ) |# b))\n(my_fn 1 2)";
+    let (forms, errors) = parse_module_recovering(FileId(0), string);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(forms.len(), 1);
+    assert_eq!(forms[0].to_sexpr(0), "(my_fn 1 2)");
+}
+
+#[test]
+fn test_recover_stray_close_paren() {
+    let (forms, errors) = parse_module_recovering(FileId(0), ") (my_fn 1 2)");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(forms.len(), 1);
+}
+
+#[test]
+fn test_recover_unclosed_paren() {
+    let string = "(my_fn 1 2)\n(my_fn 3";
+    let (forms, errors) = parse_module_recovering(FileId(0), string);
+    assert_eq!(forms.len(), 1);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(LineTable::new(string).position(errors[0].pos.start).row, 2);
+}
+
+#[test]
+fn test_recover_mismatched_delimiter() {
+    let (forms, errors) = parse_module_recovering(FileId(0), "(my_fn 1 2] (my_fn 3 4)");
+    assert_eq!(forms.len(), 1);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(forms[0].to_sexpr(0), "(my_fn 3 4)");
+}
+
+#[test]
+fn test_parse_module_err_on_any_error() {
+    assert!(parse_module(FileId(0), "(my_fn 1 2) (if)").is_err());
+}
+
+/// Test that `$code` parses and desugars to `$desugared`.
+macro_rules! sugar_test {
+    ($name:ident, $code:expr, $desugared:expr) => {
+        #[test]
+        fn $name() {
+            assert_eq!( parse_module(FileId(0), $code)
+                            .unwrap()[0]
+                            .to_sexpr(0)
+                      , $desugared)
+        }
+    }
+}
+
+sugar_test!(test_infix_add, "{a + b}", "(+ a b)");
+sugar_test!(test_infix_chain, "{a + b + c}", "(+ a b c)");
+sugar_test!(test_infix_word_op, "{x max y}", "(max x y)");
+sugar_test!(test_infix_nested, "{a + {b * c}}", "(+ a (* b c))");
+sugar_test!(test_infix_in_call, "(my_fn {1 - 2} 3)", "(my_fn (- 1 2) 3)");
+
+#[test]
+fn test_infix_mixed_ops_is_error() {
+    assert!(parse_module(FileId(0), "{a + b * c}").is_err());
+}
+
+expr_test!(test_pattern_wildcard
+  , "(define const (\u{3bb} (\u{2192} int int int)\n\t((a _) a))\n)");
+expr_test!(test_pattern_typed
+  , "(define id (\u{3bb} (\u{2192} int int)\n\t((n: int) n))\n)");
+expr_test!(test_pattern_deref
+  , "(define add_to_ptr (\u{3bb} (\u{2192} &i64 i64 i64)\n\t(($a b) (+ a b)))\n)");
+expr_test!(test_pattern_literals
+  , "(define greet (\u{3bb} (\u{2192} bool int)\n\t((true) \"hi\")\n\t((false) \"bye\"))\n)");
+expr_test!(test_pattern_constructor
+  , "(define len (\u{3bb} (\u{2192} int int)\n\t(((Nil)) 0)\n\t(((Cons _ xs)) (+ 1 (len xs))))\n)");
+expr_test!(test_pattern_cons
+  , "(define head (\u{3bb} (\u{2192} int int)\n\t(([x:_]) x)\n\t(([]) 0))\n)");
+
+expr_test!(test_lit_string, r#"(my_fn "hello, world")"#);
+expr_test!(test_lit_string_escapes, r#"(my_fn "a \"quoted\"\n\tstring\\")"#);
+expr_test!(test_lit_char, r"(my_fn #\a #\space #\newline #\tab)");
+expr_test!(test_lit_bool, "(my_fn true false)");
+expr_test!(test_lit_float, "(my_fn 1.5 0.25 3.0)");
+expr_test!(test_lit_uint, "(my_fn 42u 0u)");
+expr_test!(test_lit_negative, "(my_fn -1 -2.5 (- 1))");
+sugar_test!(test_lit_char_quoted, "(my_fn 'a')", r"(my_fn #\a)");
+sugar_test!(test_lit_float_exponent, "(my_fn 2e3 1.5E-1)", "(my_fn 2000.0 0.15)");
+
+#[test]
+fn test_lit_char_is_not_a_comment() {
+    let forms = parse_module(FileId(0), "(my_fn #\\# #\\|) # a comment\n#| another |# (my_fn)")
+                    .unwrap();
+    assert_eq!(forms.len(), 2);
+    assert_eq!(forms[0].to_sexpr(0), "(my_fn #\\# #\\|)");
+}
+
+#[test]
+fn test_lit_uint_float_is_error() {
+    assert!(parse_module(FileId(0), "(my_fn 1.5u)").is_err());
+}
+
+#[test]
+fn test_lit_int_overflow_is_error() {
+    assert!(parse_module(FileId(0), "(my_fn 99999999999999999999)").is_err());
+}
+
+sugar_test!(test_let_splat
+  , "(let* ((a int 1) (b int a)) b)"
+  , "(let* [(a int 1)\n(b int a)]\nb)");
+sugar_test!(test_letrec
+  , "(letrec [(f int (g 1)) (g int (f 2))] f)"
+  , "(letrec [(f int (g 1))\n(g int (f 2))]\nf)");
+sugar_test!(test_let_named
+  , "(let loop int ((i int 0)) (loop (+ i 1)))"
+  , "(let loop int [(i int 0)]\n(loop (+ i 1)))");
+
+/// Parse a single `let` form, and return its scopes forked from an
+/// empty symbol table.
+fn with_let_scopes<F>(code: &str, f: F)
+where F: Fn(LetScopes) {
+    let forms = parse_module(FileId(0), code).unwrap();
+    let root = SymbolTable::new();
+    match *forms[0] {
+        Form::Let(ref form) => f(form.scopes(&root))
+      , ref other => panic!("expected a let form, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_let_scoping() {
+    with_let_scopes("(let ((a int 1) (b int 2)) b)", |scopes| {
+        assert!(!scopes.bindings[1].contains_key("a"));
+        assert!(scopes.body.contains_key("a"));
+        assert!(scopes.body.contains_key("b"));
+    })
+}
+
+#[test]
+fn test_let_splat_scoping() {
+    with_let_scopes("(let* ((a int 1) (b int a)) b)", |scopes| {
+        assert!(!scopes.bindings[0].contains_key("a"));
+        assert!(scopes.bindings[1].contains_key("a"));
+        assert!(!scopes.bindings[1].contains_key("b"));
+        assert!(scopes.body.contains_key("b"));
+    })
+}
+
+#[test]
+fn test_letrec_scoping() {
+    with_let_scopes("(letrec ((f int (g 1)) (g int (f 2))) f)", |scopes| {
+        assert!(scopes.bindings[0].contains_key("g"));
+        assert!(scopes.bindings[1].contains_key("f"));
+    })
+}
+
+#[test]
+fn test_let_named_scoping() {
+    with_let_scopes("(let loop int ((i int 0)) (loop i))", |scopes| {
+        assert!(!scopes.bindings[0].contains_key("loop"));
+        assert!(scopes.body.contains_key("loop"));
+        assert!(scopes.body.contains_key("i"));
+        assert!(scopes.body.get("loop").unwrap().is_fn_type());
+    })
+}
+
+expr_test!(test_class_decl
+  , "(class Eq a\n\t(== (\u{2192} a a bool))\n\t(/= (\u{2192} a a bool)))");
+
+#[test]
+fn test_instance_decl() {
+    let string =
+r#"(instance Eq int
+    (def == (fn (-> int int bool) ((a b) (prim_eq a b))))
+    (def /= (fn (-> int int bool) ((a b) (not (== a b))))))"#;
+    match *parse_module(FileId(0), string).unwrap()[0] {
+        Form::Instance(ref inst) => {
+            assert_eq!(inst.class.value, "Eq");
+            assert_eq!(inst.function_names().len(), 2);
+        }
+      , ref other => panic!("expected an instance, got {:?}", other)
+    }
+}
+
+/// Register every class and instance in `code` in a fresh symbol table,
+/// and return the errors.
+fn register_classes(code: &str) -> Errors {
+    let forms = parse_module(FileId(0), code).unwrap();
+    let mut scope = SymbolTable::new();
+    let mut errs = vec![];
+    for form in &forms {
+        let result = match **form {
+            Form::Class(ref class) => typeclass::register_class(&mut scope, class)
+          , Form::Instance(ref inst) =>
+                typeclass::register_instance(&mut scope, inst)
+          , _ => Ok(())
+        };
+        if let Err(e) = result { errs.extend(e) }
+    }
+    errs
+}
+
+#[test]
+fn test_class_only_at_top_level() {
+    assert!(parse_module(FileId(0), "(class Eq a (== (-> a a bool)))").is_ok());
+    assert!(parse_module( FileId(0)
+                        , "(let ((x 1)) (class Eq a (== (-> a a bool))))")
+                .is_err());
+    assert!(parse_module( FileId(0)
+                        , "(begin (instance Eq int \
+                             (def == (fn (-> int int bool) \
+                                       ((a b) (prim_eq a b))))))")
+                .is_err());
+}
+
+#[test]
+fn test_class_registration() {
+    let forms = parse_module(FileId(0), "(class Eq a (== (-> a a bool)))").unwrap();
+    let mut scope = SymbolTable::new();
+    match *forms[0] {
+        Form::Class(ref class) =>
+            typeclass::register_class(&mut scope, class).unwrap()
+      , ref other => panic!("expected a class, got {:?}", other)
+    }
+    assert!(scope.get("Eq").unwrap().is_class());
+    assert!(scope.get("==").unwrap().is_fn_type());
+}
+
+#[test]
+fn test_instance_registration() {
+    let errs = register_classes(
+        "(class Eq a (== (-> a a bool)))
+         (instance Eq int
+            (def == (fn (-> int int bool) ((a b) (prim_eq a b)))))");
+    assert!(errs.is_empty());
+}
+
+#[test]
+fn test_instance_of_undefined_class() {
+    let errs = register_classes(
+        "(instance Ord int
+            (def < (fn (-> int int bool) ((a b) (prim_lt a b)))))");
+    assert_eq!(errs.len(), 1);
+}
+
+#[test]
+fn test_instance_missing_and_extra_functions() {
+    let errs = register_classes(
+        "(class Eq a (== (-> a a bool)) (/= (-> a a bool)))
+         (instance Eq int
+            (def < (fn (-> int int bool) ((a b) (prim_lt a b)))))");
+    // `<` isn't a member of `Eq`, and `==` and `/=` are missing
+    assert_eq!(errs.len(), 3);
+}
+
+#[test]
+fn test_constraint_on_undefined_class() {
+    let forms = parse_module(FileId(0), "(class Eq a (== (-> a a bool)))").unwrap();
+    let mut scope = SymbolTable::new();
+    if let Form::Class(ref class) = *forms[0] {
+        typeclass::register_class(&mut scope, class).unwrap();
+    }
+    let ok = parse_module(FileId(0), "(def f (fn (-> (=> Eq a) a a) ((x) x)))").unwrap();
+    let bad = parse_module(FileId(0), "(def f (fn (-> (=> Ord a) a a) ((x) x)))").unwrap();
+    for (forms, n_errs) in vec![(ok, 0), (bad, 1)] {
+        match *forms[0] {
+            Form::Define(DefForm::Function { ref fun, .. }) =>
+                assert_eq!( typeclass::check_constraints(&scope, &fun.sig)
+                                      .len()
+                          , n_errs)
+          , ref other => panic!("expected a function def, got {:?}", other)
+        }
+    }
+}
+
+#[test]
+fn test_cst_is_lossless() {
+    let string = "#| a module |#\n\
+                  (def my_fn  # the answer\n\
+                  \t(fn (x int) -> int  ( + x  42 )))\n\
+                  (my_fn \"a \\\"string\\\"\" #\\( 'c' '(a b) ,@xs)\n\
+                  # trailing comment";
+    assert_eq!(format!("{}", cst::parse(FileId(0), string)), string);
+}
+
+#[test]
+fn test_cst_is_lossless_when_malformed() {
+    let string = "(my_fn (1 2]\n) ) \"unterminated";
+    assert_eq!(format!("{}", cst::parse(FileId(0), string)), string);
+}
+
+#[test]
+fn test_cst_keeps_trivia() {
+    let file = cst::parse(FileId(0), "# hello\n(my_fn 1 2)  ");
+    assert_eq!(file.forms.len(), 1);
+    assert_eq!( file.forms[0].first_token().leading
+              , vec![ cst::Trivia::LineComment("# hello")
+                    , cst::Trivia::Whitespace("\n") ]);
+    assert_eq!(file.trailing, vec![cst::Trivia::Whitespace("  ")]);
+}
+
+#[test]
+fn test_cst_lowers_to_ast() {
+    let string = "(my_fn 1 2) # comment\n\
+                  #| block |# (my_other_fn (f x) y)";
+    let (forms, errors) = cst::parse(FileId(0), string).lower();
+    assert!(errors.is_empty());
+    let expected = parse_module( FileId(0)
+                               , "(my_fn 1 2)\n(my_other_fn (f x) y)")
+                       .unwrap();
+    assert_eq!( forms.iter().map(|f| f.to_sexpr(0)).collect::<Vec<_>>()
+              , expected.iter().map(|f| f.to_sexpr(0)).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_cst_positions() {
+    let file = cst::parse(FileId(0), "(a)\n  (b)");
+    let pos = file.forms[1].first_token().pos;
+    assert_eq!((pos.row, pos.col), (2, 3));
+}
+
+#[test]
+fn test_spans() {
+    let string = "(def my_fn  # comment\n\
+                  \t(fn {int -> int}\n\
+                  \t\t((n) (+ n 1))))\n\
+                  (my_fn 2)";
+    let forms = parse_module(FileId(3), string).unwrap();
+    assert_eq!(forms[0].position.file, FileId(3));
+    assert_eq!( &string[forms[0].position.start..forms[0].position.end]
+              , &string[..string.rfind('\n').unwrap()]);
+    assert_eq!( &string[forms[1].position.start..forms[1].position.end]
+              , "(my_fn 2)");
+    match forms[1].node {
+        Form::App(ref app) => {
+            assert_eq!(&string[app.fun.pos.start..app.fun.pos.end], "my_fn");
+            let arg = &app.params[0].position;
+            assert_eq!(&string[arg.start..arg.end], "2");
+        }
+      , ref other => panic!("expected an application, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_error_span_points_at_failure() {
+    let string = "(my_fn 1 2)\n  (if)";
+    let (_, errors) = parse_module_recovering(FileId(0), string);
+    let lines = LineTable::new(string);
+    assert_eq!(lines.position(errors[0].pos.start).row, 2);
+    assert!(errors[0].describe(&lines).contains("  (if)"));
+}
+
+/// Create a fresh directory containing the given files, for loader tests.
+fn module_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mnemosyne-{}", test));
+    let _ = fs::remove_dir_all(&dir);
+    for &(name, code) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(&path).unwrap()
+            .write_all(code.as_bytes()).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn test_load_imports_in_dependency_order() {
+    let dir = module_dir("load-order",
+        &[ ("main.mn", "(import util std/list)\n(my_fn (length xs))")
+         , ("util.mn", "(use std/list)\n(util_fn 1)")
+         , ("lib/std/list.mn", "(length 1)")
+         ]);
+    let buffers = Buffers::new();
+    let mut loader = Loader::new(&buffers, vec![dir.join("lib")]);
+    loader.load_root(dir.join("main.mn")).unwrap();
+    let (modules, errors) = loader.modules();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!( modules.iter().map(|m| m.name.value.clone()).collect::<Vec<_>>()
+              , vec!["std/list", "util", "main"]);
+    assert_eq!( modules[2].imports.iter().map(|i| i.value.clone()).collect::<Vec<_>>()
+              , vec!["util", "std/list"]);
+    assert_eq!(modules[2].body.len(), 1);
+    assert_eq!(modules[2].body[0].to_sexpr(0), "(my_fn (length xs))");
+    // std/list is only loaded once
+    assert_eq!(loader.sources().len(), 3);
+}
+
+#[test]
+fn test_load_reports_cycles() {
+    let dir = module_dir("load-cycle",
+        &[ ("a.mn", "(import b)")
+         , ("b.mn", "(import c)")
+         , ("c.mn", "(import a)")
+         ]);
+    let buffers = Buffers::new();
+    let mut loader = Loader::new(&buffers, vec![]);
+    loader.load_root(dir.join("a.mn")).unwrap();
+    let (_, errors) = loader.modules();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].value.contains("a -> b -> c -> a"), "{}", errors[0]);
+    let c = loader.sources().find(fs::canonicalize(dir.join("c.mn")).unwrap());
+    assert_eq!(Some(errors[0].pos.file), c);
+}
+
+#[test]
+fn test_load_reports_missing_modules() {
+    let dir = module_dir("load-missing", &[("main.mn", "(my_fn 1)\n(import nope)")]);
+    let buffers = Buffers::new();
+    let mut loader = Loader::new(&buffers, vec![]);
+    let main = loader.load_root(dir.join("main.mn")).unwrap();
+    let (modules, errors) = loader.modules();
+    assert_eq!(modules.len(), 1);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].pos.file, main);
+    assert_eq!(loader.sources().position(errors[0].pos).row, 2);
+}
+
+#[test]
+fn test_import_outside_module_is_error() {
+    assert!(parse_module(FileId(0), "(import foo)").is_err());
+}
+
+/// Parse `code` as the module `main`.
+fn module(code: &str) -> (Module<UnscopedState>, Errors) {
+    parse_file(FileId(0), Positional::at(FileId(0), 0, 0, String::from("main")), code)
+}
+
+#[test]
+fn test_module_header() {
+    let (module, errors) = module(
+        "(module shapes (export area Shape)\n\
+         \t(import util)\n\
+         \t(def Shape data (| Circle Square))\n\
+         \t(def area int 1)\n\
+         \t(def secret int 2))");
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(module.name.value, "shapes");
+    assert_eq!( module.exporting.iter().map(|i| i.value.clone()).collect::<Vec<_>>()
+              , vec!["area", "Shape"]);
+    assert_eq!( module.imports.iter().map(|i| i.value.clone()).collect::<Vec<_>>()
+              , vec!["util"]);
+    assert_eq!(module.body.len(), 3);
+}
+
+#[test]
+fn test_module_without_exports() {
+    let (module, errors) = module("(module quiet (def x int 1))");
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(!module.is_lib());
+    assert!(scope::annotate_module(module, vec![]).unwrap()
+                  .exported_symbols()
+                  .is_empty());
+}
+
+#[test]
+fn test_module_without_header_exports_definitions() {
+    let (module, errors) = module(
+        "(def x int 1)\n\
+         (def Shape data (| Circle Square))\n\
+         (define-syntax inc (syntax-rules () ((_ x) (+ x 1))))");
+    assert!(errors.is_empty(), "{:?}", errors);
+    let mut names = module.exporting.iter()
+                          .map(|i| i.value.clone())
+                          .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["Circle", "Shape", "Square", "x"]);
+}
+
+#[test]
+fn test_module_exports_must_be_defined() {
+    let string = "(module m (export x missing)\n(def x int 1))";
+    let (_, errors) = module(string);
+    assert_eq!(errors.len(), 1);
+    assert!( errors[0].value.contains("exports missing"), "{}", errors[0]);
+    assert_eq!(&string[errors[0].pos.start..errors[0].pos.end], "missing");
+}
+
+#[test]
+fn test_module_exported_symbols() {
+    let (module, errors) = module(
+        "(module m (export visible)\n\
+         \t(def visible int 1)\n\
+         \t(def hidden int 2))");
+    assert!(errors.is_empty(), "{:?}", errors);
+    let symbols = scope::annotate_module(module, vec![]).unwrap()
+                        .exported_symbols();
+    assert_eq!( symbols.iter().map(|&(ref name, _)| name.clone()).collect::<Vec<_>>()
+              , vec!["visible"]);
+}
+
+#[test]
+fn test_module_exports_types_and_classes() {
+    let (util, errors) = module(
+        "(module util (export Shape Eq)\n\
+         \t(def Shape data (| (Circle int) Point))\n\
+         \t(class Eq a (eq (-> a a bool)))\n\
+         \t(instance Eq int (def eq (fn (-> int int bool) ((a b) true)))))");
+    assert!(errors.is_empty(), "{:?}", errors);
+    let symbols = scope::annotate_module(util, vec![]).unwrap()
+                        .exported_symbols();
+    let mut names = symbols.iter()
+                           .map(|&(ref name, _)| name.clone())
+                           .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["Circle", "Eq", "Point", "Shape", "eq"]);
+    match symbols.iter().find(|&&(ref name, _)| name == "Eq") {
+        Some(&(_, SymbolAnnotation::Class { ref instances, .. })) =>
+            assert_eq!(*instances, vec![Type::Prim(Primitive::IntSize)])
+      , other => panic!("expected a class, got {:?}", other)
+    }
+    let (main, _) = module(
+        "(instance Eq Shape (def eq (fn (-> Shape Shape bool) ((a b) true))))\n\
+         (def test bool (eq Point (Circle 1)))");
+    assert!(scope::annotate_module(main, symbols).is_ok());
+}
+
+#[test]
+fn test_module_exports_instances_of_imported_classes() {
+    let (util, _) = module("(module util (export Eq)\n\
+                            \t(class Eq a (eq (-> a a bool))))");
+    let util = scope::annotate_module(util, vec![]).unwrap();
+    let (shapes, errors) = module(
+        "(module shapes (export Shape)\n\
+         \t(def Shape data (| Circle Square))\n\
+         \t(instance Eq Shape (def eq (fn (-> Shape Shape bool) ((a b) true)))))");
+    assert!(errors.is_empty(), "{:?}", errors);
+    let shapes = scope::annotate_module(shapes, util.exported_symbols()).unwrap();
+    let symbols = shapes.exported_symbols();
+    match symbols.iter().find(|&&(ref name, _)| name == "Eq") {
+        Some(&(_, SymbolAnnotation::Class { ref instances, .. })) =>
+            assert_eq!(instances.len(), 1)
+      , other => panic!("expected a class, got {:?}", other)
+    }
+
+    // an importer of both modules sees the instance with the class
+    let (main, _) = module("(def test bool (eq Circle Square))");
+    let mut imported = util.exported_symbols();
+    imported.extend(symbols);
+    let mut main = scope::annotate_module(main, imported).unwrap();
+    let typing = infer::check_module(&mut main).unwrap();
+    assert!(instances::resolve_module(&mut main, &typing, vec![]).is_ok());
+}
+
+#[test]
+fn test_module_macros_cannot_be_exported() {
+    let string = "(module m (export inc)\n\
+                  (define-syntax inc (syntax-rules () ((_ x) (+ x 1)))))";
+    let (_, errors) = module(string);
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].value.contains("macros cannot be exported"), "{}", errors[0]);
+    assert_eq!(&string[errors[0].pos.start..errors[0].pos.end], "inc");
+}
+
+#[test]
+fn test_module_must_be_only_form() {
+    let (_, errors) = module("(module m (def x int 1))\n(def y int 2)");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].value.contains("outside"), "{}", errors[0]);
+}
+
+#[test]
+fn test_module_outside_module_file_is_error() {
+    assert!(parse_module(FileId(0), "(module m (def x int 1))").is_err());
+}
+
+#[test]
+fn test_load_imports_inside_module_form() {
+    let dir = module_dir("load-module-form",
+        &[ ("main.mn", "(module main (import util) (my_fn (util_fn 1)))")
+         , ("util.mn", "(module util (export util_fn) \
+                          (def util_fn int 1))")
+         ]);
+    let buffers = Buffers::new();
+    let mut loader = Loader::new(&buffers, vec![]);
+    loader.load_root(dir.join("main.mn")).unwrap();
+    let (modules, errors) = loader.modules();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!( modules.iter().map(|m| m.name.value.clone()).collect::<Vec<_>>()
+              , vec!["util", "main"]);
+}
+
+#[test]
+fn test_load_resolves_imports_by_file() {
+    let dir = module_dir("load-renamed-module",
+        &[ ("main.mn", "(module main (import util) (my_fn (util_fn 1)))")
+         , ("util.mn", "(module helpers (export util_fn) \
+                          (def util_fn int 1))")
+         ]);
+    let buffers = Buffers::new();
+    let mut loader = Loader::new(&buffers, vec![]);
+    let main = loader.load_root(dir.join("main.mn")).unwrap();
+    let (modules, errors) = loader.modules();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(modules[0].name.value, "helpers");
+    assert_eq!(loader.dependencies(main), vec![modules[0].name.pos.file]);
+}
+
+expr_test!(test_quote_list, "(quote (a b c))");
+expr_test!(test_quote_symbol, "(quote a)");
+expr_test!(test_quasiquote_long, "(quasiquote (a (unquote b) (unquote-splicing c)))");
+sugar_test!(test_quote_sugar, "'(a b c)", "(quote (a b c))");
+sugar_test!(test_quote_symbol_sugar, "'a", "(quote a)");
+sugar_test!(test_quote_reserved_words, "'(if let -> +)", "(quote (if let -> +))");
+sugar_test!(test_quote_literals, "'(1 \"two\" #\\3 true [x])"
+           , "(quote (1 \"two\" #\\3 true (x)))");
+sugar_test!(test_quote_nested_prefixes, "'(a 'b ,c ,@d `e)"
+           , "(quote (a (quote b) (unquote c) (unquote-splicing d) \
+                      (quasiquote e)))");
+sugar_test!(test_quasiquote_sugar, "`(a ,b ,@(f c))"
+           , "(quasiquote (a (unquote b) (unquote-splicing (f c))))");
+sugar_test!(test_quasiquote_quote_keeps_unquote, "`(a '(b ,c))"
+           , "(quasiquote (a (quote (b (unquote c)))))");
+sugar_test!(test_quasiquote_nested, "`(a `(b ,c))"
+           , "(quasiquote (a (quasiquote (b (unquote c)))))");
+sugar_test!(test_quote_in_call, "(my_fn 'a 'b)", "(my_fn (quote a) (quote b))");
+sugar_test!(test_char_literal_not_quote, "(my_fn 'a')", "(my_fn #\\a)");
+
+#[test]
+fn test_stray_unquote_is_error() {
+    let errors = parse_module(FileId(0), ",a").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].value.contains("quasiquote"), "{}", errors[0]);
+    assert!(parse_module(FileId(0), "(my_fn ,a)").is_err());
+}
+
+#[test]
+fn test_quoted_symbol_type() {
+    let forms = parse_module(FileId(0), "'red").unwrap();
+    match forms[0].node {
+        Form::Quote(ref datum) =>
+            assert_eq!(datum.symbol_type(), Some(Type::Symbol(String::from("red"))))
+      , ref other => panic!("expected a quote, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_cst_reads_quote_prefixes() {
+    let file = cst::parse(FileId(0), "'(a ,b ,@c) `d");
+    assert_eq!(file.forms.len(), 2);
+    match file.forms[0] {
+        cst::Cst::Prefixed { ref prefix, ref node } => {
+            assert_eq!(prefix.text, "'");
+            match **node {
+                cst::Cst::List { ref children, .. } => assert_eq!(children.len(), 3)
+              , ref other => panic!("expected a list, got {:?}", other)
+            }
+        }
+      , ref other => panic!("expected a quoted form, got {:?}", other)
+    }
+}
+
+fn expand(code: &str) -> Result<Vec<String>, Errors> {
+    use core::semantic::macros;
+    macros::expand(parse_module(FileId(0), code).unwrap())
+        .map(|body| body.iter().map(|e| e.to_sexpr(0)).collect())
+}
+
+#[test]
+fn test_macro_expands() {
+    let string = "(define-syntax inc (syntax-rules () ((_ x) (+ x 1))))\n\
+                  (inc (f y))\n\
+                  (inc (inc 1))";
+    assert_eq!( expand(string).unwrap()
+              , vec!["(+ (f y) 1)", "(+ (+ 1 1) 1)"]);
+}
+
+#[test]
+fn test_macro_rules_and_literals() {
+    let string = "(define-syntax my-if (syntax-rules (then otherwise)\n\
+                      ((_ c then a otherwise b) (if c a b))\n\
+                      ((_ c then a) (if c a false))))\n\
+                  (my-if x then y otherwise z)\n\
+                  (my-if x then y)";
+    assert_eq!( expand(string).unwrap()
+              , vec!["(if x y z)", "(if x y false)"]);
+}
+
+#[test]
+fn test_macro_ellipsis() {
+    let string = "(define-syntax my-list (syntax-rules ()\n\
+                      ((_ x ...) (cons x ... nil))))\n\
+                  (my-list 1 2 3)\n\
+                  (my-list)";
+    assert_eq!( expand(string).unwrap()
+              , vec!["(cons 1 2 3 nil)", "(cons nil)"]);
+}
+
+#[test]
+fn test_macro_is_hygienic() {
+    let string = "(define-syntax my-or (syntax-rules ()\n\
+                      ((_ a b) (let ((tmp bool a)) (if tmp tmp b)))))\n\
+                  (my-or x tmp)";
+    let expanded = expand(string).unwrap();
+    assert!(expanded[0].contains("(tmp%1 bool x)"), "{}", expanded[0]);
+    assert!(expanded[0].contains("(if tmp%1 tmp%1 tmp)"), "{}", expanded[0]);
+}
+
+#[test]
+fn test_macro_free_names_refer_to_definitions() {
+    use core::semantic::macros;
+    let string = "(define-syntax twice (syntax-rules () ((_ x) (grow (grow x)))))\n\
+                  (def grow (fn (-> int int) ((n) (* n 2))))\n\
+                  (def f (fn (-> int int)\n\
+                  \t((n) (let ((grow int 1)) (twice (+ n grow))))))";
+    let expanded = expand(string).unwrap();
+    assert!( expanded[1].contains("(grow%1 int 1)"), "{}", expanded[1]);
+    assert!( expanded[1].contains("(grow (grow (+ n grow%1)))")
+           , "{}", expanded[1]);
+
+    let (module, _) = module(string);
+    let body = macros::expand(module.body).unwrap();
+    let mut module = scope::annotate_module(Module { body: body, ..module }, vec![])
+                           .unwrap();
+    assert!(infer::check_module(&mut module).is_ok());
+}
+
+#[test]
+fn test_macro_no_matching_rule() {
+    let string = "(define-syntax inc (syntax-rules () ((_ x) (+ x 1))))\n\
+                  (inc 1 2)";
+    let errors = expand(string).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].value.contains("no rule"), "{}", errors[0]);
+    assert_eq!(errors[0].pos.start, string.find("(inc 1 2)").unwrap());
+    assert_eq!(errors[1].pos.start, string.find("inc").unwrap());
+}
+
+#[test]
+fn test_macro_definition_only_at_top_level() {
+    let string = "(let ((x int 1))\n\
+                    (define-syntax inc (syntax-rules () ((_ x) (+ x 1))))\n\
+                    (inc x))";
+    let errors = parse_module(FileId(0), string).unwrap_err();
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn test_macro_infinite_expansion() {
+    let string = "(define-syntax forever (syntax-rules () ((_ x) (forever x))))\n\
+                  (forever 1)";
+    let errors = expand(string).unwrap_err();
+    assert!(errors[0].value.contains("maximum depth"), "{}", errors[0]);
+}
+
+expr_test!(test_sigil_borrow, "(& (my_fn a))");
+expr_test!(test_sigil_box, "(@ 3256)");
+expr_test!(test_sigil_raw, "(* (my_fn a))");
+expr_test!(test_sigil_unwrap, "(? x)");
+expr_test!(test_sigil_unwrap_default, "(? (my_fn a) (+ 1 2))");
+sugar_test!(test_sigil_unwrap_sugar, "(+ 10 ?x)", "(+ 10 (? x))");
+
+#[test]
+fn test_sigil_types() {
+    let int = Type::Prim(Primitive::IntSize);
+    let option = Type::Option(Rc::new(int.clone()));
+    let sigil = |code: &'static str| match parse_module(FileId(0), code).unwrap()[0].node {
+        Form::Sigil(ref sigil) => sigil.clone()
+      , ref other => panic!("expected a sigil, got {:?}", other)
+    };
+    assert_eq!( sigil("(& x)").ty(int.clone(), None)
+              , Ok(Type::Ref(Reference::Borrowed(Rc::new(int.clone())))));
+    assert_eq!( sigil("(@ x)").ty(int.clone(), None)
+              , Ok(Type::Ref(Reference::Unique(Rc::new(int.clone())))));
+    assert_eq!( sigil("(* x)").ty(int.clone(), None)
+              , Ok(Type::Ref(Reference::Raw(Rc::new(int.clone())))));
+    let unwrap = sigil("(? x 1)");
+    assert_eq!(unwrap.ty(option.clone(), Some(int.clone())), Ok(int.clone()));
+    assert!(unwrap.ty(option.clone(), Some(Type::Prim(Primitive::Bool))).is_err());
+    assert!(unwrap.ty(int.clone(), None).is_err());
+}
+
+fn parse_type(ty: &str) -> Type {
+    let code = format!("(def x {} y)", ty);
+    match parse_module(FileId(0), &code).unwrap()[0].node {
+        Form::Define(DefForm::TopLevel { ref annot, .. }) => annot.clone()
+      , ref other => panic!("expected a definition, got {:?}", other)
+    }
+}
+
+fn named(name: &str, params: Vec<Type>) -> Type {
+    Type::Named(String::from(name), params)
+}
+
+#[test]
+fn test_primitive_types() {
+    assert_eq!(parse_type("int"), Type::Prim(Primitive::IntSize));
+    assert_eq!(parse_type("uint"), Type::Prim(Primitive::UintSize));
+    assert_eq!(parse_type("u16"), Type::Prim(Primitive::Uint(Int::Int16)));
+    assert_eq!(parse_type("string"), Type::Prim(Primitive::Str));
+    assert_eq!(parse_type("char"), Type::Prim(Primitive::Char));
+}
+
+#[test]
+fn test_named_types() {
+    assert_eq!(parse_type("Weekday"), named("Weekday", vec![]));
+    assert_eq!(parse_type("a"), Type::Var(String::from("a")));
+    assert_eq!( parse_type("(List a)")
+              , named("List", vec![Type::Var(String::from("a"))]));
+    assert_eq!( parse_type("(Map string (List Weekday))")
+              , named("Map", vec![ Type::Prim(Primitive::Str)
+                                 , named("List", vec![named("Weekday", vec![])])
+                                 ]));
+    assert!(parse_module(FileId(0), "(def x (list a) y)").is_err());
+}
+
+#[test]
+fn test_option_and_pointer_types() {
+    let int = Rc::new(Type::Prim(Primitive::Int(Int::Int64)));
+    assert_eq!(parse_type("?i64"), Type::Option(int.clone()));
+    assert_eq!(parse_type("(Option i64)"), Type::Option(int.clone()));
+    assert_eq!( parse_type("&?i64")
+              , Type::Ref(Reference::Borrowed(Rc::new(Type::Option(int.clone())))));
+    assert_eq!( parse_type("@(List a)")
+              , Type::Ref(Reference::Unique(Rc::new(
+                    named("List", vec![Type::Var(String::from("a"))])))));
+}
+
+#[test]
+fn test_function_types() {
+    let int = Type::Prim(Primitive::IntSize);
+    let int_to_int = Type::Function(Signature { constraints: None
+                                              , typechain: vec![ int.clone()
+                                                               , int.clone() ]
+                                              });
+    assert_eq!(parse_type("(-> int int)"), int_to_int);
+    assert_eq!(parse_type("{int -> int}"), int_to_int);
+
+    let string = "(def apply (fn (-> (-> int int) int int)\n\
+                  \t((f x) (f x))))";
+    match parse_module(FileId(0), string).unwrap()[0].node {
+        Form::Define(DefForm::Function { ref fun, .. }) =>
+            assert_eq!(fun.node.sig.typechain[0], int_to_int)
+      , ref other => panic!("expected a function definition, got {:?}", other)
+    }
+}
+
+expr_test!(test_field_access, "(: day my_date)");
+expr_test!(test_record_update, "(with my_date (day 1) (year (+ y 1)))");
+sugar_test!(test_field_sugar, "(+ 1 my_date.day)", "(+ 1 (: day my_date))");
+sugar_test!(test_field_sugar_chained, "a.b.c", "(: c (: b a))");
+
+#[test]
+fn test_field_checks() {
+    let string = "(def Date data '((day i64) (month i64) (year i64)))\n\
+                  (: day my_date)\n\
+                  (: dya my_date)\n\
+                  (with my_date (year 2016) (yaer 2017) (year 2018))";
+    let forms = parse_module(FileId(0), string).unwrap();
+    let date = match forms[0].node {
+        Form::Data(ref data) => data
+      , ref other => panic!("expected a data definition, got {:?}", other)
+    };
+    let i64_ty = Type::Prim(Primitive::Int(Int::Int64));
+    match (&forms[1].node, &forms[2].node) {
+        (&Form::Field(ref day), &Form::Field(ref dya)) => {
+            assert_eq!(day.check(date), Ok(i64_ty.clone()));
+            let errors = dya.check(date).unwrap_err();
+            assert!(errors[0].value.contains("no field dya"), "{}", errors[0]);
+            assert_eq!(errors[0].pos.start, string.find("dya").unwrap());
+        }
+      , other => panic!("expected field accesses, got {:?}", other)
+    }
+    match forms[3].node {
+        Form::With(ref update) => {
+            let errors = update.check(date).unwrap_err();
+            assert_eq!(errors.len(), 3);
+            assert!(errors[0].value.contains("no field yaer"), "{}", errors[0]);
+            assert!(errors[1].value.contains("more than once"), "{}", errors[1]);
+        }
+      , ref other => panic!("expected a record update, got {:?}", other)
+    }
+}
+
+expr_test!(test_match, "(match xs ([] 0) ([x:xs] x) (_ 1))");
+sugar_test!(test_case_is_match, "(case s ((Circle r) (* r r)) (Point 0))"
+           , "(match s ((Circle r) (* r r)) (Point 0))");
+sugar_test!(test_cond, "(cond ((< a b) x) ((> a b) y) (else z))"
+           , "(if (< a b) x (if (> a b) y z))");
+sugar_test!(test_cond_without_else, "(cond ((< a b) x) ((> a b) y))"
+           , "(if (< a b) x (if (> a b) y))");
+
+#[test]
+fn test_cond_clause_positions() {
+    let string = "(cond ((f a) x)\n      ((g b) y))";
+    let forms = parse_module(FileId(0), string).unwrap();
+    match forms[0].node {
+        Form::If { else_clause: Some(ref nested), .. } => {
+            let clause = "((g b) y)";
+            let start = string.find(clause).unwrap();
+            assert_eq!(nested.position.start, start);
+            assert_eq!(nested.position.end, start + clause.len());
+        }
+      , ref other => panic!("expected an if, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_match_arms() {
+    let string = "(match xs ([x:xs] x) (_ 0) (y y))";
+    let forms = parse_module(FileId(0), string).unwrap();
+    match forms[0].node {
+        Form::Match(ref form) => {
+            assert_eq!(form.arms.len(), 3);
+            assert_eq!(form.arms[1].position.start, string.find("(_ 0)").unwrap());
+            let catch_all = form.catch_all().unwrap();
+            assert_eq!(catch_all.to_sexpr(0), "(_ 0)");
+            assert_eq!(form.unreachable_arms().len(), 1);
+        }
+      , ref other => panic!("expected a match, got {:?}", other)
+    }
+}
+
+expr_test!(test_begin, "(begin (f x) (g y))");
+sugar_test!(test_do_is_begin, "(do (f x))", "(begin (f x))");
+expr_test!(test_set, "(set! x (+ x 1))");
+sugar_test!(test_mut_binding, "(let ((mut a int 1)) (set! a 2))"
+           , "(let [(mut a int 1)]\n(set! a 2))");
+
+#[test]
+fn test_set_checks() {
+    let string = "(let ((mut a int 1) (b int 2) (mut c &int d))\n\
+                  \t(set! a 2) (set! b 3) (set! c d) (set! e 4))";
+    let forms = parse_module(FileId(0), string).unwrap();
+    let root = SymbolTable::new();
+    match forms[0].node {
+        Form::Let(ref form @ LetForm::Let { .. }) => {
+            let scopes = form.scopes(&root);
+            let body = match *form { LetForm::Let { ref body, .. } => body
+                                   , _ => unreachable!() };
+            let results = body.iter().map(|expr| match expr.node {
+                Form::Set(ref set) => set.check(&scopes.body)
+              , ref other => panic!("expected set!, got {:?}", other)
+            }).collect::<Vec<_>>();
+            assert!(results[0].is_ok());
+            let immutable = results[1].clone().unwrap_err();
+            assert!(immutable[0].value.contains("immutable"), "{}", immutable[0]);
+            let borrowed = results[2].clone().unwrap_err();
+            assert!(borrowed[0].value.contains("borrowed"), "{}", borrowed[0]);
+            assert!(results[3].is_err());
+        }
+      , ref other => panic!("expected a let form, got {:?}", other)
+    }
+}
+
+/// Test that the sweet-expressions `$sweet` read the same as the
+/// S-expressions `$sexpr`.
+macro_rules! sweet_test {
+    ($name:ident, $sweet:expr, $sexpr:expr) => {
+        #[test]
+        fn $name() {
+            let translation = sweet::read(FileId(0), $sweet);
+            let (forms, errors) = translation.lower();
+            assert!(errors.is_empty(), "{:?}", errors);
+            let expected = parse_module(FileId(0), $sexpr).unwrap();
+            assert_eq!( forms.iter().map(|f| f.to_sexpr(0)).collect::<Vec<_>>()
+                      , expected.iter().map(|f| f.to_sexpr(0)).collect::<Vec<_>>())
+        }
+    }
+}
+
+sweet_test!(test_sweet_line, "my_fn 1 2", "(my_fn 1 2)");
+sweet_test!(test_sweet_single_datum, "my_fn\n(my_fn 1)", "my_fn (my_fn 1)");
+sweet_test!(test_sweet_indented_children
+  , "if (< a b)\n    my_fn a\n    b"
+  , "(if (< a b) (my_fn a) b)");
+sweet_test!(test_sweet_nested_blocks
+  , "def fac\n\
+     \tfn {int -> int}\n\
+     \t\t(0) 1\n\
+     \t\t(n) {n * (fac {n - 1})}"
+  , "(def fac (fn {int -> int} ((0) 1) ((n) {n * (fac {n - 1})})))");
+sweet_test!(test_sweet_multiline_parens
+  , "my_fn (my_other_fn a\n  b) c\nmy_fn d"
+  , "(my_fn (my_other_fn a b) c) (my_fn d)");
+sweet_test!(test_sweet_dedent_to_top_level
+  , "#!sweet\nmy_fn\n  a\n    b c\n  d\n\n# a comment\nother_fn e"
+  , "(my_fn (a (b c)) d) (other_fn e)");
+
+#[test]
+fn test_sweet_spans_refer_to_source() {
+    let string = "my_fn\n  a\n  (g b)";
+    let translation = sweet::read(FileId(0), string);
+    let (forms, errors) = translation.lower();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(&string[forms[0].position.start..forms[0].position.end], string);
+    match forms[0].node {
+        Form::App(ref app) => {
+            assert_eq!(&string[app.fun.pos.start..app.fun.pos.end], "my_fn");
+            let g = &app.params[1].position;
+            assert_eq!(&string[g.start..g.end], "(g b)");
+        }
+      , ref other => panic!("expected an application, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_sweet_syntax_error_position() {
+    let string = "my_fn\n  (if)";
+    let (_, errors) = sweet::read(FileId(0), string).lower();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(LineTable::new(string).position(errors[0].pos.start).row, 2);
+}
+
+#[test]
+fn test_sweet_inconsistent_dedent() {
+    let string = "my_fn\n    a\n  b";
+    let translation = sweet::read(FileId(0), string);
+    assert_eq!(translation.errors().len(), 1);
+    let err = &translation.errors()[0];
+    assert!(err.value.contains("dedent"), "{}", err);
+    assert_eq!(&string[err.pos.start..err.pos.end], "b");
+}
+
+#[test]
+fn test_is_sweet() {
+    assert!(sweet::is_sweet("main.smn", "my_fn 1"));
+    assert!(sweet::is_sweet("main.mn", "#!sweet\nmy_fn 1"));
+    assert!(!sweet::is_sweet("main.mn", "(my_fn 1)"));
+}
+
+#[test]
+fn test_load_sweet_and_sexpr_modules() {
+    let dir = module_dir("load-sweet",
+        &[ ("main.mn", "(import util)\n(my_fn (util_fn 1))")
+         , ("util.smn", "module util\n\
+                         \texport util_fn\n\
+                         \timport helpers\n\
+                         \tdef util_fn int 1")
+         , ("helpers.mn", "#!sweet\nhelper_fn 2")
+         ]);
+    let buffers = Buffers::new();
+    let mut loader = Loader::new(&buffers, vec![]);
+    loader.load_root(dir.join("main.mn")).unwrap();
+    let (modules, errors) = loader.modules();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!( modules.iter().map(|m| m.name.value.clone()).collect::<Vec<_>>()
+              , vec!["helpers", "util", "main"]);
+    assert_eq!(modules[0].body[0].to_sexpr(0), "(helper_fn 2)");
+    assert_eq!( modules[1].exporting.iter().map(|i| i.value.clone()).collect::<Vec<_>>()
+              , vec!["util_fn"]);
+}
+
+expr_test!(test_negation, "(- x)");
+expr_test!(test_bitwise_ops, "(bitwise-or (bitwise-and a 255) (<< b 8))");
+expr_test!(test_shift_right, "(>> x 2)");
+expr_test!(test_arith_operands, "(+ (f x) (if c 1 2) y)");
+expr_test!(test_plus_one_operand_is_call, "(+ x)");
+
+#[test]
+fn test_arith_is_num_expr() {
+    let forms = parse_module(FileId(0), "(+ 1 (* 2 x))\n{a - b}").unwrap();
+    for form in &forms {
+        match form.node {
+            Form::Num(NumExpr::BOp(_)) => {}
+          , ref other => panic!("expected an arithmetic expression, got {:?}"
+                                , other)
+        }
+    }
+}
+
+/// Expand macros and fold constants in `code`.
+fn fold(code: &str) -> Result<Vec<String>, Errors> {
+    use core::semantic::{ constants, macros };
+    macros::expand(parse_module(FileId(0), code).unwrap())
+        .and_then(constants::fold)
+        .map(|body| body.iter().map(|e| e.to_sexpr(0)).collect())
+}
+
+#[test]
+fn test_fold_constants() {
+    assert_eq!( fold("(* 2 (+ 3 4))\n(+ x (* 2 3))\n(- 10 2 3)\n(- 5)\n\
+                      (/ 1.0 4.0)\n(<< 1u 4u)\n(+ 1 2.0)").unwrap()
+              , vec!["14", "(+ x 6)", "5", "-5", "0.25", "16u", "(+ 1 2.0)"]);
+}
+
+#[test]
+fn test_fold_in_macro_expansion() {
+    let string = "(define-syntax sum (syntax-rules () ((_ x ...) (+ x ...))))\n\
+                  (sum 1 2 3)\n\
+                  (my_fn (sum a 2))";
+    assert_eq!(fold(string).unwrap(), vec!["6", "(my_fn (+ a 2))"]);
+}
+
+#[test]
+fn test_fold_division_by_zero() {
+    let string = "(+ 1 (/ 2 (- 1 1)))";
+    let errors = fold(string).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].value.contains("division by zero"), "{}", errors[0]);
+    assert_eq!(&string[errors[0].pos.start..errors[0].pos.end], "/");
+}
+
+#[test]
+fn test_fold_shift_overflow() {
+    let string = "(my_fn (<< 1 64) (>> 1 -1))";
+    let errors = fold(string).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].value.contains("shift by 64"), "{}", errors[0]);
+    assert_eq!(&string[errors[0].pos.start..errors[0].pos.end], "<<");
+    assert!(errors[1].value.contains("shift by -1"), "{}", errors[1]);
+    assert_eq!(&string[errors[1].pos.start..errors[1].pos.end], ">>");
+}
+
+#[test]
+fn test_fold_overflow() {
+    let errors = fold("(* 9223372036854775807 2)").unwrap_err();
+    assert!(errors[0].value.contains("overflows"), "{}", errors[0]);
+}
+
+expr_test!(test_less_than, "(< a b)");
+expr_test!(test_comparisons, "(== (<= a 1) (/= b (>= c d)))");
+expr_test!(test_and, "(and (> a b) c)");
+expr_test!(test_or, "(or a b)");
+expr_test!(test_if_comparison, "(if (< x 10) (+ x 1) x)");
+sugar_test!(test_logical_chain, "(and a b c)", "(and a (and b c))");
+sugar_test!(test_infix_comparison, "{a < {b + 1}}", "(< a (+ b 1))");
+sugar_test!(test_infix_logical, "{{a < b} or {b < c}}", "(or (< a b) (< b c))");
+expr_test!(test_three_way_compare_is_call, "(< a b c)");
+
+#[test]
+fn test_comparison_is_bool() {
+    let forms = parse_module(FileId(0), "(< a b)\n{a == b}").unwrap();
+    for form in &forms {
+        match form.node {
+            Form::Bool(ref cmp) =>
+                assert_eq!(cmp.ty(), Type::Prim(Primitive::Bool))
+          , ref other => panic!("expected a comparison, got {:?}", other)
+        }
+    }
+}
+
+#[test]
+fn test_or_is_disjunction() {
+    match parse_module(FileId(0), "(or a b)").unwrap()[0].node {
+        Form::Logical(Logical::Or { .. }) => {}
+      , ref other => panic!("expected a disjunction, got {:?}", other)
+    }
+}
+
+/// Resolve the scopes of the module `code`, and return the source code
+/// of each name in an error.
+fn scope_errors(code: &str) -> Vec<&str> {
+    let (module, errors) = module(code);
+    assert!(errors.is_empty(), "{:?}", errors);
+    match scope::annotate_module(module, vec![]) {
+        Ok(_) => vec![]
+      , Err(errors) => errors.iter()
+                             .map(|e| &code[e.pos.start..e.pos.end])
+                             .collect()
+    }
+}
+
+#[test]
+fn test_scope_sees_all_definitions() {
+    let (module, _) = module(
+        "(def f (fn (-> int int) ((n) (g n))))\n\
+         (def g (fn (-> int int) ((n) n)))");
+    let module = scope::annotate_module(module, vec![]).unwrap();
+    assert!(module.is_defined_here("f"));
+    assert!(module.is_defined_here("g"));
+    match module.node.body[0].node {
+        Form::Define(DefForm::Function { ref fun, .. }) => {
+            let call = &fun.node.equations[0].node.body[0];
+            assert!(call.is_defined_here("n"));
+            assert!(call.is_defined_here("g"));
+        }
+      , ref other => panic!("expected a function, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_scope_shares_tables() {
+    let (module, _) = module("(def f (fn (-> int int) ((n) (f n) (f 1))))");
+    let module = scope::annotate_module(module, vec![]).unwrap();
+    match module.node.body[0].node {
+        Form::Define(DefForm::Function { ref fun, .. }) => {
+            let body = &fun.node.equations[0].node.body;
+            let (a, b) = (body[0].scope(), body[1].scope());
+            assert_eq!( &*a as *const SymbolTable
+                      , &*b as *const SymbolTable);
+        }
+      , ref other => panic!("expected a function, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_scope_undefined_names() {
+    assert_eq!( scope_errors("(def f (fn (-> int int) ((n) (+ n m))))\n\
+                              (h 1)\n\
+                              (if {x < 1} (& x) (? x 0))")
+              , vec!["m", "h", "x", "x", "x"]);
+}
+
+#[test]
+fn test_scope_let_bindings() {
+    assert_eq!(scope_errors("(let ((a int 1) (b int a)) b)"), vec!["a"]);
+    assert!(scope_errors("(let* ((a int 1) (b int a)) b)").is_empty());
+    assert!(scope_errors("(letrec ((f int (g 1)) (g int (f 2))) f)").is_empty());
+    assert!(scope_errors("(let loop int ((i int 0)) (loop i))").is_empty());
+}
+
+#[test]
+fn test_scope_patterns() {
+    assert_eq!( scope_errors("(def Shape data (| (Circle int) Point))\n\
+                              (def f (fn (-> Shape int)\n\
+                              \t((s) (match s ((Circle r) r) (Point y) ((Square) 0)))))")
+              , vec!["y", "Square"]);
+}
+
+#[test]
+fn test_scope_match_arm_bindings() {
+    let code = "(def f (fn (-> int int) ((n) (match n (m (+ m 1))))))";
+    let (module, _) = module(code);
+    let module = scope::annotate_module(module, vec![]).unwrap();
+    match module.node.body[0].node {
+        Form::Define(DefForm::Function { ref fun, .. }) =>
+            match fun.node.equations[0].node.body[0].node {
+                Form::Match(ref form) => {
+                    assert!(form.arms[0].symbol_table().contains_key("m"));
+                    assert!(form.arms[0].node.body[0].symbol_table().contains_key("m"));
+                }
+              , ref other => panic!("expected a match, got {:?}", other)
+            }
+      , ref other => panic!("expected a function, got {:?}", other)
+    }
+    assert!(type_errors(code).is_empty());
+}
+
+#[test]
+fn test_scope_body_definitions() {
+    assert!(scope_errors("(begin (def x int 1) (f x))\n\
+                          (def f (fn (-> int int) ((n) n)))").is_empty());
+    assert_eq!(scope_errors("(begin (def x int 1))\n(f x)\n\
+                             (def f (fn (-> int int) ((n) n)))")
+              , vec!["x"]);
+}
+
+#[test]
+fn test_scope_duplicate_definition() {
+    assert_eq!(scope_errors("(def x int 1)\n(def x int 2)"), vec!["x"]);
+}
+
+#[test]
+fn test_scope_set_undefined() {
+    assert_eq!(scope_errors("(set! x 1)"), vec!["x"]);
+}
+
+#[test]
+fn test_scope_imports() {
+    let (util, _) = module("(module util (export util_fn) \
+                              (def util_fn int 1) (def hidden int 2))");
+    let (main, _) = module("(util_fn hidden)");
+    let util = scope::annotate_module(util, vec![]).unwrap();
+    let errors = scope::annotate_module(main, util.exported_symbols())
+                     .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].value.contains("undefined name hidden"), "{}", errors[0]);
+}
+
+/// Resolve the scopes of the module `code`, and infer its types.
+fn infer_types(code: &str)
+               -> (Scoped<Module<ScopedState>>, CompileResult<infer::Typing>) {
+    let (module, errors) = module(code);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let mut module = scope::annotate_module(module, vec![]).unwrap();
+    let typing = infer::check_module(&mut module);
+    (module, typing)
+}
+
+/// Infer the types of the module `code`, and return the source code at
+/// each error and note.
+fn type_errors(code: &str) -> Vec<&str> {
+    match infer_types(code).1 {
+        Ok(_) => vec![]
+      , Err(errors) => errors.iter()
+                             .map(|e| &code[e.pos.start..e.pos.end])
+                             .collect()
+    }
+}
+
+#[test]
+fn test_infer_well_typed() {
+    assert!(type_errors("(def fac (fn (-> int int)\n\
+                         \t((0) 1)\n\
+                         \t((n) (* n (fac (- n 1))))))").is_empty());
+    assert!(type_errors("(def half double (/ 1.0 2))").is_empty());
+}
+
+#[test]
+fn test_infer_mismatch() {
+    assert_eq!(type_errors("(def x int \"one\")"), vec!["\"one\"", "x"]);
+    let (_, typing) = infer_types("(def x int \"one\")");
+    let errors = typing.unwrap_err();
+    assert!( errors[0].value.contains("expected `int`, found `string`")
+           , "{}", errors[0]);
+    assert!(errors[1].value.starts_with("[note]"), "{}", errors[1]);
+}
+
+#[test]
+fn test_infer_operand_positions() {
+    assert_eq!( type_errors("(def f (fn (-> int bool) ((n) true)))\n\
+                             (def x int (+ 1 (f 2)))")
+              , vec!["(f 2)", "+"]);
+}
+
+#[test]
+fn test_infer_application() {
+    assert_eq!( type_errors("(def f (fn (-> int int) ((n) n)))\n\
+                             (def y bool (f true))")
+              , vec!["true", "f", "(f true)", "y"]);
+}
+
+#[test]
+fn test_infer_if_condition() {
+    assert_eq!(type_errors("(if 1 2 3)"), vec!["1", "(if 1 2 3)"]);
+    assert_eq!(type_errors("(if true 2 \"three\")"), vec!["\"three\"", "2"]);
+}
+
+#[test]
+fn test_infer_generalizes_let() {
+    assert!(type_errors("(def test bool\n\
+                         \t(let ((id (-> a a) (lambda (-> a a) ((x) x))))\n\
+                         \t\t(id 1)\n\
+                         \t\t(id true)))").is_empty());
+}
+
+#[test]
+fn test_infer_rigid_type_variables() {
+    assert!(type_errors("(def f (fn (-> (=> Num a) a a) ((x) (+ x 1))))").is_empty());
+    let (_, typing) = infer_types("(def f (fn (-> a a) ((x) (+ x 1))))");
+    let errors = typing.unwrap_err();
+    assert!(errors[0].value.contains("(=> Num a)"), "{}", errors[0]);
+    assert_eq!(type_errors("(def g (fn (-> a b) ((x) x)))"), vec!["x", "g"]);
+}
+
+#[test]
+fn test_infer_unknown_type_variables() {
+    assert_eq!(type_errors("(def x a 1)"), vec!["x"]);
+    assert_eq!( type_errors("(def f (fn (-> int int)\n\
+                             \t((n) (let ((m (List b) n)) n))))")
+              , vec!["m"]);
+    assert!(type_errors("(def f (fn (-> a a)\n\
+                         \t((x) (let ((y a x)) y))))").is_empty());
+    let (_, typing) = infer_types("(def x a 1)");
+    assert!(typing.unwrap_err()[0].value.contains("unknown type `a`"));
+}
+
+#[test]
+fn test_infer_predicates() {
+    let code = "(class Eq a (eq (-> a a bool)))\n\
+                (def same (fn (-> (=> Eq a) a a bool) ((x y) (eq x y))))\n\
+                (def test bool (same 1 2))";
+    let (_, typing) = infer_types(code);
+    let predicates = typing.unwrap().predicates;
+    assert_eq!(predicates.len(), 2);
+    // satisfied by the constraint on `same`
+    assert_eq!(predicates[0].class.value, "Eq");
+    assert_eq!(predicates[0].ty, Type::Var(String::from("a")));
+    assert_eq!(predicates[0].pos.start, code.find("eq x y").unwrap());
+    assert_eq!(predicates[1].class.value, "Eq");
+    assert_eq!(predicates[1].ty, Type::Prim(Primitive::IntSize));
+    assert_eq!(predicates[1].var, "a");
+    assert_eq!(predicates[1].pos.start, code.rfind("same").unwrap());
+
+    assert_eq!( type_errors("(class Eq a (eq (-> a a bool)))\n\
+                             (def same (fn (-> a a bool) ((x y) (eq x y))))")
+              , vec!["eq"]);
+}
+
+#[test]
+fn test_infer_instance_signatures() {
+    assert!(type_errors("(class Eq a (eq (-> a a bool)))\n\
+                         (instance Eq int\n\
+                         \t(def eq (fn (-> int int bool) ((a b) true))))").is_empty());
+    assert_eq!( type_errors("(class Eq a (eq (-> a a bool)))\n\
+                             (instance Eq int\n\
+                             \t(def eq (fn (-> int bool bool) ((a b) true))))")
+              , vec!["eq"]);
+}
+
+#[test]
+fn test_infer_unreachable_arms() {
+    assert_eq!( type_errors("(def f (fn (-> int int) ((n) (match n (m m) (0 1)))))")
+              , vec!["(0 1)", "(m m)"]);
+    assert!(type_errors("(def f (fn (-> int int) ((n) (match n (0 1) (m m)))))")
+                .is_empty());
+}
+
+#[test]
+fn test_infer_records_pattern_types() {
+    let (module, typing) = infer_types(
+        "(def f (fn (-> int int) ((n) (match n (m m)))))");
+    typing.unwrap();
+    match module.node.body[0].node {
+        Form::Define(DefForm::Function { ref fun, .. }) =>
+            match fun.node.equations[0].node.body[0].node {
+                Form::Match(ref form) =>
+                    match form.arms[0].node.body[0].get_type("m") {
+                        Some(&SymbolAnnotation::Value { ref ty, .. }) =>
+                            assert_eq!(*ty, Type::Prim(Primitive::IntSize))
+                      , other => panic!("expected a value, got {:?}", other)
+                    }
+              , ref other => panic!("expected a match, got {:?}", other)
+            }
+      , ref other => panic!("expected a function, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_infer_local_and_imported_records() {
+    assert_eq!( type_errors("(def f (fn (-> int int)\n\
+                             \t((n) (def P data '((x int)))\n\
+                             \t     (+ (: x (P n)) (: y (P n))))))")
+              , vec!["y"]);
+    let (geometry, _) = module("(module geometry (export Point)\n\
+                                \t(def Point data '((x int) (y int))))");
+    let (main, _) = module("(def f (fn (-> Point int) ((p) (: x (with p (y 1))))))");
+    let geometry = scope::annotate_module(geometry, vec![]).unwrap();
+    let mut main = scope::annotate_module(main, geometry.exported_symbols())
+                         .unwrap();
+    assert!(infer::check_module(&mut main).is_ok());
+    assert_eq!( type_errors("(def f (fn (-> Point int) ((p) (: x p))))")
+              , vec!["p"]);
+}
+
+#[test]
+fn test_infer_pattern_types_per_expansion() {
+    use core::semantic::macros;
+    let (module, _) = module(
+        "(define-syntax same (syntax-rules () ((_ e) (match e (v v)))))\n\
+         (def x int (same 1))\n\
+         (def y bool (same true))");
+    let body = macros::expand(module.body).unwrap();
+    let mut module = scope::annotate_module(Module { body: body, ..module }, vec![])
+                           .unwrap();
+    assert!(infer::check_module(&mut module).is_ok());
+}
+
+/// Infer the types of the module `code`, which must be well-typed, and
+/// resolve its typeclasses.
+fn resolve_instances(code: &str)
+                     -> (Scoped<Module<ScopedState>>, CompileResult<()>) {
+    let (mut module, typing) = infer_types(code);
+    let resolved = instances::resolve_module(&mut module, &typing.unwrap(), vec![])
+                             .map(|_| ());
+    (module, resolved)
+}
+
+#[test]
+fn test_resolve_specialises_constrained_functions() {
+    let (module, resolved) = resolve_instances(
+        "(class Eq a (eq (-> a a bool)))\n\
+         (instance Eq int\n\
+         \t(def eq (fn (-> int int bool) ((a b) true))))\n\
+         (def same (fn (-> (=> Eq a) a a bool) ((x y) (eq x y))))\n\
+         (def test bool (same 1 2))");
+    resolved.unwrap();
+    let names: Vec<&str> = module.node.body.iter().map(|expr| match expr.node {
+        Form::Define(ref def) => &def.name().value[..]
+      , ref other => panic!("expected a definition, got {:?}", other)
+    }).collect();
+    assert_eq!(names, vec!["Eq<int>.eq", "same<int>", "test"]);
+    assert!(module.get_type("same<int>").is_some());
+
+    match module.node.body[1].node {
+        Form::Define(DefForm::Function { ref fun, .. }) => {
+            assert_eq!(fun.node.sig.constraints, None);
+            assert_eq!(fun.node.sig.typechain[0], Type::Prim(Primitive::IntSize));
+            match fun.node.equations[0].node.body[0].node {
+                Form::App(ref app) => assert_eq!(app.fun.value, "Eq<int>.eq")
+              , ref other => panic!("expected an application, got {:?}", other)
+            }
+        }
+      , ref other => panic!("expected a function, got {:?}", other)
+    }
+    match module.node.body[2].node {
+        Form::Define(DefForm::TopLevel { ref value, .. }) => match value.node {
+            Form::App(ref app) => assert_eq!(app.fun.value, "same<int>")
+          , ref other => panic!("expected an application, got {:?}", other)
+        }
+      , ref other => panic!("expected a definition, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_resolve_imported_constrained_functions() {
+    let (util, errors) = module(
+        "(module util (export Eq same)\n\
+         \t(class Eq a (eq (-> a a bool)))\n\
+         \t(instance Eq int (def eq (fn (-> int int bool) ((a b) true))))\n\
+         \t(def same (fn (-> (=> Eq a) a a bool) ((x y) (eq x y)))))");
+    assert!(errors.is_empty(), "{:?}", errors);
+    let mut util = scope::annotate_module(util, vec![]).unwrap();
+    let util_typing = infer::check_module(&mut util).unwrap();
+    let (main, _) = module("(def test bool (same 1 2))");
+    let mut main = scope::annotate_module(main, util.exported_symbols())
+                         .unwrap();
+    let typing = infer::check_module(&mut main).unwrap();
+
+    // the importer wants a copy of `same`, which it only declares
+    let wanted = instances::resolve_module(&mut main, &typing, vec![]).unwrap();
+    assert_eq!( wanted.iter().map(|s| &s.mangled[..]).collect::<Vec<_>>()
+              , vec!["same<int>"]);
+    assert!(main.get_type("same<int>").is_some());
+    match main.node.body[0].node {
+        Form::Define(DefForm::TopLevel { ref value, .. }) => match value.node {
+            Form::App(ref app) => assert_eq!(app.fun.value, "same<int>")
+          , ref other => panic!("expected an application, got {:?}", other)
+        }
+      , ref other => panic!("expected a definition, got {:?}", other)
+    }
+
+    // and the module that defines it compiles the copy, though it never
+    // uses `same` itself
+    assert!(instances::resolve_module(&mut util, &util_typing, wanted)
+                     .unwrap()
+                     .is_empty());
+    let names: Vec<&str> = util.node.body.iter().filter_map(|expr| match expr.node {
+        Form::Define(ref def) => Some(&def.name().value[..])
+      , _ => None
+    }).collect();
+    assert_eq!(names, vec!["Eq<int>.eq", "same<int>"]);
+}
+
+#[test]
+fn test_resolve_uses_in_macro_expansions() {
+    use core::semantic::macros;
+    let (module, _) = module(
+        "(class Eq a (eq (-> a a bool)))\n\
+         (instance Eq int (def eq (fn (-> int int bool) ((a b) true))))\n\
+         (instance Eq bool (def eq (fn (-> bool bool bool) ((a b) true))))\n\
+         (define-syntax same (syntax-rules () ((_ x) (eq x x))))\n\
+         (def a bool (same 1))\n\
+         (def b bool (same true))");
+    let body = macros::expand(module.body).unwrap();
+    let mut module = scope::annotate_module(Module { body: body, ..module }, vec![])
+                           .unwrap();
+    let typing = infer::check_module(&mut module).unwrap();
+    instances::resolve_module(&mut module, &typing, vec![]).unwrap();
+    let calls: Vec<&str> = module.node.body.iter().filter_map(|expr| match expr.node {
+        Form::Define(DefForm::TopLevel { ref value, .. }) => match value.node {
+            Form::App(ref app) => Some(&app.fun.value[..])
+          , _ => None
+        }
+      , _ => None
+    }).collect();
+    assert_eq!(calls, vec!["Eq<int>.eq", "Eq<bool>.eq"]);
+}
+
+#[test]
+fn test_resolve_class_comparison() {
+    let (module, resolved) = resolve_instances(
+        "(class Eq a (== (-> a a bool)))\n\
+         (instance Eq int\n\
+         \t(def == (fn (-> int int bool) ((a b) true))))\n\
+         (def test bool (== 1 2))\n\
+         (def less bool (< 1 2))");
+    resolved.unwrap();
+    match module.node.body[1].node {
+        Form::Define(DefForm::TopLevel { ref value, .. }) => match value.node {
+            Form::App(ref app) => assert_eq!(app.fun.value, "Eq<int>.==")
+          , ref other => panic!("expected an application, got {:?}", other)
+        }
+      , ref other => panic!("expected a definition, got {:?}", other)
+    }
+    match module.node.body[2].node {
+        Form::Define(DefForm::TopLevel { ref value, .. }) => match value.node {
+            Form::Bool(_) => {}
+          , ref other => panic!("expected a comparison, got {:?}", other)
+        }
+      , ref other => panic!("expected a definition, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_resolve_missing_instance() {
+    let code = "(class Eq a (eq (-> a a bool)))\n\
+                (def test bool (eq true false))";
+    let errors = resolve_instances(code).1.unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!( errors[0].value.contains("no instance of Eq for `bool`")
+           , "{}", errors[0]);
+    assert_eq!(errors[0].pos.start, code.rfind("eq").unwrap());
+
+    let code = "(def inc (fn (-> (=> Num a) a a) ((x) (+ x 1))))\n\
+                (def y int (inc 2))\n\
+                (def z bool (inc true))";
+    let errors = resolve_instances(code).1.unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!( errors[0].value.contains("no instance of Num for `bool`")
+           , "{}", errors[0]);
+    assert_eq!(errors[0].pos.start, code.rfind("inc").unwrap());
+}
+
+#[test]
+fn test_resolve_overlapping_instances() {
+    let code = "(class Eq a (eq (-> a a bool)))\n\
+                (instance Eq int\n\
+                \t(def eq (fn (-> int int bool) ((a b) true))))\n\
+                (instance Eq int\n\
+                \t(def eq (fn (-> int int bool) ((a b) false))))";
+    let errors = resolve_instances(code).1.unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].value.contains("overlaps instance Eq int"), "{}", errors[0]);
+    assert_eq!(errors[0].pos.start, code.rfind("Eq int").unwrap());
+    assert!(errors[1].value.starts_with("[note]"), "{}", errors[1]);
+    assert_eq!(errors[1].pos.start, code.find("Eq int").unwrap());
+}
+
+#[test]
+fn test_resolve_instances_overlapping_imported_instances() {
+    let (util, _) = module(
+        "(module util (export Eq)\n\
+         \t(class Eq a (eq (-> a a bool)))\n\
+         \t(instance Eq int (def eq (fn (-> int int bool) ((a b) true)))))");
+    let util = scope::annotate_module(util, vec![]).unwrap();
+    let code = "(instance Eq int (def eq (fn (-> int int bool) ((a b) false))))";
+    let (main, _) = module(code);
+    let mut main = scope::annotate_module(main, util.exported_symbols()).unwrap();
+    let typing = infer::check_module(&mut main).unwrap();
+    let errors = instances::resolve_module(&mut main, &typing, vec![]).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!( errors[0].value.contains("overlaps the imported instance Eq int")
+           , "{}", errors[0]);
+    assert_eq!(errors[0].pos.start, code.find("Eq").unwrap());
+}
+
+/// Check the moves in the module `code`, which must be well-typed.
+fn check_moves(code: &str) -> CompileResult<()> {
+    let (module, typing) = infer_types(code);
+    typing.unwrap();
+    moves::check_module(&module)
+}
+
+const CONSUME: &'static str =
+    "(def consume (fn (-> string string string) ((a b) a)))\n";
+
+#[test]
+fn test_moves_copied_values() {
+    assert!(check_moves("(def add (fn (-> int int int) ((a b) a)))\n\
+                         (def twice (fn (-> int int) ((n) (add n (* n n)))))")
+                .is_ok());
+}
+
+#[test]
+fn test_moves_double_move() {
+    let code = format!( "{}(def twice (fn (-> string string) ((s) (consume s s))))"
+                      , CONSUME);
+    let errors = check_moves(&code).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    let first = code.rfind("s s").unwrap();
+    assert!( errors[0].value.contains("cannot move s, since it has already been moved")
+           , "{}", errors[0]);
+    assert_eq!(errors[0].pos.start, first + 2);
+    assert_eq!(errors[1].value, "[note] s was moved here");
+    assert_eq!(errors[1].pos.start, first);
+}
+
+#[test]
+fn test_moves_in_branches() {
+    assert!(check_moves(&format!(
+        "{}(def pick (fn (-> bool string string)\n\
+         \t((c s) (if c (consume s \"a\") (consume s \"b\")))))", CONSUME)).is_ok());
+
+    let code = format!( "{}(def pick (fn (-> bool string string)\n\
+                         \t((c s) (consume (if c s \"a\") s))))", CONSUME);
+    let errors = check_moves(&code).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].pos.start, code.rfind("s)").unwrap());
+    assert!( errors[1].value.contains("may have been moved here, in one branch")
+           , "{}", errors[1]);
+    assert_eq!(errors[1].pos.start, code.rfind("c s").unwrap() + 2);
+}
+
+#[test]
+fn test_moves_across_equations() {
+    assert!(check_moves(&format!(
+        "{}(def f (fn (-> int string string)\n\
+         \t((0 s) (consume s \"zero\"))\n\
+         \t((n s) s)))", CONSUME)).is_ok());
+}
+
+#[test]
+fn test_moves_out_of_parts() {
+    let code = format!( "{}(def Box data (| (Full string) Empty))\n\
+                         (def keep (fn (-> string Box string) ((s b) s)))\n\
+                         (def f (fn (-> Box string)\n\
+                         \t((b) (keep (match b ((Full s) s) ((Empty) \"\")) b))))"
+                      , CONSUME);
+    let errors = check_moves(&code).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!( errors[0].value.contains("cannot move b, since it has already been moved")
+           , "{}", errors[0]);
+    assert_eq!(errors[0].pos.start, code.rfind("b)").unwrap());
+    assert_eq!(errors[1].pos.start, code.find("match b").unwrap() + 6);
+
+    let code = format!( "{}(def Person data '((name string) (age int)))\n\
+                         (def f (fn (-> Person string)\n\
+                         \t((p) (consume (: name p) (: name p)))))"
+                      , CONSUME);
+    let errors = check_moves(&code).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].pos.start, code.rfind("p)").unwrap());
+    assert_eq!(errors[1].pos.start, code.find("name p").unwrap() + 5);
+
+    // the parts that are copied leave the value in place
+    assert!(check_moves("(def Count data (| (Many int) Zero))\n\
+                         (def Person data '((name string) (age int)))\n\
+                         (def f (fn (-> Count Person int)\n\
+                         \t((c p) (+ (match c ((Many n) n) ((Zero) (: age p)))\n\
+                         \t           (match c ((Many n) (: age p)) ((Zero) 0))))))")
+                .is_ok());
+}
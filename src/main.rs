@@ -13,13 +13,20 @@ extern crate mnemosyne_parser as parser;
 use clap::{Arg, App, SubCommand};
 
 use std::error::Error;
-use std::io::Read;
-use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::process;
 use std::path::PathBuf;
 
 use mnemosyne::ast;
 use mnemosyne::ast::Node;
 use mnemosyne::errors::UnwrapICE;
+use mnemosyne::position::FileId;
+use mnemosyne::semantic::{ constants, infer, instances, macros, moves, scope };
+use mnemosyne::semantic::annotations::{ Scoped, ScopedState };
+use mnemosyne::semantic::instances::Specialisation;
+
+use parser::loader::{ Buffers, Loader };
 
 const VERSION_MAJOR: u32 = 0;
 const VERSION_MINOR: u32 = 1;
@@ -35,26 +42,92 @@ fn main() {
         .author("Hawk Weisman <hi@hawkweisman.me>")
         .about("[Mn] Manganese: The Mnemosyne Compilation System")
         .args_from_usage(
-            "<INPUT> 'Source code file to compile'
+            "<INPUT>... 'Source code files to compile'
+             -I --include=[DIR]... 'Add a directory to the module search path'
              -d, --debug 'Display debugging information'")
         .get_matches();
 
-    let path = matches.value_of("INPUT")
-                      .map(PathBuf::from)
-                      .unwrap();
+    let search_path = matches.values_of("include")
+                             .unwrap_or(vec![])
+                             .into_iter()
+                             .map(PathBuf::from)
+                             .collect();
+    let buffers = Buffers::new();
+    let mut loader = Loader::new(&buffers, search_path);
 
-    let code = File::open(&path)
-        .map_err(|error    | String::from(error.description()) )
-        .and_then(|mut file| {
-                let mut s = String::new();
-                file.read_to_string(&mut s)
-                    .map_err(|error| String::from(error.description()) )
-                    .map(|_| s)
-            })
-        .unwrap();
+    for input in matches.values_of("INPUT").unwrap() {
+        if let Err(error) = loader.load_root(input) {
+            writeln!( &mut io::stderr(), "[error] could not read {}: {}"
+                    , input, error.description())
+                .unwrap_ice();
+            process::exit(1)
+        }
+    }
 
-     let ast = parser::parse_module(code.as_ref())
-                     .unwrap();
+    let (modules, mut errs) = loader.modules();
+    // the modules are in dependency order, so the scopes of a module's
+    // imports are resolved before its own
+    let mut scoped: Vec<Scoped<ast::Module<ScopedState>>> = vec![];
+    let mut typings = vec![];
+    for module in modules {
+        let body = match macros::expand(module.body)
+                                .and_then(constants::fold) {
+            Ok(body) => body
+          , Err(mut e) => { errs.append(&mut e); continue }
+        };
+        // imports are resolved by file, since a module's header may give
+        // it a name other than the one it was imported by
+        let deps = loader.dependencies(module.name.pos.file);
+        let imported = scoped.iter()
+                             .filter(|m| deps.contains(&m.node.name.pos.file))
+                             .flat_map(|m| m.exported_symbols())
+                             .collect();
+        let module = ast::Module { body: body, ..module };
+        let mut module = match scope::annotate_module(module, imported) {
+            Ok(module) => module
+          , Err(mut e) => { errs.append(&mut e); continue }
+        };
+        let checked = infer::check_module(&mut module)
+                            .and_then(|typing| moves::check_module(&module)
+                                                     .map(|_| typing));
+        match checked {
+            Ok(typing) => { scoped.push(module); typings.push(typing) }
+          , Err(mut e) => errs.append(&mut e)
+        }
+    }
+    // typeclasses are resolved once every module has been checked, since
+    // importers see the constrained functions that resolution replaces.
+    // Importers are resolved first, so that the specialisations they want
+    // of imported functions are made by the modules that define them
+    let mut resolved = vec![];
+    let mut wanted: Vec<(FileId, Specialisation)> = vec![];
+    for (mut module, typing) in scoped.into_iter().zip(typings).rev() {
+        let file = module.node.name.pos.file;
+        let (requested, rest): (Vec<_>, Vec<_>) =
+            wanted.into_iter()
+                  .partition(|&(importer, ref spec)|
+                      loader.dependencies(importer).contains(&file)
+                          && module.node.exporting.iter().any(|e| e.value == spec.name));
+        wanted = rest;
+        let requested = requested.into_iter().map(|(_, spec)| spec).collect();
+        match instances::resolve_module(&mut module, &typing, requested) {
+            Ok(imported) => {
+                wanted.extend(imported.into_iter().map(|spec| (file, spec)));
+                resolved.push(module)
+            }
+          , Err(mut e) => errs.append(&mut e)
+        }
+    }
+    resolved.reverse();
+    if !errs.is_empty() {
+        for err in errs {
+            writeln!(&mut io::stderr(), "{}", loader.sources().describe(&err))
+                .unwrap_ice();
+        }
+        process::exit(1)
+    }
 
-    for node in ast { println!("{}", (*node).to_sexpr(0)) }
+    for module in resolved {
+        for node in &module.node.body { println!("{}", node.to_sexpr(0)) }
+    }
 }